@@ -34,11 +34,13 @@ pub fn derive_single_process_image(input: TokenStream) -> TokenStream {
     let mut pdo_regs = vec![];
     let mut running_size = 0usize;
     let mut pdo_mapping = std::collections::HashMap::new();
+    let mut var_fields = vec![];
 
     if let syn::Data::Struct(syn::DataStruct {
         fields: syn::Fields::Named(flds), ..
     }) = input.data {
         for field in flds.named {
+            var_fields.push((field.ident.clone().unwrap(), field.ty.clone(), false));
             let ty = field.ty.into_token_stream().to_string();
             let bitlen = match &*ty {
                 "u8"  | "i8"  => 8,
@@ -143,10 +145,17 @@ pub fn derive_single_process_image(input: TokenStream) -> TokenStream {
         quote!(Some(vec![#( #sync_infos ),*]))
     };
 
+    let vars_impl = image_vars_impl(&ident, &var_fields);
+
     let mut generated = quote! {
+        #vars_impl
+
         #[automatically_derived]
         impl ProcessImage for #ident {
             const SLAVE_COUNT: usize = 1;
+            fn image_vars() -> Vec<ethercat_plc::VarInfo> {
+                <Self as ethercat_plc::ImageVars>::vars()
+            }
             fn get_slave_ids() -> Vec<ethercat::SlaveId> { vec![#slave_id] }
             fn get_slave_pdos() -> Vec<Option<Vec<(ethercat::SmCfg, Vec<ethercat::PdoCfg>)>>> {
                 vec![#sync_infos]
@@ -236,11 +245,13 @@ pub fn derive_process_image(input: TokenStream) -> TokenStream {
     let mut slave_sdos = vec![];
     let mut slave_tys = vec![];
     let mut slave_ids = vec![];
+    let mut var_fields = vec![];
 
     if let syn::Data::Struct(syn::DataStruct {
         fields: syn::Fields::Named(flds), ..
     }) = input.data {
         for field in flds.named {
+            var_fields.push((field.ident.clone().unwrap(), field.ty.clone(), false));
             let mut single_sdos = vec![];
             let mut array_sdos = vec![];
             let mut id = None;
@@ -300,10 +311,17 @@ pub fn derive_process_image(input: TokenStream) -> TokenStream {
         return compile_error("only structs with named fields can be a process image");
     }
 
+    let vars_impl = image_vars_impl(&ident, &var_fields);
+
     let generated = quote! {
+        #vars_impl
+
         #[automatically_derived]
         impl ProcessImage for #ident {
             const SLAVE_COUNT: usize = #( <#slave_tys>::SLAVE_COUNT )+*;
+            fn image_vars() -> Vec<ethercat_plc::VarInfo> {
+                <Self as ethercat_plc::ImageVars>::vars()
+            }
             fn get_slave_ids() -> Vec<ethercat::SlaveId> {
                 let mut res = vec![]; #( res.extend(#slave_ids); )* res
             }
@@ -333,13 +351,91 @@ pub fn derive_extern_image(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as syn::DeriveInput);
     let ident = input.ident;
 
-    // currently a no-op, later: auto-generate Default from #[plc] attributes
+    let var_fields = match named_fields(input.data) {
+        Some(fields) => fields,
+        None => return compile_error("only structs with named fields can be an extern image"),
+    };
+    let vars_impl = image_vars_impl(&ident, &var_fields);
+
+    // later: auto-generate Default from #[plc] attributes
     let generated = quote! {
-        impl ExternImage for #ident {}
+        #vars_impl
+
+        impl ExternImage for #ident {
+            fn image_vars() -> Vec<ethercat_plc::VarInfo> {
+                <Self as ethercat_plc::ImageVars>::vars()
+            }
+        }
     };
     generated.into()
 }
 
+#[proc_macro_derive(ImageVars, attributes(plc))]
+pub fn derive_image_vars(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    let ident = input.ident;
+
+    match named_fields(input.data) {
+        Some(fields) => image_vars_impl(&ident, &fields).into(),
+        None => compile_error("ImageVars can only be derived for structs with named fields"),
+    }
+}
+
+/// Get the fields of a struct, with a flag whether they are marked with
+/// `#[plc(read_only)]`.
+fn named_fields(data: syn::Data) -> Option<Vec<(syn::Ident, syn::Type, bool)>> {
+    if let syn::Data::Struct(syn::DataStruct {
+        fields: syn::Fields::Named(flds), ..
+    }) = data {
+        Some(flds.named.into_iter().map(|f| {
+            let read_only = f.attrs.iter().any(|attr| {
+                attr.path.is_ident("plc") && matches!(
+                    attr.parse_meta(),
+                    Ok(syn::Meta::List(syn::MetaList { nested, .. })) if nested.iter().any(
+                        |n| matches!(n, syn::NestedMeta::Meta(syn::Meta::Path(p))
+                                     if p.is_ident("read_only")))
+                )
+            });
+            (f.ident.unwrap(), f.ty, read_only)
+        }).collect())
+    } else {
+        None
+    }
+}
+
+/// Generate an `ImageVars` impl that describes each field at its offset
+/// inside the struct.  This also works for packed structs.
+fn image_vars_impl(ident: &syn::Ident, fields: &[(syn::Ident, syn::Type, bool)]) -> TokenStream2 {
+    let names = fields.iter().map(|f| &f.0).collect::<Vec<_>>();
+    let name_strs = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let tys = fields.iter().map(|f| &f.1);
+    let read_only = fields.iter().map(|f| f.2);
+    quote! {
+        #[automatically_derived]
+        impl ethercat_plc::ImageVars for #ident {
+            #[allow(unused_variables)]
+            fn collect_vars(prefix: &str, offset: usize, vars: &mut Vec<ethercat_plc::VarInfo>) {
+                #[allow(unused_imports)]
+                use ethercat_plc::{CollectFieldVars as _, SkipFieldVars as _};
+                let image = std::mem::MaybeUninit::<Self>::uninit();
+                let base = image.as_ptr();
+                #(
+                    let field_offset = unsafe {
+                        std::ptr::addr_of!((*base).#names) as usize - base as usize
+                    };
+                    let first = vars.len();
+                    (&ethercat_plc::FieldVars::<#tys>(std::marker::PhantomData)).collect_field_vars(
+                        &ethercat_plc::join_var_name(prefix, #name_strs),
+                        offset + field_offset, vars);
+                    if #read_only {
+                        vars[first..].iter_mut().for_each(|var| var.read_only = true);
+                    }
+                )*
+            }
+        }
+    }
+}
+
 fn compile_error(message: impl Into<String>) -> TokenStream {
     let message = message.into();
     quote!(compile_error! { #message }).into()
@@ -0,0 +1,17 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Fuzz the AMS/TCP framing and ADS command decoding of the `AdsHandler`.
+//!
+//! Run with `cargo fuzz run ads` from the `ethercat-plc` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ethercat_plc::{AdsConfig, AdsHandler};
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::run::<AdsHandler>(&AdsConfig { process: true }, data);
+});
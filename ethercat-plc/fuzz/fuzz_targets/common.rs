@@ -0,0 +1,79 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Driver for fuzzing the protocol handlers of the `TcpServer`.
+
+// not every target uses all of it
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use ethercat_plc::{Handler, PlcInfo, Request, Response, VarInfo, VarType};
+
+/// Size of the extern image described by `info`.
+const SIZE: usize = 16;
+
+/// PLC info with variables of several types, and no process image.
+pub fn info() -> Arc<PlcInfo> {
+    Arc::new(PlcInfo {
+        name: "fuzz".into(),
+        version: "1.0".into(),
+        extern_vars: vec![
+            VarInfo::new("magic", 0, VarType::F32),
+            VarInfo::new("dev.value", 4, VarType::I16),
+            VarInfo::new("dev.flags[0]", 6, VarType::U8),
+            VarInfo::new("dev.flags[1]", 7, VarType::U8),
+            VarInfo::new("limit", 8, VarType::F64),
+        ],
+        ..Default::default()
+    })
+}
+
+/// Answer a request like the PLC would, with an extern image of all ones.
+fn answer<X>(req: Request<X>) -> Response<X> {
+    let (addr, count) = req.read_back.unwrap_or((req.addr, req.count));
+    if req.lock.is_some() {
+        Response::Ok(req, Vec::new())
+    } else if addr.checked_add(count).map_or(true, |end| end > SIZE) ||
+        req.addr.checked_add(req.count).map_or(true, |end| end > SIZE)
+    {
+        Response::Error(req, 2)
+    } else {
+        Response::Ok(req, vec![1; count])
+    }
+}
+
+/// Feed the data to a new handler in two parts, answering its requests
+/// after each, as the server would do with data arriving in two reads.
+pub fn run<H: Handler>(config: &H::Config, data: &[u8]) {
+    drive::<H>(info(), config, data, 3, answer);
+}
+
+/// Like `run`, with the given PLC info and answers of the PLC, and the
+/// number of cycles to run after each part.
+pub fn drive<H: Handler>(info: Arc<PlcInfo>, config: &H::Config, data: &[u8], cycles: usize,
+                         mut answer: impl FnMut(Request<H::Extra>) -> Response<H::Extra>) {
+    let mut handler = H::new(1, info, config);
+    let mut output = Vec::new();
+    let mut pos = 0;
+    for end in [data.len() / 2, data.len()] {
+        let mut requests = Vec::new();
+        match handler.receive(&data[pos..end], &mut requests, &mut output) {
+            Ok(used) => {
+                assert!(used <= end - pos);
+                pos += used;
+            }
+            Err(_) => return,
+        }
+        // handlers may keep asking, e.g. for subscriptions
+        for _ in 0..cycles {
+            for req in requests.drain(..) {
+                handler.respond(answer(req), &mut output);
+            }
+            handler.poll(&mut requests);
+        }
+        handler.direct_replies();
+        if handler.finished() {
+            return;
+        }
+    }
+}
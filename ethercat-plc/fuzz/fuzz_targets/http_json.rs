@@ -0,0 +1,25 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Fuzz the JSON parser and the encoding of variable writes, with the data
+//! as body of a write request to the `HttpHandler`.
+//!
+//! Run with `cargo fuzz run http_json` from the `ethercat-plc` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ethercat_plc::{HttpConfig, HttpHandler};
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte selects the method and variable
+    let (select, body) = data.split_first().unwrap_or((&0, &[]));
+    let method = if select & 1 == 0 { "PUT" } else { "PATCH" };
+    let path = ["", "/dev", "/dev/flags", "/magic"][(select >> 1) as usize % 4];
+    let mut input = format!("{} /extern{} HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                            method, path, body.len()).into_bytes();
+    input.extend_from_slice(body);
+    common::run::<HttpHandler>(&HttpConfig::default(), &input);
+});
@@ -0,0 +1,18 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Fuzz the HTTP request parser and the JSON API of the `HttpHandler`.
+//!
+//! Run with `cargo fuzz run http_request` from the `ethercat-plc` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ethercat_plc::{HttpConfig, HttpHandler};
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let config = HttpConfig { process: true, ..Default::default() };
+    common::run::<HttpHandler>(&config, data);
+});
@@ -0,0 +1,31 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Fuzz the Modbus/TCP frame and request parsers.
+//!
+//! Run with `cargo fuzz run modbus_request` from the `ethercat-plc` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ethercat_plc::modbus::{MbapHeader, ModbusRequest, MBAP_SIZE, MAX_PDU};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() >= MBAP_SIZE {
+        let mut head = [0; MBAP_SIZE];
+        head.copy_from_slice(&data[..MBAP_SIZE]);
+        if let Ok(head) = MbapHeader::parse(&head) {
+            assert!(head.pdu_len >= 1 && head.pdu_len <= MAX_PDU);
+            let mut buf = Vec::new();
+            head.encode(&mut buf);
+            assert_eq!(MbapHeader::parse(buf[..].try_into().unwrap()), Ok(head));
+        }
+    }
+    if let Ok(req) = ModbusRequest::parse(data) {
+        // every valid request must survive an encode/parse round trip
+        let mut buf = Vec::new();
+        req.encode(&mut buf);
+        assert!(buf.len() <= MAX_PDU);
+        assert_eq!(ModbusRequest::parse(&buf), Ok(req));
+    }
+});
@@ -0,0 +1,33 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Fuzz the packet decoding of the `MqttClient` and its command messages,
+//! with the data received from the broker.
+//!
+//! Run with `cargo fuzz run mqtt` from the `ethercat-plc` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ethercat_plc::mqtt::{self, Payload};
+use ethercat_plc::{MqttCommand, MqttConfig, PlcInfo, VarInfo, VarType};
+
+fuzz_target!(|data: &[u8]| {
+    let info = PlcInfo {
+        extern_vars: vec![
+            VarInfo::new("dev.value", 0, VarType::I16),
+            VarInfo::new("dev.flags[0]", 2, VarType::U8),
+            VarInfo::new("dev.flags[1]", 3, VarType::U8),
+            VarInfo::new("limit", 8, VarType::F64),
+        ],
+        ..Default::default()
+    };
+    let config = MqttConfig {
+        commands: vec![
+            MqttCommand::new("dev", "a"),
+            MqttCommand { payload: Payload::Raw, ..MqttCommand::new("limit", "b") },
+        ],
+        ..Default::default()
+    };
+    mqtt::receive(&info, config, data);
+});
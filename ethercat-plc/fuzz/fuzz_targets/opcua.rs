@@ -0,0 +1,41 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Fuzz the chunking, secure channel and service decoding of the
+//! `OpcUaHandler`, with the data sent after a hello and an open secure
+//! channel request (secure channel ID 1).
+//!
+//! Run with `cargo fuzz run opcua` from the `ethercat-plc` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ethercat_plc::{OpcUaConfig, OpcUaHandler};
+
+mod common;
+
+const POLICY_NONE: &str = "http://opcfoundation.org/UA/SecurityPolicy#None";
+
+fn chunk(kind: &[u8], body: &[u8]) -> Vec<u8> {
+    [kind, b"F", &(body.len() as u32 + 8).to_le_bytes(), body].concat()
+}
+
+fn prefix() -> Vec<u8> {
+    let u32s = |vs: &[u32]| vs.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+    let url = "opc.tcp://localhost:4840";
+    let hello = [&u32s(&[0, 65536, 65536, 0, 0, url.len() as u32])[..], url.as_bytes()].concat();
+    let open = [
+        // channel ID, policy, no certificate and thumbprint, sequence and request ID
+        &u32s(&[0, POLICY_NONE.len() as u32])[..], POLICY_NONE.as_bytes(),
+        &u32s(&[u32::MAX, u32::MAX, 1, 1]),
+        // OpenSecureChannelRequest, with a request header without extension object
+        &[1, 0, 0xbe, 0x01, 0, 0], &[0; 8 + 4 + 4], &u32s(&[u32::MAX, 0]), &[0, 0, 0],
+        // version, issue, mode none, empty nonce, lifetime
+        &u32s(&[0, 0, 1, 0, 60000]),
+    ].concat();
+    [chunk(b"HEL", &hello), chunk(b"OPN", &open)].concat()
+}
+
+fuzz_target!(|data: &[u8]| {
+    common::run::<OpcUaHandler>(&OpcUaConfig::default(), &[&prefix()[..], data].concat());
+});
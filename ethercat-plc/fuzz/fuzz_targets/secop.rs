@@ -0,0 +1,78 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Fuzz the request lines of the `SecopHandler`, with a PLC whose indexer
+//! lists a `DiscreteOutput` named `Motor`.
+//!
+//! Run with `cargo fuzz run secop` from the `ethercat-plc` directory.
+
+#![no_main]
+
+use std::sync::Arc;
+use libfuzzer_sys::fuzz_target;
+use ethercat_plc::mlz_spec::{DISCRETE_OUTPUT, IDLE, MAGIC};
+use ethercat_plc::{PlcInfo, Request, Response, SecopConfig, SecopHandler, VarInfo, VarType};
+
+mod common;
+
+const INDEXER: usize = 8;
+const INDEXER_SIZE: usize = 24;
+const MOTOR: usize = 32;
+
+/// Answer the indexer request in the image, as done by the PLC every cycle.
+fn cycle(image: &mut [u8]) {
+    let request = u16::from_ne_bytes([image[INDEXER], image[INDEXER + 1]]);
+    if request & 0x8000 != 0 {
+        return;
+    }
+    let mut data = [0; INDEXER_SIZE - 2];
+    match request {
+        0x0100 => data[..2].copy_from_slice(&(INDEXER_SIZE as u16).to_ne_bytes()),
+        0x0400 => data[..4].copy_from_slice(b"fuzz"),
+        0x0001 => {
+            data[..2].copy_from_slice(&DISCRETE_OUTPUT.to_ne_bytes());
+            data[4..6].copy_from_slice(&(MOTOR as u16).to_ne_bytes());
+            data[8] = 0b11;
+            data[16..20].copy_from_slice(&100f32.to_ne_bytes());
+            data[20..].copy_from_slice(b"Mo");
+        }
+        0x0401 => data[..5].copy_from_slice(b"Motor"),
+        0x1001 => data[..4].copy_from_slice(b"busy"),
+        _ => (),
+    }
+    image[INDEXER..INDEXER + 2].copy_from_slice(&(request | 0x8000).to_ne_bytes());
+    image[INDEXER + 2..INDEXER + INDEXER_SIZE].copy_from_slice(&data);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let info = Arc::new(PlcInfo {
+        name: "fuzz".into(),
+        extern_vars: vec![
+            VarInfo::new("motor.value", MOTOR, VarType::I16),
+            VarInfo::new("motor.target", MOTOR + 2, VarType::I16),
+            VarInfo::new("motor.status", MOTOR + 4, VarType::U16),
+        ],
+        ..Default::default()
+    });
+    let mut image = vec![0; MOTOR + 6];
+    image[..4].copy_from_slice(&MAGIC.to_ne_bytes());
+    image[4..6].copy_from_slice(&(INDEXER as u16).to_ne_bytes());
+    image[MOTOR + 4..].copy_from_slice(&IDLE.to_ne_bytes());
+
+    // reading the description takes a query per cycle
+    common::drive::<SecopHandler>(info, &SecopConfig::default(), data, 12, |req: Request<_>| {
+        let (addr, count) = req.read_back.unwrap_or((req.addr, req.count));
+        if addr + count > image.len() || req.addr + req.count > image.len() {
+            return Response::Error(req, 2);
+        }
+        if let Some(write) = &req.write {
+            for (i, byte) in write.iter().enumerate() {
+                let mask = req.mask.as_ref().map_or(0xFF, |mask| mask[i]);
+                image[req.addr + i] = image[req.addr + i] & !mask | byte & mask;
+            }
+        }
+        cycle(&mut image);
+        let data = image[addr..addr + count].to_vec();
+        Response::Ok(req, data)
+    });
+});
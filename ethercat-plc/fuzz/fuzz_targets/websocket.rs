@@ -0,0 +1,22 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Fuzz the WebSocket framing and subscriptions of the `HttpHandler`, with
+//! the data received after the upgrade.
+//!
+//! Run with `cargo fuzz run websocket` from the `ethercat-plc` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ethercat_plc::{HttpConfig, HttpHandler};
+
+mod common;
+
+const UPGRADE: &[u8] = b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+fuzz_target!(|data: &[u8]| {
+    let config = HttpConfig { process: true, ..Default::default() };
+    common::run::<HttpHandler>(&config, &[UPGRADE, data].concat());
+});
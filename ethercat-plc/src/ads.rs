@@ -0,0 +1,824 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! A server for the ADS protocol of Beckhoff TwinCAT over AMS/TCP (usually
+//! on port 48898), so that TwinCAT tools and libraries like pyads can talk
+//! to the PLC as if it were a TwinCAT runtime.
+//!
+//! The supported commands are ReadDeviceInfo, ReadState, Read, Write and
+//! ReadWrite; WriteControl and device notifications are rejected.  Requests
+//! are answered for any target AMS Net ID and port, with source and target
+//! swapped, so no routes need to be set up.
+//!
+//! The index groups are:
+//!
+//! * `0x4020` - the extern image (`%M`), with the byte address as offset
+//! * `0xF020` - the process image (`%I`, read-only), if enabled
+//! * `0xF003` - get a handle for a symbol name (ReadWrite)
+//! * `0xF004` - read a value by symbol name (ReadWrite)
+//! * `0xF005` - read or write a value by handle, given as offset
+//! * `0xF006` - release a handle (Write)
+//! * `0xF009` - get the symbol entry for a name (ReadWrite)
+//! * `0xF00B`, `0xF00C`, `0xF00F` - symbol upload, and its size info
+//! * `0xF080`, `0xF081`, `0xF082` - sum commands: a list of reads, writes
+//!   or read-writes to the groups above in one ReadWrite command
+//!
+//! The symbols are the variables of the extern image, with their names as
+//! in `VarInfo` (e.g. `indexer.data[3]`), and those of the process image
+//! with a `process.` prefix.  As in TwinCAT, names are case-insensitive.
+//! Only primitive variables are uploaded as symbols, but handles and values
+//! by name can also refer to structs and arrays as a whole.
+//!
+//! Values are transferred in the byte order of the images, which matches
+//! ADS on little-endian hosts.
+
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use byteorder::{ByteOrder, LE};
+use log::*;
+
+use crate::image::{VarInfo, VarType};
+use crate::server::{Area, Handler, PlcInfo, Request, Response};
+
+/// Size of the AMS header.
+const AMS_HEADER: usize = 32;
+/// Maximum size of an AMS packet.
+const MAX_PACKET: usize = 1 << 20;
+/// Maximum number of commands in a sum command.
+const MAX_SUM: usize = 500;
+
+const CMD_DEVICE_INFO: u16 = 1;
+const CMD_READ: u16 = 2;
+const CMD_WRITE: u16 = 3;
+const CMD_READ_STATE: u16 = 4;
+const CMD_READ_WRITE: u16 = 9;
+
+/// State flags of responses: response and ADS command.
+const FLAGS_RESPONSE: u16 = 0x0005;
+
+const GROUP_EXTERN: u32 = 0x4020;
+const GROUP_PROCESS: u32 = 0xF020;
+const GROUP_HANDLE_BY_NAME: u32 = 0xF003;
+const GROUP_VALUE_BY_NAME: u32 = 0xF004;
+const GROUP_VALUE_BY_HANDLE: u32 = 0xF005;
+const GROUP_RELEASE_HANDLE: u32 = 0xF006;
+const GROUP_INFO_BY_NAME: u32 = 0xF009;
+const GROUP_UPLOAD: u32 = 0xF00B;
+const GROUP_UPLOAD_INFO: u32 = 0xF00C;
+const GROUP_TYPE_UPLOAD: u32 = 0xF00E;
+const GROUP_UPLOAD_INFO2: u32 = 0xF00F;
+const GROUP_SUM_READ: u32 = 0xF080;
+const GROUP_SUM_WRITE: u32 = 0xF081;
+const GROUP_SUM_READ_WRITE: u32 = 0xF082;
+
+const ERR_SERVICE_NOT_SUPPORTED: u32 = 0x701;
+const ERR_INVALID_GROUP: u32 = 0x702;
+const ERR_INVALID_OFFSET: u32 = 0x703;
+const ERR_INVALID_ACCESS: u32 = 0x704;
+const ERR_INVALID_SIZE: u32 = 0x705;
+const ERR_NOT_READY: u32 = 0x707;
+const ERR_BUSY: u32 = 0x708;
+const ERR_INVALID_PARAM: u32 = 0x70B;
+const ERR_SYMBOL_NOT_FOUND: u32 = 0x710;
+
+const STATE_RUN: u16 = 5;
+const STATE_STOP: u16 = 6;
+
+/// Symbol flag for read-only variables.
+const SYMBOL_READ_ONLY: u32 = 0x20;
+/// ADS data type of symbols that are not primitive.
+const ADST_BIGTYPE: u32 = 65;
+
+/// Configuration for the `AdsHandler`.
+#[derive(Debug, Clone, Default)]
+pub struct AdsConfig {
+    /// Also give access to the process image (read-only), at index group
+    /// `0xF020` and with symbols under `process.`.
+    pub process: bool,
+}
+
+/// The request data kept for the reply.
+#[derive(Debug, Default)]
+pub struct AdsExtra {
+    /// ID of the ADS command waiting for the request.
+    call: u32,
+    /// Index of the request's operation in the command.
+    index: usize,
+}
+
+/// The AMS header fields needed for the reply.
+#[derive(Clone, Copy)]
+struct AmsHeader {
+    /// Target Net ID and port.
+    target: [u8; 8],
+    /// Source Net ID and port.
+    source: [u8; 8],
+    command: u16,
+    invoke_id: u32,
+}
+
+/// How the results of the operations of a command are replied.
+enum Reply {
+    Read,
+    Write,
+    State,
+    /// Sum commands, with the requested read lengths.
+    SumRead(Vec<usize>),
+    SumWrite,
+    SumReadWrite,
+}
+
+/// An ADS command that waits for the PLC.
+struct Call {
+    header: AmsHeader,
+    reply: Reply,
+    results: Vec<Result<Vec<u8>, u32>>,
+    /// Number of requests to the PLC without response.
+    waiting: usize,
+}
+
+/// An operation of a command: either done right away, or a read or write
+/// (if data is given) of the PLC.
+enum Op {
+    Done(Result<Vec<u8>, u32>),
+    Plc(Area, usize, usize, Option<Vec<u8>>),
+}
+
+/// A symbol (or a struct or array of symbols) found by name.
+struct Symbol<'a> {
+    area: Area,
+    range: Range<usize>,
+    /// The variable, if primitive.
+    var: Option<&'a VarInfo>,
+}
+
+/// The ADS data type and IEC type name for a variable type.
+fn ads_type(ty: VarType) -> (u32, &'static str) {
+    match ty {
+        VarType::U8  => (17, "USINT"),
+        VarType::I8  => (16, "SINT"),
+        VarType::U16 => (18, "UINT"),
+        VarType::I16 => (2,  "INT"),
+        VarType::U32 => (19, "UDINT"),
+        VarType::I32 => (3,  "DINT"),
+        VarType::U64 => (21, "ULINT"),
+        VarType::I64 => (20, "LINT"),
+        VarType::F32 => (4,  "REAL"),
+        VarType::F64 => (5,  "LREAL"),
+    }
+}
+
+fn group_of(area: Area) -> u32 {
+    match area {
+        Area::Extern => GROUP_EXTERN,
+        Area::Process => GROUP_PROCESS,
+    }
+}
+
+/// Encode an ADS symbol entry.
+fn symbol_entry(buf: &mut Vec<u8>, name: &str, area: Area, range: &Range<usize>,
+                var: Option<&VarInfo>) {
+    let (ty, type_name) = var.map_or((ADST_BIGTYPE, ""), |var| ads_type(var.ty));
+    let read_only = area == Area::Process || var.map_or(false, |var| var.read_only);
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    for value in [group_of(area), range.start as u32, range.len() as u32, ty,
+                  if read_only { SYMBOL_READ_ONLY } else { 0 }] {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+    for len in [name.len(), type_name.len(), 0] {
+        buf.extend_from_slice(&(len as u16).to_le_bytes());
+    }
+    for s in [name, type_name, ""] {
+        buf.extend_from_slice(s.as_bytes());
+        buf.push(0);
+    }
+    let len = (buf.len() - start) as u32;
+    LE::write_u32(&mut buf[start..], len);
+}
+
+/// Whether the variable has the given name, or is a member of it, ignoring
+/// case.
+fn is_member(var: &VarInfo, name: &str) -> bool {
+    var.name.get(..name.len()).map_or(false, |prefix| prefix.eq_ignore_ascii_case(name)) &&
+        matches!(var.name.as_bytes().get(name.len()), None | Some(b'.') | Some(b'['))
+}
+
+/// The name given in the data of a request, which can be NUL-terminated.
+fn name_of(data: &[u8]) -> Option<&str> {
+    std::str::from_utf8(data.split(|&b| b == 0).next()?).ok()
+}
+
+/// The ADS error for an error code of the PLC.
+fn plc_error(code: u8, write: bool) -> u32 {
+    match code {
+        1 => ERR_INVALID_ACCESS,
+        // for writes, this is most likely a protected range
+        2 if write => ERR_INVALID_ACCESS,
+        2 => ERR_INVALID_OFFSET,
+        4 => ERR_NOT_READY,
+        6 => ERR_BUSY,
+        _ => ERR_INVALID_PARAM,
+    }
+}
+
+/// Data for a reply, at most `len` bytes of it.
+fn truncated(mut data: Vec<u8>, len: usize) -> Op {
+    data.truncate(len);
+    Op::Done(Ok(data))
+}
+
+/// Handles the ADS protocol for one connection.
+pub struct AdsHandler {
+    hid: usize,
+    info: Arc<PlcInfo>,
+    config: AdsConfig,
+    /// The encoded symbol entries for upload, and their number.
+    upload: Vec<u8>,
+    symbols: usize,
+    /// Handles given out to the client, with their area and byte range.
+    handles: HashMap<u32, (Area, Range<usize>)>,
+    next_handle: u32,
+    /// Commands waiting for the PLC.
+    calls: HashMap<u32, Call>,
+    next_call: u32,
+    /// Requests answered without the PLC, and errors among them.
+    direct: (usize, usize),
+}
+
+impl AdsHandler {
+    fn lookup(&self, name: &str) -> Option<Symbol<'_>> {
+        let (area, vars, name) = match name.get(..8) {
+            Some(prefix) if self.config.process && prefix.eq_ignore_ascii_case("process.") =>
+                (Area::Process, &self.info.process_vars, &name[8..]),
+            _ => (Area::Extern, &self.info.extern_vars, name),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        let mut members = vars.iter().filter(|var| is_member(var, name)).peekable();
+        let first = members.next()?;
+        if members.peek().is_none() && first.name.len() == name.len() {
+            return Some(Symbol { area, range: first.range(), var: Some(first) });
+        }
+        let range = members.fold(first.range(), |range, var| {
+            range.start.min(var.offset)..range.end.max(var.range().end)
+        });
+        Some(Symbol { area, range, var: None })
+    }
+
+    /// Resolve a read of `read_len` bytes, a write of `write` data or a
+    /// read-write of an index group and offset.
+    fn op(&mut self, group: u32, offset: u32, read_len: usize, write: Option<&[u8]>) -> Op {
+        let offset = offset as usize;
+        match group {
+            GROUP_EXTERN | GROUP_PROCESS => {
+                let area = if group == GROUP_EXTERN { Area::Extern } else { Area::Process };
+                if area == Area::Process && !self.config.process {
+                    return Op::Done(Err(ERR_INVALID_GROUP));
+                }
+                match write {
+                    Some(data) if read_len == 0 =>
+                        Op::Plc(area, offset, data.len(), Some(data.to_vec())),
+                    Some(_) => Op::Done(Err(ERR_INVALID_PARAM)),
+                    None => Op::Plc(area, offset, read_len, None),
+                }
+            }
+            GROUP_HANDLE_BY_NAME => {
+                let symbol = match write.and_then(name_of).and_then(|name| self.lookup(name)) {
+                    Some(_) if read_len < 4 => return Op::Done(Err(ERR_INVALID_SIZE)),
+                    Some(symbol) => (symbol.area, symbol.range),
+                    None => return Op::Done(Err(ERR_SYMBOL_NOT_FOUND)),
+                };
+                self.next_handle = self.next_handle.wrapping_add(1).max(1);
+                self.handles.insert(self.next_handle, symbol);
+                Op::Done(Ok(self.next_handle.to_le_bytes().to_vec()))
+            }
+            GROUP_RELEASE_HANDLE => match write {
+                Some(data) if data.len() == 4 => match self.handles.remove(&LE::read_u32(data)) {
+                    Some(_) => Op::Done(Ok(Vec::new())),
+                    None => Op::Done(Err(ERR_SYMBOL_NOT_FOUND)),
+                },
+                _ => Op::Done(Err(ERR_INVALID_SIZE)),
+            },
+            GROUP_VALUE_BY_HANDLE => {
+                let (area, range) = match self.handles.get(&(offset as u32)) {
+                    Some((area, range)) => (*area, range.clone()),
+                    None => return Op::Done(Err(ERR_SYMBOL_NOT_FOUND)),
+                };
+                match write {
+                    Some(data) if read_len == 0 && data.len() <= range.len() =>
+                        Op::Plc(area, range.start, data.len(), Some(data.to_vec())),
+                    None if read_len <= range.len() => Op::Plc(area, range.start, read_len, None),
+                    _ => Op::Done(Err(ERR_INVALID_SIZE)),
+                }
+            }
+            GROUP_VALUE_BY_NAME => match write.and_then(name_of).and_then(|name| self.lookup(name)) {
+                Some(symbol) if read_len <= symbol.range.len() =>
+                    Op::Plc(symbol.area, symbol.range.start, read_len, None),
+                Some(_) => Op::Done(Err(ERR_INVALID_SIZE)),
+                None => Op::Done(Err(ERR_SYMBOL_NOT_FOUND)),
+            },
+            GROUP_INFO_BY_NAME => {
+                let name = match write.and_then(name_of) {
+                    Some(name) => name,
+                    None => return Op::Done(Err(ERR_INVALID_PARAM)),
+                };
+                let mut entry = Vec::new();
+                match self.lookup(name) {
+                    Some(symbol) => symbol_entry(&mut entry, name, symbol.area, &symbol.range,
+                                                 symbol.var),
+                    None => return Op::Done(Err(ERR_SYMBOL_NOT_FOUND)),
+                }
+                truncated(entry, read_len)
+            }
+            _ if write.is_some() => Op::Done(Err(ERR_INVALID_ACCESS)),
+            GROUP_UPLOAD => truncated(self.upload.clone(), read_len),
+            GROUP_UPLOAD_INFO => {
+                let info = [self.symbols as u32, self.upload.len() as u32];
+                truncated(info.iter().flat_map(|v| v.to_le_bytes()).collect(), read_len)
+            }
+            GROUP_UPLOAD_INFO2 => {
+                // no data types and dynamic symbols
+                let info = [self.symbols as u32, self.upload.len() as u32, 0, 0, 0, 0];
+                truncated(info.iter().flat_map(|v| v.to_le_bytes()).collect(), read_len)
+            }
+            GROUP_TYPE_UPLOAD => Op::Done(Ok(Vec::new())),
+            _ => Op::Done(Err(ERR_INVALID_GROUP)),
+        }
+    }
+
+    /// Resolve the operations of a sum command, given as index group, with
+    /// `count` operations in `data`.
+    fn sum(&mut self, sum: u32, count: usize, data: &[u8]) -> Result<(Reply, Vec<Op>), u32> {
+        let head_len = if sum == GROUP_SUM_READ_WRITE { 16 } else { 12 };
+        if count == 0 || count > MAX_SUM {
+            return Err(ERR_INVALID_PARAM);
+        }
+        if data.len() < count * head_len {
+            return Err(ERR_INVALID_SIZE);
+        }
+        let (heads, mut rest) = data.split_at(count * head_len);
+        let mut ops = Vec::with_capacity(count);
+        let mut lens = Vec::with_capacity(count);
+        let mut total_read = 0;
+        for head in heads.chunks(head_len) {
+            let (group, offset) = (LE::read_u32(head), LE::read_u32(&head[4..]));
+            let len = LE::read_u32(&head[8..]) as usize;
+            let (read_len, write_len) = match sum {
+                GROUP_SUM_READ => (len, 0),
+                GROUP_SUM_WRITE => (0, len),
+                _ => (len, LE::read_u32(&head[12..]) as usize),
+            };
+            if rest.len() < write_len {
+                return Err(ERR_INVALID_SIZE);
+            }
+            let (write, tail) = rest.split_at(write_len);
+            rest = tail;
+            let write = if sum == GROUP_SUM_WRITE { Some(write) } else { read_write(write) };
+            // the reply has all requested lengths, also for errors
+            total_read += read_len;
+            if sum == GROUP_SUM_READ && total_read > MAX_PACKET {
+                return Err(ERR_INVALID_SIZE);
+            }
+            ops.push(self.op(group, offset, read_len, write));
+            lens.push(read_len);
+        }
+        let reply = match sum {
+            GROUP_SUM_READ => Reply::SumRead(lens),
+            GROUP_SUM_WRITE => Reply::SumWrite,
+            _ => Reply::SumReadWrite,
+        };
+        Ok((reply, ops))
+    }
+
+    /// Handle an ADS command; returns the reply and operations, or the
+    /// error to reply.
+    fn command(&mut self, header: &AmsHeader, data: &[u8]) -> Result<(Reply, Vec<Op>), u32> {
+        let u32_at = |pos: usize| data.get(pos..pos + 4).map(LE::read_u32).ok_or(ERR_INVALID_SIZE);
+        match header.command {
+            CMD_READ => {
+                let op = self.op(u32_at(0)?, u32_at(4)?, u32_at(8)? as usize, None);
+                Ok((Reply::Read, vec![op]))
+            }
+            CMD_WRITE => {
+                let len = u32_at(8)? as usize;
+                let write = data.get(12..12 + len).ok_or(ERR_INVALID_SIZE)?;
+                Ok((Reply::Write, vec![self.op(u32_at(0)?, u32_at(4)?, 0, Some(write))]))
+            }
+            CMD_READ_STATE => Ok((Reply::State, vec![Op::Plc(Area::Extern, 0, 0, None)])),
+            CMD_READ_WRITE => {
+                let (group, offset) = (u32_at(0)?, u32_at(4)?);
+                let (read_len, write_len) = (u32_at(8)? as usize, u32_at(12)? as usize);
+                let write = data.get(16..16 + write_len).ok_or(ERR_INVALID_SIZE)?;
+                match group {
+                    GROUP_SUM_READ | GROUP_SUM_WRITE | GROUP_SUM_READ_WRITE =>
+                        self.sum(group, offset as usize, write),
+                    _ => Ok((Reply::Read, vec![self.op(group, offset, read_len, read_write(write))])),
+                }
+            }
+            _ => Err(ERR_SERVICE_NOT_SUPPORTED),
+        }
+    }
+
+    fn device_info(&self) -> Vec<u8> {
+        let mut version = self.info.version.split('.').map(|v| v.parse::<u16>().unwrap_or(0));
+        let mut data = vec![0; 4];
+        data.push(version.next().unwrap_or(0) as u8);
+        data.push(version.next().unwrap_or(0) as u8);
+        data.extend_from_slice(&version.next().unwrap_or(0).to_le_bytes());
+        let mut name = [0; 16];
+        let len = self.info.name.len().min(15);
+        name[..len].copy_from_slice(&self.info.name.as_bytes()[..len]);
+        data.extend_from_slice(&name);
+        data
+    }
+
+    fn send(&self, header: &AmsHeader, data: &[u8], output: &mut Vec<u8>) {
+        output.extend_from_slice(&[0, 0]);
+        output.extend_from_slice(&((AMS_HEADER + data.len()) as u32).to_le_bytes());
+        output.extend_from_slice(&header.source);
+        output.extend_from_slice(&header.target);
+        output.extend_from_slice(&header.command.to_le_bytes());
+        output.extend_from_slice(&FLAGS_RESPONSE.to_le_bytes());
+        output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        output.extend_from_slice(&0u32.to_le_bytes());
+        output.extend_from_slice(&header.invoke_id.to_le_bytes());
+        output.extend_from_slice(data);
+    }
+}
+
+/// The write data of a read-write, which is only a read without data.
+fn read_write(data: &[u8]) -> Option<&[u8]> {
+    if data.is_empty() { None } else { Some(data) }
+}
+
+/// Encode the reply to a command from the results of its operations.
+fn encode(reply: &Reply, results: &[Result<Vec<u8>, u32>]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let put = |data: &mut Vec<u8>, value: u32| data.extend_from_slice(&value.to_le_bytes());
+    let code = |result: &Result<Vec<u8>, u32>| *result.as_ref().err().unwrap_or(&0);
+    match reply {
+        Reply::Read => match &results[0] {
+            Ok(values) => {
+                put(&mut data, 0);
+                put(&mut data, values.len() as u32);
+                data.extend_from_slice(values);
+            }
+            Err(code) => {
+                put(&mut data, *code);
+                put(&mut data, 0);
+            }
+        },
+        Reply::Write => put(&mut data, code(&results[0])),
+        Reply::State => {
+            // the zero-length read fails if the PLC has gone away
+            let state = if results[0].is_ok() { STATE_RUN } else { STATE_STOP };
+            put(&mut data, 0);
+            data.extend_from_slice(&state.to_le_bytes());
+            data.extend_from_slice(&[0, 0]);
+        }
+        Reply::SumRead(lens) => {
+            put(&mut data, 0);
+            put(&mut data, (4 * lens.len() + lens.iter().sum::<usize>()) as u32);
+            results.iter().for_each(|result| put(&mut data, code(result)));
+            for (result, &len) in results.iter().zip(lens) {
+                // values have the requested length, also for errors
+                let start = data.len();
+                data.extend_from_slice(result.as_deref().unwrap_or(&[]));
+                data.resize(start + len, 0);
+            }
+        }
+        Reply::SumWrite => {
+            put(&mut data, 0);
+            put(&mut data, 4 * results.len() as u32);
+            results.iter().for_each(|result| put(&mut data, code(result)));
+        }
+        Reply::SumReadWrite => {
+            let values = results.iter().map(|result| result.as_deref().unwrap_or(&[]));
+            put(&mut data, 0);
+            put(&mut data, (8 * results.len() + values.clone().map(<[u8]>::len).sum::<usize>()) as u32);
+            for (result, values) in results.iter().zip(values.clone()) {
+                put(&mut data, code(result));
+                put(&mut data, values.len() as u32);
+            }
+            values.for_each(|values| data.extend_from_slice(values));
+        }
+    }
+    data
+}
+
+impl Handler for AdsHandler {
+    type Extra = AdsExtra;
+    type Config = AdsConfig;
+
+    fn new(hid: usize, info: Arc<PlcInfo>, config: &AdsConfig) -> Self {
+        let mut upload = Vec::new();
+        for var in &info.extern_vars {
+            symbol_entry(&mut upload, &var.name, Area::Extern, &var.range(), Some(var));
+        }
+        let mut symbols = info.extern_vars.len();
+        if config.process {
+            for var in &info.process_vars {
+                symbol_entry(&mut upload, &format!("process.{}", var.name), Area::Process,
+                             &var.range(), Some(var));
+            }
+            symbols += info.process_vars.len();
+        }
+        AdsHandler { hid, info, config: config.clone(), upload, symbols, handles: HashMap::new(),
+                     next_handle: 0, calls: HashMap::new(), next_call: 0, direct: (0, 0) }
+    }
+
+    fn receive(&mut self, input: &[u8], requests: &mut Vec<Request<AdsExtra>>,
+               output: &mut Vec<u8>) -> io::Result<usize> {
+        let mut pos = 0;
+        while let Some(tcp_header) = input.get(pos..pos + 6) {
+            let len = LE::read_u32(&tcp_header[2..]) as usize;
+            if LE::read_u16(tcp_header) != 0 || !(AMS_HEADER..=MAX_PACKET).contains(&len) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid AMS/TCP header"));
+            }
+            let packet = match input.get(pos + 6..pos + 6 + len) {
+                Some(packet) => packet,
+                None => break,
+            };
+            pos += 6 + len;
+            let mut header = AmsHeader {
+                target: [0; 8], source: [0; 8], command: LE::read_u16(&packet[16..]),
+                invoke_id: LE::read_u32(&packet[28..]),
+            };
+            header.target.copy_from_slice(&packet[..8]);
+            header.source.copy_from_slice(&packet[8..16]);
+            let data_len = (LE::read_u32(&packet[20..]) as usize).min(len - AMS_HEADER);
+            let data = &packet[AMS_HEADER..AMS_HEADER + data_len];
+            debug!("client {}: ADS command {} with {} bytes", self.hid, header.command, data_len);
+
+            if header.command == CMD_DEVICE_INFO {
+                self.direct.0 += 1;
+                self.send(&header, &self.device_info(), output);
+                continue;
+            }
+            let (reply, ops) = match self.command(&header, data) {
+                Ok(command) => command,
+                Err(code) => {
+                    self.direct.0 += 1;
+                    self.direct.1 += 1;
+                    self.send(&header, &code.to_le_bytes(), output);
+                    continue;
+                }
+            };
+            self.next_call = self.next_call.wrapping_add(1);
+            let mut call = Call { header, reply, results: Vec::with_capacity(ops.len()), waiting: 0 };
+            for (index, op) in ops.into_iter().enumerate() {
+                match op {
+                    Op::Done(result) => call.results.push(result),
+                    Op::Plc(area, addr, count, write) => {
+                        call.results.push(Ok(Vec::new()));
+                        call.waiting += 1;
+                        requests.push(Request {
+                            hid: self.hid, area, addr, count, write, mask: None, read_back: None,
+                            lock: None, extra: AdsExtra { call: self.next_call, index }
+                        });
+                    }
+                }
+            }
+            if call.waiting == 0 {
+                self.direct.0 += 1;
+                if call.results.iter().any(Result::is_err) {
+                    self.direct.1 += 1;
+                }
+                self.send(&call.header, &encode(&call.reply, &call.results), output);
+            } else {
+                self.calls.insert(self.next_call, call);
+            }
+        }
+        Ok(pos)
+    }
+
+    fn respond(&mut self, response: Response<AdsExtra>, output: &mut Vec<u8>) {
+        let (req, result) = match response {
+            Response::Ok(req, _) if req.write.is_some() => (req, Ok(Vec::new())),
+            Response::Ok(req, data) => (req, Ok(data)),
+            Response::Error(req, code) => {
+                let code = plc_error(code, req.write.is_some());
+                (req, Err(code))
+            }
+        };
+        let call = match self.calls.get_mut(&req.extra.call) {
+            Some(call) => call,
+            None => return,
+        };
+        call.results[req.extra.index] = result;
+        call.waiting -= 1;
+        if call.waiting == 0 {
+            if let Some(call) = self.calls.remove(&req.extra.call) {
+                self.send(&call.header, &encode(&call.reply, &call.results), output);
+            }
+        }
+    }
+
+    fn direct_replies(&mut self) -> (usize, usize) {
+        std::mem::take(&mut self.direct)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::image::VarType;
+    use super::*;
+
+    const CMD_WRITE_CONTROL: u16 = 5;
+
+    fn handler(process: bool) -> AdsHandler {
+        let info = PlcInfo {
+            name: "test".into(),
+            version: "2.1.300".into(),
+            extern_vars: vec![
+                VarInfo::new("Speed", 0, VarType::F32),
+                VarInfo::new("dev.value", 4, VarType::I16),
+                VarInfo::new("dev.flags", 6, VarType::U16),
+            ],
+            process_vars: vec![VarInfo::new("inputs", 0, VarType::U8)],
+            ..Default::default()
+        };
+        AdsHandler::new(3, Arc::new(info), &AdsConfig { process })
+    }
+
+    fn packet(command: u16, invoke_id: u32, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0, 0];
+        packet.extend_from_slice(&((AMS_HEADER + data.len()) as u32).to_le_bytes());
+        packet.extend_from_slice(&[5, 1, 2, 3, 1, 1, 0x53, 0x03]);
+        packet.extend_from_slice(&[10, 0, 0, 1, 1, 1, 0x10, 0x80]);
+        packet.extend_from_slice(&command.to_le_bytes());
+        packet.extend_from_slice(&4u16.to_le_bytes());
+        packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&[0; 4]);
+        packet.extend_from_slice(&invoke_id.to_le_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    /// The data of the replies in the output, checking their headers.
+    fn replies(mut output: &[u8]) -> Vec<(u16, u32, Vec<u8>)> {
+        let mut replies = Vec::new();
+        while !output.is_empty() {
+            let len = LE::read_u32(&output[2..]) as usize;
+            let packet = &output[6..6 + len];
+            assert_eq!(packet[..8], [10, 0, 0, 1, 1, 1, 0x10, 0x80]);
+            assert_eq!(packet[8..16], [5, 1, 2, 3, 1, 1, 0x53, 0x03]);
+            assert_eq!(LE::read_u16(&packet[18..]), FLAGS_RESPONSE);
+            assert_eq!(LE::read_u32(&packet[20..]) as usize, len - AMS_HEADER);
+            replies.push((LE::read_u16(&packet[16..]), LE::read_u32(&packet[28..]),
+                          packet[AMS_HEADER..].to_vec()));
+            output = &output[6 + len..];
+        }
+        replies
+    }
+
+    /// Send the input, answering PLC requests from an image counting up
+    /// from 1, and return the replies.
+    fn exchange(h: &mut AdsHandler, input: &[u8]) -> Vec<(u16, u32, Vec<u8>)> {
+        let mut requests = Vec::new();
+        let mut output = Vec::new();
+        assert_eq!(h.receive(input, &mut requests, &mut output).unwrap(), input.len());
+        for req in requests {
+            let response = if req.addr + req.count > 8 {
+                Response::Error(req, 2)
+            } else {
+                let data = (req.addr as u8 + 1..).take(req.count).collect();
+                Response::Ok(req, data)
+            };
+            h.respond(response, &mut output);
+        }
+        replies(&output)
+    }
+
+    #[test]
+    fn framing() {
+        let mut h = handler(false);
+        let mut input = packet(CMD_DEVICE_INFO, 1, &[]);
+        input.extend(packet(CMD_READ_STATE, 2, &[]));
+        let partial = packet(CMD_DEVICE_INFO, 3, &[]);
+        input.extend_from_slice(&partial[..20]);
+        let mut output = Vec::new();
+        assert_eq!(h.receive(&input, &mut Vec::new(), &mut output).unwrap(), input.len() - 20);
+        assert_eq!(h.receive(&partial[..5], &mut Vec::new(), &mut output).unwrap(), 0);
+        let replies = replies(&output);
+        assert_eq!(replies.len(), 1);
+        let mut info = vec![0, 0, 0, 0, 2, 1, 44, 1];
+        info.extend_from_slice(b"test\0\0\0\0\0\0\0\0\0\0\0\0");
+        assert_eq!(replies[0], (CMD_DEVICE_INFO, 1, info));
+        assert_eq!(h.direct_replies(), (1, 0));
+
+        // the length of the AMS data is limited to the packet
+        let mut short = packet(CMD_READ, 4, &words(&[GROUP_UPLOAD_INFO, 0, 8]));
+        short[26] = 0xff;
+        let upload_info = words(&[0, 8, 3, h.upload.len() as u32]);
+        assert_eq!(exchange(&mut h, &short), [(CMD_READ, 4, upload_info)]);
+
+        for invalid in [&[1, 0, 32, 0, 0, 0][..], &[0, 0, 31, 0, 0, 0], &[0, 0, 1, 0, 16, 0]] {
+            assert!(h.receive(invalid, &mut Vec::new(), &mut Vec::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn read_write() {
+        let mut h = handler(false);
+        let mut input = packet(CMD_READ, 1, &words(&[GROUP_EXTERN, 2, 4]));
+        input.extend(packet(CMD_READ, 2, &words(&[GROUP_EXTERN, 6, 4])));
+        input.extend(packet(CMD_WRITE, 3, &[&words(&[GROUP_EXTERN, 4, 2])[..], &[7, 8]].concat()));
+        input.extend(packet(CMD_READ, 4, &words(&[GROUP_PROCESS, 0, 1])));
+        input.extend(packet(CMD_WRITE, 5, &words(&[GROUP_EXTERN, 4, 2])));
+        input.extend(packet(CMD_WRITE_CONTROL, 6, &[]));
+        input.extend(packet(CMD_READ_STATE, 7, &[]));
+        assert_eq!(exchange(&mut h, &input), [
+            (CMD_READ, 4, words(&[ERR_INVALID_GROUP, 0])),
+            (CMD_WRITE, 5, words(&[ERR_INVALID_SIZE])),
+            (CMD_WRITE_CONTROL, 6, words(&[ERR_SERVICE_NOT_SUPPORTED])),
+            (CMD_READ, 1, [&words(&[0, 4])[..], &[3, 4, 5, 6]].concat()),
+            (CMD_READ, 2, words(&[ERR_INVALID_OFFSET, 0])),
+            (CMD_WRITE, 3, words(&[0])),
+            (CMD_READ_STATE, 7, [&words(&[0])[..], &[5, 0, 0, 0]].concat()),
+        ]);
+        assert_eq!(h.direct_replies(), (3, 3));
+    }
+
+    #[test]
+    fn symbols() {
+        let mut h = handler(true);
+        let read_write = |id, group, read_len, name: &[u8]| {
+            let head = words(&[group, 0, read_len, name.len() as u32]);
+            packet(CMD_READ_WRITE, id, &[&head[..], name].concat())
+        };
+        let mut input = read_write(1, GROUP_HANDLE_BY_NAME, 4, b"DEV\0");
+        input.extend(read_write(2, GROUP_VALUE_BY_NAME, 4, b"speed"));
+        input.extend(read_write(3, GROUP_VALUE_BY_NAME, 5, b"speed"));
+        input.extend(read_write(4, GROUP_HANDLE_BY_NAME, 4, b"dev.val"));
+        input.extend(read_write(5, GROUP_VALUE_BY_NAME, 1, b"process.inputs"));
+        input.extend(packet(CMD_READ, 6, &words(&[GROUP_VALUE_BY_HANDLE, 1, 4])));
+        input.extend(packet(CMD_READ, 7, &words(&[GROUP_VALUE_BY_HANDLE, 1, 5])));
+        input.extend(packet(CMD_WRITE, 8, &words(&[GROUP_RELEASE_HANDLE, 0, 4, 1])));
+        input.extend(packet(CMD_READ, 9, &words(&[GROUP_VALUE_BY_HANDLE, 1, 4])));
+        let replies = exchange(&mut h, &input);
+        let data = |id| &replies.iter().find(|reply| reply.1 == id).unwrap().2;
+        assert_eq!(data(1), &words(&[0, 4, 1]));
+        assert_eq!(data(2), &[&words(&[0, 4])[..], &[1, 2, 3, 4]].concat());
+        assert_eq!(data(3), &words(&[ERR_INVALID_SIZE, 0]));
+        assert_eq!(data(4), &words(&[ERR_SYMBOL_NOT_FOUND, 0]));
+        assert_eq!(data(5), &[&words(&[0, 1])[..], &[1]].concat());
+        // the handle is for the whole struct
+        assert_eq!(data(6), &[&words(&[0, 4])[..], &[5, 6, 7, 8]].concat());
+        assert_eq!(data(7), &words(&[ERR_INVALID_SIZE, 0]));
+        assert_eq!(data(8), &words(&[0]));
+        assert_eq!(data(9), &words(&[ERR_SYMBOL_NOT_FOUND, 0]));
+
+        let replies = exchange(&mut h, &read_write(10, GROUP_INFO_BY_NAME, 100, b"dev.value"));
+        let mut entry = words(&[0, 0]);
+        symbol_entry(&mut entry, "dev.value", Area::Extern, &(4..6), Some(&h.info.extern_vars[1]));
+        let len = entry.len() as u32 - 8;
+        LE::write_u32(&mut entry[4..], len);
+        assert_eq!(replies[0].2, entry);
+
+        let replies = exchange(&mut h, &packet(CMD_READ, 11, &words(&[GROUP_UPLOAD_INFO, 0, 8])));
+        assert_eq!(replies[0].2, words(&[0, 8, 4, h.upload.len() as u32]));
+    }
+
+    #[test]
+    fn sum_commands() {
+        let mut h = handler(false);
+        let sum = |id, group, count, data: &[u8], read_len| {
+            let head = words(&[group, count, read_len, data.len() as u32]);
+            packet(CMD_READ_WRITE, id, &[&head[..], data].concat())
+        };
+        let reads = words(&[GROUP_EXTERN, 0, 2, GROUP_EXTERN, 7, 2, 0x1234, 0, 1]);
+        let writes = [&words(&[GROUP_EXTERN, 0, 1, GROUP_RELEASE_HANDLE, 0, 4])[..],
+                      &[9], &words(&[5])].concat();
+        let read_writes = [&words(&[GROUP_VALUE_BY_NAME, 0, 2, 5, GROUP_EXTERN, 4, 1, 0])[..],
+                           b"speed"].concat();
+        let mut input = sum(1, GROUP_SUM_READ, 3, &reads, 17);
+        input.extend(sum(2, GROUP_SUM_WRITE, 2, &writes, 8));
+        input.extend(sum(3, GROUP_SUM_READ_WRITE, 2, &read_writes, 19));
+        input.extend(sum(4, GROUP_SUM_READ, 0, &[], 0));
+        input.extend(sum(5, GROUP_SUM_READ, 4, &reads, 0));
+        input.extend(sum(6, GROUP_SUM_WRITE, 2, &writes[..28], 8));
+        let huge = words(&[GROUP_EXTERN, 0, MAX_PACKET as u32, GROUP_EXTERN, 0, 1]);
+        input.extend(sum(7, GROUP_SUM_READ, 2, &huge, 0));
+        let replies = exchange(&mut h, &input);
+        let data = |id| &replies.iter().find(|reply| reply.1 == id).unwrap().2;
+        assert_eq!(data(1), &[&words(&[0, 17, 0, ERR_INVALID_OFFSET, ERR_INVALID_GROUP])[..],
+                              &[1, 2, 0, 0, 0]].concat());
+        assert_eq!(data(2), &words(&[0, 8, 0, ERR_SYMBOL_NOT_FOUND]));
+        assert_eq!(data(3), &[&words(&[0, 19, 0, 2, 0, 1])[..], &[1, 2, 5]].concat());
+        assert_eq!(data(4), &words(&[ERR_INVALID_PARAM]));
+        for id in [5, 6, 7] {
+            assert_eq!(data(id), &words(&[ERR_INVALID_SIZE]));
+        }
+    }
+}
+
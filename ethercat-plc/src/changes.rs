@@ -0,0 +1,153 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Notification of client writes to the cycle code.
+
+use std::ops::Range;
+
+use crate::record::AppliedWrite;
+
+/// The client writes that were applied to the extern image since the
+/// previous cycle, in the order they were applied.
+///
+/// A write counts even if it didn't change the value, so that command
+/// fields (like `status = START`) can be handled as edge-triggered.
+#[derive(Debug)]
+pub struct Changes<'a> {
+    /// Address of the extern image, to locate fields given by reference.
+    base: usize,
+    size: usize,
+    writes: &'a [AppliedWrite],
+}
+
+impl<'a> Changes<'a> {
+    pub(crate) fn new<E>(ext: &E, writes: &'a [AppliedWrite]) -> Self {
+        Self { base: ext as *const E as usize, size: std::mem::size_of::<E>(), writes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// All writes, with the ID of the client that sent them.
+    pub fn writes(&self) -> &'a [AppliedWrite] {
+        self.writes
+    }
+
+    /// The byte ranges of the extern image that were written.
+    pub fn ranges(&self) -> impl Iterator<Item=Range<usize>> + 'a {
+        self.writes.iter().flat_map(AppliedWrite::ranges)
+    }
+
+    /// Whether any write overlaps the given byte range, e.g. the range of a
+    /// variable found with `ImageVars::find_var`.
+    pub fn touches(&self, range: Range<usize>) -> bool {
+        self.writes.iter().any(|w| w.touches(range.clone()))
+    }
+
+    /// Whether a client wrote to the given field of the extern image:
+    ///
+    /// ```ignore
+    /// if changes.changed(&ext.if_magnet.status) && ext.if_magnet.status == START { ... }
+    /// ```
+    ///
+    /// Returns false for references outside of the extern image.
+    pub fn changed<T>(&self, field: &T) -> bool {
+        let addr = field as *const T as usize;
+        if addr < self.base || addr + std::mem::size_of::<T>() > self.base + self.size {
+            return false;
+        }
+        let start = addr - self.base;
+        self.touches(start..start + std::mem::size_of::<T>())
+    }
+
+    /// The IDs of clients that wrote to the given byte range.
+    pub fn clients(&self, range: Range<usize>) -> impl Iterator<Item=usize> + 'a {
+        self.writes.iter()
+            .filter(move |w| w.touches(range.clone()))
+            .map(|w| w.client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, packed)]
+    #[derive(Default)]
+    struct Status {
+        flag: u8,
+        value: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Ext {
+        a: u16,
+        b: u16,
+        status: Status,
+        last: [u8; 3],
+    }
+
+    fn write(addr: usize, len: usize, mask: Option<Vec<u8>>) -> AppliedWrite {
+        AppliedWrite { client: 1, addr, data: vec![0; len], mask }
+    }
+
+    #[test]
+    fn fields() {
+        let ext = Ext::default();
+        let writes = [write(2, 2, None), write(10, 1, None)];
+        let changes = Changes::new(&ext, &writes);
+        assert!(!changes.changed(&ext.a));
+        assert!(changes.changed(&ext.b));
+        assert!(changes.changed(&ext.last));
+        assert!(!changes.changed(&ext.last[0]) && changes.changed(&ext.last[1]));
+        assert!(changes.changed(&ext));
+
+        // partially written, or masked out
+        let writes = [write(1, 2, None), write(8, 4, Some(vec![0, 0xFF, 0, 0]))];
+        let changes = Changes::new(&ext, &writes);
+        assert!(changes.changed(&ext.a) && changes.changed(&ext.b));
+        assert!(!changes.changed(&ext.status));
+        assert!(changes.changed(&ext.last[0]) && !changes.changed(&ext.last[1]));
+
+        assert!(!Changes::new(&ext, &[]).changed(&ext));
+    }
+
+    #[test]
+    fn outside_fields() {
+        struct Outer {
+            before: u32,
+            ext: Ext,
+            after: u32,
+        }
+        let outer = Outer { before: 0, ext: Ext::default(), after: 0 };
+        let writes = [write(0, std::mem::size_of::<Ext>(), None)];
+        let changes = Changes::new(&outer.ext, &writes);
+        assert!(changes.changed(&outer.ext.a));
+        assert!(!changes.changed(&outer.before));
+        assert!(!changes.changed(&outer.after));
+        // overlapping, but not within the image
+        assert!(!changes.changed(&outer));
+        let local = outer.ext.b;
+        assert!(!changes.changed(&local));
+    }
+
+    #[test]
+    fn packed_fields() {
+        let ext = Ext::default();
+        // only bytes of the unaligned `value` are written
+        let writes = [write(6, 2, None)];
+        let changes = Changes::new(&ext, &writes);
+        assert!(changes.changed(&ext.status));
+        assert!(!changes.changed(&ext.status.flag));
+        // `value` can't be referenced; a copy is not part of the image
+        assert!(!changes.changed(&{ ext.status.value }));
+        assert!(changes.touches(5..9));
+
+        let writes = [write(4, 1, None)];
+        let changes = Changes::new(&ext, &writes);
+        assert!(changes.changed(&ext.status.flag));
+        assert!(!changes.touches(5..9));
+    }
+}
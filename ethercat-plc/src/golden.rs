@@ -0,0 +1,379 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Regression testing of cycle logic against stored "golden" traces.
+//!
+//! A cycle function is run on a sequence of inputs, either scripted or taken
+//! from a recording (see the `record` module).  After each cycle, all
+//! variables of the process image and the extern image are compared against
+//! the stored trace, and differences are reported per field.
+//!
+//! The trace is a text file that lists, for each cycle, the variables that
+//! changed since the previous cycle:
+//!
+//! ```text
+//! cycle 0
+//! P ana_out.ch1 = 0
+//! E if_magnet.status = 4096
+//! cycle 1
+//! E if_magnet.status = 24576
+//! ```
+//!
+//! When the `PLC_GOLDEN_ACCEPT` environment variable is set, or `accept` is
+//! enabled, the trace is (re)written from the current output instead.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs};
+use anyhow::{bail, Context};
+
+use crate::changes::Changes;
+use crate::image::{ExternImage, ProcessImage, VarInfo};
+use crate::record::{self, AppliedWrite};
+
+/// A difference between expected and actual value of a variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    pub cycle: usize,
+    /// Variable name, prefixed by `P` for the process image or `E` for the
+    /// extern image.
+    pub field: String,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+/// The error returned when the output doesn't match the golden trace.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub diffs: Vec<FieldDiff>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const MAX_SHOWN: usize = 50;
+        writeln!(f, "output does not match golden trace {} ({} differences):",
+                 self.path.display(), self.diffs.len())?;
+        for diff in self.diffs.iter().take(MAX_SHOWN) {
+            writeln!(f, "  cycle {}: {}: expected {}, got {}", diff.cycle, diff.field,
+                     diff.expected.as_deref().unwrap_or("nothing"),
+                     diff.actual.as_deref().unwrap_or("nothing"))?;
+        }
+        if self.diffs.len() > MAX_SHOWN {
+            writeln!(f, "  ... and {} more", self.diffs.len() - MAX_SHOWN)?;
+        }
+        write!(f, "set PLC_GOLDEN_ACCEPT=1 to accept the new output")
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// Full state of all variables, for each cycle.
+type Trace = Vec<BTreeMap<String, String>>;
+
+/// A golden trace test for a cycle function.
+pub struct Golden {
+    path: PathBuf,
+    accept: bool,
+}
+
+impl Golden {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            accept: matches!(env::var_os("PLC_GOLDEN_ACCEPT"), Some(v) if v != "0"),
+        }
+    }
+
+    /// Write the trace from the current output instead of checking it.
+    pub fn accept(mut self, accept: bool) -> Self {
+        self.accept = accept;
+        self
+    }
+
+    /// Run the cycle function for the given number of cycles.  Before each
+    /// cycle, the script is called with the cycle number to set inputs.
+    pub fn check_script<P, E, S, F>(&self, cycles: usize, script: S,
+                                    mut cycle_fn: F) -> anyhow::Result<()>
+    where P: ProcessImage, E: ExternImage,
+          S: FnMut(usize, &mut P, &mut E), F: FnMut(&mut P, &mut E)
+    {
+        self.check_script_with_changes(cycles, script, |data, ext, _| cycle_fn(data, ext))
+    }
+
+    /// Like `check_script`, but also pass the changes the script made to the
+    /// extern image to the cycle function, as if a client had written them.
+    /// Since they are found by comparison, writes of the current value of a
+    /// variable are not seen.
+    pub fn check_script_with_changes<P, E, S, F>(&self, cycles: usize, mut script: S,
+                                                 mut cycle_fn: F) -> anyhow::Result<()>
+    where P: ProcessImage, E: ExternImage,
+          S: FnMut(usize, &mut P, &mut E), F: FnMut(&mut P, &mut E, &Changes)
+    {
+        let mut data = vec![0; P::size()];
+        let mut ext = E::default();
+        let mut trace = Vec::with_capacity(cycles);
+        let snapshot = Snapshotter::new::<P, E>();
+        for cycle in 0..cycles {
+            let before = ext.cast().to_vec();
+            script(cycle, P::cast(&mut data), &mut ext);
+            let writes = diff_writes(&before, ext.cast());
+            let changes = Changes::new(&ext, &writes);
+            cycle_fn(P::cast(&mut data), &mut ext, &changes);
+            trace.push(snapshot.take(&data, &mut ext));
+        }
+        self.check(trace)
+    }
+
+    /// Run the cycle function on the inputs and server writes from a
+    /// recording.  The extern image is initialized from the first record.
+    pub fn check_recording<P, E, F>(&self, recording: impl AsRef<Path>,
+                                    mut cycle_fn: F) -> anyhow::Result<()>
+    where P: ProcessImage, E: ExternImage, F: FnMut(&mut P, &mut E)
+    {
+        self.check_recording_with_changes(recording, |data, ext, _| cycle_fn(data, ext))
+    }
+
+    /// Like `check_recording`, but also pass the recorded server writes to
+    /// the cycle function.
+    pub fn check_recording_with_changes<P, E, F>(&self, recording: impl AsRef<Path>,
+                                                 mut cycle_fn: F) -> anyhow::Result<()>
+    where P: ProcessImage, E: ExternImage, F: FnMut(&mut P, &mut E, &Changes)
+    {
+        let mut trace = Vec::new();
+        let snapshot = Snapshotter::new::<P, E>();
+        record::replay_domain::<P, E, _>(recording, |domain, ext, changes| {
+            cycle_fn(P::cast(domain), ext, changes);
+            trace.push(snapshot.take(domain, ext));
+        })?;
+        self.check(trace)
+    }
+
+    fn check(&self, actual: Trace) -> anyhow::Result<()> {
+        if self.accept {
+            return fs::write(&self.path, format_trace(&actual))
+                .with_context(|| format!("writing golden trace {}", self.path.display()));
+        }
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("reading golden trace {} (set PLC_GOLDEN_ACCEPT=1 \
+                                      to create it)", self.path.display()))?;
+        let expected = parse_trace(&text)
+            .with_context(|| format!("parsing golden trace {}", self.path.display()))?;
+
+        let mut diffs = Vec::new();
+        let empty = BTreeMap::new();
+        for cycle in 0..expected.len().max(actual.len()) {
+            let exp = expected.get(cycle).unwrap_or(&empty);
+            let act = actual.get(cycle).unwrap_or(&empty);
+            for field in exp.keys().chain(act.keys().filter(|k| !exp.contains_key(*k))) {
+                if exp.get(field) != act.get(field) {
+                    diffs.push(FieldDiff {
+                        cycle,
+                        field: field.clone(),
+                        expected: exp.get(field).cloned(),
+                        actual: act.get(field).cloned(),
+                    });
+                }
+            }
+        }
+        if !diffs.is_empty() {
+            return Err(Mismatch { path: self.path.clone(), diffs }.into());
+        }
+        Ok(())
+    }
+}
+
+/// The byte ranges that differ between two versions of the extern image,
+/// as writes from client 0.
+fn diff_writes(before: &[u8], after: &[u8]) -> Vec<AppliedWrite> {
+    let mut writes: Vec<AppliedWrite> = Vec::new();
+    for (addr, (old, new)) in before.iter().zip(after).enumerate() {
+        if old == new {
+            continue;
+        }
+        match writes.last_mut() {
+            Some(write) if write.addr + write.data.len() == addr => write.data.push(*new),
+            _ => writes.push(AppliedWrite { client: 0, addr, data: vec![*new], mask: None }),
+        }
+    }
+    writes
+}
+
+struct Snapshotter {
+    p_vars: Vec<VarInfo>,
+    e_vars: Vec<VarInfo>,
+}
+
+impl Snapshotter {
+    fn new<P: ProcessImage, E: ExternImage>() -> Self {
+        Self { p_vars: P::image_vars(), e_vars: E::image_vars() }
+    }
+
+    fn take<E: ExternImage>(&self, data: &[u8], ext: &mut E) -> BTreeMap<String, String> {
+        let mut state = BTreeMap::new();
+        for var in &self.p_vars {
+            state.insert(format!("P {}", var.name), var.format_value(data));
+        }
+        let ext_data = ext.cast();
+        for var in &self.e_vars {
+            state.insert(format!("E {}", var.name), var.format_value(ext_data));
+        }
+        state
+    }
+}
+
+fn format_trace(trace: &Trace) -> String {
+    let empty = BTreeMap::new();
+    let mut text = String::new();
+    for (cycle, state) in trace.iter().enumerate() {
+        let prev = if cycle > 0 { &trace[cycle - 1] } else { &empty };
+        text.push_str(&format!("cycle {}\n", cycle));
+        for (field, value) in state {
+            if prev.get(field) != Some(value) {
+                text.push_str(&format!("{} = {}\n", field, value));
+            }
+        }
+    }
+    text
+}
+
+fn parse_trace(text: &str) -> anyhow::Result<Trace> {
+    let mut trace: Trace = Vec::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(cycle) = line.strip_prefix("cycle ") {
+            if cycle.parse::<usize>().ok() != Some(trace.len()) {
+                bail!("line {}: expected cycle {}", lineno + 1, trace.len());
+            }
+            let state = trace.last().cloned().unwrap_or_default();
+            trace.push(state);
+        } else if let Some((field, value)) = line.split_once(" = ") {
+            match trace.last_mut() {
+                Some(state) => { state.insert(field.into(), value.into()); }
+                None => bail!("line {}: value before first cycle", lineno + 1),
+            }
+        } else {
+            bail!("line {}: invalid syntax", lineno + 1);
+        }
+    }
+    Ok(trace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beckhoff::{EL1008, EL2008};
+    use crate::record::Recorder;
+    use ethercat_derive::{ExternImage, ProcessImage};
+
+    #[repr(C, packed)]
+    #[derive(ProcessImage)]
+    struct Plant {
+        inp: EL1008,
+        out: EL2008,
+    }
+
+    #[repr(C)]
+    #[derive(Default, ExternImage)]
+    struct Ext {
+        count: u16,
+        command: u8,
+        invert: u8,
+    }
+
+    /// Counts commands, and copies the input to the output.
+    fn cycle(data: &mut Plant, ext: &mut Ext, changes: &Changes) {
+        if changes.changed(&ext.command) {
+            ext.count += 1;
+        }
+        data.out.output = data.inp.input ^ ext.invert;
+    }
+
+    fn script(cycle: usize, data: &mut Plant, ext: &mut Ext) {
+        data.inp.input = cycle as u8;
+        if cycle == 2 {
+            ext.command = 1;
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("ethercat-plc-golden-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn trace_roundtrip() {
+        let trace = vec![
+            BTreeMap::from([("P a".into(), "1".into()), ("E b".into(), "2".into())]),
+            BTreeMap::from([("P a".into(), "1".into()), ("E b".into(), "3".into())]),
+        ];
+        let text = format_trace(&trace);
+        assert_eq!(text, "cycle 0\nE b = 2\nP a = 1\ncycle 1\nE b = 3\n");
+        assert_eq!(parse_trace(&text).unwrap(), trace);
+    }
+
+    #[test]
+    fn trace_errors() {
+        assert!(parse_trace("P a = 1\n").is_err());
+        assert!(parse_trace("cycle 0\ncycle 2\n").is_err());
+        assert!(parse_trace("cycle 0\nP a\n").is_err());
+        assert_eq!(parse_trace("# comment\n\ncycle 0\n").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn script_mismatch() {
+        let path = temp_path("script.trace");
+        Golden::new(&path).accept(true).check_script_with_changes(4, script, cycle).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("cycle 2\nE command = 1\nE count = 1\n"), "{}", text);
+        Golden::new(&path).accept(false).check_script_with_changes(4, script, cycle).unwrap();
+
+        let err = Golden::new(&path).accept(false).check_script_with_changes(
+            4, script, |data: &mut Plant, ext: &mut Ext, changes: &Changes| {
+                cycle(data, ext, changes);
+                data.out.output = 0;
+            }).unwrap_err();
+        let mismatch = err.downcast::<Mismatch>().unwrap();
+        assert_eq!(mismatch.diffs, (1..4).map(|cycle| FieldDiff {
+            cycle,
+            field: "P out.output".into(),
+            expected: Some(cycle.to_string()),
+            actual: Some("0".into()),
+        }).collect::<Vec<_>>());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recording_changes() {
+        let recording = temp_path("changes.rec");
+        let path = temp_path("changes.trace");
+        let mut recorder = Recorder::create(&recording, Plant::size(), Ext::size()).unwrap();
+        recorder.begin_cycle(&[5, 0], &[0; 4]).unwrap();
+        // a masked write that only covers the invert byte
+        recorder.add_writes(&[AppliedWrite { client: 1, addr: 2, data: vec![1, 0xff],
+                                             mask: Some(vec![0, 0xff]) }]);
+        recorder.begin_cycle(&[6, 0], &[0, 0, 0, 0xff]).unwrap();
+        recorder.add_writes(&[AppliedWrite { client: 1, addr: 2, data: vec![1], mask: None }]);
+        recorder.begin_cycle(&[7, 0], &[0, 0, 1, 0xff]).unwrap();
+        drop(recorder);
+
+        Golden::new(&path).accept(true).check_recording_with_changes(&recording, cycle).unwrap();
+        let expected = parse_trace(&fs::read_to_string(&path).unwrap()).unwrap();
+        let values = |field: &str| expected.iter().map(|state| state[field].clone())
+                                                  .collect::<Vec<_>>();
+        assert_eq!(values("E count"), ["0", "0", "1"]);
+        assert_eq!(values("E command"), ["0", "0", "1"]);
+        assert_eq!(values("P out.output"), ["5", "249", "248"]);
+
+        // the plain cycle function doesn't see the writes
+        let err = Golden::new(&path).accept(false).check_recording(
+            &recording, |data, ext| cycle(data, ext, &Changes::new(ext, &[]))).unwrap_err();
+        assert_eq!(err.downcast::<Mismatch>().unwrap().diffs, [FieldDiff {
+            cycle: 2, field: "E count".into(), expected: Some("1".into()), actual: Some("0".into()),
+        }]);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&recording).unwrap();
+    }
+}
@@ -0,0 +1,572 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! An HTTP/JSON API for the `TcpServer`, giving access to image variables
+//! by name instead of by address.
+//!
+//! The routes are:
+//!
+//! * `GET /` - PLC name and version
+//! * `GET /vars/extern`, `GET /vars/process` - the variables of the images,
+//!   with type, offset and size
+//! * `GET /extern` - the whole extern image as a JSON object
+//! * `GET /extern/<name>` - a single variable, or a struct or array as JSON
+//!   object or array.  Names are given as in `VarInfo`, or with slashes
+//!   (`/extern/indexer/data/3` is the same as `/extern/indexer.data[3]`).
+//! * `PUT /extern/<name>` - write all (writable) members of the variable
+//! * `PATCH /extern/<name>` - write only the given members; `null` array
+//!   items are skipped
+//! * `GET /process[/<name>]` - like for the extern image, if enabled
+//! * `GET /metrics` - statistics of the PLC and its servers in the
+//!   Prometheus text format
+//! * `GET /ws` - upgrade to a WebSocket for live subscriptions to
+//!   variables, see the `websocket` module
+//!
+//! Writes reply with the value of the variable after the PLC has run one
+//! cycle with the written data.  Errors are replied with a JSON object
+//! with an `error` member.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+
+use crate::image::VarInfo;
+use crate::json::{encode_write, span, value, Value};
+use crate::server::{Area, Handler, PlcInfo, Request, Response};
+use self::websocket::WebSocket;
+
+mod websocket;
+
+/// Maximum size of the request line and headers.
+const MAX_HEAD: usize = 8192;
+/// Maximum size of a request body.
+const MAX_BODY: usize = 1 << 16;
+
+/// Configuration for the `HttpHandler`.
+#[derive(Debug, Clone, Default)]
+pub struct HttpConfig {
+    /// Also serve the process image (read-only) under `/process`.
+    pub process: bool,
+    /// Value of the `Access-Control-Allow-Origin` header, to allow
+    /// dashboards served from other origins to use the API.
+    pub allow_origin: Option<String>,
+}
+
+/// The request data kept for the reply.
+#[derive(Debug, Default)]
+pub struct HttpExtra {
+    /// Sequence number of the HTTP request on its connection.
+    seq: u64,
+    /// The requested variable name.
+    name: String,
+    /// Whether this is a sample for WebSocket subscriptions.
+    sample: bool,
+}
+
+/// Handles the HTTP/JSON API for one connection.
+pub struct HttpHandler {
+    hid: usize,
+    info: Arc<PlcInfo>,
+    config: HttpConfig,
+    /// Sequence number of the next received HTTP request.
+    next_seq: u64,
+    /// Sequence number of the next HTTP response to send.
+    sent_seq: u64,
+    /// Responses that wait for earlier ones, since HTTP replies must be in
+    /// request order.
+    ready: BTreeMap<u64, Vec<u8>>,
+    /// Sequence number of the last request before closing the connection.
+    last_seq: Option<u64>,
+    /// Set after the upgrade to a WebSocket.
+    ws: Option<WebSocket>,
+    /// Requests answered without the PLC, and errors among them.
+    direct: (usize, usize),
+}
+
+/// A parsed HTTP request.
+struct HttpRequest<'a> {
+    method: &'a str,
+    path: &'a str,
+    body: &'a [u8],
+}
+
+/// An HTTP response status with a JSON body.
+type Reply = (u16, Value);
+
+fn error(status: u16, msg: impl Into<String>) -> Reply {
+    (status, Value::object([("error", Value::str(msg))]))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        101 => "Switching Protocols",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn decode_percent(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Convert the path segments after the image to a variable name.
+fn var_name(path: &str) -> Option<String> {
+    let mut name = String::new();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        let segment = decode_percent(segment)?;
+        if name.is_empty() {
+            name = segment;
+        } else if segment.bytes().all(|b| b.is_ascii_digit()) {
+            write!(name, "[{}]", segment).unwrap();
+        } else {
+            write!(name, ".{}", segment).unwrap();
+        }
+    }
+    Some(name)
+}
+
+fn var_list(vars: &[VarInfo]) -> Value {
+    Value::Array(vars.iter().map(|var| Value::object([
+        ("name", Value::str(&var.name)),
+        ("type", Value::str(var.ty.name())),
+        ("offset", Value::num(var.offset)),
+        ("size", Value::num(var.size())),
+        ("read_only", Value::Bool(var.read_only)),
+    ])).collect())
+}
+
+impl HttpHandler {
+    fn vars(&self, area: Area) -> &[VarInfo] {
+        match area {
+            Area::Extern => &self.info.extern_vars,
+            Area::Process => &self.info.process_vars,
+        }
+    }
+
+    /// The variables that make up the named variable, or all for an empty
+    /// name.
+    fn select(&self, area: Area, name: &str) -> Vec<&VarInfo> {
+        self.vars(area).iter().filter(|v| name.is_empty() || v.is_within(name)).collect()
+    }
+
+    /// Handle a complete HTTP request, either with a direct reply or with a
+    /// request to the PLC.
+    fn handle(&self, req: &HttpRequest, seq: u64) -> Result<Request<HttpExtra>, Reply> {
+        let path = req.path.split('?').next().unwrap_or("");
+        let (area, rest) = if let Some(rest) = path.strip_prefix("/extern") {
+            (Area::Extern, rest)
+        } else if let Some(rest) = path.strip_prefix("/process").filter(|_| self.config.process) {
+            (Area::Process, rest)
+        } else {
+            return Err(match (req.method, path) {
+                ("GET", "/") => (200, Value::object([
+                    ("name", Value::str(&self.info.name)),
+                    ("version", Value::str(&self.info.version)),
+                ])),
+                ("GET", "/vars/extern") => (200, var_list(&self.info.extern_vars)),
+                ("GET", "/vars/process") if self.config.process =>
+                    (200, var_list(&self.info.process_vars)),
+                (_, "/" | "/vars/extern") => error(405, "method not allowed"),
+                _ => error(404, "not found"),
+            });
+        };
+        if !(rest.is_empty() || rest.starts_with('/')) {
+            return Err(error(404, "not found"));
+        }
+        let name = var_name(rest).ok_or_else(|| error(400, "invalid path"))?;
+        let vars = self.select(area, &name);
+        if vars.is_empty() {
+            return Err(error(404, format!("no variable {}", name)));
+        }
+        let all = span(vars.iter().copied());
+        let extra = HttpExtra { seq, name, sample: false };
+        let mut request = Request {
+            hid: self.hid, area, addr: all.start, count: all.len(), write: None, mask: None,
+            read_back: None, lock: None, extra,
+        };
+        match req.method {
+            "GET" => return Ok(request),
+            "PUT" | "PATCH" if area == Area::Extern => (),
+            _ => return Err(error(405, "method not allowed")),
+        }
+
+        let body = std::str::from_utf8(req.body).map_err(|_| error(400, "body is not UTF-8"))?;
+        let body = Value::parse(body).map_err(|e| error(400, format!("invalid JSON: {}", e)))?;
+        let (range, data, mask) = match encode_write(&vars, &request.extra.name, &body, req.method == "PUT")
+            .map_err(|e| error(400, e))?
+        {
+            Some(write) => write,
+            // nothing to write, reply with the current value
+            None => return Ok(request),
+        };
+        request.read_back = Some((request.addr, request.count));
+        request.addr = range.start;
+        request.count = range.len();
+        request.write = Some(data);
+        request.mask = mask;
+        Ok(request)
+    }
+
+    /// Queue the response to a request, and send all responses that are now
+    /// in order.
+    fn finish(&mut self, seq: u64, (status, body): Reply, output: &mut Vec<u8>) {
+        let body = if status == 204 { String::new() } else { body.to_string() };
+        self.finish_with(seq, status, "application/json", body, output);
+    }
+
+    /// Reply to a request without involving the PLC.
+    fn finish_direct(&mut self, seq: u64, reply: Reply, output: &mut Vec<u8>) {
+        self.direct.0 += 1;
+        if reply.0 >= 400 {
+            self.direct.1 += 1;
+        }
+        self.finish(seq, reply, output);
+    }
+
+    /// Like `finish`, with a body of any content type.
+    fn finish_with(&mut self, seq: u64, status: u16, content_type: &str, body: String,
+                   output: &mut Vec<u8>) {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason(status));
+        if status != 204 {
+            write!(head, "Content-Type: {}\r\nContent-Length: {}\r\n",
+                   content_type, body.len()).unwrap();
+        }
+        if let Some(origin) = &self.config.allow_origin {
+            write!(head, "Access-Control-Allow-Origin: {}\r\n", origin).unwrap();
+        }
+        if status == 204 {
+            head.push_str("Access-Control-Allow-Methods: GET, PUT, PATCH, OPTIONS\r\n\
+                           Access-Control-Allow-Headers: Content-Type\r\n");
+        }
+        if self.last_seq == Some(seq) {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        head.push_str(&body);
+        self.send(seq, head.into_bytes(), output);
+    }
+
+    /// Queue the raw response to a request, and send all responses that are
+    /// now in order.
+    fn send(&mut self, seq: u64, data: Vec<u8>, output: &mut Vec<u8>) {
+        self.ready.insert(seq, data);
+        while let Some(data) = self.ready.remove(&self.sent_seq) {
+            output.extend_from_slice(&data);
+            self.sent_seq += 1;
+        }
+    }
+
+    /// Reply to a malformed request, and close the connection afterwards.
+    fn fail(&mut self, reply: Reply, output: &mut Vec<u8>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.last_seq = Some(seq);
+        self.finish_direct(seq, reply, output);
+    }
+}
+
+impl Handler for HttpHandler {
+    type Extra = HttpExtra;
+    type Config = HttpConfig;
+
+    fn new(hid: usize, info: Arc<PlcInfo>, config: &HttpConfig) -> Self {
+        HttpHandler { hid, info, config: config.clone(), next_seq: 0, sent_seq: 0,
+                      ready: BTreeMap::new(), last_seq: None, ws: None,
+                      direct: (0, 0) }
+    }
+
+    fn receive(&mut self, input: &[u8], requests: &mut Vec<Request<HttpExtra>>,
+               output: &mut Vec<u8>) -> io::Result<usize> {
+        if let Some(ws) = &mut self.ws {
+            let used = ws.receive(input, output);
+            ws.poll(requests);
+            return Ok(used);
+        }
+        let mut pos = 0;
+        while self.last_seq.is_none() && self.ws.is_none() {
+            let rest = &input[pos..];
+            let head_len = match rest.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(n) => n + 4,
+                None if rest.len() > MAX_HEAD => {
+                    self.fail(error(431, "request head too large"), output);
+                    break;
+                }
+                None => break,
+            };
+            let head = match std::str::from_utf8(&rest[..head_len]) {
+                Ok(head) => head,
+                Err(_) => {
+                    self.fail(error(400, "invalid request"), output);
+                    break;
+                }
+            };
+            let mut lines = head.split("\r\n");
+            let mut parts = lines.next().unwrap_or("").split(' ');
+            let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") =>
+                    (method, path, version),
+                _ => {
+                    self.fail(error(400, "invalid request line"), output);
+                    break;
+                }
+            };
+            let mut length = 0;
+            let mut close = version == "HTTP/1.0";
+            let mut chunked = false;
+            let mut ws_key = None;
+            for line in lines.filter(|l| !l.is_empty()) {
+                let (key, value) = line.split_once(':').unwrap_or((line, ""));
+                let value = value.trim();
+                if key.eq_ignore_ascii_case("content-length") {
+                    length = value.parse().unwrap_or(usize::MAX);
+                } else if key.eq_ignore_ascii_case("transfer-encoding") {
+                    chunked = true;
+                } else if key.eq_ignore_ascii_case("sec-websocket-key") {
+                    ws_key = Some(value);
+                } else if key.eq_ignore_ascii_case("connection") {
+                    close = if value.eq_ignore_ascii_case("close") { true }
+                            else if value.eq_ignore_ascii_case("keep-alive") { false }
+                            else { close };
+                }
+            }
+            if chunked {
+                self.fail(error(411, "chunked requests are not supported"), output);
+                break;
+            }
+            if length > MAX_BODY {
+                self.fail(error(413, "request body too large"), output);
+                break;
+            }
+            let body = match rest.get(head_len..head_len + length) {
+                Some(body) => body,
+                None => break,
+            };
+            pos += head_len + length;
+
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            if close {
+                self.last_seq = Some(seq);
+            }
+            if method == "OPTIONS" {
+                self.finish_direct(seq, (204, Value::Null), output);
+                continue;
+            }
+            if method == "GET" && path.split('?').next() == Some("/metrics") {
+                self.direct.0 += 1;
+                let text = self.info.metrics.render(&self.info);
+                self.finish_with(seq, 200, "text/plain; version=0.0.4", text, output);
+                continue;
+            }
+            if let (Some(key), true) = (ws_key, self.last_seq.is_none()) {
+                if method != "GET" || path.split('?').next() != Some("/ws") {
+                    self.finish_direct(seq, error(404, "not found"), output);
+                } else if self.sent_seq != seq {
+                    self.finish_direct(seq, error(400, "upgrade with requests in progress"),
+                                       output);
+                } else {
+                    let head = format!("HTTP/1.1 101 {}\r\nUpgrade: websocket\r\n\
+                                        Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                                       reason(101), websocket::accept_key(key));
+                    self.direct.0 += 1;
+                    self.send(seq, head.into_bytes(), output);
+                    self.ws = Some(WebSocket::new(self.hid, self.info.clone(), self.config.process));
+                }
+                continue;
+            }
+            match self.handle(&HttpRequest { method, path, body }, seq) {
+                Ok(request) => requests.push(request),
+                Err(reply) => self.finish_direct(seq, reply, output),
+            }
+        }
+        if let Some(ws) = &mut self.ws {
+            pos += ws.receive(&input[pos..], output);
+            ws.poll(requests);
+        }
+        // after the last request, further input is ignored
+        Ok(if self.last_seq.is_some() { input.len() } else { pos })
+    }
+
+    fn respond(&mut self, response: Response<HttpExtra>, output: &mut Vec<u8>) {
+        if let Some(ws) = &mut self.ws {
+            match response {
+                Response::Ok(req, data) if req.extra.sample =>
+                    return ws.sample(req.area, req.addr, &data, output),
+                Response::Error(req, code) if req.extra.sample =>
+                    return ws.sample_failed(req.area, code, output),
+                _ => ()
+            }
+        }
+        let (seq, reply) = match response {
+            Response::Ok(req, data) => {
+                let base = req.read_back.map_or(req.addr, |(addr, _)| addr);
+                let value = value(self.vars(req.area), &req.extra.name, &data, base);
+                (req.extra.seq, (200, value))
+            }
+            Response::Error(req, code) => (req.extra.seq, match code {
+                2 if req.write.is_some() => error(403, "variable is read-only"),
+                4 => error(503, "PLC is not running"),
+                6 => error(409, "variable is locked by another client"),
+                _ => error(500, format!("PLC error {}", code)),
+            }),
+        };
+        self.finish(seq, reply, output);
+    }
+
+    fn direct_replies(&mut self) -> (usize, usize) {
+        std::mem::take(&mut self.direct)
+    }
+
+    fn poll(&mut self, requests: &mut Vec<Request<HttpExtra>>) {
+        if let Some(ws) = &mut self.ws {
+            ws.poll(requests);
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.ws.as_ref().map_or(false, WebSocket::closed) ||
+            self.last_seq.map_or(false, |seq| self.sent_seq > seq)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::image::VarType;
+    use super::*;
+
+    fn handler(config: HttpConfig) -> HttpHandler {
+        let info = PlcInfo {
+            name: "test".into(),
+            version: "1.0".into(),
+            extern_vars: vec![VarInfo::new("s.a", 0, VarType::U16),
+                              VarInfo::new("s.b[0]", 2, VarType::I8),
+                              VarInfo::new("s.b[1]", 3, VarType::I8)],
+            ..Default::default()
+        };
+        HttpHandler::new(1, Arc::new(info), &config)
+    }
+
+    fn receive(handler: &mut HttpHandler, input: &str)
+               -> (usize, Vec<Request<HttpExtra>>, String) {
+        let mut requests = Vec::new();
+        let mut output = Vec::new();
+        let used = handler.receive(input.as_bytes(), &mut requests, &mut output).unwrap();
+        (used, requests, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let mut h = handler(HttpConfig::default());
+        let input = "GET / HTTP/1.1\r\n\r\n\
+                     GET /extern/s/b HTTP/1.1\r\nHost: x\r\n\r\n\
+                     PATCH /extern/s HTTP/1.1\r\nContent-Length: 14\r\n\r\n{\"b\":[null,5]}\
+                     GET /";
+        let (used, mut requests, output) = receive(&mut h, input);
+        assert_eq!(used, input.len() - 5);
+        assert_eq!(output, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                            Content-Length: 31\r\n\r\n{\"name\":\"test\",\"version\":\"1.0\"}");
+        assert_eq!(requests.len(), 2);
+        let (read, write) = (&requests[0], &requests[1]);
+        assert_eq!((read.area, read.addr, read.count, read.write.is_none()),
+                   (Area::Extern, 2, 2, true));
+        assert_eq!((write.addr, write.count, write.write.as_deref(), write.read_back),
+                   (3, 1, Some(&[5][..]), Some((0, 4))));
+
+        // replies are sent in request order
+        let mut output = Vec::new();
+        let write = requests.pop().unwrap();
+        h.respond(Response::Ok(write, vec![1, 0, 0xFF, 5]), &mut output);
+        assert!(output.is_empty());
+        h.respond(Response::Ok(requests.pop().unwrap(), vec![0xFF, 5]), &mut output);
+        let output = String::from_utf8(output).unwrap();
+        let bodies = output.split("HTTP/1.1 200 OK").map(|r| r.split("\r\n\r\n").nth(1))
+                           .collect::<Vec<_>>();
+        assert_eq!(bodies, [None, Some("[-1,5]"), Some(r#"{"a":1,"b":[-1,5]}"#)]);
+        assert!(!h.finished());
+    }
+
+    #[test]
+    fn direct_errors() {
+        let mut h = handler(HttpConfig::default());
+        let (_, requests, output) = receive(&mut h, "GET /extern/s/c HTTP/1.1\r\n\r\n\
+            POST /extern/s HTTP/1.1\r\n\r\n\
+            GET /process HTTP/1.1\r\n\r\n\
+            PUT /extern/s HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}\
+            PUT /extern/s/a HTTP/1.1\r\nContent-Length: 3\r\n\r\n1,2\
+            GET /extern/%zz HTTP/1.1\r\n\r\n");
+        assert!(requests.is_empty());
+        let statuses = output.split("HTTP/1.1 ").skip(1).map(|r| &r[..3]).collect::<Vec<_>>();
+        assert_eq!(statuses, ["404", "405", "404", "400", "400", "400"]);
+        assert!(output.contains(r#"{"error":"missing value for s.a"}"#));
+        assert_eq!(h.direct_replies(), (6, 6));
+    }
+
+    #[test]
+    fn closing_requests() {
+        let mut h = handler(HttpConfig::default());
+        // input after the last request is ignored
+        let input = "GET / HTTP/1.0\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let (used, _, output) = receive(&mut h, input);
+        assert_eq!(used, input.len());
+        assert_eq!(output.matches("HTTP/1.1 200").count(), 1);
+        assert!(output.contains("Connection: close"));
+        assert!(h.finished());
+
+        for (input, status) in [
+            ("PUT /extern/s HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n", "411"),
+            ("PUT /extern/s HTTP/1.1\r\nContent-Length: 100000\r\n\r\n", "413"),
+            ("PUT /extern/s HTTP/1.1\r\nContent-Length: -1\r\n\r\n", "413"),
+            ("GET /\r\n\r\n", "400"),
+        ] {
+            let mut h = handler(HttpConfig::default());
+            let (_, _, output) = receive(&mut h, input);
+            assert!(output.starts_with(&format!("HTTP/1.1 {}", status)), "{}", output);
+            assert!(h.finished());
+        }
+        let mut h = handler(HttpConfig::default());
+        let (used, _, output) = receive(&mut h, &"x".repeat(MAX_HEAD + 1));
+        assert!(output.starts_with("HTTP/1.1 431"));
+        assert_eq!(used, MAX_HEAD + 1);
+    }
+
+    #[test]
+    fn process_image_and_cors() {
+        let config = HttpConfig { process: true, allow_origin: Some("*".into()) };
+        let mut h = handler(config);
+        let (_, requests, output) = receive(&mut h, "OPTIONS /extern HTTP/1.1\r\n\r\n\
+                                                     GET /process HTTP/1.1\r\n\r\n\
+                                                     GET /vars/process HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 204 No Content\r\nAccess-Control-Allow-Origin: *\r\n"));
+        assert!(output.ends_with("\r\n\r\n[]"));
+        // no process variables
+        assert!(output.contains("404 Not Found"));
+        assert!(requests.is_empty());
+        assert_eq!(var_name("/indexer/data/3").as_deref(), Some("indexer.data[3]"));
+        assert_eq!(var_name("/a%2Eb").as_deref(), Some("a.b"));
+        assert_eq!(var_name("/%C3"), None);
+    }
+}
+
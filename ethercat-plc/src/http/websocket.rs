@@ -0,0 +1,649 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! WebSocket connections with live subscriptions to variables.
+//!
+//! Clients send JSON text messages:
+//!
+//! * `{"op": "subscribe", "id": 1, "vars": ["flags", "indexer.data"]}`
+//!   subscribes to variables (single ones, or structs and arrays).  Instead
+//!   of, or in addition to, `vars`, `"regions": [[addr, count], ...]`
+//!   subscribes to byte ranges.  With `"area": "process"`, the process
+//!   image is used (if enabled in the `HttpConfig`).
+//!
+//!   By default, the values of the variables and regions that changed are
+//!   sent at the end of each PLC cycle in which they changed.  With
+//!   `"interval": <ms>`, all values are sent at this rate instead.
+//! * `{"op": "unsubscribe", "id": 1}` ends the subscription.
+//!
+//! The server sends `{"id": 1, "values": {"flags": 5, "indexer.data":
+//! [...], "0+4": "0a0b0c0d"}}` messages, where regions are given as hex
+//! strings, and `{"id": 1, "error": "..."}` if a subscription fails.  The
+//! first message of a subscription contains all values.
+//!
+//! Samples are taken by keeping read requests in flight that the PLC
+//! answers at the end of the following cycles, so that changes lasting only
+//! one cycle are seen unless the server falls behind by several cycles.  If
+//! the client doesn't keep up with the messages, samples are skipped;
+//! changes are then reported with the next message that can be sent.
+
+use std::fmt::Write;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::*;
+
+use crate::json::{span, value, Value};
+use crate::server::{Area, PlcInfo, Request};
+use super::HttpExtra;
+
+/// Maximum size of a (possibly fragmented) message from the client.
+const MAX_MESSAGE: usize = 1 << 16;
+/// Samples are skipped while more output than this is waiting to be sent.
+const MAX_BACKLOG: usize = 1 << 16;
+/// Number of sample requests kept in flight for each image.  The PLC
+/// answers one per cycle, so the others bridge delays of the server.
+const SAMPLES_IN_FLIGHT: usize = 4;
+/// Longest sampling interval of a subscription, one day, in ms.
+const MAX_INTERVAL: f64 = 86_400_000.;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONT:  u8 = 0x0;
+const OP_TEXT:  u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING:  u8 = 0x9;
+const OP_PONG:  u8 = 0xA;
+
+const CLOSE_PROTOCOL: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[4*i], block[4*i+1], block[4*i+2], block[4*i+3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i-3] ^ w[i-8] ^ w[i-14] ^ w[i-16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19  => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _       => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut out = [0; 20];
+    for (chunk, v) in out.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// The `Sec-WebSocket-Accept` value for the client's key.
+pub(super) fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+fn frame(opcode: u8, payload: &[u8], output: &mut Vec<u8>) {
+    output.push(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => output.push(n as u8),
+        n if n < 0x10000 => {
+            output.push(126);
+            output.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            output.push(127);
+            output.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    output.extend_from_slice(payload);
+}
+
+fn send(msg: Value, output: &mut Vec<u8>) {
+    frame(OP_TEXT, msg.to_string().as_bytes(), output);
+}
+
+fn area_index(area: Area) -> usize {
+    match area {
+        Area::Extern => 0,
+        Area::Process => 1,
+    }
+}
+
+/// A subscribed variable or region.
+enum Item {
+    Var(String, Range<usize>),
+    Region(Range<usize>),
+}
+
+impl Item {
+    fn range(&self) -> &Range<usize> {
+        match self {
+            Item::Var(_, range) | Item::Region(range) => range,
+        }
+    }
+
+    fn key(&self) -> String {
+        match self {
+            Item::Var(name, _) => name.clone(),
+            Item::Region(range) => format!("{}+{}", range.start, range.len()),
+        }
+    }
+}
+
+struct Subscription {
+    id: Value,
+    area: Area,
+    items: Vec<Item>,
+    interval: Option<Duration>,
+    /// Time of the next sample, for subscriptions with an interval.
+    next: Instant,
+    /// The last sent data of each item, for change notifications.
+    last: Vec<Option<Vec<u8>>>,
+}
+
+/// The state of a connection after the upgrade to WebSocket.
+pub(super) struct WebSocket {
+    hid: usize,
+    info: Arc<PlcInfo>,
+    process: bool,
+    subs: Vec<Subscription>,
+    /// Sample requests in flight, per image.
+    in_flight: [usize; 2],
+    /// Payload of a fragmented message, while receiving it.
+    message: Option<Vec<u8>>,
+    closed: bool,
+}
+
+impl WebSocket {
+    pub fn new(hid: usize, info: Arc<PlcInfo>, process: bool) -> Self {
+        Self { hid, info, process, subs: Vec::new(), in_flight: [0; 2],
+               message: None, closed: false }
+    }
+
+    /// Whether the connection has been closed.
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    fn close(&mut self, code: u16, output: &mut Vec<u8>) {
+        if !self.closed {
+            frame(OP_CLOSE, &code.to_be_bytes(), output);
+            self.closed = true;
+        }
+    }
+
+    /// Process received frames, returning the number of bytes consumed.
+    pub fn receive(&mut self, input: &[u8], output: &mut Vec<u8>) -> usize {
+        let mut pos = 0;
+        while !self.closed {
+            let rest = &input[pos..];
+            if rest.len() < 2 {
+                break;
+            }
+            let (fin, opcode) = (rest[0] & 0x80 != 0, rest[0] & 0x0F);
+            if rest[1] & 0x80 == 0 {
+                // client frames must be masked
+                self.close(CLOSE_PROTOCOL, output);
+                break;
+            }
+            let (len, head) = match rest[1] & 0x7F {
+                126 if rest.len() >= 4 => (u16::from_be_bytes([rest[2], rest[3]]) as u64, 4),
+                127 if rest.len() >= 10 => {
+                    let mut buf = [0; 8];
+                    buf.copy_from_slice(&rest[2..10]);
+                    (u64::from_be_bytes(buf), 10)
+                }
+                126 | 127 => break,
+                n => (n as u64, 2),
+            };
+            if len > MAX_MESSAGE as u64 || self.message.as_ref().map_or(0, Vec::len) + len as usize > MAX_MESSAGE {
+                self.close(CLOSE_TOO_BIG, output);
+                break;
+            }
+            let len = len as usize;
+            let payload = match rest.get(head + 4..head + 4 + len) {
+                Some(payload) => payload,
+                None => break,
+            };
+            let key = &rest[head..head + 4];
+            let payload = payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]).collect::<Vec<_>>();
+            pos += head + 4 + len;
+
+            match opcode {
+                OP_PING => frame(OP_PONG, &payload, output),
+                OP_PONG => (),
+                OP_CLOSE => {
+                    frame(OP_CLOSE, payload.get(..2).unwrap_or_default(), output);
+                    self.closed = true;
+                }
+                OP_TEXT | OP_CONT => {
+                    // continuation frames only after an unfinished text frame
+                    if (opcode == OP_TEXT) == self.message.is_some() {
+                        self.close(CLOSE_PROTOCOL, output);
+                        break;
+                    }
+                    let mut message = self.message.take().unwrap_or_default();
+                    message.extend_from_slice(&payload);
+                    if !fin {
+                        self.message = Some(message);
+                    } else {
+                        match String::from_utf8(message) {
+                            Ok(text) => self.handle(&text, output),
+                            Err(_) => self.close(CLOSE_PROTOCOL, output),
+                        }
+                    }
+                }
+                _ => self.close(CLOSE_UNSUPPORTED, output),
+            }
+        }
+        if self.closed { input.len() } else { pos }
+    }
+
+    /// Handle a message from the client.
+    fn handle(&mut self, text: &str, output: &mut Vec<u8>) {
+        let msg = match Value::parse(text) {
+            Ok(msg) => msg,
+            Err(e) => return send(Value::object([
+                ("error", Value::str(format!("invalid JSON: {}", e)))]), output),
+        };
+        let id = msg.get("id").cloned().unwrap_or(Value::Null);
+        let result = match msg.get("op").and_then(Value::as_str) {
+            Some("subscribe") => self.subscribe(&msg, id.clone()),
+            Some("unsubscribe") => {
+                self.subs.retain(|sub| sub.id != id);
+                Ok(())
+            }
+            _ => Err("unknown operation".into()),
+        };
+        if let Err(e) = result {
+            send(Value::object([("id", id), ("error", Value::str(e))]), output);
+        }
+    }
+
+    fn subscribe(&mut self, msg: &Value, id: Value) -> Result<(), String> {
+        if self.subs.iter().any(|sub| sub.id == id) {
+            return Err("subscription ID already in use".into());
+        }
+        let area = match msg.get("area").and_then(Value::as_str) {
+            None | Some("extern") => Area::Extern,
+            Some("process") if self.process => Area::Process,
+            Some(area) => return Err(format!("unknown area {}", area)),
+        };
+        let vars = match area {
+            Area::Extern => &self.info.extern_vars,
+            Area::Process => &self.info.process_vars,
+        };
+        let size = vars.iter().map(|v| v.offset + v.size()).max().unwrap_or(0);
+        let list = |key| match msg.get(key) {
+            None => Ok(&[][..]),
+            Some(list) => list.as_array().ok_or(format!("{} must be an array", key)),
+        };
+        let mut items = Vec::new();
+        for name in list("vars")? {
+            let name = name.as_str().ok_or("variable names must be strings")?;
+            let within = vars.iter().filter(|v| v.is_within(name)).collect::<Vec<_>>();
+            if within.is_empty() {
+                return Err(format!("no variable {}", name));
+            }
+            items.push(Item::Var(name.into(), span(within)));
+        }
+        for region in list("regions")? {
+            let int = |v: &Value| v.as_i128().and_then(|v| usize::try_from(v).ok());
+            let range = match region.as_array() {
+                Some([addr, count]) => int(addr).zip(int(count)).and_then(
+                    |(addr, count)| Some(addr..addr.checked_add(count)?)),
+                _ => None,
+            };
+            match range {
+                Some(range) if !range.is_empty() && range.end <= size => items.push(Item::Region(range)),
+                _ => return Err(format!("invalid region {}", region)),
+            }
+        }
+        if items.is_empty() {
+            return Err("nothing to subscribe".into());
+        }
+        let interval = match msg.get("interval") {
+            None => None,
+            Some(ms) => match ms.as_f64() {
+                Some(ms) if ms > 0.0 && ms <= MAX_INTERVAL =>
+                    Some(Duration::from_secs_f64(ms / 1000.)),
+                _ => return Err("invalid interval".into()),
+            },
+        };
+        debug!("client {} subscribed to {}", self.hid, msg);
+        let last = items.iter().map(|_| None).collect();
+        self.subs.push(Subscription { id, area, items, interval, next: Instant::now(), last });
+        Ok(())
+    }
+
+    /// Add sample requests for the subscribed images.
+    pub fn poll(&mut self, requests: &mut Vec<Request<HttpExtra>>) {
+        for area in [Area::Extern, Area::Process] {
+            let ranges = self.subs.iter().filter(|sub| sub.area == area)
+                                         .flat_map(|sub| sub.items.iter().map(Item::range));
+            let (start, end) = ranges.fold((usize::MAX, 0), |(start, end), range| {
+                (start.min(range.start), end.max(range.end))
+            });
+            if start >= end {
+                continue;
+            }
+            while self.in_flight[area_index(area)] < SAMPLES_IN_FLIGHT {
+                self.in_flight[area_index(area)] += 1;
+                requests.push(Request {
+                    hid: self.hid, area, addr: start, count: end - start, write: None,
+                    mask: None, read_back: Some((start, end - start)), lock: None,
+                    extra: HttpExtra { sample: true, ..Default::default() }
+                });
+            }
+        }
+    }
+
+    /// Process a sample of image data starting at `base`.
+    pub fn sample(&mut self, area: Area, base: usize, data: &[u8], output: &mut Vec<u8>) {
+        self.in_flight[area_index(area)] -= 1;
+        if self.closed {
+            return;
+        }
+        let now = Instant::now();
+        let vars = match area {
+            Area::Extern => &self.info.extern_vars,
+            Area::Process => &self.info.process_vars,
+        };
+        let sample = base..base + data.len();
+        for sub in self.subs.iter_mut().filter(|sub| sub.area == area) {
+            if output.len() > MAX_BACKLOG {
+                break;
+            }
+            // the sample can be from before the subscription was made
+            if !sub.items.iter().all(|item| item.range().start >= sample.start &&
+                                            item.range().end <= sample.end) {
+                continue;
+            }
+            if let Some(interval) = sub.interval {
+                if now < sub.next {
+                    continue;
+                }
+                sub.next = (sub.next + interval).max(now);
+            }
+            let mut values = Vec::new();
+            for (item, last) in sub.items.iter().zip(&mut sub.last) {
+                let bytes = &data[item.range().start - base..item.range().end - base];
+                if sub.interval.is_none() && last.as_deref() == Some(bytes) {
+                    continue;
+                }
+                *last = Some(bytes.to_vec());
+                let value = match item {
+                    Item::Var(name, _) => value(vars, name, data, base),
+                    Item::Region(_) => Value::String(bytes.iter().fold(String::new(), |mut s, b| {
+                        let _ = write!(s, "{:02x}", b);
+                        s
+                    })),
+                };
+                values.push((item.key(), value));
+            }
+            if !values.is_empty() {
+                let msg = Value::Object(vec![("id".into(), sub.id.clone()),
+                                             ("values".into(), Value::Object(values))]);
+                send(msg, output);
+            }
+        }
+    }
+
+    /// A sample request failed; end all subscriptions for the image.
+    pub fn sample_failed(&mut self, area: Area, code: u8, output: &mut Vec<u8>) {
+        self.in_flight[area_index(area)] -= 1;
+        if self.closed {
+            return;
+        }
+        warn!("client {}: sampling failed with error {}", self.hid, code);
+        for sub in std::mem::take(&mut self.subs) {
+            if sub.area == area {
+                let msg = format!("sampling failed with error {}", code);
+                send(Value::object([("id", sub.id), ("error", Value::str(msg))]), output);
+            } else {
+                self.subs.push(sub);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::image::{VarInfo, VarType};
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn accept_keys() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+                   "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        assert_eq!(hex(&sha1(&[b'a'; 1000])), "291e9a6c66994949b57ba5e650361e98fc36b1ba");
+        let encoded = ["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg==", "Zm9vYmE=", "Zm9vYmFy"];
+        for (len, expected) in encoded.iter().enumerate() {
+            assert_eq!(base64(&b"foobar"[..len]), *expected);
+        }
+        // the example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn server_frames() {
+        for (len, head) in [(0, &[0x81, 0][..]), (125, &[0x81, 125]), (126, &[0x81, 126, 0, 126]),
+                            (65536, &[0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0])] {
+            let mut output = Vec::new();
+            frame(OP_TEXT, &vec![b'x'; len], &mut output);
+            assert_eq!(&output[..head.len()], head);
+            assert_eq!(output.len(), head.len() + len);
+        }
+    }
+
+    /// A masked frame, as sent by clients.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let key = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![(fin as u8) << 7 | opcode];
+        match payload.len() {
+            n if n < 126 => frame.push(0x80 | n as u8),
+            n if n < 0x10000 => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&key);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        frame
+    }
+
+    /// The text messages, or other frames, sent by the server.
+    fn server_messages(mut output: &[u8]) -> Vec<String> {
+        let mut msgs = Vec::new();
+        while !output.is_empty() {
+            let (len, head) = match output[1] {
+                126 => (u16::from_be_bytes([output[2], output[3]]) as usize, 4),
+                n => (n as usize, 2),
+            };
+            let payload = &output[head..head + len];
+            msgs.push(if output[0] == 0x81 { String::from_utf8(payload.to_vec()).unwrap() }
+                      else { format!("{:x} {}", output[0], hex(payload)) });
+            output = &output[head + len..];
+        }
+        msgs
+    }
+
+    fn websocket() -> WebSocket {
+        let info = PlcInfo {
+            extern_vars: vec![VarInfo::new("flags", 0, VarType::U8),
+                              VarInfo::new("dev.value", 2, VarType::I16),
+                              VarInfo::new("dev.target", 4, VarType::I16)],
+            ..Default::default()
+        };
+        WebSocket::new(1, Arc::new(info), false)
+    }
+
+    #[test]
+    fn control_frames() {
+        let mut ws = websocket();
+        let mut input = client_frame(true, OP_PING, b"hi");
+        input.extend(client_frame(true, OP_PONG, b""));
+        input.extend(client_frame(false, OP_TEXT, b"{\"op\":"));
+        input.extend(client_frame(true, OP_CONT, b"1}"));
+        let partial = client_frame(true, OP_CLOSE, &1000u16.to_be_bytes());
+        input.extend_from_slice(&partial[..3]);
+        let mut output = Vec::new();
+        assert_eq!(ws.receive(&input, &mut output), input.len() - 3);
+        assert_eq!(server_messages(&output),
+                   ["8a 6869", r#"{"id":null,"error":"unknown operation"}"#]);
+
+        output.clear();
+        assert_eq!(ws.receive(&partial, &mut output), partial.len());
+        assert_eq!(server_messages(&output), ["88 03e8"]);
+        assert!(ws.closed());
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let unmasked = vec![0x81, 1, b'x'];
+        let mut too_big = client_frame(false, OP_TEXT, &vec![b' '; MAX_MESSAGE]);
+        too_big.extend(client_frame(true, OP_CONT, b" "));
+        for (input, code) in [
+            (unmasked, "03ea"),
+            (client_frame(true, OP_CONT, b"{}"), "03ea"),
+            ([client_frame(false, OP_TEXT, b"["), client_frame(true, OP_TEXT, b"]")].concat(),
+             "03ea"),
+            (client_frame(true, OP_TEXT, b"\xff"), "03ea"),
+            (client_frame(true, 0x2, b"binary"), "03eb"),
+            (too_big, "03f1"),
+            (vec![0x81, 0xFF, 0, 0, 0, 0, 0, 1, 0, 1], "03f1"),
+        ] {
+            let mut ws = websocket();
+            let mut output = Vec::new();
+            assert_eq!(ws.receive(&input, &mut output), input.len());
+            assert_eq!(server_messages(&output), [format!("88 {}", code)]);
+            assert!(ws.closed());
+        }
+    }
+
+    fn message(ws: &mut WebSocket, text: &str) -> Vec<String> {
+        let mut output = Vec::new();
+        ws.receive(&client_frame(true, OP_TEXT, text.as_bytes()), &mut output);
+        server_messages(&output)
+    }
+
+    #[test]
+    fn subscriptions() {
+        let mut ws = websocket();
+        assert!(message(&mut ws, r#"{"op":"subscribe","id":1,"vars":["dev"],
+                                     "regions":[[0,1]]}"#).is_empty());
+        let mut requests = Vec::new();
+        ws.poll(&mut requests);
+        assert_eq!(requests.len(), SAMPLES_IN_FLIGHT);
+        assert_eq!(requests[0].read_back, Some((0, 6)));
+
+        let mut output = Vec::new();
+        let mut data = [7, 0, 1, 0, 2, 0];
+        ws.sample(Area::Extern, 0, &data, &mut output);
+        ws.sample(Area::Extern, 0, &data, &mut output);
+        data[4] = 3;
+        ws.sample(Area::Extern, 0, &data, &mut output);
+        let value = i16::from_ne_bytes([1, 0]);
+        let target = i16::from_ne_bytes([3, 0]);
+        assert_eq!(server_messages(&output), [
+            format!(r#"{{"id":1,"values":{{"dev":{{"value":{},"target":{}}},"0+1":"07"}}}}"#,
+                    value, i16::from_ne_bytes([2, 0])),
+            format!(r#"{{"id":1,"values":{{"dev":{{"value":{},"target":{}}}}}}}"#, value, target),
+        ]);
+        // one more request for each answered sample
+        requests.clear();
+        ws.poll(&mut requests);
+        assert_eq!(requests.len(), 3);
+
+        assert_eq!(message(&mut ws, r#"{"op":"subscribe","id":1,"vars":["flags"]}"#),
+                   [r#"{"id":1,"error":"subscription ID already in use"}"#]);
+        assert!(message(&mut ws, r#"{"op":"unsubscribe","id":1}"#).is_empty());
+        output.clear();
+        ws.sample(Area::Extern, 0, &data, &mut output);
+        assert!(output.is_empty());
+
+        assert!(message(&mut ws, r#"{"op":"subscribe","id":"x","vars":["flags"]}"#).is_empty());
+        output.clear();
+        ws.sample_failed(Area::Extern, 4, &mut output);
+        assert_eq!(server_messages(&output),
+                   [r#"{"id":"x","error":"sampling failed with error 4"}"#]);
+    }
+
+    #[test]
+    fn subscription_errors() {
+        let mut ws = websocket();
+        for (msg, error) in [
+            (r#"{"op":"subscribe","vars":["nope"]}"#, "no variable nope"),
+            (r#"{"op":"subscribe","vars":"flags"}"#, "vars must be an array"),
+            (r#"{"op":"subscribe","vars":[1]}"#, "variable names must be strings"),
+            (r#"{"op":"subscribe"}"#, "nothing to subscribe"),
+            (r#"{"op":"subscribe","area":"process","vars":["flags"]}"#, "unknown area process"),
+            (r#"{"op":"subscribe","regions":[[6,1]]}"#, "invalid region [6,1]"),
+            (r#"{"op":"subscribe","regions":[[0,0]]}"#, "invalid region [0,0]"),
+            (r#"{"op":"subscribe","regions":[[-1,2]]}"#, "invalid region [-1,2]"),
+            (r#"{"op":"subscribe","regions":[[18446744073709551615,1]]}"#,
+             "invalid region [18446744073709551615,1]"),
+            (r#"{"op":"subscribe","vars":["flags"],"interval":0}"#, "invalid interval"),
+            (r#"{"op":"subscribe","vars":["flags"],"interval":1e400}"#, "invalid interval"),
+        ] {
+            assert_eq!(message(&mut ws, msg), [format!(r#"{{"id":null,"error":"{}"}}"#, error)]);
+        }
+        assert_eq!(message(&mut ws, "{"),
+                   [r#"{"error":"invalid JSON: expected member name at offset 1"}"#]);
+        let mut requests = Vec::new();
+        ws.poll(&mut requests);
+        assert!(requests.is_empty());
+    }
+}
+
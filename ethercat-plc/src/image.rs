@@ -3,8 +3,211 @@
 
 //! Tools to create a typesafe process image matching with possible slave PDOs.
 
+use byteorder::{ByteOrder, NativeEndian as NE};
 use ethercat::*;
 
+/// Primitive types that can appear as variables in a process or extern image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarType {
+    U8, I8, U16, I16, U32, I32, U64, I64, F32, F64,
+}
+
+impl VarType {
+    pub fn size(self) -> usize {
+        match self {
+            VarType::U8  | VarType::I8  => 1,
+            VarType::U16 | VarType::I16 => 2,
+            VarType::U32 | VarType::I32 | VarType::F32 => 4,
+            VarType::U64 | VarType::I64 | VarType::F64 => 8,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VarType::U8  => "u8",
+            VarType::I8  => "i8",
+            VarType::U16 => "u16",
+            VarType::I16 => "i16",
+            VarType::U32 => "u32",
+            VarType::I32 => "i32",
+            VarType::U64 => "u64",
+            VarType::I64 => "i64",
+            VarType::F32 => "f32",
+            VarType::F64 => "f64",
+        }
+    }
+
+    pub fn is_float(self) -> bool {
+        matches!(self, VarType::F32 | VarType::F64)
+    }
+}
+
+/// A single primitive variable inside an image, with its dotted path name
+/// (e.g. `ana_out.ch1` or `indexer.data[3]`) and byte offset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarInfo {
+    pub name: String,
+    pub offset: usize,
+    pub ty: VarType,
+    /// Set for fields marked `#[plc(read_only)]`; remote clients may not
+    /// write to them.
+    pub read_only: bool,
+}
+
+impl VarInfo {
+    pub fn new(name: impl Into<String>, offset: usize, ty: VarType) -> Self {
+        Self { name: name.into(), offset, ty, read_only: false }
+    }
+
+    /// Whether this is the variable with the given name, or a member of it
+    /// (e.g. `indexer.data[3]` is a member of `indexer` and `indexer.data`).
+    pub fn is_within(&self, name: &str) -> bool {
+        self.name.strip_prefix(name).map_or(false, |rest| {
+            rest.is_empty() || rest.starts_with('.') || rest.starts_with('[')
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.ty.size()
+    }
+
+    /// Byte range of the variable inside the image.
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.offset..self.offset + self.ty.size()
+    }
+
+    /// Read the variable from the (native endian) image data as a float.
+    pub fn read_f64(&self, data: &[u8]) -> f64 {
+        let d = &data[self.range()];
+        match self.ty {
+            VarType::U8  => d[0] as f64,
+            VarType::I8  => d[0] as i8 as f64,
+            VarType::U16 => NE::read_u16(d) as f64,
+            VarType::I16 => NE::read_i16(d) as f64,
+            VarType::U32 => NE::read_u32(d) as f64,
+            VarType::I32 => NE::read_i32(d) as f64,
+            VarType::U64 => NE::read_u64(d) as f64,
+            VarType::I64 => NE::read_i64(d) as f64,
+            VarType::F32 => NE::read_f32(d) as f64,
+            VarType::F64 => NE::read_f64(d),
+        }
+    }
+
+    /// Format the variable's current value from the (native endian) image data.
+    pub fn format_value(&self, data: &[u8]) -> String {
+        let d = &data[self.range()];
+        match self.ty {
+            VarType::U8  => d[0].to_string(),
+            VarType::I8  => (d[0] as i8).to_string(),
+            VarType::U16 => NE::read_u16(d).to_string(),
+            VarType::I16 => NE::read_i16(d).to_string(),
+            VarType::U32 => NE::read_u32(d).to_string(),
+            VarType::I32 => NE::read_i32(d).to_string(),
+            VarType::U64 => NE::read_u64(d).to_string(),
+            VarType::I64 => NE::read_i64(d).to_string(),
+            VarType::F32 => NE::read_f32(d).to_string(),
+            VarType::F64 => NE::read_f64(d).to_string(),
+        }
+    }
+
+    /// Write the variable into the (native endian) image data, rounding and
+    /// saturating to the range of integer types.
+    pub fn write_f64(&self, data: &mut [u8], value: f64) {
+        let d = &mut data[self.range()];
+        let int = value.round();
+        match self.ty {
+            VarType::U8  => d[0] = int as u8,
+            VarType::I8  => d[0] = int as i8 as u8,
+            VarType::U16 => NE::write_u16(d, int as u16),
+            VarType::I16 => NE::write_i16(d, int as i16),
+            VarType::U32 => NE::write_u32(d, int as u32),
+            VarType::I32 => NE::write_i32(d, int as i32),
+            VarType::U64 => NE::write_u64(d, int as u64),
+            VarType::I64 => NE::write_i64(d, int as i64),
+            VarType::F32 => NE::write_f32(d, value as f32),
+            VarType::F64 => NE::write_f64(d, value),
+        }
+    }
+}
+
+/// Describes the layout of a type as a list of primitive variables.
+///
+/// This is implemented for primitive numbers and arrays, and can be derived
+/// for structs.  The `ExternImage`, `ProcessImage` and `SlaveProcessImage`
+/// derives implement it automatically.  Fields whose type does not implement
+/// it are left out of the variable list, so they can't be accessed by name.
+pub trait ImageVars {
+    fn collect_vars(prefix: &str, offset: usize, vars: &mut Vec<VarInfo>);
+
+    fn vars() -> Vec<VarInfo> {
+        let mut vars = Vec::new();
+        Self::collect_vars("", 0, &mut vars);
+        vars
+    }
+
+    fn find_var(name: &str) -> Option<VarInfo> {
+        Self::vars().into_iter().find(|v| v.name == name)
+    }
+}
+
+/// Join a variable path prefix and a field name.
+pub fn join_var_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.into()
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
+
+macro_rules! impl_image_vars {
+    ($($ty:ty => $var:ident),*) => {
+        $(
+            impl ImageVars for $ty {
+                fn collect_vars(prefix: &str, offset: usize, vars: &mut Vec<VarInfo>) {
+                    vars.push(VarInfo::new(prefix, offset, VarType::$var));
+                }
+            }
+        )*
+    };
+}
+
+impl_image_vars!(u8 => U8, i8 => I8, u16 => U16, i16 => I16, u32 => U32, i32 => I32,
+                 u64 => U64, i64 => I64, f32 => F32, f64 => F64);
+
+impl<T: ImageVars, const N: usize> ImageVars for [T; N] {
+    fn collect_vars(prefix: &str, offset: usize, vars: &mut Vec<VarInfo>) {
+        for i in 0..N {
+            T::collect_vars(&format!("{}[{}]", prefix, i),
+                            offset + i * std::mem::size_of::<T>(), vars);
+        }
+    }
+}
+
+// Used by the derives to collect the variables of a field if its type
+// implements `ImageVars`, and to skip it otherwise.  Method resolution
+// prefers `CollectFieldVars` on `FieldVars<T>` over `SkipFieldVars` on
+// `&FieldVars<T>`, which only applies when the former does not.
+#[doc(hidden)]
+pub struct FieldVars<T>(pub std::marker::PhantomData<T>);
+
+#[doc(hidden)]
+pub trait CollectFieldVars {
+    fn collect_field_vars(&self, prefix: &str, offset: usize, vars: &mut Vec<VarInfo>);
+}
+
+impl<T: ImageVars> CollectFieldVars for FieldVars<T> {
+    fn collect_field_vars(&self, prefix: &str, offset: usize, vars: &mut Vec<VarInfo>) {
+        T::collect_vars(prefix, offset, vars)
+    }
+}
+
+#[doc(hidden)]
+pub trait SkipFieldVars {
+    fn collect_field_vars(&self, _prefix: &str, _offset: usize, _vars: &mut Vec<VarInfo>) {}
+}
+
+impl<T> SkipFieldVars for &FieldVars<T> {}
+
 pub trait ProcessImage {
     // configuration APIs
     const SLAVE_COUNT: usize;
@@ -16,6 +219,10 @@ pub trait ProcessImage {
         vec![(None, None)]
     }
 
+    /// Variables of the image that servers and tools can access by name.
+    /// The derive lists all fields; other impls have no named variables.
+    fn image_vars() -> Vec<VarInfo> where Self: Sized { Vec::new() }
+
     fn size() -> usize where Self: Sized {
         std::mem::size_of::<Self>()
     }
@@ -26,6 +233,10 @@ pub trait ProcessImage {
 }
 
 pub trait ExternImage : Default {
+    /// Variables of the image that servers and tools can access by name.
+    /// The derive lists all fields; other impls have no named variables.
+    fn image_vars() -> Vec<VarInfo> where Self: Sized { Vec::new() }
+
     fn size() -> usize where Self: Sized {
         std::mem::size_of::<Self>()
     }
@@ -53,7 +264,7 @@ impl ProcessConfig for std::collections::HashMap<String, Box<dyn SdoData>> {
     }
 }
 
-impl<'a> ProcessConfig for std::collections::HashMap<&'a str, Box<dyn SdoData>> {
+impl ProcessConfig for std::collections::HashMap<&str, Box<dyn SdoData>> {
     fn get_sdo_var(&self, var: &str) -> Option<&dyn SdoData> {
         self.get(var).map(|s| &**s)
     }
@@ -0,0 +1,629 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! A minimal JSON value type, with parser and serializer, for the
+//! text-based server protocols, and the conversion of image variables to
+//! and from JSON.
+
+use std::fmt::{self, Write};
+use std::ops::Range;
+use byteorder::{ByteOrder, NativeEndian as NE};
+
+use crate::image::{VarInfo, VarType};
+
+/// Maximum nesting depth of parsed documents.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    /// Numbers are kept as their text, so that 64-bit integers don't lose
+    /// precision.
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    /// Members in document order.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn str(s: impl Into<String>) -> Self {
+        Value::String(s.into())
+    }
+
+    pub fn num(n: impl ToString) -> Self {
+        Value::Number(n.to_string())
+    }
+
+    pub fn object<'a>(members: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Value::Object(members.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => n.parse().ok(),
+            Value::Bool(b) => Some(*b as u8 as f64),
+            _ => None,
+        }
+    }
+
+    /// The value as an integer, if it is a number without fractional part.
+    pub fn as_i128(&self) -> Option<i128> {
+        match self {
+            Value::Number(n) => n.parse().ok().or_else(|| {
+                let f = n.parse::<f64>().ok()?;
+                // beyond this, floats can't be converted exactly anyway
+                if f.fract() == 0.0 && f.abs() < 1e38 { Some(f as i128) } else { None }
+            }),
+            Value::Bool(b) => Some(*b as i128),
+            _ => None,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Value, String> {
+        let mut parser = Parser { text: text.as_bytes(), pos: 0 };
+        let value = parser.value(0)?;
+        parser.skip_ws();
+        if parser.pos != text.len() {
+            return Err(format!("trailing characters at offset {}", parser.pos));
+        }
+        Ok(value)
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => f.write_str(n),
+            Value::String(s) => write_str(f, s),
+            Value::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Value::Object(members) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error<T>(&self, what: &str) -> Result<T, String> {
+        Err(format!("{} at offset {}", what, self.pos))
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.text.get(self.pos), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.skip_ws();
+        if self.text.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.eat(c) { Ok(()) } else { self.error(&format!("expected '{}'", c as char)) }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        if self.text[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            self.error("invalid literal")
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return self.error("nesting too deep");
+        }
+        self.skip_ws();
+        match self.text.get(self.pos) {
+            None => self.error("unexpected end"),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Value::Array(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_ws();
+                        if self.text.get(self.pos) != Some(&b'"') {
+                            return self.error("expected member name");
+                        }
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        members.push((key, self.value(depth + 1)?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+                Ok(Value::Object(members))
+            }
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => self.error("unexpected character"),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        let digits = |p: &mut Self| {
+            let from = p.pos;
+            while p.text.get(p.pos).map_or(false, u8::is_ascii_digit) {
+                p.pos += 1;
+            }
+            p.pos > from
+        };
+        if self.text.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        if !digits(self) {
+            return self.error("invalid number");
+        }
+        if self.text.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            if !digits(self) {
+                return self.error("invalid number");
+            }
+        }
+        if matches!(self.text.get(self.pos), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.text.get(self.pos), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                return self.error("invalid number");
+            }
+        }
+        // only ASCII characters have been accepted
+        Ok(Value::Number(String::from_utf8_lossy(&self.text[start..self.pos]).into_owned()))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let code = self.text.get(self.pos..self.pos + 4)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+        match code {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        // skip the opening quote
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            match self.text.get(self.pos) {
+                None => return self.error("unterminated string"),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = match self.text.get(self.pos) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\x08',
+                        Some(b'f') => '\x0c',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            let mut code = self.hex4()?;
+                            if (0xD800..0xDC00).contains(&code) &&
+                                self.text[self.pos..].starts_with(b"\\u")
+                            {
+                                // combine with a following low surrogate
+                                let high_end = self.pos;
+                                self.pos += 2;
+                                match self.hex4()? {
+                                    low @ 0xDC00..=0xDFFF =>
+                                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00),
+                                    _ => self.pos = high_end,
+                                }
+                            }
+                            self.pos -= 1;
+                            char::from_u32(code).unwrap_or('\u{FFFD}')
+                        }
+                        _ => return self.error("invalid escape"),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                Some(&c) if c < 0x20 => return self.error("control character in string"),
+                Some(&c) => bytes.push(c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        String::from_utf8(bytes).or_else(|_| self.error("invalid UTF-8 in string"))
+    }
+}
+
+
+/// A part of a variable name below a struct or array.
+enum Segment<'a> {
+    Member(&'a str),
+    Index(usize),
+}
+
+/// Split the part of a variable name below `prefix` into segments.
+fn segments<'a>(name: &'a str, prefix: &str) -> Vec<Segment<'a>> {
+    let mut rest = &name[prefix.len()..];
+    let mut result = Vec::new();
+    while !rest.is_empty() {
+        if let Some(index) = rest.strip_prefix('[') {
+            let end = index.find(']').unwrap_or(index.len());
+            result.push(Segment::Index(index[..end].parse().unwrap_or(0)));
+            rest = index.get(end + 1..).unwrap_or("");
+        } else {
+            let member = rest.strip_prefix('.').unwrap_or(rest);
+            let end = member.find(['.', '[']).unwrap_or(member.len());
+            result.push(Segment::Member(&member[..end]));
+            rest = &member[end..];
+        }
+    }
+    result
+}
+
+/// Place a leaf value in a tree of objects and arrays.
+fn insert(target: &mut Value, path: &[Segment], leaf: Value) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return *target = leaf,
+    };
+    match first {
+        Segment::Member(key) => {
+            if !matches!(target, Value::Object(_)) {
+                *target = Value::Object(Vec::new());
+            }
+            if let Value::Object(members) = target {
+                let pos = match members.iter().position(|(k, _)| k == key) {
+                    Some(pos) => pos,
+                    None => {
+                        members.push((key.to_string(), Value::Null));
+                        members.len() - 1
+                    }
+                };
+                insert(&mut members[pos].1, rest, leaf);
+            }
+        }
+        Segment::Index(index) => {
+            if !matches!(target, Value::Array(_)) {
+                *target = Value::Array(Vec::new());
+            }
+            if let Value::Array(items) = target {
+                if items.len() <= *index {
+                    items.resize(index + 1, Value::Null);
+                }
+                insert(&mut items[*index], rest, leaf);
+            }
+        }
+    }
+}
+
+/// Flatten a JSON value into variable names and leaf values.
+fn flatten<'v>(value: &'v Value, name: String, leaves: &mut Vec<(String, &'v Value)>) {
+    match value {
+        Value::Object(members) => for (key, value) in members {
+            let name = if name.is_empty() { key.clone() } else { format!("{}.{}", name, key) };
+            flatten(value, name, leaves);
+        }
+        Value::Array(items) => for (i, value) in items.iter().enumerate() {
+            if *value != Value::Null {
+                flatten(value, format!("{}[{}]", name, i), leaves);
+            }
+        }
+        _ => leaves.push((name, value)),
+    }
+}
+
+/// Format a variable from the image data, which starts at `base`.
+pub(crate) fn to_json(var: &VarInfo, data: &[u8], base: usize) -> Value {
+    let var = VarInfo { offset: var.offset - base, ..var.clone() };
+    if var.ty.is_float() && !var.read_f64(data).is_finite() {
+        return Value::Null;
+    }
+    Value::Number(var.format_value(data))
+}
+
+/// The range of values of an integer type, or `None` for floats.
+pub(crate) fn int_range(ty: VarType) -> Option<(i128, i128)> {
+    Some(match ty {
+        VarType::U8  => (0, u8::MAX as i128),
+        VarType::I8  => (i8::MIN as i128, i8::MAX as i128),
+        VarType::U16 => (0, u16::MAX as i128),
+        VarType::I16 => (i16::MIN as i128, i16::MAX as i128),
+        VarType::U32 => (0, u32::MAX as i128),
+        VarType::I32 => (i32::MIN as i128, i32::MAX as i128),
+        VarType::U64 => (0, u64::MAX as i128),
+        VarType::I64 => (i64::MIN as i128, i64::MAX as i128),
+        VarType::F32 | VarType::F64 => return None,
+    })
+}
+
+/// Encode a JSON value for a variable of the given type.
+pub(crate) fn from_json(ty: VarType, value: &Value) -> Option<Vec<u8>> {
+    let mut buf = vec![0; ty.size()];
+    if ty.is_float() {
+        let v = value.as_f64()?;
+        if ty == VarType::F32 {
+            NE::write_f32(&mut buf, v as f32);
+        } else {
+            NE::write_f64(&mut buf, v);
+        }
+        return Some(buf);
+    }
+    let v = value.as_i128()?;
+    let (min, max) = int_range(ty)?;
+    if v < min || v > max {
+        return None;
+    }
+    match ty {
+        VarType::U8 | VarType::I8 => buf[0] = v as u8,
+        VarType::U16 | VarType::I16 => NE::write_u16(&mut buf, v as u16),
+        VarType::U32 | VarType::I32 => NE::write_u32(&mut buf, v as u32),
+        _ => NE::write_u64(&mut buf, v as u64),
+    }
+    Some(buf)
+}
+
+/// Build the JSON value of the named variable (or of all variables for an
+/// empty name) from image data starting at `base`.
+pub(crate) fn value(vars: &[VarInfo], name: &str, data: &[u8], base: usize) -> Value {
+    let mut value = Value::Null;
+    for var in vars.iter().filter(|v| name.is_empty() || v.is_within(name)) {
+        insert(&mut value, &segments(&var.name, name), to_json(var, data, base));
+    }
+    value
+}
+
+/// The byte range covering all given (at least one) variables.
+pub(crate) fn span<'a>(vars: impl IntoIterator<Item = &'a VarInfo>) -> Range<usize> {
+    let (start, end) = vars.into_iter().fold((usize::MAX, 0), |(start, end), var| {
+        (start.min(var.offset), end.max(var.offset + var.size()))
+    });
+    start..end
+}
+
+/// The image data of a write of a JSON value to the named variable, which
+/// is made up of `vars`: the byte range, data, and mask if not all bytes in
+/// the range are written.  With `complete`, values must be given for all
+/// writable variables.  Returns `None` if there is nothing to write.
+pub(crate) fn encode_write(vars: &[&VarInfo], name: &str, value: &Value, complete: bool)
+                           -> Result<Option<(Range<usize>, Vec<u8>, Option<Vec<u8>>)>, String> {
+    let mut leaves = Vec::new();
+    flatten(value, name.into(), &mut leaves);
+    let mut writes = Vec::new();
+    for (name, value) in leaves {
+        let var = vars.iter().find(|v| v.name == name)
+                             .ok_or_else(|| format!("no variable {}", name))?;
+        let bytes = from_json(var.ty, value).ok_or_else(
+            || format!("invalid value for {} ({}): {}", name, var.ty.name(), value))?;
+        writes.push((*var, bytes));
+    }
+    if complete {
+        if let Some(var) = vars.iter().find(|v| !v.read_only &&
+                                                !writes.iter().any(|(w, _)| w.name == v.name)) {
+            return Err(format!("missing value for {}", var.name));
+        }
+    }
+    if writes.is_empty() {
+        return Ok(None);
+    }
+
+    let range = span(writes.iter().map(|(var, _)| *var));
+    let mut data = vec![0; range.len()];
+    let mut mask = vec![0; range.len()];
+    for (var, bytes) in writes {
+        let at = var.offset - range.start..var.offset - range.start + var.size();
+        data[at.clone()].copy_from_slice(&bytes);
+        mask[at].iter_mut().for_each(|m| *m = 0xFF);
+    }
+    let mask = if mask.contains(&0) { Some(mask) } else { None };
+    Ok(Some((range, data, mask)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        let text = r#" {"a": [1, -2.5e+3, true, null], "b": "q\"\\/\n\u00e9\ud83d\ude00",
+                        "c": {}, "d": [], "e": 18446744073709551615} "#;
+        let value = Value::parse(text).unwrap();
+        assert_eq!(value, Value::object([
+            ("a", Value::Array(vec![Value::num(1), Value::num("-2.5e+3"), Value::Bool(true),
+                                    Value::Null])),
+            ("b", Value::str("q\"\\/\n\u{e9}\u{1F600}")),
+            ("c", Value::Object(vec![])),
+            ("d", Value::Array(vec![])),
+            ("e", Value::num(u64::MAX)),
+        ]));
+        assert_eq!(value.get("e").and_then(Value::as_i128), Some(u64::MAX as i128));
+        assert_eq!(value.to_string(), "{\"a\":[1,-2.5e+3,true,null],\
+                                       \"b\":\"q\\\"\\\\/\\n\u{e9}\u{1F600}\",\
+                                       \"c\":{},\"d\":[],\"e\":18446744073709551615}");
+        assert_eq!(Value::parse(&value.to_string()), Ok(value));
+        assert_eq!(Value::str("\u{1}\t").to_string(), "\"\\u0001\\t\"");
+    }
+
+    #[test]
+    fn surrogates() {
+        let parse = |text| Value::parse(text).unwrap();
+        assert_eq!(parse(r#""\ud800""#), Value::str("\u{FFFD}"));
+        assert_eq!(parse(r#""\ud800\u0041""#), Value::str("\u{FFFD}A"));
+        assert_eq!(parse(r#""\udc00\ud800x""#), Value::str("\u{FFFD}\u{FFFD}x"));
+        assert_eq!(parse(r#""\uDBFF\uDFFF""#), Value::str("\u{10FFFF}"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let deep = "[".repeat(MAX_DEPTH + 2);
+        for text in ["", " ", "[1,]", "[1 2]", "{\"a\" 1}", "{\"a\":1,}", "{1:2}", "- 1", "-",
+                     "1.", "1e", ".5", "+1", "nul", "True", "\"abc", "\"\\x\"", "\"\\u12\"",
+                     "\"\\u+123\"", "\"\\ud800\\u12\"", "\"\x01\"", "1 2", "[]]",
+                     "\"\\", &deep] {
+            assert!(Value::parse(text).is_err(), "{:?} was accepted", text);
+        }
+        assert!(Value::parse(&format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH)))
+            .is_ok());
+    }
+
+    #[test]
+    fn numbers() {
+        let num = |n: &str| Value::Number(n.into());
+        assert_eq!(num("-12").as_i128(), Some(-12));
+        assert_eq!(num("1.0e3").as_i128(), Some(1000));
+        assert_eq!(num("1.5").as_i128(), None);
+        assert_eq!(num("1e300").as_i128(), None);
+        assert_eq!(num("1.5").as_f64(), Some(1.5));
+        assert_eq!(Value::Bool(true).as_i128(), Some(1));
+        assert_eq!(Value::str("1").as_f64(), None);
+    }
+
+    fn vars() -> Vec<VarInfo> {
+        vec![
+            VarInfo::new("s.a", 0, VarType::U16),
+            VarInfo::new("s.b[0]", 2, VarType::I8),
+            VarInfo::new("s.b[1]", 3, VarType::I8),
+            VarInfo { read_only: true, ..VarInfo::new("f", 4, VarType::F32) },
+        ]
+    }
+
+    #[test]
+    fn variable_values() {
+        let vars = vars();
+        let mut data = vec![0; 8];
+        NE::write_u16(&mut data, 513);
+        data[2] = 0xFF;
+        data[3] = 7;
+        NE::write_f32(&mut data[4..], 0.5);
+        assert_eq!(value(&vars, "", &data, 0).to_string(), r#"{"s":{"a":513,"b":[-1,7]},"f":0.5}"#);
+        assert_eq!(value(&vars, "s.b", &data[2..4], 2).to_string(), "[-1,7]");
+        assert_eq!(value(&vars, "s.b[1]", &data[3..4], 3).to_string(), "7");
+        NE::write_f32(&mut data[4..], f32::NAN);
+        assert_eq!(value(&vars, "f", &data, 0), Value::Null);
+        assert_eq!(span(&vars[1..]), 2..8);
+    }
+
+    #[test]
+    fn variable_writes() {
+        let vars = vars();
+        let vars = vars.iter().collect::<Vec<_>>();
+        let write = |name, json, complete| {
+            let sel = vars.iter().copied().filter(|v| v.is_within(name)).collect::<Vec<_>>();
+            encode_write(&sel, name, &Value::parse(json).unwrap(), complete)
+        };
+        let mut a = [0; 2];
+        NE::write_u16(&mut a, 1000);
+        assert_eq!(write("s", r#"{"a": 1000, "b": [-2, 3]}"#, true),
+                   Ok(Some((0..4, vec![a[0], a[1], 0xFE, 3], None))));
+        assert_eq!(write("s", r#"{"a": 1000, "b": [null, 3]}"#, false),
+                   Ok(Some((0..4, vec![a[0], a[1], 0, 3], Some(vec![0xFF, 0xFF, 0, 0xFF])))));
+        assert_eq!(write("s.b[1]", "-128", true), Ok(Some((3..4, vec![0x80], None))));
+        assert_eq!(write("s", "{}", false), Ok(None));
+        assert_eq!(write("s", r#"{"b": [1, 2]}"#, true), Err("missing value for s.a".into()));
+        assert_eq!(write("s", r#"{"c": 1}"#, false), Err("no variable s.c".into()));
+        assert_eq!(write("s.b", "[128]", false),
+                   Err("invalid value for s.b[0] (i8): 128".into()));
+        assert_eq!(write("s.a", "1.5", false), Err("invalid value for s.a (u16): 1.5".into()));
+        assert_eq!(write("s.a", r#""1""#, false), Err(r#"invalid value for s.a (u16): "1""#.into()));
+        // read-only variables need not be given
+        assert_eq!(write("f", "{}", true), Ok(None));
+        assert_eq!(from_json(VarType::F32, &Value::num(0.5)), Some(0.5f32.to_ne_bytes().to_vec()));
+        assert_eq!(from_json(VarType::U64, &Value::num("-1")), None);
+    }
+}
+
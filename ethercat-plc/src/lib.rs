@@ -3,14 +3,48 @@
 
 #![allow(clippy::type_complexity)]
 
+// allow the derive macros to refer to `ethercat_plc` paths from within this crate
+extern crate self as ethercat_plc;
+
 mod plc;
 mod image;
 mod server;
+mod sim;
+mod changes;
+mod locks;
+mod json;
+mod metrics;
+
+pub mod modbus;
+pub mod record;
+pub mod golden;
+pub mod shm;
+pub mod http;
+pub mod opcua;
+pub mod ads;
+pub mod mqtt;
+pub mod secop;
 
 pub mod beckhoff;
 pub mod mlz_spec;
 
-pub use self::plc::{Plc, PlcBuilder, PlcSimulator};
-pub use self::image::{ExternImage, ProcessImage, ProcessConfig};
-pub use self::server::{Server, NoServer, TcpServer, ModbusHandler, SimpleHandler};
-pub use ethercat_derive::{ExternImage, ProcessImage, SlaveProcessImage};
+pub use self::plc::{Plc, PlcBuilder, PlcSimulator, PlantSimulator};
+pub use self::sim::Wiring;
+pub use self::changes::Changes;
+pub use self::metrics::{Metrics, ServerMetrics};
+pub use self::image::{ExternImage, ProcessImage, ProcessConfig, ImageVars, VarInfo, VarType,
+                      join_var_name};
+#[doc(hidden)]
+pub use self::image::{FieldVars, CollectFieldVars, SkipFieldVars};
+pub use self::server::{Server, NoServer, TcpServer, TcpConfig, SimpleHandler, PlcInfo};
+// for the fuzz targets, which drive the protocol handlers directly
+#[doc(hidden)]
+pub use self::server::{Handler, Request, Response};
+pub use self::modbus::{ModbusHandler, ModbusConfig, ModbusRtuHandler, RtuServer, RtuConfig,
+                       WordOrder, RegisterOrder};
+pub use self::http::{HttpHandler, HttpConfig};
+pub use self::opcua::{OpcUaHandler, OpcUaConfig};
+pub use self::ads::{AdsHandler, AdsConfig};
+pub use self::mqtt::{MqttClient, MqttConfig, MqttPublish, MqttCommand};
+pub use self::secop::{SecopHandler, SecopConfig};
+pub use ethercat_derive::{ExternImage, ProcessImage, SlaveProcessImage, ImageVars};
@@ -0,0 +1,187 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Write ownership of extern image ranges among clients.
+
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use log::*;
+
+use crate::server::{LockOp, Request};
+
+#[derive(Debug)]
+struct Lock {
+    owner: usize,
+    range: Range<usize>,
+    /// The time the lock is renewed for.
+    timeout: Duration,
+    expires: Instant,
+}
+
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// The currently held locks.
+#[derive(Debug)]
+pub(crate) struct Locks {
+    default_timeout: Duration,
+    allow_force: bool,
+    locks: Vec<Lock>,
+}
+
+impl Locks {
+    pub fn new(default_timeout: Duration, allow_force: bool) -> Self {
+        Self { default_timeout, allow_force, locks: Vec::new() }
+    }
+
+    /// Whether clients may use the operation at all.
+    pub fn allows(&self, op: LockOp) -> bool {
+        op != LockOp::ForceRelease || self.allow_force
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.locks.retain(|lock| {
+            if lock.expires <= now {
+                info!("lock on {:?} of client {} expired", lock.range, lock.owner);
+            }
+            lock.expires > now
+        });
+    }
+
+    /// Apply a lock operation for the client.  Returns false if the range
+    /// is locked by another client.
+    pub fn apply(&mut self, owner: usize, range: Range<usize>, op: LockOp) -> bool {
+        self.expire();
+        match op {
+            LockOp::Claim(timeout) => {
+                if self.locks.iter().any(|l| l.owner != owner && overlaps(&l.range, &range)) {
+                    return false;
+                }
+                let timeout = timeout.unwrap_or(self.default_timeout);
+                let expires = Instant::now() + timeout;
+                match self.locks.iter_mut().find(|l| l.owner == owner && l.range == range) {
+                    Some(lock) => {
+                        lock.timeout = timeout;
+                        lock.expires = expires;
+                    }
+                    None => {
+                        info!("client {} locked {:?}", owner, range);
+                        self.locks.push(Lock { owner, range, timeout, expires });
+                    }
+                }
+            }
+            LockOp::Release => self.locks.retain(|l| l.owner != owner || !overlaps(&l.range, &range)),
+            LockOp::ForceRelease => self.locks.retain(|l| {
+                let hit = overlaps(&l.range, &range);
+                if hit && l.owner != owner {
+                    info!("client {} force-released lock on {:?} of client {}",
+                          owner, l.range, l.owner);
+                }
+                !hit
+            }),
+            LockOp::ReleaseAll => self.locks.retain(|l| l.owner != owner),
+        }
+        true
+    }
+
+    /// Whether the client of a write request may write to all bytes it
+    /// changes.  The client's own locks touched by the write are renewed.
+    pub fn may_write<X>(&mut self, req: &Request<X>) -> bool {
+        self.expire();
+        if self.locks.iter().any(|l| l.owner != req.hid && req.writes_to(&l.range)) {
+            return false;
+        }
+        let now = Instant::now();
+        for lock in &mut self.locks {
+            if req.writes_to(&lock.range) {
+                lock.expires = now + lock.timeout;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::server::Area;
+    use super::*;
+
+    fn write(hid: usize, addr: usize, mask: &[u8]) -> Request<()> {
+        Request { hid, area: Area::Extern, addr, count: mask.len(),
+                  write: Some(vec![0; mask.len()]), mask: Some(mask.to_vec()),
+                  read_back: None, lock: None, extra: () }
+    }
+
+    #[test]
+    fn claims() {
+        let mut locks = Locks::new(Duration::from_secs(60), false);
+        assert!(locks.apply(1, 0..4, LockOp::Claim(None)));
+        assert!(!locks.apply(2, 2..6, LockOp::Claim(None)));
+        assert!(locks.apply(2, 4..8, LockOp::Claim(None)));
+        assert!(locks.apply(1, 0..4, LockOp::Claim(None)));
+        assert_eq!(locks.locks.len(), 2);
+
+        // only bytes that are actually changed count
+        assert!(!locks.may_write(&write(2, 2, &[0xFF, 0xFF])));
+        assert!(locks.may_write(&write(2, 2, &[0, 0, 0xFF])));
+        assert!(locks.may_write(&write(1, 0, &[0xFF; 4])));
+        let mut read = write(2, 0, &[0xFF; 4]);
+        read.write = None;
+        assert!(locks.may_write(&read));
+
+        // releases only affect the client's own locks
+        assert!(locks.apply(2, 0..8, LockOp::Release));
+        assert!(!locks.may_write(&write(2, 0, &[0xFF])));
+        assert!(locks.may_write(&write(1, 4, &[0xFF])));
+        assert!(!locks.apply(2, 0..8, LockOp::Claim(None)));
+    }
+
+    #[test]
+    fn force_release() {
+        let locks = Locks::new(Duration::from_secs(60), false);
+        assert!(locks.allows(LockOp::Claim(None)) && locks.allows(LockOp::Release));
+        assert!(!locks.allows(LockOp::ForceRelease));
+
+        let mut locks = Locks::new(Duration::from_secs(60), true);
+        assert!(locks.allows(LockOp::ForceRelease));
+        assert!(locks.apply(1, 0..4, LockOp::Claim(None)));
+        assert!(locks.apply(1, 8..12, LockOp::Claim(None)));
+        assert!(locks.apply(2, 2..3, LockOp::ForceRelease));
+        assert!(locks.may_write(&write(2, 0, &[0xFF; 4])));
+        assert!(!locks.may_write(&write(2, 8, &[0xFF])));
+    }
+
+    #[test]
+    fn release_all() {
+        // what the servers do when a client disconnects
+        let mut locks = Locks::new(Duration::from_secs(60), false);
+        assert!(locks.apply(1, 0..4, LockOp::Claim(None)));
+        assert!(locks.apply(1, 8..12, LockOp::Claim(None)));
+        assert!(locks.apply(2, 4..8, LockOp::Claim(None)));
+        assert!(locks.apply(1, 0..0, LockOp::ReleaseAll));
+        assert!(locks.may_write(&write(3, 0, &[0xFF; 4])));
+        assert!(locks.may_write(&write(3, 8, &[0xFF; 4])));
+        assert!(!locks.may_write(&write(3, 4, &[0xFF])));
+    }
+
+    #[test]
+    fn expiry() {
+        let timeout = Duration::from_millis(200);
+        let mut locks = Locks::new(timeout, false);
+        assert!(locks.apply(1, 0..4, LockOp::Claim(Some(timeout / 4))));
+        assert!(locks.apply(1, 4..8, LockOp::Claim(None)));
+        thread::sleep(timeout / 2);
+        assert!(locks.may_write(&write(2, 0, &[0xFF])));
+
+        // writes renew the lock
+        assert!(locks.may_write(&write(1, 4, &[0xFF])));
+        thread::sleep(timeout * 3 / 4);
+        assert!(!locks.may_write(&write(2, 4, &[0xFF])));
+        thread::sleep(timeout / 2);
+        assert!(locks.may_write(&write(2, 4, &[0xFF])));
+        assert!(locks.locks.is_empty());
+    }
+}
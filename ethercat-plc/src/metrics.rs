@@ -0,0 +1,305 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Runtime statistics of the PLC and its servers, which can be rendered in
+//! the Prometheus text format (served by the `HttpHandler` at `/metrics`).
+
+use std::fmt::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering::Relaxed};
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in microseconds.
+const BUCKETS: [u64; 13] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Counts per bucket (not cumulative); the last one is for larger values.
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let us = value.as_micros() as u64;
+        let bucket = BUCKETS.iter().position(|&b| us <= b).unwrap_or(BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Relaxed);
+        self.sum_ns.fetch_add(value.as_nanos() as u64, Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) -> fmt::Result {
+        writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name)?;
+        let mut total = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            total += count.load(Relaxed);
+            match BUCKETS.get(i) {
+                Some(us) => writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, *us as f64 / 1e6, total)?,
+                None => writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, total)?,
+            }
+        }
+        writeln!(out, "{}_sum {}", name, self.sum_ns.load(Relaxed) as f64 / 1e9)?;
+        writeln!(out, "{}_count {}", name, total)
+    }
+}
+
+/// Statistics of one server.
+#[derive(Debug)]
+pub struct ServerMetrics {
+    addr: String,
+    handler: String,
+    clients: AtomicUsize,
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl ServerMetrics {
+    pub fn set_clients(&self, n: usize) {
+        self.clients.store(n, Relaxed);
+    }
+
+    pub fn add_requests(&self, n: usize) {
+        self.requests.fetch_add(n as u64, Relaxed);
+    }
+
+    /// Count requests answered with an error.
+    pub fn add_errors(&self, n: usize) {
+        self.errors.fetch_add(n as u64, Relaxed);
+    }
+}
+
+/// Statistics of the PLC, shared between the PLC and its servers.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    period_ns: AtomicU64,
+    cycles: AtomicU64,
+    overruns: AtomicU64,
+    duration: Histogram,
+    jitter: Histogram,
+    /// The domain's working counter state (0 = zero, 1 = incomplete,
+    /// 2 = complete) plus one, or 0 if unknown.
+    wc_state: AtomicU8,
+    working_counter: AtomicU32,
+    /// AL state of each slave (1 = Init, 2 = PreOp, 3 = Boot, 4 = SafeOp,
+    /// 8 = Op), in bus order; 0 if unknown.
+    al_states: Mutex<Vec<u8>>,
+    servers: Mutex<Vec<Arc<ServerMetrics>>>,
+}
+
+impl Metrics {
+    pub(crate) fn new(period: Duration) -> Self {
+        let metrics = Self::default();
+        metrics.period_ns.store(period.as_nanos() as u64, Relaxed);
+        metrics
+    }
+
+    /// The configured cycle period.
+    pub fn period(&self) -> Duration {
+        Duration::from_nanos(self.period_ns.load(Relaxed))
+    }
+
+    /// Record a cycle that started `jitter` after its scheduled time and
+    /// took `duration`.  It overran if the next cycle can't start in time.
+    pub(crate) fn record_cycle(&self, jitter: Duration, duration: Duration, overrun: bool) {
+        self.cycles.fetch_add(1, Relaxed);
+        if overrun {
+            self.overruns.fetch_add(1, Relaxed);
+        }
+        self.jitter.observe(jitter);
+        self.duration.observe(duration);
+    }
+
+    pub(crate) fn set_working_counter(&self, wc_state: u8, working_counter: u32) {
+        self.working_counter.store(working_counter, Relaxed);
+        self.wc_state.store(wc_state + 1, Relaxed);
+    }
+
+    pub(crate) fn set_al_states(&self, al_states: Vec<u8>) {
+        *self.al_states.lock().unwrap() = al_states;
+    }
+
+    /// Register a server, to which it reports its statistics.
+    pub fn add_server(&self, addr: &str, handler: &str) -> Arc<ServerMetrics> {
+        let server = Arc::new(ServerMetrics {
+            addr: addr.into(),
+            handler: handler.into(),
+            clients: AtomicUsize::new(0),
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        });
+        self.servers.lock().unwrap().push(server.clone());
+        server
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self, info: &crate::PlcInfo) -> String {
+        let mut out = String::new();
+        // writing to a string can't fail
+        let _ = self.render_into(&mut out, info);
+        out
+    }
+
+    fn render_into(&self, out: &mut String, info: &crate::PlcInfo) -> fmt::Result {
+        let simple = |out: &mut String, name: &str, kind: &str, help: &str, value: f64| {
+            writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value)
+        };
+        writeln!(out, "# HELP plc_info Name and version of the PLC.\n# TYPE plc_info gauge")?;
+        writeln!(out, "plc_info{{name=\"{}\",version=\"{}\"}} 1",
+                 escape(&info.name), escape(&info.version))?;
+        simple(out, "plc_cycle_period_seconds", "gauge", "Configured cycle period.",
+               self.period_ns.load(Relaxed) as f64 / 1e9)?;
+        simple(out, "plc_cycles_total", "counter", "Number of cycles run.",
+               self.cycles.load(Relaxed) as f64)?;
+        simple(out, "plc_cycle_overruns_total", "counter",
+               "Number of cycles that ended after the next one should have started.",
+               self.overruns.load(Relaxed) as f64)?;
+        self.duration.render(out, "plc_cycle_duration_seconds",
+                             "Time taken by the cycle function and data exchange.")?;
+        self.jitter.render(out, "plc_cycle_jitter_seconds",
+                           "Delay of the cycle start after its scheduled time.")?;
+
+        if let Some(wc_state) = self.wc_state.load(Relaxed).checked_sub(1) {
+            simple(out, "plc_domain_wc_state", "gauge",
+                   "Working counter state of the domain (0 = zero, 1 = incomplete, 2 = complete).",
+                   wc_state as f64)?;
+            simple(out, "plc_domain_working_counter", "gauge",
+                   "Working counter of the domain.", self.working_counter.load(Relaxed) as f64)?;
+        }
+        let al_states = self.al_states.lock().unwrap();
+        if !al_states.is_empty() {
+            writeln!(out, "# HELP plc_slave_al_state AL state of the slave (1 = Init, \
+                           2 = PreOp, 3 = Boot, 4 = SafeOp, 8 = Op, 0 = unknown).\n\
+                           # TYPE plc_slave_al_state gauge")?;
+            for (pos, state) in al_states.iter().enumerate() {
+                writeln!(out, "plc_slave_al_state{{slave=\"{}\"}} {}", pos, state)?;
+            }
+        }
+        drop(al_states);
+
+        let servers = self.servers.lock().unwrap();
+        let families: [(&str, &str, &str, fn(&ServerMetrics) -> u64); 3] = [
+            ("plc_server_clients", "gauge", "Number of connected clients.",
+             |s| s.clients.load(Relaxed) as u64),
+            ("plc_server_requests_total", "counter", "Number of requests handled.",
+             |s| s.requests.load(Relaxed)),
+            ("plc_server_errors_total", "counter", "Number of requests answered with an error.",
+             |s| s.errors.load(Relaxed)),
+        ];
+        for (name, kind, help, get) in families {
+            if servers.is_empty() {
+                break;
+            }
+            writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind)?;
+            for server in servers.iter() {
+                writeln!(out, "{}{{server=\"{}\",handler=\"{}\"}} {}", name,
+                         escape(&server.addr), escape(&server.handler), get(server))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Escape a label value.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PlcInfo;
+    use super::*;
+
+    #[test]
+    fn exposition() {
+        let metrics = Metrics::new(Duration::from_millis(1));
+        // families without data are left out
+        let text = metrics.render(&PlcInfo::default());
+        assert!(text.contains("plc_cycles_total 0\n"));
+        assert!(!text.contains("plc_domain") && !text.contains("plc_slave"));
+        assert!(!text.contains("plc_server"));
+
+        metrics.record_cycle(Duration::from_micros(20), Duration::from_micros(300), false);
+        metrics.record_cycle(Duration::from_micros(5), Duration::from_millis(200), true);
+        metrics.set_working_counter(2, 6);
+        metrics.set_al_states(vec![8, 4]);
+        let server = metrics.add_server("unix:/run/\"plc\"", "ModbusHandler");
+        server.set_clients(2);
+        server.add_requests(10);
+        server.add_errors(1);
+        metrics.add_server("127.0.0.1:502", "SimpleHandler");
+        let info = PlcInfo { name: "a \"b\"\nc".into(), version: "1\\2".into(),
+                             ..PlcInfo::default() };
+        assert_eq!(metrics.render(&info), EXPECTED);
+    }
+
+    const EXPECTED: &str = r#"# HELP plc_info Name and version of the PLC.
+# TYPE plc_info gauge
+plc_info{name="a \"b\"\nc",version="1\\2"} 1
+# HELP plc_cycle_period_seconds Configured cycle period.
+# TYPE plc_cycle_period_seconds gauge
+plc_cycle_period_seconds 0.001
+# HELP plc_cycles_total Number of cycles run.
+# TYPE plc_cycles_total counter
+plc_cycles_total 2
+# HELP plc_cycle_overruns_total Number of cycles that ended after the next one should have started.
+# TYPE plc_cycle_overruns_total counter
+plc_cycle_overruns_total 1
+# HELP plc_cycle_duration_seconds Time taken by the cycle function and data exchange.
+# TYPE plc_cycle_duration_seconds histogram
+plc_cycle_duration_seconds_bucket{le="0.00001"} 0
+plc_cycle_duration_seconds_bucket{le="0.000025"} 0
+plc_cycle_duration_seconds_bucket{le="0.00005"} 0
+plc_cycle_duration_seconds_bucket{le="0.0001"} 0
+plc_cycle_duration_seconds_bucket{le="0.00025"} 0
+plc_cycle_duration_seconds_bucket{le="0.0005"} 1
+plc_cycle_duration_seconds_bucket{le="0.001"} 1
+plc_cycle_duration_seconds_bucket{le="0.0025"} 1
+plc_cycle_duration_seconds_bucket{le="0.005"} 1
+plc_cycle_duration_seconds_bucket{le="0.01"} 1
+plc_cycle_duration_seconds_bucket{le="0.025"} 1
+plc_cycle_duration_seconds_bucket{le="0.05"} 1
+plc_cycle_duration_seconds_bucket{le="0.1"} 1
+plc_cycle_duration_seconds_bucket{le="+Inf"} 2
+plc_cycle_duration_seconds_sum 0.2003
+plc_cycle_duration_seconds_count 2
+# HELP plc_cycle_jitter_seconds Delay of the cycle start after its scheduled time.
+# TYPE plc_cycle_jitter_seconds histogram
+plc_cycle_jitter_seconds_bucket{le="0.00001"} 1
+plc_cycle_jitter_seconds_bucket{le="0.000025"} 2
+plc_cycle_jitter_seconds_bucket{le="0.00005"} 2
+plc_cycle_jitter_seconds_bucket{le="0.0001"} 2
+plc_cycle_jitter_seconds_bucket{le="0.00025"} 2
+plc_cycle_jitter_seconds_bucket{le="0.0005"} 2
+plc_cycle_jitter_seconds_bucket{le="0.001"} 2
+plc_cycle_jitter_seconds_bucket{le="0.0025"} 2
+plc_cycle_jitter_seconds_bucket{le="0.005"} 2
+plc_cycle_jitter_seconds_bucket{le="0.01"} 2
+plc_cycle_jitter_seconds_bucket{le="0.025"} 2
+plc_cycle_jitter_seconds_bucket{le="0.05"} 2
+plc_cycle_jitter_seconds_bucket{le="0.1"} 2
+plc_cycle_jitter_seconds_bucket{le="+Inf"} 2
+plc_cycle_jitter_seconds_sum 0.000025
+plc_cycle_jitter_seconds_count 2
+# HELP plc_domain_wc_state Working counter state of the domain (0 = zero, 1 = incomplete, 2 = complete).
+# TYPE plc_domain_wc_state gauge
+plc_domain_wc_state 2
+# HELP plc_domain_working_counter Working counter of the domain.
+# TYPE plc_domain_working_counter gauge
+plc_domain_working_counter 6
+# HELP plc_slave_al_state AL state of the slave (1 = Init, 2 = PreOp, 3 = Boot, 4 = SafeOp, 8 = Op, 0 = unknown).
+# TYPE plc_slave_al_state gauge
+plc_slave_al_state{slave="0"} 8
+plc_slave_al_state{slave="1"} 4
+# HELP plc_server_clients Number of connected clients.
+# TYPE plc_server_clients gauge
+plc_server_clients{server="unix:/run/\"plc\"",handler="ModbusHandler"} 2
+plc_server_clients{server="127.0.0.1:502",handler="SimpleHandler"} 0
+# HELP plc_server_requests_total Number of requests handled.
+# TYPE plc_server_requests_total counter
+plc_server_requests_total{server="unix:/run/\"plc\"",handler="ModbusHandler"} 10
+plc_server_requests_total{server="127.0.0.1:502",handler="SimpleHandler"} 0
+# HELP plc_server_errors_total Number of requests answered with an error.
+# TYPE plc_server_errors_total counter
+plc_server_errors_total{server="unix:/run/\"plc\"",handler="ModbusHandler"} 1
+plc_server_errors_total{server="127.0.0.1:502",handler="SimpleHandler"} 0
+"#;
+}
@@ -2,19 +2,26 @@
 // This work is dual-licensed under Apache 2.0 and MIT terms.
 
 use byteorder::{ByteOrder, NativeEndian as NE};
+use ethercat_derive::ImageVars;
 
 pub const MAGIC: f32 = 2015.02;
 
-pub const RESET: u16 = 0x0000;
-pub const IDLE:  u16 = 0x1000;
-pub const WARN:  u16 = 0x3000;
-pub const START: u16 = 0x5000;
-pub const BUSY:  u16 = 0x6000;
-pub const STOP:  u16 = 0x7000;
-pub const ERROR: u16 = 0x8000;
+pub const RESET:    u16 = 0x0000;
+pub const IDLE:     u16 = 0x1000;
+pub const DISABLED: u16 = 0x2000;
+pub const WARN:     u16 = 0x3000;
+pub const START:    u16 = 0x5000;
+pub const BUSY:     u16 = 0x6000;
+pub const STOP:     u16 = 0x7000;
+pub const ERROR:    u16 = 0x8000;
+
+/// Type codes of the devices, as reported by the indexer: the device class
+/// in the high byte, and the size in words in the low byte.
+pub const DISCRETE_OUTPUT: u16 = 0x1E03;
+pub const FLAT_OUTPUT_1:   u16 = 0x3008;
 
 #[repr(C)]
-#[derive(Default)]
+#[derive(Default, ImageVars)]
 pub struct DiscreteOutput {
     pub value:  i16,
     pub target: i16,
@@ -22,7 +29,7 @@ pub struct DiscreteOutput {
 }
 
 #[repr(C)]
-#[derive(Default)]
+#[derive(Default, ImageVars)]
 pub struct FlatOutput1 {
     pub value:  f32,
     pub target: f32,
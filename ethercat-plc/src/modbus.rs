@@ -0,0 +1,881 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Modbus protocol support: frame and PDU parsing, a Modbus/TCP handler for
+//! the `TcpServer`, Modbus RTU on serial lines or over TCP, and a client for
+//! polling external devices.
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+use log::*;
+use byteorder::{ByteOrder, BE};
+
+use crate::server::{Area, Handler, LockOp, PlcInfo, Request, Response};
+use self::order::{RegisterLayout, RegisterMap};
+
+mod order;
+mod rtu;
+mod client;
+
+pub use self::order::{WordOrder, RegisterOrder};
+
+pub use self::rtu::{crc16, Parity, RtuConfig, RtuServer, ModbusRtuHandler};
+pub use self::client::{ModbusClient, ReadJob, WriteJob, JobStatus};
+
+/// Modbus exception codes.
+pub mod exception {
+    pub const ILLEGAL_FUNCTION: u8 = 1;
+    pub const ILLEGAL_ADDRESS:  u8 = 2;
+    pub const ILLEGAL_VALUE:    u8 = 3;
+    pub const DEVICE_FAILURE:   u8 = 4;
+    pub const DEVICE_BUSY:      u8 = 6;
+    pub const GATEWAY_TARGET:   u8 = 11;
+}
+
+/// Maximum size of a PDU (function code and data).
+pub const MAX_PDU: usize = 253;
+/// Size of the Modbus/TCP (MBAP) header, including the unit ID.
+pub const MBAP_SIZE: usize = 7;
+
+const MAX_READ_BITS: u16 = 2000;
+const MAX_WRITE_BITS: u16 = 1968;
+const MAX_READ_REGS: u16 = 125;
+const MAX_WRITE_REGS: u16 = 123;
+const MAX_RW_WRITE_REGS: u16 = 121;
+
+/// Error in the framing of a Modbus/TCP message.  The connection cannot
+/// be used anymore after such an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    ProtocolId(u16),
+    Length(u16),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::ProtocolId(id) => write!(f, "invalid protocol ID {}", id),
+            FrameError::Length(len) => write!(f, "invalid frame length {}", len),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// The Modbus/TCP application protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MbapHeader {
+    pub tid: u16,
+    pub unit: u8,
+    /// Length of the following PDU.
+    pub pdu_len: usize,
+}
+
+impl MbapHeader {
+    pub fn parse(buf: &[u8; MBAP_SIZE]) -> Result<Self, FrameError> {
+        let protocol = BE::read_u16(&buf[2..]);
+        if protocol != 0 {
+            return Err(FrameError::ProtocolId(protocol));
+        }
+        // the length includes the unit ID, and the PDU has at least
+        // the function code
+        let length = BE::read_u16(&buf[4..]);
+        if length < 2 || length as usize > MAX_PDU + 1 {
+            return Err(FrameError::Length(length));
+        }
+        Ok(Self { tid: BE::read_u16(buf), unit: buf[6], pdu_len: length as usize - 1 })
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; MBAP_SIZE]);
+        BE::write_u16(&mut buf[start..], self.tid);
+        BE::write_u16(&mut buf[start+4..], self.pdu_len as u16 + 1);
+        buf[start+6] = self.unit;
+    }
+}
+
+/// A parsed Modbus request PDU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModbusRequest {
+    ReadCoils { addr: u16, count: u16 },
+    ReadDiscreteInputs { addr: u16, count: u16 },
+    ReadHoldingRegisters { addr: u16, count: u16 },
+    ReadInputRegisters { addr: u16, count: u16 },
+    WriteSingleCoil { addr: u16, value: bool },
+    WriteSingleRegister { addr: u16, value: u16 },
+    /// Coil values are packed into bytes, LSB first.
+    WriteMultipleCoils { addr: u16, count: u16, values: Vec<u8> },
+    /// Register values are in big-endian wire order.
+    WriteMultipleRegisters { addr: u16, values: Vec<u8> },
+    MaskWriteRegister { addr: u16, and_mask: u16, or_mask: u16 },
+    ReadWriteRegisters { read_addr: u16, read_count: u16, write_addr: u16, values: Vec<u8> },
+    ReadDeviceId { code: u8, object: u8 },
+    /// Write locks on holding registers, with the user-defined function
+    /// code 65.  `op` is one of the `LOCK_*` constants, and `timeout` is in
+    /// seconds (0 for the PLC's default).  The reply echoes the request.
+    LockRegisters { op: u8, addr: u16, count: u16, timeout: u16 },
+}
+
+/// Claim a write lock on registers.
+pub const LOCK_CLAIM: u8 = 1;
+/// Release own write locks on registers.
+pub const LOCK_RELEASE: u8 = 2;
+/// Release all write locks on registers, regardless of owner, if allowed
+/// by `PlcBuilder::allow_force_release`.
+pub const LOCK_FORCE_RELEASE: u8 = 3;
+
+fn check_range(addr: u16, count: u16, max: u16) -> Result<(), u8> {
+    if count == 0 || count > max {
+        Err(exception::ILLEGAL_VALUE)
+    } else if addr as usize + count as usize > 0x10000 {
+        Err(exception::ILLEGAL_ADDRESS)
+    } else {
+        Ok(())
+    }
+}
+
+impl ModbusRequest {
+    /// Parse a request PDU (starting with the function code).  On error,
+    /// returns the exception code to reply with.
+    pub fn parse(pdu: &[u8]) -> Result<Self, u8> {
+        let (&fc, data) = pdu.split_first().ok_or(exception::ILLEGAL_VALUE)?;
+        let fixed = |len: usize| if data.len() == len { Ok(()) } else { Err(exception::ILLEGAL_VALUE) };
+        let word = |i: usize| BE::read_u16(&data[i..]);
+        Ok(match fc {
+            1..=4 => {
+                fixed(4)?;
+                let (addr, count) = (word(0), word(2));
+                check_range(addr, count, if fc <= 2 { MAX_READ_BITS } else { MAX_READ_REGS })?;
+                match fc {
+                    1 => ModbusRequest::ReadCoils { addr, count },
+                    2 => ModbusRequest::ReadDiscreteInputs { addr, count },
+                    3 => ModbusRequest::ReadHoldingRegisters { addr, count },
+                    _ => ModbusRequest::ReadInputRegisters { addr, count },
+                }
+            }
+            5 => {
+                fixed(4)?;
+                let value = match word(2) {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(exception::ILLEGAL_VALUE),
+                };
+                ModbusRequest::WriteSingleCoil { addr: word(0), value }
+            }
+            6 => {
+                fixed(4)?;
+                ModbusRequest::WriteSingleRegister { addr: word(0), value: word(2) }
+            }
+            15 | 16 => {
+                if data.len() < 5 || data.len() != 5 + data[4] as usize {
+                    return Err(exception::ILLEGAL_VALUE);
+                }
+                let (addr, count, bytecount) = (word(0), word(2), data[4] as usize);
+                let values = data[5..].to_vec();
+                if fc == 15 {
+                    check_range(addr, count, MAX_WRITE_BITS)?;
+                    if bytecount != (count as usize + 7) / 8 {
+                        return Err(exception::ILLEGAL_VALUE);
+                    }
+                    ModbusRequest::WriteMultipleCoils { addr, count, values }
+                } else {
+                    check_range(addr, count, MAX_WRITE_REGS)?;
+                    if bytecount != 2 * count as usize {
+                        return Err(exception::ILLEGAL_VALUE);
+                    }
+                    ModbusRequest::WriteMultipleRegisters { addr, values }
+                }
+            }
+            22 => {
+                fixed(6)?;
+                ModbusRequest::MaskWriteRegister { addr: word(0), and_mask: word(2), or_mask: word(4) }
+            }
+            23 => {
+                if data.len() < 9 || data.len() != 9 + data[8] as usize {
+                    return Err(exception::ILLEGAL_VALUE);
+                }
+                let (read_addr, read_count) = (word(0), word(2));
+                let (write_addr, write_count) = (word(4), word(6));
+                check_range(read_addr, read_count, MAX_READ_REGS)?;
+                check_range(write_addr, write_count, MAX_RW_WRITE_REGS)?;
+                if data[8] as usize != 2 * write_count as usize {
+                    return Err(exception::ILLEGAL_VALUE);
+                }
+                ModbusRequest::ReadWriteRegisters { read_addr, read_count, write_addr,
+                                                    values: data[9..].to_vec() }
+            }
+            43 => {
+                // only the "read device identification" MEI type is supported
+                if data.first() != Some(&14) {
+                    return Err(exception::ILLEGAL_FUNCTION);
+                }
+                fixed(3)?;
+                if !(1..=4).contains(&data[1]) {
+                    return Err(exception::ILLEGAL_VALUE);
+                }
+                ModbusRequest::ReadDeviceId { code: data[1], object: data[2] }
+            }
+            65 => {
+                fixed(7)?;
+                let (op, addr, count) = (data[0], word(1), word(3));
+                if !(LOCK_CLAIM..=LOCK_FORCE_RELEASE).contains(&op) {
+                    return Err(exception::ILLEGAL_VALUE);
+                }
+                check_range(addr, count, u16::MAX)?;
+                ModbusRequest::LockRegisters { op, addr, count, timeout: word(5) }
+            }
+            _ => return Err(exception::ILLEGAL_FUNCTION),
+        })
+    }
+
+    pub fn function_code(&self) -> u8 {
+        match self {
+            ModbusRequest::ReadCoils { .. } => 1,
+            ModbusRequest::ReadDiscreteInputs { .. } => 2,
+            ModbusRequest::ReadHoldingRegisters { .. } => 3,
+            ModbusRequest::ReadInputRegisters { .. } => 4,
+            ModbusRequest::WriteSingleCoil { .. } => 5,
+            ModbusRequest::WriteSingleRegister { .. } => 6,
+            ModbusRequest::WriteMultipleCoils { .. } => 15,
+            ModbusRequest::WriteMultipleRegisters { .. } => 16,
+            ModbusRequest::MaskWriteRegister { .. } => 22,
+            ModbusRequest::ReadWriteRegisters { .. } => 23,
+            ModbusRequest::ReadDeviceId { .. } => 43,
+            ModbusRequest::LockRegisters { .. } => 65,
+        }
+    }
+
+    /// Encode the request PDU, the inverse of `parse`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        fn words(buf: &mut Vec<u8>, words: &[u16]) {
+            for &w in words {
+                buf.extend_from_slice(&w.to_be_bytes());
+            }
+        }
+        buf.push(self.function_code());
+        match self {
+            ModbusRequest::ReadCoils { addr, count } |
+            ModbusRequest::ReadDiscreteInputs { addr, count } |
+            ModbusRequest::ReadHoldingRegisters { addr, count } |
+            ModbusRequest::ReadInputRegisters { addr, count } => words(buf, &[*addr, *count]),
+            ModbusRequest::WriteSingleCoil { addr, value } =>
+                words(buf, &[*addr, if *value { 0xFF00 } else { 0 }]),
+            ModbusRequest::WriteSingleRegister { addr, value } => words(buf, &[*addr, *value]),
+            ModbusRequest::WriteMultipleCoils { addr, count, values } => {
+                words(buf, &[*addr, *count]);
+                buf.push(values.len() as u8);
+                buf.extend_from_slice(values);
+            }
+            ModbusRequest::WriteMultipleRegisters { addr, values } => {
+                words(buf, &[*addr, values.len() as u16 / 2]);
+                buf.push(values.len() as u8);
+                buf.extend_from_slice(values);
+            }
+            ModbusRequest::MaskWriteRegister { addr, and_mask, or_mask } =>
+                words(buf, &[*addr, *and_mask, *or_mask]),
+            ModbusRequest::ReadWriteRegisters { read_addr, read_count, write_addr, values } => {
+                words(buf, &[*read_addr, *read_count, *write_addr, values.len() as u16 / 2]);
+                buf.push(values.len() as u8);
+                buf.extend_from_slice(values);
+            }
+            ModbusRequest::ReadDeviceId { code, object } => buf.extend_from_slice(&[14, *code, *object]),
+            ModbusRequest::LockRegisters { op, addr, count, timeout } => {
+                buf.push(*op);
+                words(buf, &[*addr, *count, *timeout]);
+            }
+        }
+    }
+}
+
+/// Configuration for Modbus handlers.
+#[derive(Debug, Clone)]
+pub struct ModbusConfig {
+    /// Unit IDs that this server responds to.  Requests for other units are
+    /// answered with a "gateway target failed to respond" exception.  If
+    /// empty, all unit IDs are accepted.
+    pub unit_ids: Vec<u8>,
+    /// Conversion of variables to registers, big-endian by default.
+    pub order: RegisterOrder,
+}
+
+impl Default for ModbusConfig {
+    fn default() -> Self {
+        // 0 is the traditional ID, 255 is recommended for Modbus/TCP
+        Self { unit_ids: vec![0, 255], order: RegisterOrder::default() }
+    }
+}
+
+impl ModbusConfig {
+    pub fn accepts(&self, unit: u8) -> bool {
+        self.unit_ids.is_empty() || self.unit_ids.contains(&unit)
+    }
+}
+
+/// Request information that is needed to form the reply.
+#[derive(Debug, Default)]
+pub struct ModbusExtra {
+    tid: u16,
+    unit: u8,
+    fc: u8,
+    /// Modbus address of the request (register or bit number).
+    addr: u16,
+    /// Number of registers or bits, the written value for single writes, or
+    /// the AND mask for mask writes.
+    count: u16,
+    /// The OR mask for mask writes, or the timeout for lock requests.
+    or_mask: u16,
+    /// The operation of lock requests.
+    op: u8,
+    /// For register reads, the conversion of the returned data.
+    convert: Option<Convert>,
+}
+
+/// How to convert the data returned by the PLC to registers.
+#[derive(Debug)]
+struct Convert {
+    layout: Arc<RegisterLayout>,
+    /// Image address of the returned data.
+    start: usize,
+    /// The requested range within the returned data.
+    range: std::ops::Range<usize>,
+}
+
+/// Translate a register range into the (possibly larger) image range that
+/// is transferred, and the conversion of the data read from it.
+fn register_range(layout: &Arc<RegisterLayout>, addr: u16, count: u16)
+                  -> (usize, usize, Option<Convert>) {
+    let range = 2 * addr as usize..2 * (addr as usize + count as usize);
+    if layout.is_empty() {
+        return (range.start, range.len(), None);
+    }
+    let cover = layout.cover(range.clone());
+    let convert = Convert {
+        layout: layout.clone(),
+        start: cover.start,
+        range: range.start - cover.start..range.end - cover.start,
+    };
+    (cover.start, cover.len(), Some(convert))
+}
+
+/// Form the data and mask for a register write, in image order.
+fn register_write(layout: &RegisterLayout, addr: u16, values: Vec<u8>, mask: Option<Vec<u8>>)
+                  -> (usize, usize, Vec<u8>, Option<Vec<u8>>) {
+    let range = 2 * addr as usize..2 * addr as usize + values.len();
+    let cover = layout.cover(range.clone());
+    if cover == range && layout.is_empty() {
+        return (range.start, range.len(), values, mask);
+    }
+    // bytes outside of the requested range are masked out
+    let offset = range.start - cover.start;
+    let mut data = vec![0; cover.len()];
+    let mut full_mask = vec![0; cover.len()];
+    data[offset..offset + values.len()].copy_from_slice(&values);
+    match mask {
+        Some(mask) => full_mask[offset..offset + mask.len()].copy_from_slice(&mask),
+        None => full_mask[offset..offset + values.len()].fill(0xFF),
+    }
+    layout.to_native(cover.start, &mut data);
+    layout.to_native(cover.start, &mut full_mask);
+    (cover.start, cover.len(), data, Some(full_mask))
+}
+
+/// What to do with a parsed request.
+pub(crate) enum Action {
+    /// Send the request to the PLC, and reply when the response arrives.
+    Plc(Request<ModbusExtra>),
+    /// Reply directly with the given PDU.
+    Reply(Vec<u8>),
+}
+
+/// Convert a range of bit addresses into the covering byte range, and the
+/// bit offset of the first bit in the first byte.
+fn bit_range(addr: u16, count: u16) -> (usize, usize, usize) {
+    let (addr, count) = (addr as usize, count as usize);
+    let first = addr / 8;
+    let last = (addr + count - 1) / 8;
+    (first, last - first + 1, addr % 8)
+}
+
+pub(crate) fn exception_pdu(fc: u8, code: u8) -> Vec<u8> {
+    vec![fc | 0x80, code]
+}
+
+/// Translate a parsed request into an action.
+fn process_request(hid: usize, tid: u16, unit: u8, req: ModbusRequest,
+                   info: &PlcInfo, map: &RegisterMap) -> Action {
+    let fc = req.function_code();
+    let extra = |addr, count| ModbusExtra { tid, unit, fc, addr, count, or_mask: 0, op: 0, convert: None };
+    let plc = |area, addr, count, write, mask, read_back, extra| Action::Plc(Request {
+        hid, area, addr, count, write, mask, read_back, lock: None, extra
+    });
+    match req {
+        // coils are bits of the extern image, discrete inputs bits of the
+        // process image
+        ModbusRequest::ReadCoils { addr, count } |
+        ModbusRequest::ReadDiscreteInputs { addr, count } => {
+            let area = if fc == 1 { Area::Extern } else { Area::Process };
+            let (byte, nbytes, _) = bit_range(addr, count);
+            plc(area, byte, nbytes, None, None, None, extra(addr, count))
+        }
+        // holding registers map to the extern image, input registers to
+        // the process image
+        ModbusRequest::ReadHoldingRegisters { addr, count } |
+        ModbusRequest::ReadInputRegisters { addr, count } => {
+            let (area, layout) = if fc == 3 { (Area::Extern, &map.ext) }
+                                 else { (Area::Process, &map.process) };
+            let (start, len, convert) = register_range(layout, addr, count);
+            plc(area, start, len, None, None, None, ModbusExtra { convert, ..extra(addr, count) })
+        }
+        ModbusRequest::WriteSingleCoil { addr, value } => {
+            let (byte, _, offset) = bit_range(addr, 1);
+            plc(Area::Extern, byte, 1, Some(vec![(value as u8) << offset]),
+                Some(vec![1 << offset]), None, extra(addr, if value { 0xFF00 } else { 0 }))
+        }
+        ModbusRequest::WriteSingleRegister { addr, value } => {
+            let (start, len, data, mask) = register_write(
+                &map.ext, addr, value.to_be_bytes().to_vec(), None);
+            plc(Area::Extern, start, len, Some(data), mask, None, extra(addr, value))
+        }
+        ModbusRequest::WriteMultipleCoils { addr, count, values: bits } => {
+            let (byte, nbytes, offset) = bit_range(addr, count);
+            let mut values = vec![0; nbytes];
+            let mut mask = vec![0; nbytes];
+            for i in 0..count as usize {
+                let pos = offset + i;
+                if bits[i / 8] & (1 << (i % 8)) != 0 {
+                    values[pos / 8] |= 1 << (pos % 8);
+                }
+                mask[pos / 8] |= 1 << (pos % 8);
+            }
+            plc(Area::Extern, byte, nbytes, Some(values), Some(mask), None, extra(addr, count))
+        }
+        ModbusRequest::WriteMultipleRegisters { addr, values } => {
+            let count = values.len() as u16 / 2;
+            let (start, len, data, mask) = register_write(&map.ext, addr, values, None);
+            plc(Area::Extern, start, len, Some(data), mask, None, extra(addr, count))
+        }
+        ModbusRequest::MaskWriteRegister { addr, and_mask, or_mask } => {
+            // result = (current AND and_mask) OR (or_mask AND NOT and_mask)
+            let (start, len, data, mask) = register_write(
+                &map.ext, addr, or_mask.to_be_bytes().to_vec(),
+                Some((!and_mask).to_be_bytes().to_vec()));
+            plc(Area::Extern, start, len, Some(data), mask, None,
+                ModbusExtra { or_mask, ..extra(addr, and_mask) })
+        }
+        ModbusRequest::ReadWriteRegisters { read_addr, read_count, write_addr, values } => {
+            // the write is applied first, and the registers are read back
+            // after the PLC has processed it in one cycle
+            let (start, len, data, mask) = register_write(&map.ext, write_addr, values, None);
+            let (read_start, read_len, convert) = register_range(&map.ext, read_addr, read_count);
+            plc(Area::Extern, start, len, Some(data), mask, Some((read_start, read_len)),
+                ModbusExtra { convert, ..extra(read_addr, read_count) })
+        }
+        ModbusRequest::ReadDeviceId { code, object } => {
+            Action::Reply(device_id_pdu(code, object, info))
+        }
+        ModbusRequest::LockRegisters { op, addr, count, timeout } => {
+            let lock = match op {
+                LOCK_CLAIM => LockOp::Claim(
+                    if timeout == 0 { None } else { Some(Duration::from_secs(timeout as u64)) }),
+                LOCK_RELEASE => LockOp::Release,
+                _ => LockOp::ForceRelease,
+            };
+            // the locked range covers whole converted variables
+            let (start, len, _) = register_range(&map.ext, addr, count);
+            Action::Plc(Request {
+                hid, area: Area::Extern, addr: start, count: len, write: None, mask: None,
+                read_back: None, lock: Some(lock),
+                extra: ModbusExtra { or_mask: timeout, op, ..extra(addr, count) }
+            })
+        }
+    }
+}
+
+/// Parse a request PDU and translate it into an action.  Invalid requests
+/// are answered with an exception.
+pub(crate) fn pdu_action(hid: usize, tid: u16, unit: u8, pdu: &[u8], info: &PlcInfo,
+                         map: &RegisterMap) -> Action {
+    match ModbusRequest::parse(pdu) {
+        Ok(req) => process_request(hid, tid, unit, req, info, map),
+        Err(code) => {
+            let fc = pdu.first().copied().unwrap_or(0);
+            warn!("invalid request for fc {}: exception {}", fc, code);
+            Action::Reply(exception_pdu(fc, code))
+        }
+    }
+}
+
+/// Form the reply to a "read device identification" request.
+fn device_id_pdu(code: u8, object: u8, info: &PlcInfo) -> Vec<u8> {
+    let objects: [(u8, &str); 4] = [
+        (0, "ethercat-plc"),
+        (1, &info.name),
+        (2, &info.version),
+        (4, &info.name),
+    ];
+    // the basic objects for code 1, all for codes 2 and 3 (we have no
+    // extended objects), or a single one for code 4
+    let available = if code == 1 { &objects[..3] } else { &objects[..] };
+    let first = match available.iter().position(|o| o.0 == object) {
+        Some(i) => i,
+        None => return exception_pdu(43, exception::ILLEGAL_ADDRESS),
+    };
+    // stream access starts at the given object
+    let selected = if code == 4 { &available[first..first+1] } else { &available[first..] };
+    let mut pdu = vec![43, 14, code, 0x82, 0, 0, selected.len() as u8];
+    for (id, value) in selected {
+        // limit the value so that the response fits into a frame
+        let value = &value.as_bytes()[..value.len().min(48)];
+        pdu.push(*id);
+        pdu.push(value.len() as u8);
+        pdu.extend_from_slice(value);
+    }
+    pdu
+}
+
+/// Form the reply PDU for a response from the PLC.
+pub(crate) fn response_pdu(response: Response<ModbusExtra>) -> (ModbusExtra, Vec<u8>) {
+    match response {
+        Response::Ok(req, mut values) => {
+            let extra = req.extra;
+            if let Some(convert) = &extra.convert {
+                convert.layout.to_wire(convert.start, &mut values);
+                values = values[convert.range.clone()].to_vec();
+            }
+            let mut pdu = vec![extra.fc];
+            match extra.fc {
+                1 | 2 => {
+                    let nbits = extra.count as usize;
+                    let offset = extra.addr as usize % 8;
+                    let nbytes = (nbits + 7) / 8;
+                    pdu.push(nbytes as u8);
+                    pdu.resize(2 + nbytes, 0);
+                    for i in 0..nbits {
+                        let pos = offset + i;
+                        if values[pos / 8] & (1 << (pos % 8)) != 0 {
+                            pdu[2 + i / 8] |= 1 << (i % 8);
+                        }
+                    }
+                }
+                3 | 4 | 23 => {
+                    pdu.push(values.len() as u8);
+                    pdu.extend_from_slice(&values);
+                }
+                5 | 6 | 15 | 16 => {
+                    pdu.extend_from_slice(&extra.addr.to_be_bytes());
+                    pdu.extend_from_slice(&extra.count.to_be_bytes());
+                }
+                22 => {
+                    pdu.extend_from_slice(&extra.addr.to_be_bytes());
+                    pdu.extend_from_slice(&extra.count.to_be_bytes());
+                    pdu.extend_from_slice(&extra.or_mask.to_be_bytes());
+                }
+                65 => {
+                    pdu.push(extra.op);
+                    pdu.extend_from_slice(&extra.addr.to_be_bytes());
+                    pdu.extend_from_slice(&extra.count.to_be_bytes());
+                    pdu.extend_from_slice(&extra.or_mask.to_be_bytes());
+                }
+                x => panic!("impossible function code {}", x)
+            }
+            (extra, pdu)
+        }
+        Response::Error(req, ec) => {
+            let pdu = exception_pdu(req.extra.fc, ec);
+            (req.extra, pdu)
+        }
+    }
+}
+
+
+pub struct ModbusHandler {
+    hid:    usize,
+    info:   Arc<PlcInfo>,
+    config: ModbusConfig,
+    map:    RegisterMap,
+    /// Requests answered without the PLC, and exceptions among them.
+    direct: (usize, usize),
+}
+
+fn encode_reply(tid: u16, unit: u8, pdu: &[u8], output: &mut Vec<u8>) {
+    MbapHeader { tid, unit, pdu_len: pdu.len() }.encode(output);
+    output.extend_from_slice(pdu);
+}
+
+impl Handler for ModbusHandler {
+    type Extra = ModbusExtra;
+    type Config = ModbusConfig;
+
+    fn new(hid: usize, info: Arc<PlcInfo>, config: &ModbusConfig) -> Self {
+        let map = RegisterMap::new(&info, &config.order);
+        ModbusHandler { hid, info, config: config.clone(), map, direct: (0, 0) }
+    }
+
+    fn receive(&mut self, input: &[u8], requests: &mut Vec<Request<ModbusExtra>>,
+               output: &mut Vec<u8>) -> io::Result<usize> {
+        let mut pos = 0;
+        while let Some(headbuf) = input.get(pos..pos + MBAP_SIZE) {
+            // after a framing error, we can't find the next frame boundary
+            let head = MbapHeader::parse(headbuf.try_into().unwrap())
+                .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+            let pdu = match input.get(pos + MBAP_SIZE..pos + MBAP_SIZE + head.pdu_len) {
+                Some(pdu) => pdu,
+                None => break,
+            };
+            pos += MBAP_SIZE + head.pdu_len;
+            let reply = if !self.config.accepts(head.unit) {
+                debug!("request for unit {} not handled", head.unit);
+                exception_pdu(pdu[0], exception::GATEWAY_TARGET)
+            } else {
+                match pdu_action(self.hid, head.tid, head.unit, pdu, &self.info, &self.map) {
+                    Action::Reply(pdu) => pdu,
+                    Action::Plc(req) => {
+                        debug!("got request: {:?}", req);
+                        requests.push(req);
+                        continue;
+                    }
+                }
+            };
+            self.direct.0 += 1;
+            if reply[0] & 0x80 != 0 {
+                self.direct.1 += 1;
+            }
+            encode_reply(head.tid, head.unit, &reply, output);
+        }
+        Ok(pos)
+    }
+
+    fn direct_replies(&mut self) -> (usize, usize) {
+        std::mem::take(&mut self.direct)
+    }
+
+    fn respond(&mut self, response: Response<ModbusExtra>, output: &mut Vec<u8>) {
+        debug!("sending response: {:?}", response);
+        let (extra, pdu) = response_pdu(response);
+        encode_reply(extra.tid, extra.unit, &pdu, output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::{VarInfo, VarType};
+    use super::*;
+
+    fn frame(tid: u16, unit: u8, pdu: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        encode_reply(tid, unit, pdu, &mut frame);
+        frame
+    }
+
+    #[test]
+    fn frame_header() {
+        let head = |length: u16| {
+            let mut buf = [0x12, 0x34, 0, 0, 0, 0, 7];
+            BE::write_u16(&mut buf[4..], length);
+            MbapHeader::parse(&buf)
+        };
+        // too short for a function code, this used to underflow
+        assert_eq!(head(0), Err(FrameError::Length(0)));
+        assert_eq!(head(1), Err(FrameError::Length(1)));
+        assert_eq!(head(2), Ok(MbapHeader { tid: 0x1234, unit: 7, pdu_len: 1 }));
+        // larger than the maximum PDU, this used to overrun the buffer
+        assert_eq!(head(254).unwrap().pdu_len, MAX_PDU);
+        assert_eq!(head(255), Err(FrameError::Length(255)));
+        assert_eq!(head(0xFFFF), Err(FrameError::Length(0xFFFF)));
+        assert_eq!(MbapHeader::parse(&[0, 0, 0, 1, 0, 2, 0]), Err(FrameError::ProtocolId(1)));
+
+        let buf = frame(0x1234, 7, &[3, 0, 0, 0, 1]);
+        assert_eq!(MbapHeader::parse(buf[..MBAP_SIZE].try_into().unwrap()),
+                   Ok(MbapHeader { tid: 0x1234, unit: 7, pdu_len: 5 }));
+    }
+
+    #[test]
+    fn request_validation() {
+        use self::exception::*;
+        let parse = ModbusRequest::parse;
+        let read = |fc: u8, addr: u16, count: u16| {
+            let mut pdu = vec![fc];
+            pdu.extend_from_slice(&addr.to_be_bytes());
+            pdu.extend_from_slice(&count.to_be_bytes());
+            parse(&pdu)
+        };
+        assert_eq!(parse(&[]), Err(ILLEGAL_VALUE));
+        assert_eq!(parse(&[7]), Err(ILLEGAL_FUNCTION));
+        assert_eq!(parse(&[3, 0, 0, 0]), Err(ILLEGAL_VALUE));
+        assert_eq!(parse(&[3, 0, 0, 0, 1, 0]), Err(ILLEGAL_VALUE));
+
+        // the register and bit limits
+        assert_eq!(read(3, 0, 125),
+                   Ok(ModbusRequest::ReadHoldingRegisters { addr: 0, count: 125 }));
+        assert_eq!(read(4, 0, 126), Err(ILLEGAL_VALUE));
+        assert_eq!(read(1, 0, 2000), Ok(ModbusRequest::ReadCoils { addr: 0, count: 2000 }));
+        assert_eq!(read(2, 0, 2001), Err(ILLEGAL_VALUE));
+        // a wrong count is exception 3, a wrong address exception 2
+        assert_eq!(read(3, 0, 0), Err(ILLEGAL_VALUE));
+        assert_eq!(read(3, 0xFFFF, 1),
+                   Ok(ModbusRequest::ReadHoldingRegisters { addr: 0xFFFF, count: 1 }));
+        assert_eq!(read(3, 0xFFFF, 2), Err(ILLEGAL_ADDRESS));
+        assert_eq!(read(3, 0xFFFF, 200), Err(ILLEGAL_VALUE));
+
+        assert_eq!(parse(&[5, 0, 1, 0x12, 0x34]), Err(ILLEGAL_VALUE));
+        assert_eq!(parse(&[16, 0, 0, 0, 2, 4, 1, 2, 3]), Err(ILLEGAL_VALUE));
+        assert_eq!(parse(&[16, 0, 0, 0, 2, 3, 1, 2, 3]), Err(ILLEGAL_VALUE));
+        assert_eq!(parse(&[16, 0xFF, 0xFF, 0, 2, 4, 1, 2, 3, 4]), Err(ILLEGAL_ADDRESS));
+        assert_eq!(parse(&[15, 0, 0, 0, 9, 1, 0xFF]), Err(ILLEGAL_VALUE));
+        let mut pdu = vec![16, 0, 0, 0, 124, 248];
+        pdu.resize(6 + 248, 0);
+        assert_eq!(parse(&pdu), Err(ILLEGAL_VALUE));
+
+        // encoding is the inverse of parsing
+        for req in [
+            ModbusRequest::ReadInputRegisters { addr: 3, count: 4 },
+            ModbusRequest::WriteSingleCoil { addr: 9, value: true },
+            ModbusRequest::WriteSingleRegister { addr: 1, value: 0xABCD },
+            ModbusRequest::WriteMultipleCoils { addr: 2, count: 10, values: vec![0xFF, 0x03] },
+            ModbusRequest::WriteMultipleRegisters { addr: 100, values: vec![1; 246] },
+            ModbusRequest::LockRegisters { op: LOCK_CLAIM, addr: 0, count: 300, timeout: 5 },
+        ] {
+            let mut pdu = Vec::new();
+            req.encode(&mut pdu);
+            assert_eq!(parse(&pdu), Ok(req));
+        }
+    }
+
+    /// Pass a request to the handler, and return the request for the PLC
+    /// or the direct reply PDU.
+    fn handle(handler: &mut ModbusHandler, pdu: &[u8])
+              -> (Option<Request<ModbusExtra>>, Vec<u8>) {
+        let mut requests = Vec::new();
+        let mut output = Vec::new();
+        handler.receive(&frame(1, 0, pdu), &mut requests, &mut output).unwrap();
+        if output.is_empty() {
+            assert_eq!(requests.len(), 1);
+            (requests.pop(), output)
+        } else {
+            assert!(requests.is_empty());
+            (None, output.split_off(MBAP_SIZE))
+        }
+    }
+
+    fn to_plc(handler: &mut ModbusHandler, pdu: &[u8]) -> Request<ModbusExtra> {
+        handle(handler, pdu).0.expect("no request")
+    }
+
+    fn direct(handler: &mut ModbusHandler, pdu: &[u8]) -> Vec<u8> {
+        handle(handler, pdu).1
+    }
+
+    #[test]
+    fn mask_and_read_write() {
+        let info = PlcInfo { extern_vars: vec![VarInfo::new("x", 0, VarType::U32)],
+                             ..PlcInfo::default() };
+        let mut handler = ModbusHandler::new(0, Arc::new(info), &ModbusConfig::default());
+        let reply = |handler: &mut ModbusHandler, req, values| {
+            let mut output = Vec::new();
+            handler.respond(Response::Ok(req, values), &mut output);
+            output[MBAP_SIZE..].to_vec()
+        };
+
+        // the mask write of the low register covers the whole variable
+        let req = to_plc(&mut handler, &[22, 0, 1, 0xFF, 0, 0, 0x12]);
+        assert_eq!((req.area, req.addr, req.count), (Area::Extern, 0, 4));
+        assert_eq!(req.write.as_deref(), Some(&0x0000_0012u32.to_ne_bytes()[..]));
+        assert_eq!(req.mask.as_deref(), Some(&0x0000_00FFu32.to_ne_bytes()[..]));
+        assert_eq!(reply(&mut handler, req, vec![]), [22, 0, 1, 0xFF, 0, 0, 0x12]);
+
+        // the write happens first, the read in the next cycle
+        let req = to_plc(&mut handler, &[23, 0, 0, 0, 2, 0, 2, 0, 1, 2, 0xBE, 0xEF]);
+        assert_eq!((req.addr, req.count, req.read_back), (4, 2, Some((0, 4))));
+        assert_eq!(req.write.as_deref(), Some(&[0xBE, 0xEF][..]));
+        assert_eq!(reply(&mut handler, req, 0x1122_3344u32.to_ne_bytes().to_vec()),
+                   [23, 4, 0x11, 0x22, 0x33, 0x44]);
+        // only the second register of the variable
+        let req = to_plc(&mut handler, &[23, 0, 1, 0, 1, 0, 2, 0, 1, 2, 0xBE, 0xEF]);
+        assert_eq!(req.read_back, Some((0, 4)));
+        assert_eq!(reply(&mut handler, req, 0x1122_3344u32.to_ne_bytes().to_vec()),
+                   [23, 2, 0x33, 0x44]);
+
+        // the write count is limited to 121 registers
+        assert_eq!(direct(&mut handler, &[23, 0, 0, 0, 1, 0, 0, 0, 122, 2, 0, 0]),
+                   [0x97, exception::ILLEGAL_VALUE]);
+        assert_eq!(direct(&mut handler, &[22, 0, 1, 0xFF, 0, 0]),
+                   [0x96, exception::ILLEGAL_VALUE]);
+    }
+
+    #[test]
+    fn device_id() {
+        let info = PlcInfo { name: "test".into(), version: "1.2".into(), ..PlcInfo::default() };
+        let mut handler = ModbusHandler::new(0, Arc::new(info), &ModbusConfig::default());
+        let reply = |code, objects: &[(u8, &str)]| {
+            let mut pdu = vec![43, 14, code, 0x82, 0, 0, objects.len() as u8];
+            for (id, value) in objects {
+                pdu.extend_from_slice(&[*id, value.len() as u8]);
+                pdu.extend_from_slice(value.as_bytes());
+            }
+            pdu
+        };
+        let (vendor, name, version) = ((0, "ethercat-plc"), (1, "test"), (2, "1.2"));
+
+        // stream access starts at the requested object
+        assert_eq!(direct(&mut handler, &[43, 14, 1, 0]), reply(1, &[vendor, name, version]));
+        assert_eq!(direct(&mut handler, &[43, 14, 1, 2]), reply(1, &[version]));
+        assert_eq!(direct(&mut handler, &[43, 14, 2, 1]),
+                   reply(2, &[name, version, (4, "test")]));
+        assert_eq!(direct(&mut handler, &[43, 14, 3, 4]), reply(3, &[(4, "test")]));
+        // individual access
+        assert_eq!(direct(&mut handler, &[43, 14, 4, 2]), reply(4, &[version]));
+
+        // unknown objects, or objects not in the category
+        let illegal_address = [0xAB, exception::ILLEGAL_ADDRESS];
+        assert_eq!(direct(&mut handler, &[43, 14, 4, 3]), illegal_address);
+        assert_eq!(direct(&mut handler, &[43, 14, 2, 3]), illegal_address);
+        assert_eq!(direct(&mut handler, &[43, 14, 1, 4]), illegal_address);
+        assert_eq!(direct(&mut handler, &[43, 14, 5, 0]), [0xAB, exception::ILLEGAL_VALUE]);
+        assert_eq!(direct(&mut handler, &[43, 13, 1, 0]), [0xAB, exception::ILLEGAL_FUNCTION]);
+
+        // long values are cut off
+        let info = PlcInfo { name: "x".repeat(100), ..PlcInfo::default() };
+        let mut handler = ModbusHandler::new(0, Arc::new(info), &ModbusConfig::default());
+        assert_eq!(direct(&mut handler, &[43, 14, 4, 1]), reply(4, &[(1, &"x".repeat(48))]));
+    }
+
+    #[test]
+    fn unit_ids() {
+        let info = Arc::new(PlcInfo::default());
+        let mut requests = Vec::new();
+        let mut output = Vec::new();
+        let read = [3, 0, 0, 0, 1];
+
+        // by default, the traditional and the recommended unit IDs
+        let config = ModbusConfig::default();
+        assert!(config.accepts(0) && config.accepts(255) && !config.accepts(1));
+        let config = ModbusConfig { unit_ids: vec![], ..ModbusConfig::default() };
+        assert!(config.accepts(0) && config.accepts(1) && config.accepts(255));
+
+        let config = ModbusConfig { unit_ids: vec![0, 5], ..ModbusConfig::default() };
+        let mut handler = ModbusHandler::new(3, info, &config);
+        let input = [frame(1, 5, &read), frame(2, 6, &read), frame(3, 0, &read)].concat();
+        // the last frame is incomplete
+        let len = handler.receive(&input[..input.len() - 1], &mut requests, &mut output).unwrap();
+        assert_eq!(len, input.len() - MBAP_SIZE - read.len());
+
+        // other unit IDs are not ignored, but answered with exception 11
+        assert_eq!(output, frame(2, 6, &[0x83, exception::GATEWAY_TARGET]));
+        assert_eq!(handler.direct_replies(), (1, 1));
+
+        // the reply goes to the unit ID of the request
+        assert_eq!(requests.len(), 1);
+        let req = requests.pop().unwrap();
+        assert_eq!((req.hid, req.area, req.addr, req.count), (3, Area::Extern, 0, 2));
+        output.clear();
+        handler.respond(Response::Ok(req, vec![0x12, 0x34]), &mut output);
+        assert_eq!(output, frame(1, 5, &[3, 2, 0x12, 0x34]));
+
+        // framing errors close the connection
+        let err = handler.receive(&[0, 0, 0, 0, 0, 1, 0], &mut requests, &mut output).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,716 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! A Modbus TCP client for polling external devices from cycle code.
+//!
+//! Jobs are registered on a `ModbusClient` before it is started; each job
+//! returns a handle that the cycle function can use without blocking.  The
+//! client then runs all jobs on a background thread, once per interval:
+//! changed write jobs are written first, then all read jobs are read.
+//!
+//! ```ignore
+//! let mut chiller = ModbusClient::new("10.0.0.20:502").unit(1);
+//! let temp = chiller.read_holding_registers(100, 2);
+//! let setpoint = chiller.write_registers(200, 2);
+//! chiller.start()?;
+//!
+//! plc.run(|data, ext| {
+//!     temp.store(&mut ext.cast()[TEMP_RANGE]);
+//!     setpoint.load(&ext.cast()[SETPOINT_RANGE]);
+//! });
+//! ```
+
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context};
+use byteorder::{ByteOrder, BE, NativeEndian as NE};
+use log::*;
+
+use super::{MbapHeader, ModbusRequest, MBAP_SIZE, MAX_PDU};
+
+/// Status of a client job, as seen by the cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    /// The job has not completed yet.
+    Pending,
+    /// The last transaction was successful.
+    Ok,
+    /// The device replied with the given Modbus exception code.
+    Exception(u8),
+    /// The device could not be reached, or sent an invalid reply.
+    Offline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobKind {
+    ReadCoils,
+    ReadDiscreteInputs,
+    ReadHoldingRegisters,
+    ReadInputRegisters,
+    WriteCoils,
+    WriteRegisters,
+}
+
+impl JobKind {
+    fn is_bits(self) -> bool {
+        matches!(self, JobKind::ReadCoils | JobKind::ReadDiscreteInputs | JobKind::WriteCoils)
+    }
+
+    fn is_write(self) -> bool {
+        matches!(self, JobKind::WriteCoils | JobKind::WriteRegisters)
+    }
+
+    fn max_count(self) -> u16 {
+        match self {
+            JobKind::ReadCoils | JobKind::ReadDiscreteInputs => 2000,
+            JobKind::ReadHoldingRegisters | JobKind::ReadInputRegisters => 125,
+            JobKind::WriteCoils => 1968,
+            JobKind::WriteRegisters => 123,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct JobState {
+    status: JobStatus,
+    /// Register values, or bit values as 0/1.
+    values: Vec<u16>,
+    /// For writes, whether the values need to be written.
+    dirty: bool,
+    /// For writes, incremented on every change.
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct Job {
+    kind: JobKind,
+    addr: u16,
+    count: u16,
+    state: Mutex<JobState>,
+}
+
+impl Job {
+    fn new(kind: JobKind, addr: u16, count: u16) -> Arc<Self> {
+        Arc::new(Job { kind, addr, count, state: Mutex::new(JobState {
+            status: JobStatus::Pending,
+            values: vec![0; count as usize],
+            dirty: false,
+            generation: 0,
+        }) })
+    }
+
+    fn state(&self) -> MutexGuard<'_, JobState> {
+        // the state is always consistent, even if a holder panicked
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Handle to a cyclic read job.
+#[derive(Debug, Clone)]
+pub struct ReadJob(Arc<Job>);
+
+impl ReadJob {
+    pub fn status(&self) -> JobStatus {
+        self.0.state().status
+    }
+
+    /// Get the last read value of the register or bit with the given index
+    /// (relative to the job's start address).  Bits are returned as 0 or 1.
+    pub fn get(&self, index: usize) -> Option<u16> {
+        let state = self.0.state();
+        if state.status != JobStatus::Ok {
+            return None;
+        }
+        state.values.get(index).copied()
+    }
+
+    /// Copy the last read values into `dst`, and return the job status.
+    /// Nothing is copied unless the status is `Ok`.
+    pub fn read_into(&self, dst: &mut [u16]) -> JobStatus {
+        let state = self.0.state();
+        if state.status == JobStatus::Ok {
+            let n = dst.len().min(state.values.len());
+            dst[..n].copy_from_slice(&state.values[..n]);
+        }
+        state.status
+    }
+
+    /// Store the last read values into a part of the extern image: registers
+    /// as native endian words, bits packed into bytes (LSB first).  Nothing
+    /// is stored unless the status is `Ok`.
+    pub fn store(&self, dst: &mut [u8]) -> JobStatus {
+        let state = self.0.state();
+        if state.status == JobStatus::Ok {
+            if self.0.kind.is_bits() {
+                for (i, &bit) in state.values.iter().enumerate().take(dst.len() * 8) {
+                    if bit != 0 {
+                        dst[i / 8] |= 1 << (i % 8);
+                    } else {
+                        dst[i / 8] &= !(1 << (i % 8));
+                    }
+                }
+            } else {
+                let n = (dst.len() / 2).min(state.values.len());
+                NE::write_u16_into(&state.values[..n], &mut dst[..2*n]);
+            }
+        }
+        state.status
+    }
+}
+
+/// Handle to a write job.  Values are written when they change, and again
+/// after each reconnect.
+#[derive(Debug, Clone)]
+pub struct WriteJob(Arc<Job>);
+
+impl WriteJob {
+    pub fn status(&self) -> JobStatus {
+        self.0.state().status
+    }
+
+    /// Set the register or bit (nonzero = on) with the given index, relative
+    /// to the job's start address.
+    pub fn set(&self, index: usize, value: u16) {
+        let mut state = self.0.state();
+        if let Some(slot) = state.values.get_mut(index) {
+            if *slot != value {
+                *slot = value;
+                state.dirty = true;
+                state.generation += 1;
+            }
+        }
+    }
+
+    /// Set all values at once.
+    pub fn set_all(&self, values: &[u16]) {
+        let mut state = self.0.state();
+        let n = values.len().min(state.values.len());
+        if state.values[..n] != values[..n] {
+            state.values[..n].copy_from_slice(&values[..n]);
+            state.dirty = true;
+            state.generation += 1;
+        }
+    }
+
+    /// Load the values from a part of the extern image, in the same format
+    /// as `ReadJob::store`.
+    pub fn load(&self, src: &[u8]) {
+        let count = self.0.count as usize;
+        let values = if self.0.kind.is_bits() {
+            (0..count.min(src.len() * 8)).map(|i| (src[i / 8] >> (i % 8)) as u16 & 1).collect()
+        } else {
+            let mut values = vec![0; count.min(src.len() / 2)];
+            NE::read_u16_into(&src[..2*values.len()], &mut values);
+            values
+        };
+        self.set_all(&values);
+    }
+}
+
+/// A Modbus TCP client that polls a device in the background.
+pub struct ModbusClient {
+    addr: String,
+    unit: u8,
+    interval: Duration,
+    timeout: Duration,
+    jobs: Vec<Arc<Job>>,
+}
+
+impl ModbusClient {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            unit: 255,
+            interval: Duration::from_millis(100),
+            timeout: Duration::from_secs(1),
+            jobs: Vec::new(),
+        }
+    }
+
+    pub fn unit(mut self, unit: u8) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Set the interval in which all jobs are run.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the timeout for connecting and for each transaction.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn add_job(&mut self, kind: JobKind, addr: u16, count: u16) -> Arc<Job> {
+        let job = Job::new(kind, addr, count);
+        self.jobs.push(job.clone());
+        job
+    }
+
+    pub fn read_coils(&mut self, addr: u16, count: u16) -> ReadJob {
+        ReadJob(self.add_job(JobKind::ReadCoils, addr, count))
+    }
+
+    pub fn read_discrete_inputs(&mut self, addr: u16, count: u16) -> ReadJob {
+        ReadJob(self.add_job(JobKind::ReadDiscreteInputs, addr, count))
+    }
+
+    pub fn read_holding_registers(&mut self, addr: u16, count: u16) -> ReadJob {
+        ReadJob(self.add_job(JobKind::ReadHoldingRegisters, addr, count))
+    }
+
+    pub fn read_input_registers(&mut self, addr: u16, count: u16) -> ReadJob {
+        ReadJob(self.add_job(JobKind::ReadInputRegisters, addr, count))
+    }
+
+    pub fn write_coils(&mut self, addr: u16, count: u16) -> WriteJob {
+        WriteJob(self.add_job(JobKind::WriteCoils, addr, count))
+    }
+
+    pub fn write_registers(&mut self, addr: u16, count: u16) -> WriteJob {
+        WriteJob(self.add_job(JobKind::WriteRegisters, addr, count))
+    }
+
+    /// Start polling on a background thread.  The thread stops when all
+    /// job handles have been dropped.
+    pub fn start(self) -> anyhow::Result<()> {
+        for job in &self.jobs {
+            if job.count == 0 || job.count > job.kind.max_count() ||
+                job.addr as usize + job.count as usize > 0x10000
+            {
+                bail!("invalid Modbus client job: {:?} of {} at {}",
+                      job.kind, job.count, job.addr);
+            }
+        }
+        // resolve once, so that invalid addresses are reported early
+        let addr = self.addr.to_socket_addrs()
+            .with_context(|| format!("resolving Modbus device address {}", self.addr))?
+            .next()
+            .with_context(|| format!("no address found for {}", self.addr))?;
+        let mut poller = Poller {
+            name: self.addr,
+            addr,
+            unit: self.unit,
+            interval: self.interval,
+            timeout: self.timeout,
+            jobs: self.jobs,
+            stream: None,
+            tid: 0,
+            buf: Vec::with_capacity(MBAP_SIZE + MAX_PDU),
+        };
+        thread::Builder::new()
+            .name(format!("modbus client {}", poller.name))
+            .spawn(move || poller.run())
+            .context("starting Modbus client thread")?;
+        Ok(())
+    }
+}
+
+/// Reasons for a failed transaction.
+enum Failure {
+    Exception(u8),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Io(err)
+    }
+}
+
+struct Poller {
+    name: String,
+    addr: std::net::SocketAddr,
+    unit: u8,
+    interval: Duration,
+    timeout: Duration,
+    jobs: Vec<Arc<Job>>,
+    stream: Option<TcpStream>,
+    tid: u16,
+    buf: Vec<u8>,
+}
+
+impl Poller {
+    fn run(&mut self) {
+        mlzlog::set_thread_prefix(format!("Modbus client {}: ", self.name));
+        let mut online = true;
+
+        loop {
+            // jobs whose handles were all dropped are not needed anymore
+            self.jobs.retain(|job| Arc::strong_count(job) > 1);
+            if self.jobs.is_empty() {
+                debug!("no jobs left, stopping");
+                break;
+            }
+            let started = Instant::now();
+            match self.round() {
+                Ok(()) => {
+                    if !online {
+                        info!("device is online again");
+                        online = true;
+                    }
+                }
+                Err(err) => {
+                    if online {
+                        warn!("device is offline: {}", err);
+                        online = false;
+                    }
+                    self.stream = None;
+                    for job in &self.jobs {
+                        job.state().status = JobStatus::Offline;
+                    }
+                }
+            }
+            if let Some(rest) = self.interval.checked_sub(started.elapsed()) {
+                thread::sleep(rest);
+            }
+        }
+    }
+
+    /// Run all jobs once.  Only I/O errors are returned; exceptions are
+    /// reported in the respective job's status.
+    fn round(&mut self) -> io::Result<()> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect_timeout(&self.addr, self.timeout)?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            stream.set_nodelay(true)?;
+            self.stream = Some(stream);
+            // the device might have lost the written values
+            for job in self.jobs.iter().filter(|job| job.kind.is_write()) {
+                job.state().dirty = true;
+            }
+        }
+        let jobs = self.jobs.clone();
+        for job in jobs.iter().filter(|job| job.kind.is_write()) {
+            let (values, generation) = {
+                let state = job.state();
+                if !state.dirty {
+                    continue;
+                }
+                (state.values.clone(), state.generation)
+            };
+            let request = if job.kind == JobKind::WriteCoils {
+                let mut bits = vec![0; (values.len() + 7) / 8];
+                for (i, _) in values.iter().enumerate().filter(|(_, &v)| v != 0) {
+                    bits[i / 8] |= 1 << (i % 8);
+                }
+                ModbusRequest::WriteMultipleCoils { addr: job.addr, count: job.count, values: bits }
+            } else {
+                let mut bytes = vec![0; 2 * values.len()];
+                BE::write_u16_into(&values, &mut bytes);
+                ModbusRequest::WriteMultipleRegisters { addr: job.addr, values: bytes }
+            };
+            let result = self.transact(&request);
+            let mut state = job.state();
+            match result {
+                Ok(_) => {
+                    state.status = JobStatus::Ok;
+                    // only if the values didn't change in the meantime
+                    if state.generation == generation {
+                        state.dirty = false;
+                    }
+                }
+                Err(Failure::Exception(code)) => state.status = JobStatus::Exception(code),
+                Err(Failure::Io(err)) => return Err(err),
+            }
+        }
+        for job in jobs.iter().filter(|job| !job.kind.is_write()) {
+            let (addr, count) = (job.addr, job.count);
+            let request = match job.kind {
+                JobKind::ReadCoils => ModbusRequest::ReadCoils { addr, count },
+                JobKind::ReadDiscreteInputs => ModbusRequest::ReadDiscreteInputs { addr, count },
+                JobKind::ReadHoldingRegisters => ModbusRequest::ReadHoldingRegisters { addr, count },
+                _ => ModbusRequest::ReadInputRegisters { addr, count },
+            };
+            let result = self.transact(&request).and_then(|pdu| Self::decode_read(job, &pdu));
+            let mut state = job.state();
+            match result {
+                Ok(values) => {
+                    state.values = values;
+                    state.status = JobStatus::Ok;
+                }
+                Err(Failure::Exception(code)) => state.status = JobStatus::Exception(code),
+                Err(Failure::Io(err)) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_read(job: &Job, pdu: &[u8]) -> Result<Vec<u16>, Failure> {
+        let count = job.count as usize;
+        let nbytes = if job.kind.is_bits() { (count + 7) / 8 } else { 2 * count };
+        if pdu.len() != 2 + nbytes || pdu[1] as usize != nbytes {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid reply length").into());
+        }
+        let data = &pdu[2..];
+        Ok(if job.kind.is_bits() {
+            (0..count).map(|i| (data[i / 8] >> (i % 8)) as u16 & 1).collect()
+        } else {
+            let mut values = vec![0; count];
+            BE::read_u16_into(data, &mut values);
+            values
+        })
+    }
+
+    /// Send a request and wait for the matching reply PDU.
+    fn transact(&mut self, request: &ModbusRequest) -> Result<Vec<u8>, Failure> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return Err(io::Error::from(ErrorKind::NotConnected).into()),
+        };
+        self.tid = self.tid.wrapping_add(1);
+        self.buf.clear();
+        self.buf.extend_from_slice(&[0; MBAP_SIZE]);
+        request.encode(&mut self.buf);
+        let pdu_len = self.buf.len() - MBAP_SIZE;
+        let mut head = Vec::with_capacity(MBAP_SIZE);
+        MbapHeader { tid: self.tid, unit: self.unit, pdu_len }.encode(&mut head);
+        self.buf[..MBAP_SIZE].copy_from_slice(&head);
+        stream.write_all(&self.buf)?;
+
+        let mut headbuf = [0; MBAP_SIZE];
+        stream.read_exact(&mut headbuf)?;
+        let head = MbapHeader::parse(&headbuf)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        let mut pdu = vec![0; head.pdu_len];
+        stream.read_exact(&mut pdu)?;
+        // requests are never pipelined, and the connection is dropped after
+        // a timeout, so there are no late replies to skip
+        if head.tid != self.tid {
+            return Err(io::Error::new(ErrorKind::InvalidData, "reply for wrong transaction").into());
+        }
+        let fc = request.function_code();
+        if pdu[0] == fc | 0x80 && pdu.len() == 2 {
+            Err(Failure::Exception(pdu[1]))
+        } else if pdu[0] != fc {
+            Err(io::Error::new(ErrorKind::InvalidData, "reply for wrong function").into())
+        } else {
+            Ok(pdu)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use crate::modbus::{exception, exception_pdu};
+    use super::*;
+
+    /// A fake device with 32 holding registers and 32 coils.
+    #[derive(Default)]
+    struct Device {
+        regs: [u16; 32],
+        coils: [bool; 32],
+        connections: usize,
+        writes: usize,
+        /// Close connections instead of replying.
+        down: bool,
+        /// Reply to the next request with a wrong transaction ID.
+        wrong_tid: bool,
+    }
+
+    impl Device {
+        fn reply(&mut self, request: ModbusRequest) -> Vec<u8> {
+            let fc = request.function_code();
+            let (addr, count) = match request {
+                ModbusRequest::ReadCoils { addr, count } |
+                ModbusRequest::ReadHoldingRegisters { addr, count } |
+                ModbusRequest::WriteMultipleCoils { addr, count, .. } => (addr, count),
+                ModbusRequest::WriteMultipleRegisters { addr, ref values } =>
+                    (addr, values.len() as u16 / 2),
+                _ => return exception_pdu(fc, exception::ILLEGAL_FUNCTION),
+            };
+            let range = addr as usize..addr as usize + count as usize;
+            if range.end > 32 {
+                return exception_pdu(fc, exception::ILLEGAL_ADDRESS);
+            }
+            let mut pdu = vec![fc];
+            match request {
+                ModbusRequest::ReadCoils { .. } => {
+                    let mut bits = vec![0; (range.len() + 7) / 8];
+                    for (i, _) in self.coils[range].iter().enumerate().filter(|(_, &c)| c) {
+                        bits[i / 8] |= 1 << (i % 8);
+                    }
+                    pdu.push(bits.len() as u8);
+                    pdu.extend_from_slice(&bits);
+                }
+                ModbusRequest::ReadHoldingRegisters { .. } => {
+                    pdu.push(2 * count as u8);
+                    for reg in &self.regs[range] {
+                        pdu.extend_from_slice(&reg.to_be_bytes());
+                    }
+                }
+                ModbusRequest::WriteMultipleCoils { values, .. } => {
+                    for (i, coil) in self.coils[range].iter_mut().enumerate() {
+                        *coil = values[i / 8] & (1 << (i % 8)) != 0;
+                    }
+                    self.writes += 1;
+                    pdu.extend_from_slice(&[(addr >> 8) as u8, addr as u8, 0, count as u8]);
+                }
+                ModbusRequest::WriteMultipleRegisters { values, .. } => {
+                    BE::read_u16_into(&values, &mut self.regs[range]);
+                    self.writes += 1;
+                    pdu.extend_from_slice(&[(addr >> 8) as u8, addr as u8, 0, count as u8]);
+                }
+                _ => unreachable!(),
+            }
+            pdu
+        }
+    }
+
+    fn serve(stream: &mut TcpStream, device: &Mutex<Device>) -> io::Result<()> {
+        loop {
+            let mut headbuf = [0; MBAP_SIZE];
+            stream.read_exact(&mut headbuf)?;
+            let mut head = MbapHeader::parse(&headbuf).unwrap();
+            assert_eq!(head.unit, 1);
+            let mut pdu = vec![0; head.pdu_len];
+            stream.read_exact(&mut pdu)?;
+            let mut device = device.lock().unwrap();
+            if device.down {
+                return Ok(());
+            }
+            let reply = device.reply(ModbusRequest::parse(&pdu).unwrap());
+            if device.wrong_tid {
+                device.wrong_tid = false;
+                head.tid = head.tid.wrapping_sub(1);
+            }
+            let mut buf = Vec::new();
+            MbapHeader { pdu_len: reply.len(), ..head }.encode(&mut buf);
+            buf.extend_from_slice(&reply);
+            stream.write_all(&buf)?;
+        }
+    }
+
+    /// Start a fake device, and return a client for it.
+    fn start_device() -> (Arc<Mutex<Device>>, ModbusClient) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let device = Arc::new(Mutex::new(Device::default()));
+        let dev = device.clone();
+        thread::spawn(move || for stream in listener.incoming() {
+            dev.lock().unwrap().connections += 1;
+            let _ = serve(&mut stream.unwrap(), &dev);
+        });
+        let client = ModbusClient::new(addr.to_string())
+            .unit(1)
+            .interval(Duration::from_millis(10))
+            .timeout(Duration::from_millis(500));
+        (device, client)
+    }
+
+    fn wait_for(what: &str, cond: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !cond() {
+            assert!(Instant::now() < deadline, "timeout waiting for {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn read_write() {
+        let (device, mut client) = start_device();
+        device.lock().unwrap().regs[..4].copy_from_slice(&[1, 2, 0x1234, 4]);
+        device.lock().unwrap().coils[..3].copy_from_slice(&[true, false, true]);
+        let regs = client.read_holding_registers(0, 4);
+        let coils = client.read_coils(0, 10);
+        let set_regs = client.write_registers(8, 2);
+        let set_coils = client.write_coils(16, 9);
+        client.start().unwrap();
+
+        wait_for("reads", || regs.status() == JobStatus::Ok && coils.status() == JobStatus::Ok);
+        assert_eq!(regs.get(2), Some(0x1234));
+        assert_eq!(regs.get(4), None);
+        let mut values = [0; 4];
+        assert_eq!(regs.read_into(&mut values), JobStatus::Ok);
+        assert_eq!(values, [1, 2, 0x1234, 4]);
+        let mut bytes = [0xFF; 4];
+        regs.store(&mut bytes);
+        assert_eq!(bytes, [1u16.to_ne_bytes(), 2u16.to_ne_bytes()].concat()[..]);
+        // bits beyond the job are left alone
+        let mut bits = [0xF0, 0xFF];
+        coils.store(&mut bits);
+        assert_eq!(bits, [0b101, 0xFC]);
+
+        // reads are repeated every interval
+        device.lock().unwrap().regs[0] = 42;
+        wait_for("cyclic read", || regs.get(0) == Some(42));
+
+        // the initial values are written once after connecting
+        wait_for("initial write", || device.lock().unwrap().writes == 2);
+        set_regs.load(&[7u16.to_ne_bytes(), 8u16.to_ne_bytes()].concat());
+        set_coils.set(0, 1);
+        set_coils.set(8, 1);
+        wait_for("writes", || device.lock().unwrap().writes == 4);
+        assert_eq!(set_regs.status(), JobStatus::Ok);
+        assert_eq!(set_coils.status(), JobStatus::Ok);
+        let device = device.lock().unwrap();
+        assert_eq!(device.regs[8..10], [7, 8]);
+        assert_eq!(device.coils[16..26], [true, false, false, false, false, false, false, false,
+                                          true, false]);
+    }
+
+    #[test]
+    fn exceptions() {
+        let (device, mut client) = start_device();
+        let good = client.read_holding_registers(28, 4);
+        let bad = client.read_holding_registers(30, 4);
+        let bad_write = client.write_registers(31, 2);
+        let wrong_kind = client.read_input_registers(0, 1);
+        client.start().unwrap();
+
+        wait_for("replies", || good.status() == JobStatus::Ok &&
+                 bad.status() != JobStatus::Pending &&
+                 bad_write.status() != JobStatus::Pending &&
+                 wrong_kind.status() != JobStatus::Pending);
+        assert_eq!(bad.status(), JobStatus::Exception(exception::ILLEGAL_ADDRESS));
+        assert_eq!(bad.get(0), None);
+        assert_eq!(bad_write.status(), JobStatus::Exception(exception::ILLEGAL_ADDRESS));
+        assert_eq!(wrong_kind.status(), JobStatus::Exception(exception::ILLEGAL_FUNCTION));
+        // exceptions don't break the connection
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(device.lock().unwrap().connections, 1);
+    }
+
+    #[test]
+    fn reconnect() {
+        let (device, mut client) = start_device();
+        let regs = client.read_holding_registers(0, 2);
+        let set_regs = client.write_registers(8, 1);
+        set_regs.set(0, 5);
+        client.start().unwrap();
+        wait_for("first write", || device.lock().unwrap().writes == 1);
+        wait_for("read", || regs.status() == JobStatus::Ok);
+
+        // all jobs go offline when the device stops replying
+        device.lock().unwrap().down = true;
+        wait_for("offline", || regs.status() == JobStatus::Offline &&
+                 set_regs.status() == JobStatus::Offline);
+        assert_eq!(regs.get(0), None);
+
+        // the values are written again, since the device may have lost them
+        {
+            let mut device = device.lock().unwrap();
+            device.regs[8] = 0;
+            device.down = false;
+        }
+        wait_for("online", || regs.status() == JobStatus::Ok);
+        wait_for("second write", || device.lock().unwrap().writes == 2);
+        assert_eq!(device.lock().unwrap().regs[8], 5);
+
+        // a reply with a wrong transaction ID is invalid, the client
+        // reconnects and goes on
+        let connections = device.lock().unwrap().connections;
+        device.lock().unwrap().wrong_tid = true;
+        wait_for("reconnect", || device.lock().unwrap().connections > connections);
+        device.lock().unwrap().regs[1] = 9;
+        wait_for("read after reconnect", || regs.get(1) == Some(9));
+    }
+}
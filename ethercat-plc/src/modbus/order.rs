@@ -0,0 +1,270 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Conversion between image bytes and Modbus registers.
+//!
+//! Image variables are stored in native byte order.  Modbus registers are
+//! big-endian, and there are several conventions for the order of registers
+//! making up a 32- or 64-bit value.  Since the conversion is a permutation
+//! of the bytes within each variable, it is applied to whole variables, and
+//! requests that cover only part of a variable are extended accordingly.
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::image::VarInfo;
+use crate::server::PlcInfo;
+
+/// Order of the bytes of a value in registers.  The letters denote the
+/// bytes of a 32-bit value, from most to least significant, in the order
+/// they are transmitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordOrder {
+    /// Transmit the image bytes as they are, without conversion.  This was
+    /// the only behavior before the word order became configurable.
+    Native,
+    /// Big-endian, most significant register first (the Modbus standard).
+    Abcd,
+    /// Big-endian registers, least significant register first.
+    Cdab,
+    /// Byte-swapped registers, most significant register first.
+    Badc,
+}
+
+/// Word order settings for a Modbus server.
+///
+/// The default is `WordOrder::Abcd` for all variables, as the Modbus
+/// standard specifies.  Note that this is a change for existing clients,
+/// which got the image bytes in native (usually little-endian) order;
+/// set `default` to `WordOrder::Native` to keep the old behavior.
+#[derive(Debug, Clone)]
+pub struct RegisterOrder {
+    /// The order used for all variables not matched below.
+    pub default: WordOrder,
+    /// Orders for single variables or regions, given by variable name or
+    /// a prefix of structured variables (e.g. `if_magnet` also matches
+    /// `if_magnet.value`).  Later entries take precedence.
+    pub fields: Vec<(String, WordOrder)>,
+}
+
+impl Default for RegisterOrder {
+    fn default() -> Self {
+        Self { default: WordOrder::Abcd, fields: Vec::new() }
+    }
+}
+
+impl RegisterOrder {
+    fn order_for(&self, var: &VarInfo) -> WordOrder {
+        self.fields.iter().rev()
+            .find(|(name, _)| var.is_within(name))
+            .map_or(self.default, |(_, order)| *order)
+    }
+}
+
+/// Returns the permutation `p` such that `wire[i] = native[p[i]]`, or None
+/// if no conversion is necessary.
+fn permutation(size: usize, order: WordOrder) -> Option<Vec<usize>> {
+    if size < 2 || order == WordOrder::Native {
+        return None;
+    }
+    let big_endian = |i: usize| if cfg!(target_endian = "little") { size - 1 - i } else { i };
+    let perm = (0..size).map(|i| big_endian(match order {
+        WordOrder::Cdab => (size / 2 - 1 - i / 2) * 2 + i % 2,
+        WordOrder::Badc => i ^ 1,
+        _ => i,
+    })).collect::<Vec<_>>();
+    if perm.iter().enumerate().all(|(i, &p)| i == p) {
+        None
+    } else {
+        Some(perm)
+    }
+}
+
+/// The variables of one image area that need conversion.
+#[derive(Debug, Default)]
+pub(crate) struct RegisterLayout {
+    /// Sorted by address, non-overlapping.
+    fields: Vec<(Range<usize>, Vec<usize>)>,
+}
+
+impl RegisterLayout {
+    fn new(vars: &[VarInfo], order: &RegisterOrder) -> Self {
+        let mut fields = vars.iter().filter_map(|var| {
+            permutation(var.size(), order.order_for(var)).map(|perm| (var.range(), perm))
+        }).collect::<Vec<_>>();
+        fields.sort_by_key(|(range, _)| range.start);
+        Self { fields }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// All converted fields overlapping with the given range.
+    fn overlapping(&self, range: Range<usize>) -> &[(Range<usize>, Vec<usize>)] {
+        let first = self.fields.partition_point(|(f, _)| f.end <= range.start);
+        let last = self.fields.partition_point(|(f, _)| f.start < range.end);
+        &self.fields[first..last.max(first)]
+    }
+
+    /// Extend a byte range so that it covers all converted fields it
+    /// touches.
+    pub fn cover(&self, range: Range<usize>) -> Range<usize> {
+        let fields = self.overlapping(range.clone());
+        let start = fields.first().map_or(range.start, |(f, _)| f.start.min(range.start));
+        let end = fields.last().map_or(range.end, |(f, _)| f.end.max(range.end));
+        start..end
+    }
+
+    /// Convert image bytes, starting at address `start`, to wire order.
+    pub fn to_wire(&self, start: usize, data: &mut [u8]) {
+        self.permute(start, data, false)
+    }
+
+    /// Convert bytes in wire order, starting at address `start`, to image
+    /// order.
+    pub fn to_native(&self, start: usize, data: &mut [u8]) {
+        self.permute(start, data, true)
+    }
+
+    fn permute(&self, start: usize, data: &mut [u8], inverse: bool) {
+        let mut tmp = [0u8; 8];
+        for (field, perm) in self.overlapping(start..start + data.len()) {
+            // partially covered fields can't be converted
+            if field.start < start || field.end > start + data.len() {
+                continue;
+            }
+            let bytes = &mut data[field.start - start..field.end - start];
+            let tmp = &mut tmp[..bytes.len()];
+            tmp.copy_from_slice(bytes);
+            for (i, &p) in perm.iter().enumerate() {
+                if inverse {
+                    bytes[p] = tmp[i];
+                } else {
+                    bytes[i] = tmp[p];
+                }
+            }
+        }
+    }
+}
+
+/// Register layouts for the extern and the process image.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RegisterMap {
+    pub ext: Arc<RegisterLayout>,
+    pub process: Arc<RegisterLayout>,
+}
+
+impl RegisterMap {
+    pub fn new(info: &PlcInfo, order: &RegisterOrder) -> Self {
+        Self {
+            ext: Arc::new(RegisterLayout::new(&info.extern_vars, order)),
+            process: Arc::new(RegisterLayout::new(&info.process_vars, order)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::VarType;
+    use crate::modbus::{register_range, register_write};
+    use super::*;
+
+    fn layout(vars: &[(&str, usize, VarType)], default: WordOrder,
+              fields: &[(&str, WordOrder)]) -> RegisterLayout {
+        let vars = vars.iter().map(|&(name, offset, ty)| VarInfo::new(name, offset, ty))
+                                .collect::<Vec<_>>();
+        let fields = fields.iter().map(|&(name, order)| (name.into(), order)).collect();
+        RegisterLayout::new(&vars, &RegisterOrder { default, fields })
+    }
+
+    /// Convert a single value to wire order, and check that it converts back.
+    fn wire(order: WordOrder, ty: VarType, native: &[u8]) -> Vec<u8> {
+        let layout = layout(&[("v", 0, ty)], order, &[]);
+        let mut data = native.to_vec();
+        layout.to_wire(0, &mut data);
+        let mut back = data.clone();
+        layout.to_native(0, &mut back);
+        assert_eq!(back, native);
+        data
+    }
+
+    #[test]
+    fn word_orders() {
+        let u32 = 0x1122_3344u32.to_ne_bytes();
+        assert_eq!(wire(WordOrder::Abcd, VarType::U32, &u32), [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(wire(WordOrder::Cdab, VarType::U32, &u32), [0x33, 0x44, 0x11, 0x22]);
+        assert_eq!(wire(WordOrder::Badc, VarType::U32, &u32), [0x22, 0x11, 0x44, 0x33]);
+        assert_eq!(wire(WordOrder::Native, VarType::U32, &u32), u32);
+
+        let f32 = (-1.5f32).to_ne_bytes();
+        assert_eq!(wire(WordOrder::Abcd, VarType::F32, &f32), [0xBF, 0xC0, 0, 0]);
+        assert_eq!(wire(WordOrder::Cdab, VarType::F32, &f32), [0, 0, 0xBF, 0xC0]);
+        assert_eq!(wire(WordOrder::Badc, VarType::F32, &f32), [0xC0, 0xBF, 0, 0]);
+
+        let f64 = 0x0102_0304_0506_0708u64.to_ne_bytes();
+        assert_eq!(wire(WordOrder::Abcd, VarType::F64, &f64), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(wire(WordOrder::Cdab, VarType::F64, &f64), [7, 8, 5, 6, 3, 4, 1, 2]);
+        assert_eq!(wire(WordOrder::Badc, VarType::F64, &f64), [2, 1, 4, 3, 6, 5, 8, 7]);
+
+        // single registers only depend on the byte order
+        let u16 = 0x1122u16.to_ne_bytes();
+        assert_eq!(wire(WordOrder::Abcd, VarType::U16, &u16), [0x11, 0x22]);
+        assert_eq!(wire(WordOrder::Cdab, VarType::U16, &u16), [0x11, 0x22]);
+        assert_eq!(wire(WordOrder::Badc, VarType::U16, &u16), [0x22, 0x11]);
+        assert_eq!(permutation(1, WordOrder::Badc), None);
+    }
+
+    #[test]
+    fn overrides() {
+        let vars = [("a", 0, VarType::U32), ("mag.value", 4, VarType::U32),
+                    ("mag.target", 8, VarType::U32), ("magnet", 12, VarType::U32)];
+        let abcd = layout(&vars, WordOrder::Abcd,
+                          &[("mag", WordOrder::Cdab), ("mag.target", WordOrder::Native)]);
+        let native = 0x1122_3344u32.to_ne_bytes();
+        let mut data = [native; 4].concat();
+        abcd.to_wire(0, &mut data);
+        assert_eq!(data[..8], [0x11, 0x22, 0x33, 0x44, 0x33, 0x44, 0x11, 0x22]);
+        assert_eq!(data[8..12], native);
+        // "mag" is not a prefix of "magnet"
+        assert_eq!(data[12..], [0x11, 0x22, 0x33, 0x44]);
+
+        // the whole image can be kept native, except for some fields
+        let native = layout(&vars, WordOrder::Native, &[("a", WordOrder::Abcd)]);
+        assert_eq!(native.fields.len(), 1);
+        assert_eq!(native.cover(2..6), 0..6);
+        assert!(layout(&vars, WordOrder::Native, &[]).is_empty());
+    }
+
+    #[test]
+    fn partial_access() {
+        let layout = Arc::new(layout(&[("a", 0, VarType::U32), ("b", 4, VarType::U32)],
+                                     WordOrder::Abcd, &[]));
+        assert_eq!(layout.cover(2..4), 0..4);
+        assert_eq!(layout.cover(2..6), 0..8);
+        assert_eq!(layout.cover(8..10), 8..10);
+
+        // fields that are only partially contained are not converted
+        let mut data = [1, 2, 3, 4];
+        layout.to_wire(2, &mut data);
+        assert_eq!(data, [1, 2, 3, 4]);
+
+        // reading one register transfers the whole value
+        let (start, len, convert) = register_range(&layout, 1, 2);
+        let convert = convert.unwrap();
+        assert_eq!((start, len, convert.start, convert.range), (0, 8, 0, 2..6));
+
+        // writing one register writes the whole value, with the other
+        // register masked out
+        let (start, len, data, mask) = register_write(&layout, 1, vec![0x33, 0x44], None);
+        assert_eq!((start, len), (0, 4));
+        assert_eq!(data, 0x0000_3344u32.to_ne_bytes());
+        assert_eq!(mask.unwrap(), 0x0000_FFFFu32.to_ne_bytes());
+
+        // and mask writes keep their mask, in image order
+        let (_, _, data, mask) = register_write(&layout, 2, vec![0x11, 0x22],
+                                                Some(vec![0x0F, 0xF0]));
+        assert_eq!(data, 0x1122_0000u32.to_ne_bytes());
+        assert_eq!(mask.unwrap(), 0x0FF0_0000u32.to_ne_bytes());
+    }
+}
@@ -0,0 +1,519 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Modbus RTU, on a serial line or tunneled over TCP.
+//!
+//! An RTU frame consists of the unit ID, the PDU and a CRC-16.  On a serial
+//! line, frames are separated by a silent interval of 3.5 character times;
+//! over TCP there is no such separation, and the frame length is determined
+//! from the function code and byte counts of the request.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, ErrorKind};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{mem, thread};
+use log::*;
+use crossbeam_channel::{Sender, Receiver};
+
+use crate::metrics::ServerMetrics;
+use crate::server::{new_client_id, Handler, PlcInfo, Request, Response, Server};
+use super::{pdu_action, response_pdu, Action, ModbusExtra, MAX_PDU};
+use super::order::{RegisterMap, RegisterOrder};
+
+/// Size of the longest possible request frame (function 23 with 255 data
+/// bytes), so that oversized requests can be read and rejected properly.
+const MAX_REQUEST: usize = 11 + 255 + 2;
+/// Size of the longest reply frame.
+const MAX_REPLY: usize = 1 + MAX_PDU + 2;
+
+/// Calculate the Modbus CRC-16 of the given data.  It is transmitted with
+/// the low byte first.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Check the CRC of a frame and split it into unit ID and PDU.
+fn split_frame(frame: &[u8]) -> Option<(u8, &[u8])> {
+    if frame.len() < 4 {
+        return None;
+    }
+    let (data, crc) = frame.split_at(frame.len() - 2);
+    if crc16(data).to_le_bytes() != crc {
+        return None;
+    }
+    Some((data[0], &data[1..]))
+}
+
+fn encode_frame(unit: u8, pdu: &[u8], buf: &mut Vec<u8>) {
+    let start = buf.len();
+    buf.push(unit);
+    buf.extend_from_slice(pdu);
+    let crc = crc16(&buf[start..]);
+    buf.extend_from_slice(&crc.to_le_bytes());
+}
+
+/// The length of a request frame, as far as it can be determined from the
+/// bytes received so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameLen {
+    /// The frame has this total length, including unit ID and CRC.
+    Known(usize),
+    /// At least this many bytes are needed to determine the length.
+    NeedMore(usize),
+    /// The function code is unknown.
+    Unknown,
+}
+
+fn request_len(frame: &[u8]) -> FrameLen {
+    let with_bytecount = |index: usize| match frame.get(index) {
+        Some(&n) => FrameLen::Known(index + 1 + n as usize + 2),
+        None => FrameLen::NeedMore(index + 1),
+    };
+    match frame.get(1) {
+        None => FrameLen::NeedMore(2),
+        Some(1..=6) => FrameLen::Known(8),
+        Some(7 | 11 | 12 | 17) => FrameLen::Known(4),
+        Some(15 | 16) => with_bytecount(6),
+        Some(22) => FrameLen::Known(10),
+        Some(23) => with_bytecount(10),
+        Some(43) => FrameLen::Known(7),
+        Some(65) => FrameLen::Known(11),
+        Some(_) => FrameLen::Unknown,
+    }
+}
+
+
+/// Parity setting of a serial line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Configuration for the Modbus RTU serial server, and for
+/// `ModbusRtuHandler`, which ignores the serial line settings.
+#[derive(Debug, Clone)]
+pub struct RtuConfig {
+    /// Unit ID of the PLC on the bus.  Requests for other units are ignored,
+    /// broadcasts (unit 0) are executed but not answered.
+    pub unit: u8,
+    pub baud: u32,
+    pub parity: Parity,
+    /// 1 or 2.
+    pub stop_bits: u8,
+    /// Silent interval that terminates a frame.  By default, 3.5 character
+    /// times (and 1.75 ms above 19200 baud) as required by the standard.
+    pub frame_gap: Option<Duration>,
+    /// Conversion of variables to registers.
+    pub order: RegisterOrder,
+}
+
+impl Default for RtuConfig {
+    fn default() -> Self {
+        // 19200 baud, 8E1 is the default required by the standard
+        Self { unit: 1, baud: 19200, parity: Parity::Even, stop_bits: 1, frame_gap: None,
+               order: RegisterOrder::default() }
+    }
+}
+
+impl RtuConfig {
+    fn frame_gap(&self) -> Duration {
+        self.frame_gap.unwrap_or_else(|| {
+            if self.baud > 19200 {
+                Duration::from_micros(1750)
+            } else {
+                let bits = 1 + 8 + (self.parity != Parity::None) as u64 + self.stop_bits as u64;
+                Duration::from_micros(3_500_000 * bits / self.baud as u64)
+            }
+        })
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, msg)
+}
+
+fn baud_constant(baud: u32) -> Option<libc::speed_t> {
+    Some(match baud {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        _ => return None,
+    })
+}
+
+/// A serial port set up for Modbus RTU.
+struct SerialPort {
+    file: File,
+    gap: Duration,
+}
+
+impl SerialPort {
+    fn open(path: &str, config: &RtuConfig) -> io::Result<Self> {
+        let speed = baud_constant(config.baud)
+            .ok_or_else(|| invalid_input(format!("unsupported baud rate {}", config.baud)))?;
+        if !matches!(config.stop_bits, 1 | 2) {
+            return Err(invalid_input(format!("invalid number of stop bits {}",
+                                             config.stop_bits)));
+        }
+        let file = OpenOptions::new().read(true).write(true)
+                                     .custom_flags(libc::O_NOCTTY).open(path)?;
+        let fd = file.as_raw_fd();
+        // SAFETY: the fd is valid while `file` lives, and termios is plain data
+        unsafe {
+            let mut tio: libc::termios = mem::zeroed();
+            if libc::tcgetattr(fd, &mut tio) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut tio);
+            libc::cfsetispeed(&mut tio, speed);
+            libc::cfsetospeed(&mut tio, speed);
+            tio.c_cflag &= !(libc::CSIZE | libc::PARENB | libc::PARODD | libc::CSTOPB |
+                             libc::CRTSCTS);
+            tio.c_cflag |= libc::CS8 | libc::CLOCAL | libc::CREAD;
+            match config.parity {
+                Parity::None => (),
+                Parity::Even => tio.c_cflag |= libc::PARENB,
+                Parity::Odd => tio.c_cflag |= libc::PARENB | libc::PARODD,
+            }
+            if config.stop_bits == 2 {
+                tio.c_cflag |= libc::CSTOPB;
+            }
+            tio.c_cc[libc::VMIN] = 1;
+            tio.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::tcflush(fd, libc::TCIOFLUSH);
+        }
+        Ok(Self { file, gap: config.frame_gap() })
+    }
+
+    /// Wait until data is available, or the timeout expires.  Returns false
+    /// on timeout.
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLIN,
+                                        revents: 0 };
+        let timeout = timeout.map_or(-1, |t| ((t.as_micros() + 999) / 1000) as i32);
+        loop {
+            // SAFETY: we pass exactly one valid pollfd
+            match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+                n if n >= 0 => return Ok(n > 0),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read the next frame, which ends with a silent interval on the line,
+    /// or as soon as it is complete according to its contents.  Returns
+    /// `None` if the frame was too long for the buffer.
+    fn read_frame(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.wait_readable(None)?;
+        let mut len = 0;
+        let mut overrun = false;
+        loop {
+            if len == buf.len() {
+                // discard everything until the line is silent again
+                overrun = true;
+                len = 0;
+            }
+            let n = self.file.read(&mut buf[len..])?;
+            if n == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            len += n;
+            if !overrun {
+                if let FrameLen::Known(total) = request_len(&buf[..len]) {
+                    if len >= total {
+                        if len > total {
+                            debug!("ignoring {} bytes after frame", len - total);
+                        }
+                        return Ok(Some(total));
+                    }
+                }
+            }
+            if !self.wait_readable(Some(self.gap))? {
+                return Ok(if overrun { None } else { Some(len) });
+            }
+        }
+    }
+}
+
+/// A Modbus RTU server on a serial line.  The address given to
+/// `PlcBuilder::with_server` is the path of the serial device, which can
+/// also be a pseudo terminal (e.g. created by `socat`) for testing.
+pub struct RtuServer {
+    /// Client ID used for all requests from the serial line.
+    hid:      usize,
+    port:     SerialPort,
+    config:   RtuConfig,
+    info:     PlcInfo,
+    map:      RegisterMap,
+    metrics:  Arc<ServerMetrics>,
+    to_plc:   Sender<Request<ModbusExtra>>,
+    from_plc: Receiver<Response<ModbusExtra>>,
+}
+
+impl Server for RtuServer {
+    type Extra = ModbusExtra;
+    type Config = RtuConfig;
+
+    fn start(addr: &str, info: &PlcInfo, config: RtuConfig,
+             w_to_plc: Sender<Request<ModbusExtra>>,
+             r_from_plc: Receiver<Response<ModbusExtra>>) -> io::Result<()> {
+        let port = SerialPort::open(addr, &config)?;
+        let map = RegisterMap::new(info, &config.order);
+        // the master on the serial line counts as the one client
+        let metrics = info.metrics.add_server(addr, "RtuServer");
+        metrics.set_clients(1);
+        let srv = RtuServer { hid: new_client_id(), port, config, info: info.clone(), map,
+                              metrics, to_plc: w_to_plc, from_plc: r_from_plc };
+        let path = addr.to_string();
+        thread::spawn(move || srv.run(path));
+        Ok(())
+    }
+}
+
+impl RtuServer {
+    fn run(mut self, path: String) {
+        mlzlog::set_thread_prefix("RTU: ");
+        info!("listening on {} as unit {}", path, self.config.unit);
+
+        let mut buf = [0u8; MAX_REQUEST];
+        let mut reply = Vec::with_capacity(MAX_REPLY);
+
+        loop {
+            let len = match self.port.read_frame(&mut buf) {
+                Ok(Some(len)) => len,
+                Ok(None) => {
+                    debug!("ignoring overlong frame");
+                    continue;
+                }
+                Err(err) => {
+                    warn!("error reading from serial port, stopping: {}", err);
+                    break;
+                }
+            };
+            // frames with CRC errors are ignored, the master will time out
+            let (unit, pdu) = match split_frame(&buf[..len]) {
+                Some(frame) => frame,
+                None => {
+                    debug!("ignoring invalid frame: {:?}", &buf[..len]);
+                    continue;
+                }
+            };
+            // so are requests for other devices on the bus
+            if unit != self.config.unit && unit != 0 {
+                continue;
+            }
+            self.metrics.add_requests(1);
+            let pdu = match pdu_action(self.hid, 0, unit, pdu, &self.info, &self.map) {
+                Action::Reply(pdu) => {
+                    if pdu[0] & 0x80 != 0 {
+                        self.metrics.add_errors(1);
+                    }
+                    pdu
+                }
+                Action::Plc(req) => {
+                    debug!("got request: {:?}", req);
+                    if let Err(e) = self.to_plc.send(req) {
+                        warn!("couldn't send request to PLC, stopping: {}", e);
+                        break;
+                    }
+                    match self.from_plc.recv() {
+                        Ok(response) => {
+                            if let Response::Error(..) = response {
+                                self.metrics.add_errors(1);
+                            }
+                            response_pdu(response).1
+                        }
+                        Err(e) => {
+                            warn!("couldn't receive response from PLC, stopping: {}", e);
+                            break;
+                        }
+                    }
+                }
+            };
+            // broadcasts are never answered
+            if unit == 0 {
+                continue;
+            }
+            reply.clear();
+            encode_frame(unit, &pdu, &mut reply);
+            if let Err(err) = self.port.file.write_all(&reply) {
+                warn!("error writing to serial port, stopping: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+
+/// A handler for Modbus RTU frames tunneled over TCP, to be used with
+/// `TcpServer`.  Like the serial server, it answers only requests for its
+/// unit ID, and executes broadcasts without answering them.
+pub struct ModbusRtuHandler {
+    hid:    usize,
+    info:   Arc<PlcInfo>,
+    config: RtuConfig,
+    map:    RegisterMap,
+    /// Requests answered without the PLC, and exceptions among them.
+    direct: (usize, usize),
+}
+
+impl Handler for ModbusRtuHandler {
+    type Extra = ModbusExtra;
+    type Config = RtuConfig;
+
+    fn new(hid: usize, info: Arc<PlcInfo>, config: &RtuConfig) -> Self {
+        let map = RegisterMap::new(&info, &config.order);
+        ModbusRtuHandler { hid, info, config: config.clone(), map, direct: (0, 0) }
+    }
+
+    fn receive(&mut self, input: &[u8], requests: &mut Vec<Request<ModbusExtra>>,
+               output: &mut Vec<u8>) -> io::Result<usize> {
+        let mut pos = 0;
+        loop {
+            // frames are delimited by their length
+            let len = match request_len(&input[pos..]) {
+                FrameLen::Known(len) if pos + len <= input.len() => len,
+                FrameLen::Known(_) | FrameLen::NeedMore(_) => return Ok(pos),
+                // without the length, we can't find the next frame boundary
+                FrameLen::Unknown => return Err(io::Error::new(
+                    ErrorKind::InvalidData, format!("unknown function code {}", input[pos + 1]))),
+            };
+            let frame = &input[pos..pos + len];
+            pos += len;
+            let (unit, pdu) = match split_frame(frame) {
+                Some(frame) => frame,
+                None => {
+                    debug!("ignoring frame with invalid CRC: {:?}", frame);
+                    continue;
+                }
+            };
+            if unit != self.config.unit && unit != 0 {
+                debug!("ignoring request for unit {}", unit);
+                continue;
+            }
+            let reply = match pdu_action(self.hid, 0, unit, pdu, &self.info, &self.map) {
+                Action::Reply(pdu) => pdu,
+                Action::Plc(req) => {
+                    debug!("got request: {:?}", req);
+                    requests.push(req);
+                    continue;
+                }
+            };
+            self.direct.0 += 1;
+            if reply[0] & 0x80 != 0 {
+                self.direct.1 += 1;
+            }
+            if unit != 0 {
+                encode_frame(unit, &reply, output);
+            }
+        }
+    }
+
+    fn direct_replies(&mut self) -> (usize, usize) {
+        std::mem::take(&mut self.direct)
+    }
+
+    fn respond(&mut self, response: Response<ModbusExtra>, output: &mut Vec<u8>) {
+        debug!("sending response: {:?}", response);
+        let (extra, pdu) = response_pdu(response);
+        if extra.unit != 0 {
+            encode_frame(extra.unit, &pdu, output);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::unix::io::FromRawFd;
+    use crossbeam_channel::unbounded;
+    use crate::server::Area;
+    use super::*;
+
+    /// Open a pseudo terminal, and return its master side and the path of
+    /// the slave side.
+    fn open_pty() -> (File, String) {
+        // SAFETY: the fd is checked, and ptsname returns a C string
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0);
+            assert_eq!(libc::grantpt(fd), 0);
+            assert_eq!(libc::unlockpt(fd), 0);
+            let path = CStr::from_ptr(libc::ptsname(fd)).to_str().unwrap().to_string();
+            (File::from_raw_fd(fd), path)
+        }
+    }
+
+    #[test]
+    fn serial_line() {
+        let (master, path) = open_pty();
+        let mut master = SerialPort { file: master, gap: Duration::from_millis(10) };
+        let (w_to_plc, r_to_plc) = unbounded();
+        let (w_from_plc, r_from_plc) = unbounded();
+        RtuServer::start(&path, &PlcInfo::default(), RtuConfig::default(),
+                         w_to_plc, r_from_plc).unwrap();
+
+        let timeout = Duration::from_secs(1);
+        let frame = |unit, pdu: &[u8]| {
+            let mut frame = Vec::new();
+            encode_frame(unit, pdu, &mut frame);
+            frame
+        };
+        let mut send = |frame: Vec<u8>| {
+            master.file.write_all(&frame).unwrap();
+            // let the frame end before the next one
+            thread::sleep(Duration::from_millis(20));
+        };
+        let respond = || {
+            let req = r_to_plc.recv_timeout(timeout).unwrap();
+            let unit = req.extra.unit;
+            assert_eq!((req.area, req.addr, req.write.as_deref()), (Area::Extern, 0, Some(&[8][..])));
+            w_from_plc.send(Response::Ok(req, vec![])).unwrap();
+            unit
+        };
+
+        // broadcast: executed, but not answered
+        send(frame(0, &[5, 0, 3, 0xFF, 0]));
+        assert_eq!(respond(), 0);
+        // another unit, and an invalid CRC: ignored
+        send(frame(2, &[5, 0, 3, 0xFF, 0]));
+        send(vec![1, 5, 0, 3, 0xFF, 0, 0, 0]);
+        // the default unit ID
+        send(frame(1, &[5, 0, 3, 0xFF, 0]));
+        assert_eq!(respond(), 1);
+
+        let mut reply = [0; MAX_REPLY];
+        assert!(master.wait_readable(Some(timeout)).unwrap());
+        let len = master.read_frame(&mut reply).unwrap().unwrap();
+        assert_eq!(split_frame(&reply[..len]), Some((1, &[5, 0, 3, 0xFF, 0][..])));
+        assert!(!master.wait_readable(Some(Duration::from_millis(50))).unwrap());
+        assert!(r_to_plc.is_empty());
+    }
+}
@@ -0,0 +1,829 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! An MQTT client (protocol version 3.1.1) that publishes image variables
+//! to a broker, and writes variables from messages on command topics.
+//!
+//! It is started like a server, with `PlcBuilder::add_server::<MqttClient>`
+//! and the address of the broker (e.g. `localhost:1883`).  If the broker
+//! can't be reached or the connection fails, it reconnects every few
+//! seconds.
+//!
+//! Published variables are sampled every cycle, and published with QoS 0
+//! either when any of their bytes changed, or periodically.  Variables are
+//! named as in `VarInfo`; structs and arrays can be published as a whole.
+//! The payload is either JSON (a number, or an object or array like in the
+//! HTTP API) or raw (the bytes of the variable in the image).
+//!
+//! Messages on command topics (subscribed with QoS 1) are written to their
+//! variable like writes of other clients, so read-only variables and write
+//! locks apply.  JSON payloads can contain only some members of a struct or
+//! array; raw payloads must have the size of the variable.  Since there is
+//! no reply in MQTT, failed writes are only logged.
+//!
+//! With a status topic, `online` is published there (retained) after
+//! connecting, and `offline` is registered as the last will.
+
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, BE};
+use crossbeam_channel::{never, select, unbounded, Receiver, Sender};
+use log::*;
+
+use crate::image::VarInfo;
+use crate::json::{self, Value};
+use crate::metrics::ServerMetrics;
+use crate::server::{new_client_id, Area, PlcInfo, Request, Response, Server, QUEUE_SIZE};
+
+/// Delay between connection attempts.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Timeout for connecting and for the broker's connection acknowledgment.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum size of a received packet.
+const MAX_PACKET: usize = 1 << 20;
+/// Number of sample requests kept in flight for each image.  The PLC
+/// answers one per cycle, so this avoids missing cycles.
+const SAMPLES_IN_FLIGHT: usize = 2;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const DISCONNECT: u8 = 0xE0;
+
+/// The payload format of a topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload {
+    /// The value as JSON text.
+    Json,
+    /// The bytes of the variable, in the byte order of the image.
+    Raw,
+}
+
+/// A variable to publish.
+#[derive(Debug, Clone)]
+pub struct MqttPublish {
+    /// Name of the variable, or of a struct or array.
+    pub var: String,
+    /// Whether the variable is in the process image, instead of the extern
+    /// image.
+    pub process: bool,
+    pub topic: String,
+    /// Publish at this interval, instead of when the value changes.
+    pub interval: Option<Duration>,
+    pub payload: Payload,
+    /// Whether the broker should keep the last message for new subscribers.
+    pub retain: bool,
+}
+
+impl MqttPublish {
+    /// Publish an extern image variable as JSON when it changes.
+    pub fn new(var: impl Into<String>, topic: impl Into<String>) -> Self {
+        Self { var: var.into(), process: false, topic: topic.into(), interval: None,
+               payload: Payload::Json, retain: false }
+    }
+}
+
+/// A topic on which messages are written to an extern image variable.
+#[derive(Debug, Clone)]
+pub struct MqttCommand {
+    /// Name of the variable, or of a struct or array.
+    pub var: String,
+    pub topic: String,
+    pub payload: Payload,
+}
+
+impl MqttCommand {
+    /// Write JSON messages on the topic to the variable.
+    pub fn new(var: impl Into<String>, topic: impl Into<String>) -> Self {
+        Self { var: var.into(), topic: topic.into(), payload: Payload::Json }
+    }
+}
+
+/// Configuration for the `MqttClient`.
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    /// Client identifier, by default `ethercat-plc-<name>` with the name of
+    /// the PLC.
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Interval in which the client shows that it is alive.
+    pub keep_alive: Duration,
+    /// Topic for the `online`/`offline` status of the client.
+    pub status_topic: Option<String>,
+    pub publish: Vec<MqttPublish>,
+    pub commands: Vec<MqttCommand>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            client_id: None,
+            username: None,
+            password: None,
+            keep_alive: Duration::from_secs(30),
+            status_topic: None,
+            publish: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+}
+
+/// The request data kept for the reply.
+#[derive(Debug, Default)]
+pub struct MqttExtra {
+    /// Whether this is a sample of published variables.
+    sample: bool,
+    /// Index of the command for writes.
+    command: usize,
+}
+
+/// A client for an MQTT broker; see the module documentation.
+pub struct MqttClient;
+
+impl Server for MqttClient {
+    type Extra = MqttExtra;
+    type Config = MqttConfig;
+
+    fn start(addr: &str, info: &PlcInfo, config: MqttConfig,
+             w_to_plc: Sender<Request<MqttExtra>>,
+             r_from_plc: Receiver<Response<MqttExtra>>) -> io::Result<()> {
+        let client = Client::new(addr, info, config, w_to_plc, r_from_plc)?;
+        thread::spawn(move || client.run());
+        Ok(())
+    }
+}
+
+/// The variables of a published or written variable.
+struct Target {
+    area: Area,
+    name: String,
+    vars: Vec<VarInfo>,
+    range: Range<usize>,
+}
+
+impl Target {
+    fn new(info: &PlcInfo, area: Area, name: &str) -> io::Result<Self> {
+        let vars: Vec<_> = match area {
+            Area::Extern => &info.extern_vars,
+            Area::Process => &info.process_vars,
+        }.iter().filter(|var| var.is_within(name)).cloned().collect();
+        if vars.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                                      format!("no variable {} for MQTT", name)));
+        }
+        Ok(Target { area, name: name.into(), range: json::span(&vars), vars })
+    }
+}
+
+struct Published {
+    target: Target,
+    /// The last published value.
+    last: Option<Vec<u8>>,
+    /// When to publish periodically published variables next.
+    next: Instant,
+}
+
+/// The connection to the broker.
+struct Connection {
+    stream: TcpStream,
+    /// Packets received by the reader thread, with their first header byte.
+    incoming: Receiver<(u8, Vec<u8>)>,
+    next_packet_id: u16,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+struct Client {
+    addr: String,
+    hid: usize,
+    metrics: Arc<ServerMetrics>,
+    info: Arc<PlcInfo>,
+    config: MqttConfig,
+    publish: Vec<Published>,
+    commands: Vec<Target>,
+    to_plc: Sender<Request<MqttExtra>>,
+    from_plc: Receiver<Response<MqttExtra>>,
+    conn: Option<Connection>,
+    /// Number of requests to the PLC without response.
+    in_flight: usize,
+    /// The ranges of each image to sample, if any.
+    sample_ranges: [Option<Range<usize>>; 2],
+    samples_in_flight: [usize; 2],
+    /// The last sample of each image, with its base address.
+    samples: [Option<(usize, Vec<u8>)>; 2],
+}
+
+fn area_index(area: Area) -> usize {
+    match area {
+        Area::Extern => 0,
+        Area::Process => 1,
+    }
+}
+
+/// Encode a packet with the given first header byte.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = vec![header];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        buf.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    buf.extend_from_slice(body);
+    buf
+}
+
+/// Append a string or binary data with its length.
+fn put_str(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s);
+}
+
+/// Read packets from the broker and pass them on, until the connection is
+/// closed.
+fn reader(mut stream: impl Read, incoming: Sender<(u8, Vec<u8>)>) -> io::Result<()> {
+    loop {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header)?;
+        let (mut len, mut shift, mut byte) = (0, 0, header[1]);
+        loop {
+            len |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            if shift > 21 {
+                return Err(io::Error::new(ErrorKind::InvalidData, "invalid packet length"));
+            }
+            let mut next = [0];
+            stream.read_exact(&mut next)?;
+            byte = next[0];
+        }
+        if len > MAX_PACKET {
+            return Err(io::Error::new(ErrorKind::InvalidData, "packet too large"));
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body)?;
+        if incoming.send((header[0], body)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+impl Client {
+    fn new(addr: &str, info: &PlcInfo, config: MqttConfig, to_plc: Sender<Request<MqttExtra>>,
+           from_plc: Receiver<Response<MqttExtra>>) -> io::Result<Self> {
+        let publish: Vec<_> = config.publish.iter().map(|publish| {
+            let area = if publish.process { Area::Process } else { Area::Extern };
+            Ok(Published { target: Target::new(info, area, &publish.var)?, last: None,
+                           next: Instant::now() })
+        }).collect::<io::Result<_>>()?;
+        let commands = config.commands.iter()
+            .map(|command| Target::new(info, Area::Extern, &command.var))
+            .collect::<io::Result<_>>()?;
+        let sample_range = |area| {
+            let mut vars = publish.iter().filter(|p: &&Published| p.target.area == area)
+                                         .flat_map(|p| &p.target.vars).peekable();
+            vars.peek()?;
+            Some(json::span(vars))
+        };
+        let sample_ranges = [sample_range(Area::Extern), sample_range(Area::Process)];
+        Ok(Client {
+            addr: addr.into(), hid: new_client_id(),
+            metrics: info.metrics.add_server(addr, "MqttClient"),
+            info: Arc::new(info.clone()), config, publish, commands,
+            to_plc, from_plc, conn: None, in_flight: 0,
+            sample_ranges, samples_in_flight: [0; 2], samples: [None, None],
+        })
+    }
+
+    fn run(mut self) {
+        mlzlog::set_thread_prefix("MQTT: ");
+
+        let mut reconnect_at = Instant::now();
+        loop {
+            if self.conn.is_none() && Instant::now() >= reconnect_at {
+                match self.connect() {
+                    Ok(()) => info!("connected to {}", self.addr),
+                    Err(e) => {
+                        warn!("could not connect to {}: {}", self.addr, e);
+                        reconnect_at = Instant::now() + RECONNECT_DELAY;
+                    }
+                }
+            }
+            let connected = self.conn.is_some();
+            if connected {
+                self.request_samples();
+            }
+
+            let timeout = self.timeout(reconnect_at);
+            let incoming = self.conn.as_ref().map_or(never(), |conn| conn.incoming.clone());
+            select! {
+                recv(self.from_plc) -> resp => match resp {
+                    Ok(resp) => self.response(resp),
+                    Err(_) => {
+                        warn!("PLC has gone away");
+                        return;
+                    }
+                },
+                recv(incoming) -> packet => match packet {
+                    Ok((header, body)) => self.packet(header, &body),
+                    Err(_) => self.disconnect("connection closed by broker"),
+                },
+                default(timeout) => (),
+            }
+            self.publish_periodic();
+            self.keep_alive();
+            if connected && self.conn.is_none() {
+                reconnect_at = Instant::now() + RECONNECT_DELAY;
+            }
+        }
+    }
+
+    /// Time until something needs to be done without an event.
+    fn timeout(&self, reconnect_at: Instant) -> Duration {
+        let now = Instant::now();
+        let mut until = match &self.conn {
+            None => reconnect_at,
+            Some(conn) => conn.last_sent + self.config.keep_alive / 2,
+        };
+        if self.conn.is_some() {
+            for (publish, config) in self.publish.iter().zip(&self.config.publish) {
+                if config.interval.is_some() {
+                    until = until.min(publish.next);
+                }
+            }
+        }
+        until.saturating_duration_since(now)
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        let mut last_error = io::Error::new(ErrorKind::NotFound, "no address");
+        let mut stream = None;
+        for addr in self.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let mut stream = stream.ok_or(last_error)?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(CONNECT_TIMEOUT.max(self.config.keep_alive)))?;
+
+        let config = &self.config;
+        let client_id = config.client_id.clone()
+            .unwrap_or_else(|| format!("ethercat-plc-{}", self.info.name));
+        let mut flags = 0x02;  // clean session
+        let mut body = Vec::new();
+        put_str(&mut body, b"MQTT");
+        body.push(4);
+        body.push(0);
+        body.extend_from_slice(&(config.keep_alive.as_secs().min(0xFFFF) as u16).to_be_bytes());
+        put_str(&mut body, client_id.as_bytes());
+        if let Some(topic) = &config.status_topic {
+            // will with QoS 0 and retain
+            flags |= 0x24;
+            put_str(&mut body, topic.as_bytes());
+            put_str(&mut body, b"offline");
+        }
+        if let Some(username) = &config.username {
+            flags |= 0x80;
+            put_str(&mut body, username.as_bytes());
+        }
+        if let Some(password) = &config.password {
+            flags |= 0x40;
+            put_str(&mut body, password.as_bytes());
+        }
+        body[7] = flags;
+        stream.write_all(&packet(CONNECT, &body))?;
+
+        let (w_incoming, r_incoming) = unbounded();
+        let read_stream = stream.try_clone()?;
+        thread::spawn(move || {
+            mlzlog::set_thread_prefix("MQTT: ");
+            if let Err(e) = reader(read_stream, w_incoming) {
+                debug!("reading from broker: {}", e);
+            }
+        });
+        match r_incoming.recv_timeout(CONNECT_TIMEOUT) {
+            Ok((CONNACK, body)) if body.len() == 2 && body[1] == 0 => (),
+            Ok((CONNACK, body)) if body.len() == 2 => {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                return Err(io::Error::new(ErrorKind::ConnectionRefused,
+                                          format!("refused by broker with code {}", body[1])));
+            }
+            _ => {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                return Err(io::Error::new(ErrorKind::InvalidData, "no connection acknowledgment"));
+            }
+        }
+
+        let now = Instant::now();
+        self.conn = Some(Connection { stream, incoming: r_incoming, next_packet_id: 0,
+                                      last_sent: now, last_received: now });
+        self.metrics.set_clients(1);
+        if !self.config.commands.is_empty() {
+            let mut body = self.packet_id().to_be_bytes().to_vec();
+            for command in &self.config.commands {
+                put_str(&mut body, command.topic.as_bytes());
+                body.push(1);
+            }
+            self.send(&packet(SUBSCRIBE, &body));
+        }
+        if let Some(topic) = self.config.status_topic.clone() {
+            self.publish(&topic, b"online", true);
+        }
+        // publish everything anew
+        for publish in &mut self.publish {
+            publish.last = None;
+            publish.next = now;
+        }
+        for (area, sample) in [Area::Extern, Area::Process].into_iter().zip(self.samples.clone()) {
+            if let Some((base, data)) = sample {
+                self.sampled(area, base, &data);
+            }
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self, reason: &str) {
+        if let Some(conn) = self.conn.take() {
+            warn!("disconnected from {}: {}", self.addr, reason);
+            let _ = conn.stream.shutdown(std::net::Shutdown::Both);
+            self.metrics.set_clients(0);
+        }
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => return 1,
+        };
+        conn.next_packet_id = conn.next_packet_id.wrapping_add(1).max(1);
+        conn.next_packet_id
+    }
+
+    /// Send a packet, disconnecting on errors.
+    fn send(&mut self, packet: &[u8]) {
+        let result = match &mut self.conn {
+            Some(conn) => {
+                conn.last_sent = Instant::now();
+                conn.stream.write_all(packet)
+            }
+            None => return,
+        };
+        if let Err(e) = result {
+            self.disconnect(&e.to_string());
+        }
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+        put_str(&mut body, topic.as_bytes());
+        body.extend_from_slice(payload);
+        self.send(&packet(PUBLISH | retain as u8, &body));
+    }
+
+    fn keep_alive(&mut self) {
+        let keep_alive = self.config.keep_alive;
+        let (ping, dead) = match &self.conn {
+            Some(conn) if keep_alive > Duration::ZERO =>
+                (conn.last_sent.elapsed() >= keep_alive / 2,
+                 conn.last_received.elapsed() > keep_alive * 3 / 2),
+            _ => return,
+        };
+        if dead {
+            self.disconnect("no reply from broker");
+        } else if ping {
+            self.send(&packet(PINGREQ, &[]));
+        }
+    }
+
+    /// Handle a packet from the broker.
+    fn packet(&mut self, header: u8, body: &[u8]) {
+        if let Some(conn) = &mut self.conn {
+            conn.last_received = Instant::now();
+        }
+        match header & 0xF0 {
+            PUBLISH => {
+                let qos = (header >> 1) & 3;
+                // a body shorter than the length field fails the check below
+                let topic_len = body.get(..2).map_or(0, |len| BE::read_u16(len) as usize);
+                let id_len = if qos > 0 { 2 } else { 0 };
+                if body.len() < 2 + topic_len + id_len {
+                    return self.disconnect("invalid publish packet");
+                }
+                let topic = String::from_utf8_lossy(&body[2..2 + topic_len]).into_owned();
+                if qos > 0 {
+                    let id = &body[2 + topic_len..2 + topic_len + 2];
+                    self.send(&packet(PUBACK, id));
+                }
+                self.command(&topic, &body[2 + topic_len + id_len..]);
+            }
+            SUBACK if body.iter().skip(2).any(|&code| code == 0x80) =>
+                warn!("broker refused subscription to command topics"),
+            // PINGRESP, and successful SUBACK
+            _ => (),
+        }
+    }
+
+    /// Write a command message to its variable.
+    fn command(&mut self, topic: &str, payload: &[u8]) {
+        let mut requests = Vec::new();
+        for (index, command) in self.config.commands.iter().enumerate() {
+            if command.topic != topic {
+                continue;
+            }
+            let target = &self.commands[index];
+            let write = match command.payload {
+                Payload::Raw if payload.len() == target.range.len() =>
+                    Ok(Some((target.range.clone(), payload.to_vec(), None))),
+                Payload::Raw => Err(format!("expected {} bytes", target.range.len())),
+                Payload::Json => std::str::from_utf8(payload)
+                    .map_err(|_| "payload is not UTF-8".to_string())
+                    .and_then(|text| Value::parse(text).map_err(|e| format!("invalid JSON: {}", e)))
+                    .and_then(|value| json::encode_write(&target.vars.iter().collect::<Vec<_>>(),
+                                                         &target.name, &value, false)),
+            };
+            let (range, data, mask) = match write {
+                Ok(Some(write)) => write,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("invalid command on {}: {}", topic, e);
+                    continue;
+                }
+            };
+            requests.push(Request {
+                hid: self.hid, area: Area::Extern, addr: range.start, count: range.len(),
+                write: Some(data), mask, read_back: None, lock: None,
+                extra: MqttExtra { sample: false, command: index },
+            });
+        }
+        for req in requests {
+            if self.in_flight >= QUEUE_SIZE {
+                warn!("too many requests in flight, dropping command on {}", topic);
+                return;
+            }
+            self.submit(req);
+        }
+    }
+
+    fn submit(&mut self, req: Request<MqttExtra>) {
+        // the PLC keeps running until all servers are gone, so this
+        // only fails if it has stopped
+        if self.to_plc.send(req).is_ok() {
+            self.in_flight += 1;
+            self.metrics.add_requests(1);
+        }
+    }
+
+    /// Keep sample requests in flight for the images with published
+    /// variables.
+    fn request_samples(&mut self) {
+        for area in [Area::Extern, Area::Process] {
+            let range = match &self.sample_ranges[area_index(area)] {
+                Some(range) => range.clone(),
+                None => continue,
+            };
+            while self.samples_in_flight[area_index(area)] < SAMPLES_IN_FLIGHT {
+                self.samples_in_flight[area_index(area)] += 1;
+                self.submit(Request {
+                    hid: self.hid, area, addr: range.start, count: range.len(), write: None,
+                    mask: None, read_back: Some((range.start, range.len())), lock: None,
+                    extra: MqttExtra { sample: true, command: 0 },
+                });
+            }
+        }
+    }
+
+    fn response(&mut self, resp: Response<MqttExtra>) {
+        self.in_flight -= 1;
+        match resp {
+            Response::Ok(req, data) if req.extra.sample => {
+                self.samples_in_flight[area_index(req.area)] -= 1;
+                let base = req.read_back.map_or(req.addr, |(addr, _)| addr);
+                self.sampled(req.area, base, &data);
+                self.samples[area_index(req.area)] = Some((base, data));
+            }
+            Response::Ok(..) => (),
+            Response::Error(req, code) => {
+                self.metrics.add_errors(1);
+                if req.extra.sample {
+                    self.samples_in_flight[area_index(req.area)] -= 1;
+                    warn!("sampling failed with error {}", code);
+                } else {
+                    warn!("command on {} failed with error {}",
+                          self.config.commands[req.extra.command].topic, code);
+                }
+            }
+        }
+    }
+
+    /// Publish variables that changed in a new sample.
+    fn sampled(&mut self, area: Area, base: usize, data: &[u8]) {
+        for index in 0..self.publish.len() {
+            let publish = &self.publish[index];
+            let range = publish.target.range.start - base..publish.target.range.end - base;
+            if publish.target.area != area || self.config.publish[index].interval.is_some() ||
+                publish.last.as_deref() == Some(&data[range.clone()])
+            {
+                continue;
+            }
+            self.publish_var(index, base, data);
+        }
+    }
+
+    /// Publish periodically published variables that are due.
+    fn publish_periodic(&mut self) {
+        let now = Instant::now();
+        for index in 0..self.publish.len() {
+            let interval = match self.config.publish[index].interval {
+                Some(interval) if self.publish[index].next <= now => interval,
+                _ => continue,
+            };
+            let sample = match &self.samples[area_index(self.publish[index].target.area)] {
+                Some((base, data)) => (*base, data.clone()),
+                None => continue,
+            };
+            self.publish[index].next = now + interval;
+            self.publish_var(index, sample.0, &sample.1);
+        }
+    }
+
+    fn publish_var(&mut self, index: usize, base: usize, data: &[u8]) {
+        if self.conn.is_none() {
+            return;
+        }
+        let target = &self.publish[index].target;
+        let bytes = data[target.range.start - base..target.range.end - base].to_vec();
+        let config = &self.config.publish[index];
+        let payload = match config.payload {
+            Payload::Json => json::value(&target.vars, &target.name, data, base).to_string()
+                                                                                .into_bytes(),
+            Payload::Raw => bytes.clone(),
+        };
+        let (topic, retain) = (config.topic.clone(), config.retain);
+        self.publish(&topic, &payload, retain);
+        self.publish[index].last = Some(bytes);
+    }
+}
+
+/// Pass data received from the broker to a client that is not connected,
+/// and return its requests to the PLC.  This is for the fuzz targets.
+#[doc(hidden)]
+pub fn receive(info: &PlcInfo, config: MqttConfig, data: &[u8]) -> Vec<Request<MqttExtra>> {
+    let (w_to_plc, r_to_plc) = unbounded();
+    let mut client = match Client::new("broker", info, config, w_to_plc, never()) {
+        Ok(client) => client,
+        Err(_) => return Vec::new(),
+    };
+    let (w_incoming, r_incoming) = unbounded();
+    let _ = reader(data, w_incoming);
+    for (header, body) in r_incoming.try_iter() {
+        client.packet(header, &body);
+    }
+    r_to_plc.try_iter().collect()
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // a clean disconnect doesn't trigger the last will
+        if let Some(topic) = self.config.status_topic.clone() {
+            self.publish(&topic, b"offline", true);
+        }
+        self.send(&packet(DISCONNECT, &[]));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use crate::image::VarType;
+    use super::*;
+
+    fn info() -> PlcInfo {
+        PlcInfo {
+            name: "test".into(),
+            extern_vars: vec![
+                VarInfo::new("speed", 0, VarType::F32),
+                VarInfo::new("dev.value", 4, VarType::I16),
+                VarInfo::new("dev.flags", 6, VarType::U16),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            commands: vec![
+                MqttCommand::new("dev", "cmd/dev"),
+                MqttCommand { payload: Payload::Raw, ..MqttCommand::new("speed", "cmd/speed") },
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn publish(qos: u8, topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_str(&mut body, topic.as_bytes());
+        if qos > 0 {
+            body.extend_from_slice(&[0x12, 0x34]);
+        }
+        body.extend_from_slice(payload);
+        packet(PUBLISH | qos << 1, &body)
+    }
+
+    fn writes(requests: Vec<Request<MqttExtra>>) -> Vec<(usize, Vec<u8>, usize)> {
+        requests.into_iter().map(|req| (req.addr, req.write.unwrap(), req.extra.command)).collect()
+    }
+
+    #[test]
+    fn packets() {
+        let mut data = publish(0, "cmd/dev", br#"{"value": -2}"#);
+        data.extend(packet(SUBACK, &[0, 1, 1, 0x80]));
+        data.extend(publish(1, "cmd/speed", &[1, 2, 3, 4]));
+        data.extend(publish(1, "cmd/speed", &[1, 2, 3]));
+        data.extend(publish(0, "cmd/other", b"1"));
+        data.extend(publish(2, "cmd/dev", br#"{"flags": "x"}"#));
+        // topic and packet ID beyond the body
+        data.extend(packet(PUBLISH, &[0, 9, b'c']));
+        data.extend(packet(PUBLISH | 2, &[0, 7, b'c', b'm', b'd', b'/', b'd', b'e', b'v', 0]));
+        // a length in two bytes
+        let padded = format!(r#"{{"flags": 258{}}}"#, " ".repeat(200));
+        data.extend(publish(0, "cmd/dev", padded.as_bytes()));
+        assert_eq!(writes(receive(&info(), config(), &data)),
+                   [(4, vec![0xfe, 0xff], 0), (0, vec![1, 2, 3, 4], 1), (6, vec![2, 1], 0)]);
+
+        // packets end at a length of more than four bytes, or a short body
+        let mut data = publish(0, "cmd/dev", br#"{"value": 1}"#);
+        data.extend([PUBLISH, 0x80, 0x80, 0x80, 0x80, 0x01]);
+        data.extend(publish(0, "cmd/dev", br#"{"value": 2}"#));
+        assert_eq!(writes(receive(&info(), config(), &data)).len(), 1);
+        let data = publish(0, "cmd/dev", br#"{"value": 1}"#);
+        assert!(receive(&info(), config(), &data[..data.len() - 1]).is_empty());
+    }
+
+    #[test]
+    fn broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let config = MqttConfig {
+            status_topic: Some("plc/status".into()),
+            publish: vec![
+                MqttPublish { payload: Payload::Raw, ..MqttPublish::new("speed", "plc/speed") },
+            ],
+            ..config()
+        };
+        let (w_to_plc, r_to_plc) = unbounded();
+        let (w_from_plc, r_from_plc) = unbounded();
+        MqttClient::start(&addr, &info(), config, w_to_plc, r_from_plc).unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let (w_incoming, r_incoming) = unbounded();
+        let read_stream = stream.try_clone().unwrap();
+        thread::spawn(move || reader(read_stream, w_incoming));
+        let timeout = Duration::from_secs(5);
+        let next = || r_incoming.recv_timeout(timeout).unwrap();
+
+        let (header, connect) = next();
+        assert_eq!(header, CONNECT);
+        assert_eq!(connect[..10], [0, 4, b'M', b'Q', b'T', b'T', 4, 0x26, 0, 30]);
+        assert_eq!(connect[10..], *b"\0\x11ethercat-plc-test\0\x0aplc/status\0\x07offline");
+        stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+        let (header, subscribe) = next();
+        assert_eq!(header, SUBSCRIBE);
+        assert_eq!(subscribe, b"\0\x01\0\x07cmd/dev\x01\0\x09cmd/speed\x01");
+        assert_eq!(next(), (PUBLISH | 1, b"\0\x0aplc/statusonline".to_vec()));
+
+        // samples are published when they change
+        for data in [[1, 2, 3, 4], [1, 2, 3, 4], [5, 6, 7, 8]] {
+            let req = r_to_plc.recv_timeout(timeout).unwrap();
+            assert_eq!(req.read_back, Some((0, 4)));
+            w_from_plc.send(Response::Ok(req, data.to_vec())).unwrap();
+        }
+        assert_eq!(next(), (PUBLISH, b"\0\x09plc/speed\x01\x02\x03\x04".to_vec()));
+        assert_eq!(next(), (PUBLISH, b"\0\x09plc/speed\x05\x06\x07\x08".to_vec()));
+
+        // commands with QoS 1 are acknowledged
+        stream.write_all(&publish(1, "cmd/dev", br#"{"value": 7}"#)).unwrap();
+        assert_eq!(next(), (PUBACK, vec![0x12, 0x34]));
+        let req = r_to_plc.iter().find(|req| !req.extra.sample).unwrap();
+        assert_eq!((req.addr, req.write.as_deref()), (4, Some(&[7, 0][..])));
+    }
+}
@@ -0,0 +1,1121 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! An OPC UA server for the `TcpServer`, speaking the binary protocol
+//! (`opc.tcp://`).
+//!
+//! Only the `None` security policy and anonymous sessions are supported.
+//! The address space contains the variables of the extern and process
+//! images, see the `nodes` module for its layout.  The supported services
+//! are:
+//!
+//! * GetEndpoints, FindServers
+//! * CreateSession, ActivateSession, CloseSession
+//! * Browse, BrowseNext, TranslateBrowsePathsToNodeIds, RegisterNodes,
+//!   UnregisterNodes
+//! * Read, and Write of the values of writable extern image variables
+//! * CreateSubscription, ModifySubscription, SetPublishingMode,
+//!   DeleteSubscriptions, Publish
+//! * CreateMonitoredItems, ModifyMonitoredItems, SetMonitoringMode,
+//!   DeleteMonitoredItems, with data change filters with absolute deadband
+//!
+//! Each connection has its own secure channel, with at most one session.
+//! Image variables read together are read with one request to the PLC per
+//! image, so that their values are from the same cycle.  Each written
+//! variable is a separate request, so that errors are reported per
+//! variable: read-only variables give `BadNotWritable`, and variables
+//! locked by another client `BadUserAccessDenied`.
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::Arc;
+use byteorder::{ByteOrder, LE};
+use log::*;
+
+use crate::server::{Area, Handler, PlcInfo, Request, Response};
+use self::codec::{id, now, status, DataValue, DecodeError, DecodeResult, NodeId, Put, Reader};
+use self::nodes::{attr, AddressSpace, BrowseDescription, PathElement, ReadResult};
+use self::subscription::Subscriptions;
+
+mod codec;
+mod nodes;
+mod subscription;
+
+const POLICY_NONE: &str = "http://opcfoundation.org/UA/SecurityPolicy#None";
+const TRANSPORT_PROFILE: &str =
+    "http://opcfoundation.org/UA-Profile/Transport/uatcp-uasc-uabinary";
+const PRODUCT_URI: &str = "urn:ethercat-plc";
+
+/// Size of our buffers for message chunks.
+const BUFFER_SIZE: u32 = 65536;
+/// Smallest buffer size allowed by the protocol.
+const MIN_BUFFER_SIZE: u32 = 8192;
+/// Maximum size of a message from the client.
+const MAX_MESSAGE: usize = 1 << 22;
+/// Size of the headers of a symmetric chunk.
+const CHUNK_HEADER: usize = 24;
+/// Maximum number of operations in one service call.
+const MAX_OPERATIONS: usize = 10000;
+/// Maximum number of browse continuation points of a session.
+const MAX_CONTINUATION_POINTS: usize = 16;
+/// Number of sample requests kept in flight for each image.  The PLC
+/// answers one per cycle, so the others bridge delays of the server.
+const SAMPLES_IN_FLIGHT: usize = 4;
+
+const SECURITY_MODE_NONE: u32 = 1;
+const REQUEST_TYPE_ISSUE: u32 = 0;
+
+/// Configuration for the `OpcUaHandler`.
+#[derive(Debug, Clone, Default)]
+pub struct OpcUaConfig {
+    /// URI of the namespace of the image variables (namespace index 1), by
+    /// default `urn:ethercat-plc:<name>` with the name of the PLC.
+    pub namespace_uri: Option<String>,
+}
+
+/// The request data kept for the reply.
+#[derive(Debug, Default)]
+pub struct OpcUaExtra {
+    /// ID of the service call waiting for the request.
+    call: u32,
+    /// The results that the request provides, by index, with the index of
+    /// their variable.
+    items: Vec<(usize, usize)>,
+    /// Whether this is a sample for subscriptions.
+    sample: bool,
+}
+
+/// The results of a service call that waits for the PLC.
+enum Results {
+    Read(Vec<DataValue>),
+    Write(Vec<u32>),
+}
+
+struct Call {
+    request_id: u32,
+    handle: u32,
+    results: Results,
+    /// Number of requests to the PLC without response.
+    waiting: usize,
+}
+
+struct Session {
+    id: NodeId,
+    token: NodeId,
+    activated: bool,
+}
+
+/// The common header of service requests.
+struct RequestHeader {
+    token: NodeId,
+    handle: u32,
+}
+
+fn request_header(r: &mut Reader) -> DecodeResult<RequestHeader> {
+    let token = r.node_id()?;
+    let (_timestamp, handle, _diagnostics) = (r.u64()?, r.u32()?, r.u32()?);
+    let (_audit_entry, _timeout) = (r.string()?, r.u32()?);
+    r.extension_object()?;
+    Ok(RequestHeader { token, handle })
+}
+
+fn response_header(buf: &mut Vec<u8>, handle: u32, status: u32) {
+    buf.put_i64(now());
+    buf.put_u32(handle);
+    buf.put_u32(status);
+    // no diagnostics, string table and additional header
+    buf.put_u8(0);
+    buf.put_len(0);
+    buf.put_null_extension_object();
+}
+
+/// Skip an ApplicationDescription.
+fn application_description(r: &mut Reader) -> DecodeResult<()> {
+    let (_uri, _product_uri, _name) = (r.string()?, r.string()?, r.localized_text()?);
+    let (_ty, _gateway, _profile) = (r.u32()?, r.string()?, r.string()?);
+    r.array(Reader::string)?;
+    Ok(())
+}
+
+/// The status for an error code of the PLC.
+fn plc_status(code: u8) -> u32 {
+    match code {
+        2 => status::BAD_NOT_WRITABLE,
+        4 => status::BAD_COMMUNICATION_ERROR,
+        6 => status::BAD_USER_ACCESS_DENIED,
+        _ => status::BAD_INTERNAL_ERROR,
+    }
+}
+
+fn area_index(area: Area) -> usize {
+    match area {
+        Area::Extern => 0,
+        Area::Process => 1,
+    }
+}
+
+/// Check the number of operations in a service call.
+fn check_count<T>(items: &[T]) -> Result<(), u32> {
+    match items.len() {
+        0 => Err(status::BAD_NOTHING_TO_DO),
+        n if n > MAX_OPERATIONS => Err(status::BAD_TOO_MANY_OPERATIONS),
+        _ => Ok(()),
+    }
+}
+
+/// Handles the OPC UA protocol for one connection.
+pub struct OpcUaHandler {
+    hid: usize,
+    info: Arc<PlcInfo>,
+    space: AddressSpace,
+    application_uri: String,
+    /// The endpoint URL given by the client in its hello message.
+    endpoint_url: String,
+    /// Maximum size of chunks from and to the client; zero before the
+    /// hello message.
+    receive_buffer: usize,
+    send_buffer: usize,
+    /// Maximum size and chunk count of responses, zero for no limit.
+    max_response: usize,
+    max_chunks: usize,
+    /// ID of the secure channel, zero before it is opened.
+    channel_id: u32,
+    token_id: u32,
+    /// Sequence number of the last sent chunk.
+    seq: u32,
+    /// The chunks received so far of an incomplete message.
+    partial: Vec<u8>,
+    session: Option<Session>,
+    /// Service calls waiting for the PLC.
+    calls: HashMap<u32, Call>,
+    next_call: u32,
+    subs: Subscriptions,
+    /// Sample requests in flight, per image.
+    in_flight: [usize; 2],
+    /// Set for images whose sampling failed.
+    failed: [bool; 2],
+    /// Remaining references of browse results, with the continuation
+    /// point's ID and the maximum number of references per result.
+    continuations: Vec<(u32, usize, Vec<Vec<u8>>)>,
+    next_continuation: u32,
+    closed: bool,
+    /// Requests answered without the PLC, and errors among them.
+    direct: (usize, usize),
+}
+
+impl OpcUaHandler {
+    /// Random bytes for nonces and session tokens.
+    fn random(&self, n: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(n + 8);
+        while bytes.len() < n {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_usize(self.hid);
+            hasher.write_i64(now());
+            bytes.extend_from_slice(&hasher.finish().to_le_bytes());
+        }
+        bytes.truncate(n);
+        bytes
+    }
+
+    fn write_chunk(&mut self, kind: &[u8; 3], chunk: u8, payload: &[u8], output: &mut Vec<u8>) {
+        output.extend_from_slice(kind);
+        output.push(chunk);
+        output.extend_from_slice(&(payload.len() as u32 + 8).to_le_bytes());
+        output.extend_from_slice(payload);
+    }
+
+    /// Send an error message and close the connection.
+    fn fail(&mut self, status: u32, reason: &str, output: &mut Vec<u8>) {
+        warn!("client {}: closing OPC UA connection: {}", self.hid, reason);
+        let mut payload = Vec::new();
+        payload.put_u32(status);
+        payload.put_str(Some(reason));
+        self.write_chunk(b"ERR", b'F', &payload, output);
+        self.closed = true;
+    }
+
+    /// Send a service response, or a fault if `body` is an error.
+    fn send(&mut self, request_id: u32, handle: u32, ty: u32, body: Result<&[u8], u32>,
+            output: &mut Vec<u8>) {
+        let (ty, status, body) = match body {
+            Ok(body) => (ty, status::GOOD, body),
+            Err(status) => (id::SERVICE_FAULT, status, &[][..]),
+        };
+        let mut msg = Vec::new();
+        msg.put_node_id(&NodeId::ns0(ty));
+        response_header(&mut msg, handle, status);
+        msg.put_bytes(body);
+
+        let per_chunk = self.send_buffer - CHUNK_HEADER;
+        let chunks = (msg.len() + per_chunk - 1) / per_chunk;
+        if (self.max_response != 0 && msg.len() > self.max_response) ||
+            (self.max_chunks != 0 && chunks > self.max_chunks)
+        {
+            return self.send(request_id, handle, 0, Err(status::BAD_RESPONSE_TOO_LARGE), output);
+        }
+        for (i, chunk) in msg.chunks(per_chunk).enumerate() {
+            self.seq = self.seq.wrapping_add(1);
+            let mut payload = Vec::with_capacity(chunk.len() + 16);
+            payload.put_u32(self.channel_id);
+            payload.put_u32(self.token_id);
+            payload.put_u32(self.seq);
+            payload.put_u32(request_id);
+            payload.put_bytes(chunk);
+            self.write_chunk(b"MSG", if i + 1 == chunks { b'F' } else { b'C' }, &payload, output);
+        }
+    }
+
+    fn hello(&mut self, body: &[u8], output: &mut Vec<u8>) -> DecodeResult<()> {
+        let mut r = Reader::new(body);
+        let (_version, receive, send) = (r.u32()?, r.u32()?, r.u32()?);
+        let (max_message, max_chunks) = (r.u32()?, r.u32()?);
+        self.endpoint_url = r.string()?.unwrap_or_default();
+        if receive < MIN_BUFFER_SIZE || send < MIN_BUFFER_SIZE {
+            self.fail(status::BAD_COMMUNICATION_ERROR, "buffer size too small", output);
+            return Ok(());
+        }
+        self.send_buffer = receive.min(BUFFER_SIZE) as usize;
+        self.receive_buffer = send.min(BUFFER_SIZE) as usize;
+        self.max_response = max_message as usize;
+        self.max_chunks = max_chunks as usize;
+        let mut payload = Vec::new();
+        payload.put_u32(0);
+        payload.put_u32(self.receive_buffer as u32);
+        payload.put_u32(self.send_buffer as u32);
+        payload.put_u32(MAX_MESSAGE as u32);
+        payload.put_u32(0);
+        self.write_chunk(b"ACK", b'F', &payload, output);
+        Ok(())
+    }
+
+    fn open(&mut self, body: &[u8], output: &mut Vec<u8>) -> DecodeResult<()> {
+        let mut r = Reader::new(body);
+        let (_channel_id, policy) = (r.u32()?, r.string()?);
+        let (_certificate, _thumbprint) = (r.byte_string()?, r.byte_string()?);
+        let (_seq, request_id) = (r.u32()?, r.u32()?);
+        if r.node_id()?.as_ns0() != Some(id::OPEN_SECURE_CHANNEL_REQUEST) {
+            return Err(DecodeError);
+        }
+        let header = request_header(&mut r)?;
+        let (_version, request_type, mode) = (r.u32()?, r.u32()?, r.u32()?);
+        let (_nonce, lifetime) = (r.byte_string()?, r.u32()?);
+        if policy.as_deref() != Some(POLICY_NONE) {
+            self.fail(status::BAD_SECURITY_POLICY_REJECTED, "only the None security policy \
+                                                             is supported", output);
+            return Ok(());
+        }
+        if mode != SECURITY_MODE_NONE {
+            self.fail(status::BAD_SECURITY_MODE_REJECTED, "only security mode None is supported",
+                      output);
+            return Ok(());
+        }
+        if (request_type == REQUEST_TYPE_ISSUE) != (self.channel_id == 0) {
+            self.fail(status::BAD_SECURE_CHANNEL_ID_INVALID, "invalid channel request", output);
+            return Ok(());
+        }
+        // client IDs are unique and never zero
+        self.channel_id = self.hid as u32;
+        self.token_id += 1;
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut payload = Vec::new();
+        payload.put_u32(self.channel_id);
+        payload.put_str(Some(POLICY_NONE));
+        payload.put_byte_string(None);
+        payload.put_byte_string(None);
+        payload.put_u32(self.seq);
+        payload.put_u32(request_id);
+        payload.put_node_id(&NodeId::ns0(id::OPEN_SECURE_CHANNEL_RESPONSE));
+        response_header(&mut payload, header.handle, status::GOOD);
+        payload.put_u32(0);
+        payload.put_u32(self.channel_id);
+        payload.put_u32(self.token_id);
+        payload.put_i64(now());
+        payload.put_u32(if lifetime == 0 { 3_600_000 } else { lifetime });
+        payload.put_byte_string(Some(&[]));
+        self.write_chunk(b"OPN", b'F', &payload, output);
+        Ok(())
+    }
+
+    /// Handle a complete service request.
+    fn message(&mut self, request_id: u32, body: &[u8], requests: &mut Vec<Request<OpcUaExtra>>,
+               output: &mut Vec<u8>) {
+        let mut r = Reader::new(body);
+        let (ty, header) = match r.node_id().and_then(|ty| Ok((ty, request_header(&mut r)?))) {
+            Ok((ty, header)) => (ty.as_ns0().unwrap_or(0), header),
+            Err(_) => {
+                self.direct.0 += 1;
+                self.direct.1 += 1;
+                return self.send(request_id, 0, 0, Err(status::BAD_DECODING_ERROR), output);
+            }
+        };
+        debug!("client {}: service request {}", self.hid, ty);
+        match self.service(ty, &header, request_id, &mut r, requests) {
+            Ok(Some((ty, body))) => {
+                self.direct.0 += 1;
+                self.send(request_id, header.handle, ty, Ok(&body), output);
+            }
+            // answered when the PLC or the subscriptions are ready
+            Ok(None) => (),
+            Err(status) => {
+                self.direct.0 += 1;
+                self.direct.1 += 1;
+                self.send(request_id, header.handle, 0, Err(status), output);
+            }
+        }
+        // the call can have made publish responses due
+        self.publish(output);
+    }
+
+    /// Call a service, returning the response type and body, or `None` if
+    /// the response is sent later.
+    fn service(&mut self, ty: u32, header: &RequestHeader, request_id: u32, r: &mut Reader,
+               requests: &mut Vec<Request<OpcUaExtra>>) -> Result<Option<(u32, Vec<u8>)>, u32> {
+        match ty {
+            id::GET_ENDPOINTS_REQUEST => {
+                let url = r.string()?;
+                let mut body = Vec::new();
+                body.put_len(1);
+                self.endpoint(&mut body, url);
+                return Ok(Some((id::GET_ENDPOINTS_RESPONSE, body)));
+            }
+            id::FIND_SERVERS_REQUEST => {
+                let url = r.string()?;
+                let mut body = Vec::new();
+                body.put_len(1);
+                self.application(&mut body, url);
+                return Ok(Some((id::FIND_SERVERS_RESPONSE, body)));
+            }
+            id::CREATE_SESSION_REQUEST => return self.create_session(r).map(Some),
+            id::ACTIVATE_SESSION_REQUEST => return self.activate_session(header, r).map(Some),
+            _ => match &self.session {
+                Some(session) if session.token == header.token && session.activated => (),
+                Some(session) if session.token == header.token =>
+                    return Err(status::BAD_SESSION_NOT_ACTIVATED),
+                _ => return Err(status::BAD_SESSION_ID_INVALID),
+            }
+        };
+        let period = self.info.metrics.period();
+        let (ty, body) = match ty {
+            id::CLOSE_SESSION_REQUEST => {
+                let _delete_subscriptions = r.bool()?;
+                self.session = None;
+                self.subs.clear();
+                (id::CLOSE_SESSION_RESPONSE, Vec::new())
+            }
+            id::BROWSE_REQUEST => (id::BROWSE_RESPONSE, self.browse(r)?),
+            id::BROWSE_NEXT_REQUEST => (id::BROWSE_NEXT_RESPONSE, self.browse_next(r)?),
+            id::TRANSLATE_BROWSE_PATHS_REQUEST =>
+                (id::TRANSLATE_BROWSE_PATHS_RESPONSE, self.translate(r)?),
+            id::REGISTER_NODES_REQUEST => {
+                // node IDs are used as they are
+                let nodes = r.array(Reader::node_id)?;
+                check_count(&nodes)?;
+                let mut body = Vec::new();
+                body.put_len(nodes.len());
+                nodes.iter().for_each(|node| body.put_node_id(node));
+                (id::REGISTER_NODES_RESPONSE, body)
+            }
+            id::UNREGISTER_NODES_REQUEST => {
+                check_count(&r.array(Reader::node_id)?)?;
+                (id::UNREGISTER_NODES_RESPONSE, Vec::new())
+            }
+            id::READ_REQUEST => return self.read(request_id, header.handle, r, requests),
+            id::WRITE_REQUEST => return self.write(request_id, header.handle, r, requests),
+            id::CREATE_SUBSCRIPTION_REQUEST =>
+                (id::CREATE_SUBSCRIPTION_RESPONSE, self.subs.create_subscription(r, period)?),
+            id::MODIFY_SUBSCRIPTION_REQUEST =>
+                (id::MODIFY_SUBSCRIPTION_RESPONSE, self.subs.modify_subscription(r, period)?),
+            id::SET_PUBLISHING_MODE_REQUEST =>
+                (id::SET_PUBLISHING_MODE_RESPONSE, self.subs.set_publishing_mode(r)?),
+            id::DELETE_SUBSCRIPTIONS_REQUEST =>
+                (id::DELETE_SUBSCRIPTIONS_RESPONSE, self.subs.delete_subscriptions(r)?),
+            id::CREATE_MONITORED_ITEMS_REQUEST => (id::CREATE_MONITORED_ITEMS_RESPONSE,
+                self.subs.create_monitored_items(r, &self.space, period)?),
+            id::MODIFY_MONITORED_ITEMS_REQUEST => (id::MODIFY_MONITORED_ITEMS_RESPONSE,
+                self.subs.modify_monitored_items(r, period)?),
+            id::SET_MONITORING_MODE_REQUEST =>
+                (id::SET_MONITORING_MODE_RESPONSE, self.subs.set_monitoring_mode(r)?),
+            id::DELETE_MONITORED_ITEMS_REQUEST =>
+                (id::DELETE_MONITORED_ITEMS_RESPONSE, self.subs.delete_monitored_items(r)?),
+            id::PUBLISH_REQUEST => {
+                self.subs.queue_publish(request_id, header.handle, r)?;
+                return Ok(None);
+            }
+            // messages are not kept for retransmission
+            id::REPUBLISH_REQUEST => return Err(status::BAD_MESSAGE_NOT_AVAILABLE),
+            _ => return Err(status::BAD_SERVICE_UNSUPPORTED),
+        };
+        Ok(Some((ty, body)))
+    }
+
+    /// Encode our ApplicationDescription.
+    fn application(&self, buf: &mut Vec<u8>, url: Option<String>) {
+        buf.put_str(Some(&self.application_uri));
+        buf.put_str(Some(PRODUCT_URI));
+        buf.put_localized_text(&self.info.name);
+        buf.put_u32(0);  // server
+        buf.put_str(None);
+        buf.put_str(None);
+        buf.put_len(1);
+        buf.put_str(Some(url.as_deref().filter(|url| !url.is_empty())
+                            .unwrap_or(&self.endpoint_url)));
+    }
+
+    /// Encode our only EndpointDescription.
+    fn endpoint(&self, buf: &mut Vec<u8>, url: Option<String>) {
+        let url = url.filter(|url| !url.is_empty()).unwrap_or_else(|| self.endpoint_url.clone());
+        buf.put_str(Some(&url));
+        self.application(buf, Some(url));
+        buf.put_byte_string(None);
+        buf.put_u32(SECURITY_MODE_NONE);
+        buf.put_str(Some(POLICY_NONE));
+        // anonymous user token policy
+        buf.put_len(1);
+        buf.put_str(Some("anonymous"));
+        buf.put_u32(0);
+        buf.put_str(None);
+        buf.put_str(None);
+        buf.put_str(None);
+        buf.put_str(Some(TRANSPORT_PROFILE));
+        buf.put_u8(0);
+    }
+
+    fn create_session(&mut self, r: &mut Reader) -> Result<(u32, Vec<u8>), u32> {
+        application_description(r)?;
+        let (_server_uri, url, _name) = (r.string()?, r.string()?, r.string()?);
+        let (_nonce, _certificate) = (r.byte_string()?, r.byte_string()?);
+        let (timeout, max_response) = (r.f64()?, r.u32()?);
+        if max_response != 0 && (self.max_response == 0 || (max_response as usize) < self.max_response) {
+            self.max_response = max_response as usize;
+        }
+        let session = Session {
+            id: NodeId::Opaque(1, 0x05, self.random(16)),
+            token: NodeId::Opaque(0, 0x05, self.random(32)),
+            activated: false,
+        };
+        // a new session replaces the previous one
+        self.subs.clear();
+        let mut body = Vec::new();
+        body.put_node_id(&session.id);
+        body.put_node_id(&session.token);
+        // the session ends with the connection
+        body.put_f64(timeout.clamp(10_000., 3_600_000.));
+        body.put_byte_string(Some(&self.random(32)));
+        body.put_byte_string(None);
+        body.put_len(1);
+        self.endpoint(&mut body, url);
+        // no software certificates and signature
+        body.put_len(0);
+        body.put_str(None);
+        body.put_byte_string(None);
+        body.put_u32(MAX_MESSAGE as u32);
+        self.session = Some(session);
+        Ok((id::CREATE_SESSION_RESPONSE, body))
+    }
+
+    fn activate_session(&mut self, header: &RequestHeader, r: &mut Reader)
+                        -> Result<(u32, Vec<u8>), u32> {
+        let (_algorithm, _signature) = (r.string()?, r.byte_string()?);
+        r.array(|r| Ok((r.byte_string()?, r.byte_string()?)))?;
+        let _locales = r.array(Reader::string)?;
+        let (token, _) = r.extension_object()?;
+        let nonce = self.random(32);
+        let session = match &mut self.session {
+            Some(session) if session.token == header.token => session,
+            _ => return Err(status::BAD_SESSION_ID_INVALID),
+        };
+        if !(token.is_null() || token.as_ns0() == Some(id::ANONYMOUS_IDENTITY_TOKEN)) {
+            return Err(status::BAD_IDENTITY_TOKEN_INVALID);
+        }
+        session.activated = true;
+        let mut body = Vec::new();
+        body.put_byte_string(Some(&nonce));
+        body.put_len(0);
+        body.put_len(0);
+        Ok((id::ACTIVATE_SESSION_RESPONSE, body))
+    }
+
+    fn browse(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let (view, _timestamp, _version) = (r.node_id()?, r.u64()?, r.u32()?);
+        let max = r.u32()? as usize;
+        let descs = r.array(|r| {
+            let desc = BrowseDescription {
+                node: r.node_id()?, direction: r.u32()?, ref_type: r.node_id()?,
+                subtypes: r.bool()?, class_mask: r.u32()?,
+            };
+            // all fields of the references are returned
+            let _result_mask = r.u32()?;
+            Ok(desc)
+        })?;
+        if !view.is_null() {
+            return Err(status::BAD_VIEW_ID_UNKNOWN);
+        }
+        check_count(&descs)?;
+        let mut body = Vec::new();
+        body.put_len(descs.len());
+        for desc in descs {
+            match self.space.browse(&desc) {
+                Ok(refs) => self.browse_result(refs, max, &mut body),
+                Err(status) => {
+                    body.put_u32(status);
+                    body.put_byte_string(None);
+                    body.put_len(0);
+                }
+            }
+        }
+        body.put_len(0);
+        Ok(body)
+    }
+
+    /// Encode a BrowseResult with at most `max` references, keeping the
+    /// rest for `BrowseNext`.
+    fn browse_result(&mut self, mut refs: Vec<Vec<u8>>, max: usize, body: &mut Vec<u8>) {
+        let mut status = status::GOOD;
+        let mut point = None;
+        if max != 0 && refs.len() > max {
+            let rest = refs.split_off(max);
+            if self.continuations.len() < MAX_CONTINUATION_POINTS {
+                self.next_continuation = self.next_continuation.wrapping_add(1);
+                self.continuations.push((self.next_continuation, max, rest));
+                point = Some(self.next_continuation.to_le_bytes());
+            } else {
+                status = status::BAD_NO_CONTINUATION_POINTS;
+                refs.clear();
+            }
+        }
+        body.put_u32(status);
+        body.put_byte_string(point.as_ref().map(|p| &p[..]));
+        body.put_len(refs.len());
+        refs.iter().for_each(|r| body.put_bytes(r));
+    }
+
+    fn browse_next(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let release = r.bool()?;
+        let points = r.array(|r| Ok(r.byte_string()?.unwrap_or_default().to_vec()))?;
+        check_count(&points)?;
+        let mut body = Vec::new();
+        body.put_len(points.len());
+        for point in points {
+            let pos = self.continuations.iter().position(|(id, _, _)| {
+                point.len() == 4 && *id == LE::read_u32(&point)
+            });
+            match pos.map(|pos| self.continuations.remove(pos)) {
+                Some((_, max, refs)) if !release => self.browse_result(refs, max, &mut body),
+                Some(_) => {
+                    body.put_u32(status::GOOD);
+                    body.put_byte_string(None);
+                    body.put_len(0);
+                }
+                None => {
+                    body.put_u32(status::BAD_CONTINUATION_POINT_INVALID);
+                    body.put_byte_string(None);
+                    body.put_len(0);
+                }
+            }
+        }
+        body.put_len(0);
+        Ok(body)
+    }
+
+    fn translate(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let paths = r.array(|r| {
+            let start = r.node_id()?;
+            let path = r.array(|r| Ok(PathElement {
+                ref_type: r.node_id()?, inverse: r.bool()?, subtypes: r.bool()?,
+                name: r.qualified_name()?,
+            }))?;
+            Ok((start, path))
+        })?;
+        check_count(&paths)?;
+        let mut body = Vec::new();
+        body.put_len(paths.len());
+        for (start, path) in paths {
+            let (status, targets) = match self.space.translate(&start, &path) {
+                Ok(targets) => (status::GOOD, targets),
+                Err(status) => (status, Vec::new()),
+            };
+            body.put_u32(status);
+            body.put_len(targets.len());
+            for target in targets {
+                body.put_node_id(&target);
+                body.put_u32(u32::MAX);
+            }
+        }
+        body.put_len(0);
+        Ok(body)
+    }
+
+    fn new_call(&mut self, request_id: u32, handle: u32, results: Results) -> u32 {
+        self.next_call = self.next_call.wrapping_add(1);
+        self.calls.insert(self.next_call, Call { request_id, handle, results, waiting: 0 });
+        self.next_call
+    }
+
+    fn read(&mut self, request_id: u32, handle: u32, r: &mut Reader,
+            requests: &mut Vec<Request<OpcUaExtra>>) -> Result<Option<(u32, Vec<u8>)>, u32> {
+        let (_max_age, timestamps) = (r.f64()?, r.u32()?);
+        let items = r.array(|r| {
+            let (node, attribute, range, _encoding) =
+                (r.node_id()?, r.u32()?, r.string()?, r.qualified_name()?);
+            Ok((node, attribute, range))
+        })?;
+        check_count(&items)?;
+        if timestamps > 3 {
+            return Err(status::BAD_TIMESTAMPS_TO_RETURN_INVALID);
+        }
+        let time = now();
+        let mut results = Vec::with_capacity(items.len());
+        let mut plc_items: [Vec<(usize, usize)>; 2] = Default::default();
+        for (i, (node, attribute, range)) in items.into_iter().enumerate() {
+            let value = if range.map_or(false, |r| !r.is_empty()) {
+                DataValue::error(status::BAD_INDEX_RANGE_INVALID)
+            } else {
+                match self.space.read(&node, attribute) {
+                    ReadResult::Done(value) => value,
+                    ReadResult::Plc(area, index) => {
+                        plc_items[area_index(area)].push((i, index));
+                        // the value is filled in from the response
+                        DataValue::error(status::GOOD)
+                    }
+                }
+            };
+            results.push(if value.status == status::GOOD {
+                value.stamped(timestamps, time, attribute == attr::VALUE)
+            } else {
+                value
+            });
+        }
+        if plc_items.iter().all(Vec::is_empty) {
+            return Ok(Some((id::READ_RESPONSE, encode_read(&results))));
+        }
+        let call = self.new_call(request_id, handle, Results::Read(results));
+        for (area, items) in [Area::Extern, Area::Process].into_iter().zip(plc_items) {
+            if items.is_empty() {
+                continue;
+            }
+            let (start, end) = items.iter().map(|&(_, index)| self.space.var(area, index).range())
+                .fold((usize::MAX, 0), |(start, end), range| {
+                    (start.min(range.start), end.max(range.end))
+                });
+            requests.push(Request {
+                hid: self.hid, area, addr: start, count: end - start, write: None, mask: None,
+                read_back: None, lock: None, extra: OpcUaExtra { call, items, sample: false }
+            });
+            if let Some(call) = self.calls.get_mut(&call) {
+                call.waiting += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn write(&mut self, request_id: u32, handle: u32, r: &mut Reader,
+             requests: &mut Vec<Request<OpcUaExtra>>) -> Result<Option<(u32, Vec<u8>)>, u32> {
+        let items = r.array(|r| Ok((r.node_id()?, r.u32()?, r.string()?, r.data_value()?)))?;
+        check_count(&items)?;
+        let mut results = Vec::with_capacity(items.len());
+        let mut writes = Vec::new();
+        for (i, (node, attribute, range, value)) in items.into_iter().enumerate() {
+            let status = match self.space.writable_var(&node, attribute) {
+                Err(status) => status,
+                Ok(_) if range.map_or(false, |r| !r.is_empty()) => status::BAD_INDEX_RANGE_INVALID,
+                Ok((area, index)) => {
+                    let var = self.space.var(area, index);
+                    match value.value.as_ref().and_then(|v| nodes::encode(var.ty, v)) {
+                        Some(data) => {
+                            writes.push((i, area, index, var.offset, data));
+                            status::GOOD
+                        }
+                        None => status::BAD_TYPE_MISMATCH,
+                    }
+                }
+            };
+            results.push(status);
+        }
+        if writes.is_empty() {
+            return Ok(Some((id::WRITE_RESPONSE, encode_write(&results))));
+        }
+        let call = self.new_call(request_id, handle, Results::Write(results));
+        if let Some(call) = self.calls.get_mut(&call) {
+            call.waiting = writes.len();
+        }
+        for (i, area, index, addr, data) in writes {
+            requests.push(Request {
+                hid: self.hid, area, addr, count: data.len(), write: Some(data), mask: None,
+                read_back: None, lock: None,
+                extra: OpcUaExtra { call, items: vec![(i, index)], sample: false }
+            });
+        }
+        Ok(None)
+    }
+
+    /// Send the publish responses that are due.
+    fn publish(&mut self, output: &mut Vec<u8>) {
+        let mut replies = Vec::new();
+        self.subs.publish(&mut replies);
+        for (request_id, handle, body) in replies {
+            self.direct.0 += 1;
+            if body.is_err() {
+                self.direct.1 += 1;
+            }
+            self.send(request_id, handle, id::PUBLISH_RESPONSE, body.as_deref().map_err(|s| *s),
+                      output);
+        }
+    }
+}
+
+fn encode_read(results: &[DataValue]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.put_len(results.len());
+    results.iter().for_each(|value| body.put_data_value(value));
+    body.put_len(0);
+    body
+}
+
+fn encode_write(results: &[u32]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.put_len(results.len());
+    results.iter().for_each(|&status| body.put_u32(status));
+    body.put_len(0);
+    body
+}
+
+impl Handler for OpcUaHandler {
+    type Extra = OpcUaExtra;
+    type Config = OpcUaConfig;
+
+    fn new(hid: usize, info: Arc<PlcInfo>, config: &OpcUaConfig) -> Self {
+        let application_uri = format!("{}:{}", PRODUCT_URI, info.name);
+        let namespace_uri = config.namespace_uri.clone().unwrap_or_else(|| application_uri.clone());
+        OpcUaHandler {
+            hid, space: AddressSpace::new(info.clone(), namespace_uri, application_uri.clone()),
+            info, application_uri, endpoint_url: String::new(), receive_buffer: 0,
+            send_buffer: 0, max_response: 0, max_chunks: 0, channel_id: 0, token_id: 0, seq: 0,
+            partial: Vec::new(), session: None, calls: HashMap::new(), next_call: 0,
+            subs: Subscriptions::default(), in_flight: [0; 2], failed: [false; 2],
+            continuations: Vec::new(), next_continuation: 0, closed: false, direct: (0, 0),
+        }
+    }
+
+    fn receive(&mut self, input: &[u8], requests: &mut Vec<Request<OpcUaExtra>>,
+               output: &mut Vec<u8>) -> io::Result<usize> {
+        let mut pos = 0;
+        while !self.closed {
+            let rest = &input[pos..];
+            if rest.len() < 8 {
+                break;
+            }
+            let size = LE::read_u32(&rest[4..]) as usize;
+            let limit = if self.receive_buffer == 0 { BUFFER_SIZE as usize }
+                        else { self.receive_buffer };
+            if size < 8 || size > limit {
+                self.fail(status::BAD_TCP_MESSAGE_TOO_LARGE, "invalid chunk size", output);
+                break;
+            }
+            if rest.len() < size {
+                break;
+            }
+            pos += size;
+            let (kind, chunk, body) = (&rest[..3], rest[3], &rest[8..size]);
+            let result = match kind {
+                b"HEL" if self.receive_buffer == 0 => self.hello(body, output),
+                _ if self.receive_buffer == 0 => {
+                    self.fail(status::BAD_TCP_MESSAGE_TYPE_INVALID, "expected hello", output);
+                    break;
+                }
+                b"OPN" => self.open(body, output),
+                b"CLO" => {
+                    self.closed = true;
+                    break;
+                }
+                b"MSG" => self.chunk(chunk, body, requests, output),
+                _ => {
+                    self.fail(status::BAD_TCP_MESSAGE_TYPE_INVALID, "invalid message type",
+                              output);
+                    break;
+                }
+            };
+            if result.is_err() {
+                self.fail(status::BAD_DECODING_ERROR, "invalid message", output);
+            }
+        }
+        self.poll(requests);
+        // after closing, further input is ignored
+        Ok(if self.closed { input.len() } else { pos })
+    }
+
+    fn respond(&mut self, response: Response<OpcUaExtra>, output: &mut Vec<u8>) {
+        let (req, data) = match response {
+            Response::Ok(req, data) => (req, Ok(data)),
+            Response::Error(req, code) => (req, Err(code)),
+        };
+        if req.extra.sample {
+            self.in_flight[area_index(req.area)] -= 1;
+            if let Err(code) = data {
+                warn!("client {}: sampling failed with error {}", self.hid, code);
+                self.failed[area_index(req.area)] = true;
+            }
+            let base = req.read_back.map_or(req.addr, |(addr, _)| addr);
+            let data = data.as_deref().map_err(|&code| plc_status(code));
+            self.subs.sample(&self.space, req.area, base, data);
+            return self.publish(output);
+        }
+        let call = match self.calls.get_mut(&req.extra.call) {
+            Some(call) => call,
+            None => return,
+        };
+        for &(i, index) in &req.extra.items {
+            match (&mut call.results, &data) {
+                (Results::Read(values), Ok(data)) => {
+                    let var = self.space.var(req.area, index);
+                    values[i].value = Some(nodes::decode(var.ty, &data[var.offset - req.addr..]));
+                }
+                (Results::Read(values), Err(code)) => values[i] = DataValue::error(plc_status(*code)),
+                (Results::Write(statuses), Err(code)) => statuses[i] = plc_status(*code),
+                (Results::Write(_), Ok(_)) => (),
+            }
+        }
+        call.waiting -= 1;
+        if call.waiting == 0 {
+            if let Some(call) = self.calls.remove(&req.extra.call) {
+                let (ty, body) = match &call.results {
+                    Results::Read(values) => (id::READ_RESPONSE, encode_read(values)),
+                    Results::Write(statuses) => (id::WRITE_RESPONSE, encode_write(statuses)),
+                };
+                self.send(call.request_id, call.handle, ty, Ok(&body), output);
+            }
+        }
+    }
+
+    fn poll(&mut self, requests: &mut Vec<Request<OpcUaExtra>>) {
+        for area in [Area::Extern, Area::Process] {
+            let range = match self.subs.sample_range(area) {
+                Some(range) if !self.failed[area_index(area)] => range,
+                _ => continue,
+            };
+            while self.in_flight[area_index(area)] < SAMPLES_IN_FLIGHT {
+                self.in_flight[area_index(area)] += 1;
+                requests.push(Request {
+                    hid: self.hid, area, addr: range.start, count: range.len(), write: None,
+                    mask: None, read_back: Some((range.start, range.len())), lock: None,
+                    extra: OpcUaExtra { sample: true, ..Default::default() }
+                });
+            }
+        }
+    }
+
+    fn direct_replies(&mut self) -> (usize, usize) {
+        std::mem::take(&mut self.direct)
+    }
+
+    fn finished(&self) -> bool {
+        self.closed
+    }
+}
+
+impl OpcUaHandler {
+    /// Handle a chunk of a service request.
+    fn chunk(&mut self, chunk: u8, body: &[u8], requests: &mut Vec<Request<OpcUaExtra>>,
+             output: &mut Vec<u8>) -> DecodeResult<()> {
+        let mut r = Reader::new(body);
+        let (channel_id, token_id) = (r.u32()?, r.u32()?);
+        let (_seq, request_id) = (r.u32()?, r.u32()?);
+        if channel_id == 0 || channel_id != self.channel_id {
+            self.fail(status::BAD_SECURE_CHANNEL_ID_INVALID, "invalid secure channel", output);
+            return Ok(());
+        }
+        // after renewal, the previous token can still be used for a while
+        if token_id != self.token_id && token_id.wrapping_add(1) != self.token_id {
+            self.fail(status::BAD_SECURE_CHANNEL_TOKEN_UNKNOWN, "invalid token", output);
+            return Ok(());
+        }
+        let data = r.bytes(body.len() - 16)?;
+        match chunk {
+            b'A' => self.partial.clear(),
+            b'C' | b'F' if self.partial.len() + data.len() > MAX_MESSAGE => {
+                self.fail(status::BAD_TCP_MESSAGE_TOO_LARGE, "message too large", output);
+            }
+            b'C' => self.partial.extend_from_slice(data),
+            b'F' => {
+                let mut msg = std::mem::take(&mut self.partial);
+                msg.extend_from_slice(data);
+                self.message(request_id, &msg, requests, output);
+            }
+            _ => return Err(DecodeError),
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::image::{VarInfo, VarType};
+    use super::*;
+
+    fn handler() -> OpcUaHandler {
+        let info = PlcInfo {
+            name: "test".into(),
+            extern_vars: vec![VarInfo::new("value", 0, VarType::U16)],
+            ..Default::default()
+        };
+        OpcUaHandler::new(5, Arc::new(info), &OpcUaConfig::default())
+    }
+
+    fn chunk(kind: &[u8; 3], flag: u8, body: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.push(flag);
+        chunk.put_u32(body.len() as u32 + 8);
+        chunk.put_bytes(body);
+        chunk
+    }
+
+    fn hello(buffer: u32) -> Vec<u8> {
+        let mut body = Vec::new();
+        [0, buffer, buffer, 0, 0].iter().for_each(|&v| body.put_u32(v));
+        body.put_str(Some("opc.tcp://localhost:4840"));
+        chunk(b"HEL", b'F', &body)
+    }
+
+    fn request_header(buf: &mut Vec<u8>, ty: u32, handle: u32) {
+        buf.put_node_id(&NodeId::ns0(ty));
+        buf.put_node_id(&NodeId::ns0(0));
+        buf.put_i64(0);
+        buf.put_u32(handle);
+        buf.put_u32(0);
+        buf.put_str(None);
+        buf.put_u32(0);
+        buf.put_null_extension_object();
+    }
+
+    fn open(policy: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.put_u32(0);
+        body.put_str(Some(policy));
+        body.put_byte_string(None);
+        body.put_byte_string(None);
+        body.put_u32(1);
+        body.put_u32(1);
+        request_header(&mut body, id::OPEN_SECURE_CHANNEL_REQUEST, 1);
+        [0, REQUEST_TYPE_ISSUE, SECURITY_MODE_NONE].iter().for_each(|&v| body.put_u32(v));
+        body.put_byte_string(Some(&[]));
+        body.put_u32(60000);
+        chunk(b"OPN", b'F', &body)
+    }
+
+    fn message(channel_id: u32, flag: u8, request_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        [channel_id, 1, request_id, request_id].iter().for_each(|&v| body.put_u32(v));
+        body.put_bytes(payload);
+        chunk(b"MSG", flag, &body)
+    }
+
+    /// The chunks in the output, with message type, flag and body.
+    fn chunks(mut output: &[u8]) -> Vec<(String, u8, Vec<u8>)> {
+        let mut chunks = Vec::new();
+        while !output.is_empty() {
+            let size = LE::read_u32(&output[4..]) as usize;
+            chunks.push((String::from_utf8(output[..3].to_vec()).unwrap(), output[3],
+                         output[8..size].to_vec()));
+            output = &output[size..];
+        }
+        chunks
+    }
+
+    /// The type, request handle and status of a service response.
+    fn response(body: &[u8]) -> (u32, u32, u32) {
+        let mut r = Reader::new(&body[16..]);
+        let ty = r.node_id().unwrap().as_ns0().unwrap();
+        let (_time, handle, status) = (r.u64().unwrap(), r.u32().unwrap(), r.u32().unwrap());
+        (ty, handle, status)
+    }
+
+    fn receive(h: &mut OpcUaHandler, input: &[u8]) -> (usize, Vec<Request<OpcUaExtra>>, Vec<u8>) {
+        let mut requests = Vec::new();
+        let mut output = Vec::new();
+        let used = h.receive(input, &mut requests, &mut output).unwrap();
+        (used, requests, output)
+    }
+
+    #[test]
+    fn secure_channel() {
+        let mut h = handler();
+        let mut input = hello(8192);
+        input.extend(open(POLICY_NONE));
+        let mut endpoints = Vec::new();
+        request_header(&mut endpoints, id::GET_ENDPOINTS_REQUEST, 7);
+        endpoints.put_str(None);
+        // a service request in two chunks, with an aborted one before
+        input.extend(message(5, b'C', 2, b"garbage"));
+        input.extend(message(5, b'A', 2, &[]));
+        input.extend(message(5, b'C', 3, &endpoints[..10]));
+        input.extend(message(5, b'F', 3, &endpoints[10..]));
+        let mut read = Vec::new();
+        request_header(&mut read, id::READ_REQUEST, 8);
+        input.extend(message(5, b'F', 4, &read));
+        let partial = message(5, b'F', 5, &read);
+        input.extend_from_slice(&partial[..20]);
+
+        let (used, requests, output) = receive(&mut h, &input);
+        assert_eq!(used, input.len() - 20);
+        assert!(requests.is_empty());
+        let chunks = chunks(&output);
+        assert_eq!(chunks.iter().map(|c| (c.0.as_str(), c.1)).collect::<Vec<_>>(),
+                   [("ACK", b'F'), ("OPN", b'F'), ("MSG", b'F'), ("MSG", b'F')]);
+        let mut ack = Reader::new(&chunks[0].2);
+        assert_eq!((ack.u32().unwrap(), ack.u32().unwrap(), ack.u32().unwrap()), (0, 8192, 8192));
+        let mut opn = Reader::new(&chunks[1].2);
+        assert_eq!(opn.u32().unwrap(), 5);
+        assert_eq!(response(&chunks[2].2), (id::GET_ENDPOINTS_RESPONSE, 7, status::GOOD));
+        // reads need a session
+        assert_eq!(response(&chunks[3].2), (id::SERVICE_FAULT, 8, status::BAD_SESSION_ID_INVALID));
+        assert_eq!(h.direct_replies(), (2, 1));
+        assert!(!h.finished());
+    }
+
+    fn error(h: &mut OpcUaHandler, input: &[u8]) -> u32 {
+        let (used, _, output) = receive(h, input);
+        assert_eq!(used, input.len());
+        assert!(h.finished());
+        let chunks = chunks(&output);
+        let (kind, _, body) = chunks.last().unwrap();
+        assert_eq!(kind, "ERR");
+        Reader::new(body).u32().unwrap()
+    }
+
+    #[test]
+    fn channel_errors() {
+        let mut opened = hello(65536);
+        opened.extend(open(POLICY_NONE));
+        for (input, status) in [
+            (open(POLICY_NONE), status::BAD_TCP_MESSAGE_TYPE_INVALID),
+            (hello(1024), status::BAD_COMMUNICATION_ERROR),
+            ([&hello(8192)[..], b"MSGF\x00\x00\x01\x00"].concat(), status::BAD_TCP_MESSAGE_TOO_LARGE),
+            (b"HELF\x04\x00\x00\x00".to_vec(), status::BAD_TCP_MESSAGE_TOO_LARGE),
+            (chunk(b"HEL", b'F', &[0; 5]), status::BAD_DECODING_ERROR),
+            ([hello(8192), open("http://opcfoundation.org/UA/SecurityPolicy#Basic256")].concat(),
+             status::BAD_SECURITY_POLICY_REJECTED),
+            ([&opened[..], &open(POLICY_NONE)].concat(), status::BAD_SECURE_CHANNEL_ID_INVALID),
+            ([&opened[..], &message(6, b'F', 2, &[])].concat(), status::BAD_SECURE_CHANNEL_ID_INVALID),
+            ([&opened[..], &message(5, b'X', 2, &[])].concat(), status::BAD_DECODING_ERROR),
+            ([&opened[..], b"XYZF\x08\x00\x00\x00"].concat(), status::BAD_TCP_MESSAGE_TYPE_INVALID),
+        ] {
+            assert_eq!(error(&mut handler(), &input), status);
+        }
+
+        // the previous token ID is accepted, others are not
+        let mut h = handler();
+        let mut bad_token = message(5, b'F', 2, &[]);
+        bad_token[12] = 7;
+        assert_eq!(error(&mut h, &[&opened[..], &bad_token].concat()),
+                   status::BAD_SECURE_CHANNEL_TOKEN_UNKNOWN);
+        let mut h = handler();
+        let mut wrapped = message(5, b'F', 2, &[]);
+        wrapped[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        let (_, _, output) = receive(&mut h, &[&opened[..], &wrapped].concat());
+        assert_eq!(chunks(&output).last().unwrap().0, "ERR");
+    }
+}
+
@@ -0,0 +1,788 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! The OPC UA binary encoding of the built-in types used by the server.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, LE};
+
+/// Status codes.
+pub mod status {
+    pub const GOOD:                        u32 = 0;
+    pub const BAD_INTERNAL_ERROR:          u32 = 0x8002_0000;
+    pub const BAD_COMMUNICATION_ERROR:     u32 = 0x8005_0000;
+    pub const BAD_DECODING_ERROR:          u32 = 0x8007_0000;
+    pub const BAD_SERVICE_UNSUPPORTED:     u32 = 0x800B_0000;
+    pub const BAD_NOTHING_TO_DO:           u32 = 0x800F_0000;
+    pub const BAD_TOO_MANY_OPERATIONS:     u32 = 0x8010_0000;
+    pub const BAD_USER_ACCESS_DENIED:      u32 = 0x801F_0000;
+    pub const BAD_IDENTITY_TOKEN_INVALID:  u32 = 0x8020_0000;
+    pub const BAD_SECURE_CHANNEL_ID_INVALID: u32 = 0x8022_0000;
+    pub const BAD_SESSION_ID_INVALID:      u32 = 0x8025_0000;
+    pub const BAD_SESSION_NOT_ACTIVATED:   u32 = 0x8027_0000;
+    pub const BAD_SUBSCRIPTION_ID_INVALID: u32 = 0x8028_0000;
+    pub const BAD_TIMESTAMPS_TO_RETURN_INVALID: u32 = 0x802B_0000;
+    pub const BAD_NODE_ID_UNKNOWN:         u32 = 0x8034_0000;
+    pub const BAD_ATTRIBUTE_ID_INVALID:    u32 = 0x8035_0000;
+    pub const BAD_INDEX_RANGE_INVALID:     u32 = 0x8036_0000;
+    pub const BAD_NOT_WRITABLE:            u32 = 0x803B_0000;
+    pub const BAD_MONITORING_MODE_INVALID: u32 = 0x8041_0000;
+    pub const BAD_MONITORED_ITEM_ID_INVALID: u32 = 0x8042_0000;
+    pub const BAD_MONITORED_ITEM_FILTER_UNSUPPORTED: u32 = 0x8044_0000;
+    pub const BAD_CONTINUATION_POINT_INVALID: u32 = 0x804A_0000;
+    pub const BAD_NO_CONTINUATION_POINTS:  u32 = 0x804B_0000;
+    pub const BAD_REFERENCE_TYPE_ID_INVALID: u32 = 0x804C_0000;
+    pub const BAD_BROWSE_DIRECTION_INVALID: u32 = 0x804D_0000;
+    pub const BAD_SECURITY_MODE_REJECTED:  u32 = 0x8054_0000;
+    pub const BAD_SECURITY_POLICY_REJECTED: u32 = 0x8055_0000;
+    pub const BAD_VIEW_ID_UNKNOWN:         u32 = 0x806B_0000;
+    pub const BAD_NO_MATCH:                u32 = 0x806F_0000;
+    pub const BAD_TYPE_MISMATCH:           u32 = 0x8074_0000;
+    pub const BAD_TOO_MANY_SUBSCRIPTIONS:  u32 = 0x8077_0000;
+    pub const BAD_TOO_MANY_PUBLISH_REQUESTS: u32 = 0x8078_0000;
+    pub const BAD_NO_SUBSCRIPTION:         u32 = 0x8079_0000;
+    pub const BAD_MESSAGE_NOT_AVAILABLE:   u32 = 0x807B_0000;
+    pub const BAD_TCP_MESSAGE_TYPE_INVALID: u32 = 0x807E_0000;
+    pub const BAD_TCP_MESSAGE_TOO_LARGE:   u32 = 0x8080_0000;
+    pub const BAD_SECURE_CHANNEL_TOKEN_UNKNOWN: u32 = 0x8087_0000;
+    pub const BAD_RESPONSE_TOO_LARGE:      u32 = 0x80B9_0000;
+}
+
+/// Numeric node IDs in namespace 0 that the server uses.
+pub mod id {
+    // data types
+    pub const STRING: u32 = 12;
+    pub const UTC_TIME: u32 = 294;
+    pub const SERVER_STATE: u32 = 852;
+    pub const SERVER_STATUS_DATA_TYPE: u32 = 862;
+    // reference types
+    pub const REFERENCES: u32 = 31;
+    pub const NON_HIERARCHICAL_REFERENCES: u32 = 32;
+    pub const HIERARCHICAL_REFERENCES: u32 = 33;
+    pub const HAS_CHILD: u32 = 34;
+    pub const ORGANIZES: u32 = 35;
+    pub const HAS_TYPE_DEFINITION: u32 = 40;
+    pub const AGGREGATES: u32 = 44;
+    pub const HAS_SUBTYPE: u32 = 45;
+    pub const HAS_PROPERTY: u32 = 46;
+    pub const HAS_COMPONENT: u32 = 47;
+    // type definitions
+    pub const BASE_OBJECT_TYPE: u32 = 58;
+    pub const FOLDER_TYPE: u32 = 61;
+    pub const BASE_DATA_VARIABLE_TYPE: u32 = 63;
+    pub const PROPERTY_TYPE: u32 = 68;
+    pub const SERVER_TYPE: u32 = 2004;
+    pub const SERVER_STATUS_TYPE: u32 = 2138;
+    // instances
+    pub const ROOT_FOLDER: u32 = 84;
+    pub const OBJECTS_FOLDER: u32 = 85;
+    pub const TYPES_FOLDER: u32 = 86;
+    pub const VIEWS_FOLDER: u32 = 87;
+    pub const SERVER: u32 = 2253;
+    pub const SERVER_ARRAY: u32 = 2254;
+    pub const NAMESPACE_ARRAY: u32 = 2255;
+    pub const SERVER_STATUS: u32 = 2256;
+    pub const SERVER_STATUS_CURRENT_TIME: u32 = 2258;
+    pub const SERVER_STATUS_STATE: u32 = 2259;
+    // binary encodings of structures
+    pub const ANONYMOUS_IDENTITY_TOKEN: u32 = 321;
+    pub const SERVICE_FAULT: u32 = 397;
+    pub const FIND_SERVERS_REQUEST: u32 = 422;
+    pub const FIND_SERVERS_RESPONSE: u32 = 425;
+    pub const GET_ENDPOINTS_REQUEST: u32 = 428;
+    pub const GET_ENDPOINTS_RESPONSE: u32 = 431;
+    pub const OPEN_SECURE_CHANNEL_REQUEST: u32 = 446;
+    pub const OPEN_SECURE_CHANNEL_RESPONSE: u32 = 449;
+    pub const CREATE_SESSION_REQUEST: u32 = 461;
+    pub const CREATE_SESSION_RESPONSE: u32 = 464;
+    pub const ACTIVATE_SESSION_REQUEST: u32 = 467;
+    pub const ACTIVATE_SESSION_RESPONSE: u32 = 470;
+    pub const CLOSE_SESSION_REQUEST: u32 = 473;
+    pub const CLOSE_SESSION_RESPONSE: u32 = 476;
+    pub const BROWSE_REQUEST: u32 = 527;
+    pub const BROWSE_RESPONSE: u32 = 530;
+    pub const BROWSE_NEXT_REQUEST: u32 = 533;
+    pub const BROWSE_NEXT_RESPONSE: u32 = 536;
+    pub const TRANSLATE_BROWSE_PATHS_REQUEST: u32 = 554;
+    pub const TRANSLATE_BROWSE_PATHS_RESPONSE: u32 = 557;
+    pub const REGISTER_NODES_REQUEST: u32 = 560;
+    pub const REGISTER_NODES_RESPONSE: u32 = 563;
+    pub const UNREGISTER_NODES_REQUEST: u32 = 566;
+    pub const UNREGISTER_NODES_RESPONSE: u32 = 569;
+    pub const READ_REQUEST: u32 = 631;
+    pub const READ_RESPONSE: u32 = 634;
+    pub const WRITE_REQUEST: u32 = 673;
+    pub const WRITE_RESPONSE: u32 = 676;
+    pub const CREATE_MONITORED_ITEMS_REQUEST: u32 = 751;
+    pub const CREATE_MONITORED_ITEMS_RESPONSE: u32 = 754;
+    pub const MODIFY_MONITORED_ITEMS_REQUEST: u32 = 763;
+    pub const MODIFY_MONITORED_ITEMS_RESPONSE: u32 = 766;
+    pub const SET_MONITORING_MODE_REQUEST: u32 = 769;
+    pub const SET_MONITORING_MODE_RESPONSE: u32 = 772;
+    pub const DELETE_MONITORED_ITEMS_REQUEST: u32 = 781;
+    pub const DELETE_MONITORED_ITEMS_RESPONSE: u32 = 784;
+    pub const CREATE_SUBSCRIPTION_REQUEST: u32 = 787;
+    pub const CREATE_SUBSCRIPTION_RESPONSE: u32 = 790;
+    pub const MODIFY_SUBSCRIPTION_REQUEST: u32 = 793;
+    pub const MODIFY_SUBSCRIPTION_RESPONSE: u32 = 796;
+    pub const SET_PUBLISHING_MODE_REQUEST: u32 = 799;
+    pub const SET_PUBLISHING_MODE_RESPONSE: u32 = 802;
+    pub const DATA_CHANGE_FILTER: u32 = 724;
+    pub const DATA_CHANGE_NOTIFICATION: u32 = 811;
+    pub const PUBLISH_REQUEST: u32 = 826;
+    pub const PUBLISH_RESPONSE: u32 = 829;
+    pub const REPUBLISH_REQUEST: u32 = 832;
+    pub const DELETE_SUBSCRIPTIONS_REQUEST: u32 = 847;
+    pub const DELETE_SUBSCRIPTIONS_RESPONSE: u32 = 850;
+    pub const SERVER_STATUS_DATA_TYPE_ENCODING: u32 = 864;
+}
+
+/// Error while decoding a message; it is answered with `BAD_DECODING_ERROR`.
+#[derive(Debug)]
+pub struct DecodeError;
+
+pub type DecodeResult<T> = Result<T, DecodeError>;
+
+impl From<DecodeError> for u32 {
+    fn from(_: DecodeError) -> u32 {
+        status::BAD_DECODING_ERROR
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NodeId {
+    Numeric(u16, u32),
+    String(u16, String),
+    /// GUID and ByteString node IDs, with the encoding byte and raw value.
+    Opaque(u16, u8, Vec<u8>),
+}
+
+impl NodeId {
+    pub fn ns0(id: u32) -> Self {
+        NodeId::Numeric(0, id)
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == NodeId::Numeric(0, 0)
+    }
+
+    /// The ID if this is a numeric node ID in namespace 0.
+    pub fn as_ns0(&self) -> Option<u32> {
+        match *self {
+            NodeId::Numeric(0, id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// A value of a variable or attribute.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Variant {
+    Empty,
+    Boolean(bool),
+    SByte(i8),
+    Byte(u8),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Float(f32),
+    Double(f64),
+    String(Option<String>),
+    DateTime(i64),
+    NodeId(NodeId),
+    StatusCode(u32),
+    QualifiedName(u16, String),
+    LocalizedText(String),
+    /// An extension object with binary body, by encoding ID in namespace 0.
+    ExtensionObject(u32, Vec<u8>),
+    /// An array with the type ID of its elements.
+    Array(u8, Vec<Variant>),
+}
+
+impl Variant {
+    fn type_id(&self) -> u8 {
+        match self {
+            Variant::Empty => 0,
+            Variant::Boolean(_) => 1,
+            Variant::SByte(_) => 2,
+            Variant::Byte(_) => 3,
+            Variant::Int16(_) => 4,
+            Variant::UInt16(_) => 5,
+            Variant::Int32(_) => 6,
+            Variant::UInt32(_) => 7,
+            Variant::Int64(_) => 8,
+            Variant::UInt64(_) => 9,
+            Variant::Float(_) => 10,
+            Variant::Double(_) => 11,
+            Variant::String(_) => 12,
+            Variant::DateTime(_) => 13,
+            Variant::NodeId(_) => 17,
+            Variant::StatusCode(_) => 19,
+            Variant::QualifiedName(..) => 20,
+            Variant::LocalizedText(_) => 21,
+            Variant::ExtensionObject(..) => 22,
+            Variant::Array(ty, _) => *ty,
+        }
+    }
+
+    /// The value as an integer, for numeric scalars.
+    pub fn as_i128(&self) -> Option<i128> {
+        Some(match *self {
+            Variant::Boolean(v) => v as i128,
+            Variant::SByte(v) => v as i128,
+            Variant::Byte(v) => v as i128,
+            Variant::Int16(v) => v as i128,
+            Variant::UInt16(v) => v as i128,
+            Variant::Int32(v) => v as i128,
+            Variant::UInt32(v) => v as i128,
+            Variant::Int64(v) => v as i128,
+            Variant::UInt64(v) => v as i128,
+            Variant::Float(v) if v.fract() == 0.0 && v.abs() < 1e38 => v as i128,
+            Variant::Double(v) if v.fract() == 0.0 && v.abs() < 1e38 => v as i128,
+            _ => return None,
+        })
+    }
+
+    /// The value as a float, for numeric scalars.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Variant::Float(v) => Some(v as f64),
+            Variant::Double(v) => Some(v),
+            _ => self.as_i128().map(|v| v as f64),
+        }
+    }
+}
+
+/// A value with status and timestamps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataValue {
+    pub value: Option<Variant>,
+    pub status: u32,
+    pub source_time: Option<i64>,
+    pub server_time: Option<i64>,
+}
+
+impl DataValue {
+    pub fn new(value: Variant) -> Self {
+        Self { value: Some(value), status: status::GOOD, source_time: None, server_time: None }
+    }
+
+    pub fn error(status: u32) -> Self {
+        Self { value: None, status, source_time: None, server_time: None }
+    }
+
+    /// Add the timestamps selected by a `TimestampsToReturn` value (0 =
+    /// source, 1 = server, 2 = both, 3 = neither).  Only values of the
+    /// `Value` attribute have a source timestamp.
+    pub fn stamped(mut self, which: u32, time: i64, source: bool) -> Self {
+        if source && (which == 0 || which == 2) {
+            self.source_time = Some(time);
+        }
+        if which == 1 || which == 2 {
+            self.server_time = Some(time);
+        }
+        self
+    }
+}
+
+/// The current time as OPC UA DateTime (100 ns intervals since 1601).
+pub fn now() -> i64 {
+    let since_unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    116_444_736_000_000_000 + (since_unix.as_nanos() / 100) as i64
+}
+
+/// Reads encoded values from a message body.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, n: usize) -> DecodeResult<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + n).ok_or(DecodeError)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> DecodeResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> DecodeResult<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> DecodeResult<u16> {
+        Ok(LE::read_u16(self.bytes(2)?))
+    }
+
+    pub fn u32(&mut self) -> DecodeResult<u32> {
+        Ok(LE::read_u32(self.bytes(4)?))
+    }
+
+    pub fn i32(&mut self) -> DecodeResult<i32> {
+        Ok(LE::read_i32(self.bytes(4)?))
+    }
+
+    pub fn u64(&mut self) -> DecodeResult<u64> {
+        Ok(LE::read_u64(self.bytes(8)?))
+    }
+
+    pub fn f64(&mut self) -> DecodeResult<f64> {
+        Ok(LE::read_f64(self.bytes(8)?))
+    }
+
+    /// A ByteString, or None if null.
+    pub fn byte_string(&mut self) -> DecodeResult<Option<&'a [u8]>> {
+        match self.i32()? {
+            n if n < 0 => Ok(None),
+            n => self.bytes(n as usize).map(Some),
+        }
+    }
+
+    pub fn string(&mut self) -> DecodeResult<Option<String>> {
+        self.byte_string()?.map(|b| String::from_utf8(b.to_vec()).map_err(|_| DecodeError))
+                           .transpose()
+    }
+
+    /// An array, decoding the elements with `f`.  Null arrays are empty.
+    pub fn array<T>(&mut self, mut f: impl FnMut(&mut Self) -> DecodeResult<T>) -> DecodeResult<Vec<T>> {
+        let n = self.i32()?.max(0) as usize;
+        // each element has at least one byte
+        if n > self.buf.len() - self.pos {
+            return Err(DecodeError);
+        }
+        (0..n).map(|_| f(self)).collect()
+    }
+
+    pub fn node_id(&mut self) -> DecodeResult<NodeId> {
+        Ok(match self.u8()? & 0x3F {
+            0x00 => NodeId::Numeric(0, self.u8()? as u32),
+            0x01 => NodeId::Numeric(self.u8()? as u16, self.u16()? as u32),
+            0x02 => NodeId::Numeric(self.u16()?, self.u32()?),
+            0x03 => NodeId::String(self.u16()?, self.string()?.unwrap_or_default()),
+            0x04 => NodeId::Opaque(self.u16()?, 0x04, self.bytes(16)?.to_vec()),
+            0x05 => NodeId::Opaque(self.u16()?, 0x05,
+                                   self.byte_string()?.unwrap_or_default().to_vec()),
+            _ => return Err(DecodeError),
+        })
+    }
+
+    pub fn qualified_name(&mut self) -> DecodeResult<(u16, String)> {
+        Ok((self.u16()?, self.string()?.unwrap_or_default()))
+    }
+
+    pub fn localized_text(&mut self) -> DecodeResult<String> {
+        let mask = self.u8()?;
+        if mask & 0x01 != 0 {
+            self.string()?;
+        }
+        Ok(if mask & 0x02 != 0 { self.string()?.unwrap_or_default() } else { String::new() })
+    }
+
+    /// An extension object, returning its type and binary body.
+    pub fn extension_object(&mut self) -> DecodeResult<(NodeId, &'a [u8])> {
+        let ty = self.node_id()?;
+        match self.u8()? {
+            0x00 => Ok((ty, &[])),
+            0x01 => Ok((ty, self.byte_string()?.unwrap_or_default())),
+            _ => Err(DecodeError),
+        }
+    }
+
+    fn scalar(&mut self, ty: u8) -> DecodeResult<Variant> {
+        Ok(match ty {
+            0 => Variant::Empty,
+            1 => Variant::Boolean(self.bool()?),
+            2 => Variant::SByte(self.u8()? as i8),
+            3 => Variant::Byte(self.u8()?),
+            4 => Variant::Int16(self.u16()? as i16),
+            5 => Variant::UInt16(self.u16()?),
+            6 => Variant::Int32(self.i32()?),
+            7 => Variant::UInt32(self.u32()?),
+            8 => Variant::Int64(self.u64()? as i64),
+            9 => Variant::UInt64(self.u64()?),
+            10 => Variant::Float(LE::read_f32(self.bytes(4)?)),
+            11 => Variant::Double(self.f64()?),
+            12 => Variant::String(self.string()?),
+            13 => Variant::DateTime(self.u64()? as i64),
+            17 => Variant::NodeId(self.node_id()?),
+            19 => Variant::StatusCode(self.u32()?),
+            20 => {
+                let (ns, name) = self.qualified_name()?;
+                Variant::QualifiedName(ns, name)
+            }
+            21 => Variant::LocalizedText(self.localized_text()?),
+            22 => {
+                let (ty, body) = self.extension_object()?;
+                Variant::ExtensionObject(ty.as_ns0().unwrap_or(0), body.to_vec())
+            }
+            _ => return Err(DecodeError),
+        })
+    }
+
+    pub fn variant(&mut self) -> DecodeResult<Variant> {
+        let mask = self.u8()?;
+        let ty = mask & 0x3F;
+        if mask & 0x80 == 0 {
+            return self.scalar(ty);
+        }
+        let items = self.array(|r| r.scalar(ty))?;
+        if mask & 0x40 != 0 {
+            // array dimensions
+            self.array(Reader::i32)?;
+        }
+        Ok(Variant::Array(ty, items))
+    }
+
+    pub fn data_value(&mut self) -> DecodeResult<DataValue> {
+        let mask = self.u8()?;
+        let mut dv = DataValue::error(status::GOOD);
+        if mask & 0x01 != 0 {
+            dv.value = Some(self.variant()?);
+        }
+        if mask & 0x02 != 0 {
+            dv.status = self.u32()?;
+        }
+        if mask & 0x04 != 0 {
+            dv.source_time = Some(self.u64()? as i64);
+        }
+        if mask & 0x10 != 0 {
+            self.u16()?;
+        }
+        if mask & 0x08 != 0 {
+            dv.server_time = Some(self.u64()? as i64);
+        }
+        if mask & 0x20 != 0 {
+            self.u16()?;
+        }
+        Ok(dv)
+    }
+}
+
+/// Writing encoded values to a message body.
+pub trait Put {
+    fn put_u8(&mut self, v: u8);
+    fn put_bytes(&mut self, v: &[u8]);
+
+    fn put_bool(&mut self, v: bool) {
+        self.put_u8(v as u8);
+    }
+
+    fn put_u16(&mut self, v: u16) {
+        self.put_bytes(&v.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, v: u32) {
+        self.put_bytes(&v.to_le_bytes());
+    }
+
+    fn put_i32(&mut self, v: i32) {
+        self.put_bytes(&v.to_le_bytes());
+    }
+
+    fn put_i64(&mut self, v: i64) {
+        self.put_bytes(&v.to_le_bytes());
+    }
+
+    fn put_f64(&mut self, v: f64) {
+        self.put_bytes(&v.to_le_bytes());
+    }
+
+    fn put_byte_string(&mut self, v: Option<&[u8]>) {
+        match v {
+            None => self.put_i32(-1),
+            Some(v) => {
+                self.put_i32(v.len() as i32);
+                self.put_bytes(v);
+            }
+        }
+    }
+
+    fn put_str(&mut self, v: Option<&str>) {
+        self.put_byte_string(v.map(str::as_bytes));
+    }
+
+    /// Write the length of an array, whose elements follow.
+    fn put_len(&mut self, n: usize) {
+        self.put_i32(n as i32);
+    }
+
+    fn put_node_id(&mut self, id: &NodeId) {
+        match id {
+            &NodeId::Numeric(0, v) if v < 256 => {
+                self.put_u8(0x00);
+                self.put_u8(v as u8);
+            }
+            &NodeId::Numeric(ns, v) if ns < 256 && v < 65536 => {
+                self.put_u8(0x01);
+                self.put_u8(ns as u8);
+                self.put_u16(v as u16);
+            }
+            &NodeId::Numeric(ns, v) => {
+                self.put_u8(0x02);
+                self.put_u16(ns);
+                self.put_u32(v);
+            }
+            NodeId::String(ns, s) => {
+                self.put_u8(0x03);
+                self.put_u16(*ns);
+                self.put_str(Some(s));
+            }
+            NodeId::Opaque(ns, enc, v) => {
+                self.put_u8(*enc);
+                self.put_u16(*ns);
+                if *enc == 0x04 {
+                    self.put_bytes(v);
+                } else {
+                    self.put_byte_string(Some(v));
+                }
+            }
+        }
+    }
+
+    fn put_qualified_name(&mut self, ns: u16, name: &str) {
+        self.put_u16(ns);
+        self.put_str(Some(name));
+    }
+
+    fn put_localized_text(&mut self, text: &str) {
+        if text.is_empty() {
+            self.put_u8(0);
+        } else {
+            self.put_u8(0x02);
+            self.put_str(Some(text));
+        }
+    }
+
+    /// An extension object with a binary body.
+    fn put_extension_object(&mut self, ty: u32, body: &[u8]) {
+        self.put_node_id(&NodeId::ns0(ty));
+        self.put_u8(0x01);
+        self.put_byte_string(Some(body));
+    }
+
+    fn put_null_extension_object(&mut self) {
+        self.put_node_id(&NodeId::ns0(0));
+        self.put_u8(0x00);
+    }
+
+    fn put_variant(&mut self, v: &Variant) {
+        match v {
+            Variant::Array(ty, items) => {
+                self.put_u8(ty | 0x80);
+                self.put_len(items.len());
+                for item in items {
+                    self.put_scalar(item);
+                }
+            }
+            v => {
+                self.put_u8(v.type_id());
+                self.put_scalar(v);
+            }
+        }
+    }
+
+    fn put_scalar(&mut self, v: &Variant) {
+        match v {
+            Variant::Empty | Variant::Array(..) => (),
+            Variant::Boolean(v) => self.put_bool(*v),
+            Variant::SByte(v) => self.put_u8(*v as u8),
+            Variant::Byte(v) => self.put_u8(*v),
+            Variant::Int16(v) => self.put_bytes(&v.to_le_bytes()),
+            Variant::UInt16(v) => self.put_u16(*v),
+            Variant::Int32(v) => self.put_i32(*v),
+            Variant::UInt32(v) => self.put_u32(*v),
+            Variant::Int64(v) => self.put_i64(*v),
+            Variant::UInt64(v) => self.put_bytes(&v.to_le_bytes()),
+            Variant::Float(v) => self.put_bytes(&v.to_le_bytes()),
+            Variant::Double(v) => self.put_f64(*v),
+            Variant::String(v) => self.put_str(v.as_deref()),
+            Variant::DateTime(v) => self.put_i64(*v),
+            Variant::NodeId(v) => self.put_node_id(v),
+            Variant::StatusCode(v) => self.put_u32(*v),
+            Variant::QualifiedName(ns, name) => self.put_qualified_name(*ns, name),
+            Variant::LocalizedText(v) => self.put_localized_text(v),
+            Variant::ExtensionObject(ty, body) => self.put_extension_object(*ty, body),
+        }
+    }
+
+    fn put_data_value(&mut self, dv: &DataValue) {
+        let mut mask = 0;
+        if dv.value.is_some() {
+            mask |= 0x01;
+        }
+        if dv.status != status::GOOD {
+            mask |= 0x02;
+        }
+        if dv.source_time.is_some() {
+            mask |= 0x04;
+        }
+        if dv.server_time.is_some() {
+            mask |= 0x08;
+        }
+        self.put_u8(mask);
+        if let Some(value) = &dv.value {
+            self.put_variant(value);
+        }
+        if dv.status != status::GOOD {
+            self.put_u32(dv.status);
+        }
+        if let Some(t) = dv.source_time {
+            self.put_i64(t);
+        }
+        if let Some(t) = dv.server_time {
+            self.put_i64(t);
+        }
+    }
+}
+
+impl Put for Vec<u8> {
+    fn put_u8(&mut self, v: u8) {
+        self.push(v);
+    }
+
+    fn put_bytes(&mut self, v: &[u8]) {
+        self.extend_from_slice(v);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn primitives() {
+        let buf = [1, 0x34, 0x12, 0x78, 0x56, 0x34, 0x12, 0xFF, 0xFF, 0xFF, 0xFF, 2, 0, 0, 0, b'h',
+                   b'i', 0xFF, 0xFF, 0xFF, 0xFF];
+        let mut r = Reader::new(&buf);
+        assert!(r.bool().unwrap());
+        assert_eq!(r.u16().unwrap(), 0x1234);
+        assert_eq!(r.u32().unwrap(), 0x12345678);
+        assert_eq!(r.i32().unwrap(), -1);
+        assert_eq!(r.string().unwrap().as_deref(), Some("hi"));
+        assert_eq!(r.byte_string().unwrap(), None);
+        assert!(r.u8().is_err());
+
+        // lengths beyond the data, and invalid UTF-8
+        assert!(Reader::new(&[3, 0, 0, 0, b'a', b'b']).string().is_err());
+        assert!(Reader::new(&[0xFF, 0xFF, 0xFF, 0x7F]).byte_string().is_err());
+        assert!(Reader::new(&[1, 0, 0, 0, 0xC3]).string().is_err());
+        assert!(Reader::new(&[0, 0, 0]).u32().is_err());
+    }
+
+    #[test]
+    fn arrays() {
+        let mut r = Reader::new(&[2, 0, 0, 0, 7, 0, 0, 0, 8, 0, 0, 0]);
+        assert_eq!(r.array(Reader::u32).unwrap(), [7, 8]);
+        // null arrays are empty
+        assert!(Reader::new(&[0xFF; 4]).array(Reader::u8).unwrap().is_empty());
+        // the length is checked before decoding any element
+        assert!(Reader::new(&[0xFF, 0xFF, 0xFF, 0x7F, 0]).array(|_| Ok(())).is_err());
+        assert!(Reader::new(&[3, 0, 0, 0, 1, 2]).array(Reader::u8).is_err());
+    }
+
+    #[test]
+    fn node_ids() {
+        let ids = [
+            NodeId::ns0(85),
+            NodeId::Numeric(1, 1000),
+            NodeId::Numeric(300, 7),
+            NodeId::Numeric(0, 70000),
+            NodeId::String(1, "extern.flags".into()),
+            NodeId::Opaque(2, 0x04, (0..16).collect()),
+            NodeId::Opaque(0, 0x05, vec![1, 2, 3]),
+        ];
+        let mut buf = Vec::new();
+        for id in &ids {
+            buf.put_node_id(id);
+        }
+        assert_eq!(&buf[..6], &[0x00, 85, 0x01, 1, 0xE8, 0x03]);
+        let mut r = Reader::new(&buf);
+        for id in &ids {
+            assert_eq!(&r.node_id().unwrap(), id);
+        }
+        assert!(r.node_id().is_err());
+        // the namespace URI and server index flags are ignored
+        assert_eq!(Reader::new(&[0xC0, 5]).node_id().unwrap(), NodeId::ns0(5));
+        assert!(Reader::new(&[0x06, 0, 0]).node_id().is_err());
+        assert!(Reader::new(&[0x04, 0, 0, 1, 2]).node_id().is_err());
+        // a null string is the empty string
+        assert_eq!(Reader::new(&[0x03, 1, 0, 0xFF, 0xFF, 0xFF, 0xFF]).node_id().unwrap(),
+                   NodeId::String(1, String::new()));
+    }
+
+    #[test]
+    fn variants() {
+        let values = [
+            Variant::Empty, Variant::Boolean(true), Variant::SByte(-2), Variant::Byte(200),
+            Variant::Int16(-300), Variant::UInt16(60000), Variant::Int32(-70000),
+            Variant::UInt32(4_000_000_000), Variant::Int64(-1 << 40), Variant::UInt64(1 << 63),
+            Variant::Float(1.5), Variant::Double(-0.25), Variant::String(None),
+            Variant::String(Some("text".into())), Variant::DateTime(now()),
+            Variant::NodeId(NodeId::Numeric(1, 5)), Variant::StatusCode(status::BAD_NO_MATCH),
+            Variant::QualifiedName(1, "name".into()), Variant::LocalizedText("label".into()),
+            Variant::LocalizedText(String::new()),
+            Variant::ExtensionObject(id::SERVER_STATUS_DATA_TYPE_ENCODING, vec![1, 2]),
+            Variant::Array(6, vec![Variant::Int32(1), Variant::Int32(-1)]),
+            Variant::Array(12, vec![]),
+        ];
+        let mut buf = Vec::new();
+        for value in &values {
+            buf.put_variant(value);
+        }
+        let mut r = Reader::new(&buf);
+        for value in &values {
+            assert_eq!(&r.variant().unwrap(), value);
+        }
+        assert!(r.u8().is_err());
+
+        // arrays with dimensions, and unsupported types
+        let mut r = Reader::new(&[0xC3, 2, 0, 0, 0, 1, 2, 1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(r.variant().unwrap(), Variant::Array(3, vec![Variant::Byte(1), Variant::Byte(2)]));
+        assert!(Reader::new(&[14, 0]).variant().is_err());
+        assert!(Reader::new(&[0x80 | 15, 1, 0, 0, 0, 0]).variant().is_err());
+        // localized text with locale
+        let mut r = Reader::new(&[0x03, 2, 0, 0, 0, b'd', b'e', 1, 0, 0, 0, b'x']);
+        assert_eq!(r.localized_text().unwrap(), "x");
+        assert!(Reader::new(&[0, 0, 2]).extension_object().is_err());
+
+        assert_eq!(Variant::Double(3.0).as_i128(), Some(3));
+        assert_eq!(Variant::Float(f32::INFINITY).as_i128(), None);
+        assert_eq!(Variant::UInt64(u64::MAX).as_f64(), Some(u64::MAX as f64));
+        assert_eq!(Variant::String(None).as_f64(), None);
+    }
+
+    #[test]
+    fn data_values() {
+        let values = [
+            DataValue::new(Variant::UInt16(7)),
+            DataValue::new(Variant::Double(1.0)).stamped(2, 1234, true),
+            DataValue::new(Variant::Byte(1)).stamped(0, 1234, false),
+            DataValue::error(status::BAD_NODE_ID_UNKNOWN).stamped(1, 99, true),
+        ];
+        let mut buf = Vec::new();
+        for value in &values {
+            buf.put_data_value(value);
+        }
+        let mut r = Reader::new(&buf);
+        for value in &values {
+            assert_eq!(&r.data_value().unwrap(), value);
+        }
+        assert_eq!(values[2].source_time, None);
+        // picoseconds are skipped
+        let mut r = Reader::new(&[0x3C, 1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 2, 0, 0, 0, 0, 0, 0, 0, 9, 0]);
+        let dv = r.data_value().unwrap();
+        assert_eq!((dv.source_time, dv.server_time), (Some(1), Some(2)));
+        assert!(r.u8().is_err());
+    }
+}
+
@@ -0,0 +1,514 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! The address space of the OPC UA server.
+//!
+//! Besides the standard nodes that clients expect (the root folders and the
+//! `Server` object with its namespace array and status), it contains an
+//! `Extern` and a `Process` folder under `Objects`.  Every variable of the
+//! images is a variable node in namespace 1 with the string ID
+//! `extern.<name>` or `process.<name>`, and structs and arrays are folders.
+//! For example, `indexer.data[3]` of the extern image is found at
+//! `Objects/Extern/indexer/data/[3]` with ID `ns=1;s=extern.indexer.data[3]`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use byteorder::{ByteOrder, NativeEndian as NE};
+
+use crate::image::{VarInfo, VarType};
+use crate::server::{Area, PlcInfo};
+use super::codec::{id, now, status, DataValue, NodeId, Put, Variant};
+
+/// Attribute IDs.
+pub mod attr {
+    pub const NODE_ID: u32 = 1;
+    pub const NODE_CLASS: u32 = 2;
+    pub const BROWSE_NAME: u32 = 3;
+    pub const DISPLAY_NAME: u32 = 4;
+    pub const DESCRIPTION: u32 = 5;
+    pub const WRITE_MASK: u32 = 6;
+    pub const USER_WRITE_MASK: u32 = 7;
+    pub const IS_ABSTRACT: u32 = 8;
+    pub const SYMMETRIC: u32 = 9;
+    pub const INVERSE_NAME: u32 = 10;
+    pub const EVENT_NOTIFIER: u32 = 12;
+    pub const VALUE: u32 = 13;
+    pub const DATA_TYPE: u32 = 14;
+    pub const VALUE_RANK: u32 = 15;
+    pub const ARRAY_DIMENSIONS: u32 = 16;
+    pub const ACCESS_LEVEL: u32 = 17;
+    pub const USER_ACCESS_LEVEL: u32 = 18;
+    pub const MINIMUM_SAMPLING_INTERVAL: u32 = 19;
+    pub const HISTORIZING: u32 = 20;
+}
+
+const CLASS_OBJECT: u32 = 1;
+const CLASS_VARIABLE: u32 = 2;
+const CLASS_OBJECT_TYPE: u32 = 8;
+const CLASS_VARIABLE_TYPE: u32 = 16;
+const CLASS_REFERENCE_TYPE: u32 = 32;
+
+const ACCESS_READ: u8 = 1;
+const ACCESS_WRITE: u8 = 2;
+
+const VALUE_RANK_SCALAR: i32 = -1;
+const VALUE_RANK_ARRAY: i32 = 1;
+
+/// Where the value of a variable node comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// A variable of an image, by index into the variable list of `PlcInfo`.
+    Plc(Area, usize),
+    NamespaceArray,
+    ServerArray,
+    ServerStatus,
+    State,
+    CurrentTime,
+}
+
+enum Kind {
+    Object,
+    /// Object, variable and reference types, which only have the nodes
+    /// needed to describe the references and type definitions used.
+    Type { is_abstract: bool, inverse_name: Option<&'static str> },
+    Variable { source: Source, data_type: u32, value_rank: i32, access: u8 },
+}
+
+struct Reference {
+    ty: u32,
+    forward: bool,
+    target: NodeId,
+}
+
+struct Node {
+    class: u32,
+    browse_name: (u16, String),
+    display_name: String,
+    description: String,
+    type_def: Option<u32>,
+    kind: Kind,
+    refs: Vec<Reference>,
+}
+
+/// The result of reading an attribute.
+pub enum ReadResult {
+    Done(DataValue),
+    /// The value of an image variable, which must be read from the PLC.
+    Plc(Area, usize),
+}
+
+/// A request to browse the references of a node.
+pub struct BrowseDescription {
+    pub node: NodeId,
+    /// 0 = forward, 1 = inverse, 2 = both.
+    pub direction: u32,
+    pub ref_type: NodeId,
+    pub subtypes: bool,
+    pub class_mask: u32,
+}
+
+/// An element of a relative path for `translate`.
+pub struct PathElement {
+    pub ref_type: NodeId,
+    pub inverse: bool,
+    pub subtypes: bool,
+    pub name: (u16, String),
+}
+
+pub struct AddressSpace {
+    info: Arc<PlcInfo>,
+    nodes: HashMap<NodeId, Node>,
+    namespace_uri: String,
+    application_uri: String,
+    started: i64,
+}
+
+/// The supertype of the reference types that the server uses.
+fn super_type(ty: u32) -> Option<u32> {
+    match ty {
+        id::HIERARCHICAL_REFERENCES | id::NON_HIERARCHICAL_REFERENCES => Some(id::REFERENCES),
+        id::HAS_CHILD | id::ORGANIZES => Some(id::HIERARCHICAL_REFERENCES),
+        id::AGGREGATES | id::HAS_SUBTYPE => Some(id::HAS_CHILD),
+        id::HAS_COMPONENT | id::HAS_PROPERTY => Some(id::AGGREGATES),
+        id::HAS_TYPE_DEFINITION => Some(id::NON_HIERARCHICAL_REFERENCES),
+        _ => None,
+    }
+}
+
+/// Whether the reference type `ty` matches the requested type.
+fn matches_type(ty: u32, requested: &NodeId, subtypes: bool) -> bool {
+    if requested.is_null() {
+        return true;
+    }
+    let mut ty = Some(ty);
+    while let Some(t) = ty {
+        if requested.as_ns0() == Some(t) {
+            return true;
+        }
+        ty = if subtypes { super_type(t) } else { None };
+    }
+    false
+}
+
+fn data_type(ty: VarType) -> u32 {
+    // the built-in type IDs are also the data type node IDs
+    match ty {
+        VarType::I8  => 2,
+        VarType::U8  => 3,
+        VarType::I16 => 4,
+        VarType::U16 => 5,
+        VarType::I32 => 6,
+        VarType::U32 => 7,
+        VarType::I64 => 8,
+        VarType::U64 => 9,
+        VarType::F32 => 10,
+        VarType::F64 => 11,
+    }
+}
+
+/// Decode the (native endian) data of a variable.
+pub fn decode(ty: VarType, d: &[u8]) -> Variant {
+    match ty {
+        VarType::U8  => Variant::Byte(d[0]),
+        VarType::I8  => Variant::SByte(d[0] as i8),
+        VarType::U16 => Variant::UInt16(NE::read_u16(d)),
+        VarType::I16 => Variant::Int16(NE::read_i16(d)),
+        VarType::U32 => Variant::UInt32(NE::read_u32(d)),
+        VarType::I32 => Variant::Int32(NE::read_i32(d)),
+        VarType::U64 => Variant::UInt64(NE::read_u64(d)),
+        VarType::I64 => Variant::Int64(NE::read_i64(d)),
+        VarType::F32 => Variant::Float(NE::read_f32(d)),
+        VarType::F64 => Variant::Double(NE::read_f64(d)),
+    }
+}
+
+/// Encode a value for a variable of the given type.  Any numeric value is
+/// accepted if it fits into the type.
+pub fn encode(ty: VarType, value: &Variant) -> Option<Vec<u8>> {
+    let mut buf = vec![0; ty.size()];
+    if ty.is_float() {
+        let v = value.as_f64()?;
+        if ty == VarType::F32 {
+            NE::write_f32(&mut buf, v as f32);
+        } else {
+            NE::write_f64(&mut buf, v);
+        }
+        return Some(buf);
+    }
+    let v = value.as_i128()?;
+    let (min, max) = match ty {
+        VarType::U8  => (0, u8::MAX as i128),
+        VarType::I8  => (i8::MIN as i128, i8::MAX as i128),
+        VarType::U16 => (0, u16::MAX as i128),
+        VarType::I16 => (i16::MIN as i128, i16::MAX as i128),
+        VarType::U32 => (0, u32::MAX as i128),
+        VarType::I32 => (i32::MIN as i128, i32::MAX as i128),
+        VarType::U64 => (0, u64::MAX as i128),
+        _            => (i64::MIN as i128, i64::MAX as i128),
+    };
+    if v < min || v > max {
+        return None;
+    }
+    match ty {
+        VarType::U8 | VarType::I8 => buf[0] = v as u8,
+        VarType::U16 | VarType::I16 => NE::write_u16(&mut buf, v as u16),
+        VarType::U32 | VarType::I32 => NE::write_u32(&mut buf, v as u32),
+        _ => NE::write_u64(&mut buf, v as u64),
+    }
+    Some(buf)
+}
+
+impl AddressSpace {
+    pub fn new(info: Arc<PlcInfo>, namespace_uri: String, application_uri: String) -> Self {
+        let mut space = Self { info: info.clone(), nodes: HashMap::new(), namespace_uri,
+                               application_uri, started: now() };
+
+        let types: [(u32, u32, &str, bool, Option<&'static str>); 16] = [
+            (id::REFERENCES, CLASS_REFERENCE_TYPE, "References", true, None),
+            (id::NON_HIERARCHICAL_REFERENCES, CLASS_REFERENCE_TYPE,
+             "NonHierarchicalReferences", true, None),
+            (id::HIERARCHICAL_REFERENCES, CLASS_REFERENCE_TYPE, "HierarchicalReferences", true, None),
+            (id::HAS_CHILD, CLASS_REFERENCE_TYPE, "HasChild", true, None),
+            (id::ORGANIZES, CLASS_REFERENCE_TYPE, "Organizes", false, Some("OrganizedBy")),
+            (id::HAS_TYPE_DEFINITION, CLASS_REFERENCE_TYPE, "HasTypeDefinition", false,
+             Some("TypeDefinitionOf")),
+            (id::AGGREGATES, CLASS_REFERENCE_TYPE, "Aggregates", true, None),
+            (id::HAS_SUBTYPE, CLASS_REFERENCE_TYPE, "HasSubtype", false, Some("SubtypeOf")),
+            (id::HAS_PROPERTY, CLASS_REFERENCE_TYPE, "HasProperty", false, Some("PropertyOf")),
+            (id::HAS_COMPONENT, CLASS_REFERENCE_TYPE, "HasComponent", false, Some("ComponentOf")),
+            (id::BASE_OBJECT_TYPE, CLASS_OBJECT_TYPE, "BaseObjectType", false, None),
+            (id::FOLDER_TYPE, CLASS_OBJECT_TYPE, "FolderType", false, None),
+            (id::SERVER_TYPE, CLASS_OBJECT_TYPE, "ServerType", false, None),
+            (id::BASE_DATA_VARIABLE_TYPE, CLASS_VARIABLE_TYPE, "BaseDataVariableType", false, None),
+            (id::PROPERTY_TYPE, CLASS_VARIABLE_TYPE, "PropertyType", false, None),
+            (id::SERVER_STATUS_TYPE, CLASS_VARIABLE_TYPE, "ServerStatusType", false, None),
+        ];
+        for (ty, class, name, is_abstract, inverse_name) in types {
+            space.add(NodeId::ns0(ty), class, (0, name), None,
+                      Kind::Type { is_abstract, inverse_name });
+        }
+
+        let object = |ty| (Some(ty), Kind::Object);
+        let variable = |ty, source, data_type, value_rank| {
+            (Some(ty), Kind::Variable { source, data_type, value_rank, access: ACCESS_READ })
+        };
+        let standard = [
+            (id::ROOT_FOLDER, None, "Root", object(id::FOLDER_TYPE)),
+            (id::OBJECTS_FOLDER, Some((id::ROOT_FOLDER, id::ORGANIZES)), "Objects",
+             object(id::FOLDER_TYPE)),
+            (id::TYPES_FOLDER, Some((id::ROOT_FOLDER, id::ORGANIZES)), "Types",
+             object(id::FOLDER_TYPE)),
+            (id::VIEWS_FOLDER, Some((id::ROOT_FOLDER, id::ORGANIZES)), "Views",
+             object(id::FOLDER_TYPE)),
+            (id::SERVER, Some((id::OBJECTS_FOLDER, id::ORGANIZES)), "Server",
+             object(id::SERVER_TYPE)),
+            (id::SERVER_ARRAY, Some((id::SERVER, id::HAS_PROPERTY)), "ServerArray",
+             variable(id::PROPERTY_TYPE, Source::ServerArray, id::STRING, VALUE_RANK_ARRAY)),
+            (id::NAMESPACE_ARRAY, Some((id::SERVER, id::HAS_PROPERTY)), "NamespaceArray",
+             variable(id::PROPERTY_TYPE, Source::NamespaceArray, id::STRING, VALUE_RANK_ARRAY)),
+            (id::SERVER_STATUS, Some((id::SERVER, id::HAS_COMPONENT)), "ServerStatus",
+             variable(id::SERVER_STATUS_TYPE, Source::ServerStatus, id::SERVER_STATUS_DATA_TYPE,
+                      VALUE_RANK_SCALAR)),
+            (id::SERVER_STATUS_CURRENT_TIME, Some((id::SERVER_STATUS, id::HAS_COMPONENT)),
+             "CurrentTime", variable(id::BASE_DATA_VARIABLE_TYPE, Source::CurrentTime,
+                                     id::UTC_TIME, VALUE_RANK_SCALAR)),
+            (id::SERVER_STATUS_STATE, Some((id::SERVER_STATUS, id::HAS_COMPONENT)), "State",
+             variable(id::BASE_DATA_VARIABLE_TYPE, Source::State, id::SERVER_STATE,
+                      VALUE_RANK_SCALAR)),
+        ];
+        for (node, parent, name, (type_def, kind)) in standard {
+            let class = if matches!(kind, Kind::Object) { CLASS_OBJECT } else { CLASS_VARIABLE };
+            space.add(NodeId::ns0(node), class, (0, name), type_def, kind);
+            if let Some((parent, ty)) = parent {
+                space.link(&NodeId::ns0(parent), ty, &NodeId::ns0(node));
+            }
+        }
+
+        space.add_image(Area::Extern, "extern", "Extern", &info.extern_vars);
+        if !info.process_vars.is_empty() {
+            space.add_image(Area::Process, "process", "Process", &info.process_vars);
+        }
+        space
+    }
+
+    fn add(&mut self, node: NodeId, class: u32, (ns, name): (u16, &str), type_def: Option<u32>,
+           kind: Kind) {
+        // type definitions are not browsable from the types
+        let refs = type_def.into_iter().map(|ty| Reference {
+            ty: id::HAS_TYPE_DEFINITION, forward: true, target: NodeId::ns0(ty)
+        }).collect();
+        self.nodes.insert(node, Node {
+            class, browse_name: (ns, name.into()), display_name: name.into(),
+            description: String::new(), type_def, kind, refs,
+        });
+    }
+
+    /// Add a reference and its inverse.
+    fn link(&mut self, from: &NodeId, ty: u32, to: &NodeId) {
+        if let Some(node) = self.nodes.get_mut(from) {
+            node.refs.push(Reference { ty, forward: true, target: to.clone() });
+        }
+        if let Some(node) = self.nodes.get_mut(to) {
+            node.refs.push(Reference { ty, forward: false, target: from.clone() });
+        }
+    }
+
+    /// Add the folder of an image, with subfolders for structs and arrays.
+    fn add_image(&mut self, area: Area, prefix: &str, name: &str, vars: &[VarInfo]) {
+        let root = NodeId::String(1, prefix.into());
+        self.add(root.clone(), CLASS_OBJECT, (1, name), Some(id::FOLDER_TYPE), Kind::Object);
+        self.link(&NodeId::ns0(id::OBJECTS_FOLDER), id::ORGANIZES, &root);
+
+        for (index, var) in vars.iter().enumerate() {
+            let mut parent = root.clone();
+            let mut start = 0;
+            let ends = var.name.match_indices(['.', '[']).map(|(i, _)| i)
+                                                         .chain([var.name.len()]);
+            for end in ends.filter(|&end| end > 0) {
+                let segment = var.name[start..end].trim_start_matches('.');
+                let node = NodeId::String(1, format!("{}.{}", prefix, &var.name[..end]));
+                start = end;
+                if end == var.name.len() {
+                    let access = if area == Area::Extern && !var.read_only {
+                        ACCESS_READ | ACCESS_WRITE
+                    } else {
+                        ACCESS_READ
+                    };
+                    self.add(node.clone(), CLASS_VARIABLE, (1, segment),
+                             Some(id::BASE_DATA_VARIABLE_TYPE), Kind::Variable {
+                                 source: Source::Plc(area, index), data_type: data_type(var.ty),
+                                 value_rank: VALUE_RANK_SCALAR, access,
+                             });
+                    if let Some(node) = self.nodes.get_mut(&node) {
+                        node.description = format!("{} at offset {}", var.ty.name(), var.offset);
+                    }
+                } else if !self.nodes.contains_key(&node) {
+                    self.add(node.clone(), CLASS_OBJECT, (1, segment), Some(id::FOLDER_TYPE),
+                             Kind::Object);
+                } else {
+                    parent = node;
+                    continue;
+                }
+                self.link(&parent, id::ORGANIZES, &node);
+                parent = node;
+            }
+        }
+    }
+
+    /// The image variable that is the value of the node, if any.
+    pub fn var(&self, area: Area, index: usize) -> &VarInfo {
+        match area {
+            Area::Extern => &self.info.extern_vars[index],
+            Area::Process => &self.info.process_vars[index],
+        }
+    }
+
+    /// The image variable to write for a node, or the status to reply.
+    pub fn writable_var(&self, node: &NodeId, attribute: u32) -> Result<(Area, usize), u32> {
+        match self.nodes.get(node) {
+            None => Err(status::BAD_NODE_ID_UNKNOWN),
+            Some(Node { kind: Kind::Variable { source: Source::Plc(area, index), access, .. },
+                        .. }) if attribute == attr::VALUE && access & ACCESS_WRITE != 0 =>
+                Ok((*area, *index)),
+            Some(_) => Err(status::BAD_NOT_WRITABLE),
+        }
+    }
+
+    fn server_status(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.put_i64(self.started);
+        body.put_i64(now());
+        body.put_u32(0);  // running
+        body.put_str(Some("urn:ethercat-plc"));
+        body.put_str(Some("ethercat-rs"));
+        body.put_str(Some(&self.info.name));
+        body.put_str(Some(&self.info.version));
+        body.put_str(Some(env!("CARGO_PKG_VERSION")));
+        body.put_i64(0);
+        body.put_u32(0);
+        body.put_localized_text("");
+        body
+    }
+
+    /// Read an attribute of a node.
+    pub fn read(&self, node: &NodeId, attribute: u32) -> ReadResult {
+        let n = match self.nodes.get(node) {
+            Some(n) => n,
+            None => return ReadResult::Done(DataValue::error(status::BAD_NODE_ID_UNKNOWN)),
+        };
+        let value = match (attribute, &n.kind) {
+            (attr::NODE_ID, _) => Variant::NodeId(node.clone()),
+            (attr::NODE_CLASS, _) => Variant::Int32(n.class as i32),
+            (attr::BROWSE_NAME, _) => Variant::QualifiedName(n.browse_name.0,
+                                                             n.browse_name.1.clone()),
+            (attr::DISPLAY_NAME, _) => Variant::LocalizedText(n.display_name.clone()),
+            (attr::DESCRIPTION, _) => Variant::LocalizedText(n.description.clone()),
+            (attr::WRITE_MASK | attr::USER_WRITE_MASK, _) => Variant::UInt32(0),
+            (attr::IS_ABSTRACT, Kind::Type { is_abstract, .. }) => Variant::Boolean(*is_abstract),
+            (attr::SYMMETRIC, Kind::Type { .. }) if n.class == CLASS_REFERENCE_TYPE =>
+                Variant::Boolean(false),
+            (attr::INVERSE_NAME, Kind::Type { inverse_name: Some(name), .. }) =>
+                Variant::LocalizedText(name.to_string()),
+            (attr::EVENT_NOTIFIER, Kind::Object) => Variant::Byte(0),
+            (attr::VALUE, Kind::Variable { source, .. }) => match *source {
+                Source::Plc(area, index) => return ReadResult::Plc(area, index),
+                Source::NamespaceArray => Variant::Array(id::STRING as u8, vec![
+                    Variant::String(Some("http://opcfoundation.org/UA/".into())),
+                    Variant::String(Some(self.namespace_uri.clone())),
+                ]),
+                Source::ServerArray => Variant::Array(id::STRING as u8, vec![
+                    Variant::String(Some(self.application_uri.clone())),
+                ]),
+                Source::ServerStatus => Variant::ExtensionObject(
+                    id::SERVER_STATUS_DATA_TYPE_ENCODING, self.server_status()),
+                Source::State => Variant::Int32(0),
+                Source::CurrentTime => Variant::DateTime(now()),
+            },
+            (attr::DATA_TYPE, Kind::Variable { data_type, .. }) =>
+                Variant::NodeId(NodeId::ns0(*data_type)),
+            (attr::VALUE_RANK, Kind::Variable { value_rank, .. }) => Variant::Int32(*value_rank),
+            (attr::ARRAY_DIMENSIONS, Kind::Variable { value_rank, .. }) => {
+                let dims = if *value_rank == VALUE_RANK_ARRAY { vec![Variant::UInt32(0)] }
+                           else { vec![] };
+                Variant::Array(7, dims)
+            }
+            (attr::ACCESS_LEVEL | attr::USER_ACCESS_LEVEL, Kind::Variable { access, .. }) =>
+                Variant::Byte(*access),
+            (attr::MINIMUM_SAMPLING_INTERVAL, Kind::Variable { .. }) => Variant::Double(0.0),
+            (attr::HISTORIZING, Kind::Variable { .. }) => Variant::Boolean(false),
+            _ => return ReadResult::Done(DataValue::error(status::BAD_ATTRIBUTE_ID_INVALID)),
+        };
+        ReadResult::Done(DataValue::new(value))
+    }
+
+    /// Browse the references of a node, returning the encoded reference
+    /// descriptions.
+    pub fn browse(&self, desc: &BrowseDescription) -> Result<Vec<Vec<u8>>, u32> {
+        let node = self.nodes.get(&desc.node).ok_or(status::BAD_NODE_ID_UNKNOWN)?;
+        if desc.direction > 2 {
+            return Err(status::BAD_BROWSE_DIRECTION_INVALID);
+        }
+        if !desc.ref_type.is_null() && desc.ref_type.as_ns0().map_or(true, |ty| {
+            self.nodes.get(&NodeId::ns0(ty)).map_or(true, |n| n.class != CLASS_REFERENCE_TYPE)
+        }) {
+            return Err(status::BAD_REFERENCE_TYPE_ID_INVALID);
+        }
+        let mut result = Vec::new();
+        for r in &node.refs {
+            if (desc.direction == 0 && !r.forward) || (desc.direction == 1 && r.forward) ||
+                !matches_type(r.ty, &desc.ref_type, desc.subtypes)
+            {
+                continue;
+            }
+            let target = match self.nodes.get(&r.target) {
+                Some(target) => target,
+                None => continue,
+            };
+            if desc.class_mask != 0 && desc.class_mask & target.class == 0 {
+                continue;
+            }
+            let mut buf = Vec::new();
+            buf.put_node_id(&NodeId::ns0(r.ty));
+            buf.put_bool(r.forward);
+            buf.put_node_id(&r.target);
+            buf.put_qualified_name(target.browse_name.0, &target.browse_name.1);
+            buf.put_localized_text(&target.display_name);
+            buf.put_u32(target.class);
+            buf.put_node_id(&NodeId::ns0(target.type_def.unwrap_or(0)));
+            result.push(buf);
+        }
+        Ok(result)
+    }
+
+    /// Follow a relative path from a node, returning the target nodes.
+    pub fn translate(&self, start: &NodeId, path: &[PathElement]) -> Result<Vec<NodeId>, u32> {
+        if !self.nodes.contains_key(start) {
+            return Err(status::BAD_NODE_ID_UNKNOWN);
+        }
+        if path.is_empty() {
+            return Err(status::BAD_NOTHING_TO_DO);
+        }
+        let mut current = vec![start.clone()];
+        for element in path {
+            let mut next = Vec::new();
+            for node in current.iter().filter_map(|id| self.nodes.get(id)) {
+                for r in &node.refs {
+                    if r.forward == element.inverse ||
+                        !matches_type(r.ty, &element.ref_type, element.subtypes) ||
+                        next.contains(&r.target)
+                    {
+                        continue;
+                    }
+                    if self.nodes.get(&r.target).map_or(false, |t| t.browse_name == element.name) {
+                        next.push(r.target.clone());
+                    }
+                }
+            }
+            if next.is_empty() {
+                return Err(status::BAD_NO_MATCH);
+            }
+            current = next;
+        }
+        Ok(current)
+    }
+}
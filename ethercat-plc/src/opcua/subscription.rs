@@ -0,0 +1,534 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Subscriptions and monitored items of a session.
+//!
+//! Image variables are sampled at the end of every PLC cycle, other values
+//! (like the server's current time) along with the extern image.  Each
+//! monitored item has a queue of size one: if it changes several times
+//! within a publishing interval, only the last value is reported.
+//! Messages are not kept for retransmission.
+
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::image::VarInfo;
+use crate::server::Area;
+use super::codec::{id, now, status, DataValue, DecodeResult, NodeId, Put, Reader, Variant};
+use super::nodes::{attr, decode, AddressSpace, ReadResult};
+
+/// Maximum number of subscriptions per session.
+const MAX_SUBSCRIPTIONS: usize = 16;
+/// Maximum number of queued publish requests.
+const MAX_PUBLISH_REQUESTS: usize = 16;
+
+/// Monitoring modes; in the sampling mode (1), changes are queued but not
+/// reported.
+const MODE_DISABLED: u32 = 0;
+const MODE_REPORTING: u32 = 2;
+
+const DEADBAND_NONE: u32 = 0;
+const DEADBAND_ABSOLUTE: u32 = 1;
+const TRIGGER_STATUS: u32 = 0;
+
+struct MonitoredItem {
+    id: u32,
+    node: NodeId,
+    attribute: u32,
+    /// The image variable, for the `Value` of variable nodes of the images.
+    var: Option<(Area, VarInfo)>,
+    client_handle: u32,
+    mode: u32,
+    timestamps: u32,
+    /// Only report changes of the status, not of the value.
+    status_only: bool,
+    /// Absolute deadband for numeric values.
+    deadband: Option<f64>,
+    /// The last reported (or queued) value.
+    last: Option<DataValue>,
+    /// The value to report with the next notification.
+    queued: Option<DataValue>,
+}
+
+impl MonitoredItem {
+    fn update(&mut self, value: DataValue) {
+        let changed = match &self.last {
+            None => true,
+            Some(last) if last.status != value.status => true,
+            Some(_) if self.status_only => false,
+            Some(last) => match (&last.value, &value.value, self.deadband) {
+                (Some(a), Some(b), Some(deadband)) => match (a.as_f64(), b.as_f64()) {
+                    (Some(a), Some(b)) => (a - b).abs() > deadband,
+                    _ => !same(a, b),
+                },
+                (Some(a), Some(b), None) => !same(a, b),
+                (a, b, _) => a.is_some() != b.is_some(),
+            },
+        };
+        if changed {
+            self.last = Some(value.clone());
+            self.queued = Some(value);
+        }
+    }
+}
+
+/// Compare values, where NaN floats are the same if their bits are.
+fn same(a: &Variant, b: &Variant) -> bool {
+    match (a, b) {
+        (Variant::Float(a), Variant::Float(b)) => a.to_bits() == b.to_bits(),
+        (Variant::Double(a), Variant::Double(b)) => a.to_bits() == b.to_bits(),
+        _ => a == b,
+    }
+}
+
+struct Subscription {
+    id: u32,
+    interval: Duration,
+    max_keepalive: u32,
+    lifetime: u32,
+    enabled: bool,
+    items: Vec<MonitoredItem>,
+    /// End of the current publishing interval.
+    next: Instant,
+    /// Publishing intervals since the last message.
+    idle: u32,
+    /// Whether a message should be sent as soon as a publish request is
+    /// available.
+    due: bool,
+    /// Sequence number of the next notification message.
+    seq: u32,
+}
+
+impl Subscription {
+    fn has_notifications(&self) -> bool {
+        self.enabled && self.items.iter().any(|item| {
+            item.mode == MODE_REPORTING && item.queued.is_some()
+        })
+    }
+
+    /// Encode the notification message, with the queued notifications or
+    /// as keep-alive.
+    fn message(&mut self) -> Vec<u8> {
+        let mut msg = Vec::new();
+        if self.has_notifications() {
+            let mut data = Vec::new();
+            let items = self.items.iter_mut().filter(|item| item.mode == MODE_REPORTING);
+            let values: Vec<_> = items.filter_map(|item| {
+                item.queued.take().map(|value| (item.client_handle, value))
+            }).collect();
+            data.put_len(values.len());
+            for (handle, value) in values {
+                data.put_u32(handle);
+                data.put_data_value(&value);
+            }
+            data.put_len(0);
+            msg.put_u32(self.seq);
+            msg.put_i64(now());
+            msg.put_len(1);
+            msg.put_extension_object(id::DATA_CHANGE_NOTIFICATION, &data);
+            self.seq = self.seq.wrapping_add(1).max(1);
+        } else {
+            msg.put_u32(self.seq);
+            msg.put_i64(now());
+            msg.put_len(0);
+        }
+        msg
+    }
+}
+
+/// A queued publish request.
+struct PublishRequest {
+    request_id: u32,
+    handle: u32,
+    /// Results of the acknowledgements sent with the request.
+    acks: Vec<u32>,
+}
+
+/// The reply to a publish request: the ID of the request and its handle,
+/// and the response body or the status for a service fault.
+pub type PublishReply = (u32, u32, Result<Vec<u8>, u32>);
+
+#[derive(Default)]
+pub struct Subscriptions {
+    subs: Vec<Subscription>,
+    publish: VecDeque<PublishRequest>,
+    next_id: u32,
+}
+
+/// Revise requested subscription parameters: publishing interval, lifetime
+/// and max keep-alive count.
+fn revise(interval: f64, lifetime: u32, keepalive: u32, period: Duration) -> (Duration, u32, u32) {
+    let interval = if interval.is_nan() { 0. } else { interval.clamp(0., 3600e3) };
+    let interval = Duration::from_secs_f64(interval / 1000.).max(period);
+    let keepalive = keepalive.clamp(1, 10000);
+    (interval, lifetime.max(3 * keepalive), keepalive)
+}
+
+impl Subscriptions {
+    /// Delete all subscriptions, e.g. when the session is closed.  Queued
+    /// publish requests are answered with the next call to `publish`.
+    pub fn clear(&mut self) {
+        self.subs.clear();
+    }
+
+    fn new_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn find(&mut self, id: u32) -> Result<&mut Subscription, u32> {
+        self.subs.iter_mut().find(|s| s.id == id).ok_or(status::BAD_SUBSCRIPTION_ID_INVALID)
+    }
+
+    /// The byte range of an image to sample, if any.  The extern image is
+    /// sampled while there are subscriptions, even without items, since the
+    /// samples drive the publishing.
+    pub fn sample_range(&self, area: Area) -> Option<Range<usize>> {
+        let ranges = self.subs.iter().flat_map(|sub| &sub.items)
+            .filter(|item| item.mode != MODE_DISABLED)
+            .filter_map(|item| item.var.as_ref().filter(|(a, _)| *a == area))
+            .map(|(_, var)| var.range());
+        let (start, end) = ranges.fold((usize::MAX, 0), |(start, end), range| {
+            (start.min(range.start), end.max(range.end))
+        });
+        if start < end {
+            Some(start..end)
+        } else if area == Area::Extern && !self.subs.is_empty() {
+            Some(0..0)
+        } else {
+            None
+        }
+    }
+
+    /// Update the monitored items from a sample of an image starting at
+    /// `base`, or from the status code if sampling failed.
+    pub fn sample(&mut self, space: &AddressSpace, area: Area, base: usize,
+                  data: Result<&[u8], u32>) {
+        let time = now();
+        for item in self.subs.iter_mut().flat_map(|sub| &mut sub.items) {
+            if item.mode == MODE_DISABLED {
+                continue;
+            }
+            let value = match (&item.var, data) {
+                (Some((a, var)), Ok(data)) if *a == area => {
+                    // the sample can be from before the item was created
+                    if var.offset < base || var.offset + var.size() > base + data.len() {
+                        continue;
+                    }
+                    DataValue::new(decode(var.ty, &data[var.offset - base..]))
+                }
+                (Some((a, _)), Err(code)) if *a == area => DataValue::error(code),
+                (None, _) if area == Area::Extern => match space.read(&item.node, item.attribute) {
+                    ReadResult::Done(value) => value,
+                    ReadResult::Plc(..) => continue,
+                },
+                _ => continue,
+            };
+            item.update(value.stamped(item.timestamps, time, item.attribute == attr::VALUE));
+        }
+    }
+
+    /// Queue a publish request, or return the status for a service fault.
+    pub fn queue_publish(&mut self, request_id: u32, handle: u32, r: &mut Reader)
+                         -> Result<(), u32> {
+        let acks = r.array(|r| Ok((r.u32()?, r.u32()?)))?;
+        if self.subs.is_empty() {
+            return Err(status::BAD_NO_SUBSCRIPTION);
+        }
+        if self.publish.len() >= MAX_PUBLISH_REQUESTS {
+            return Err(status::BAD_TOO_MANY_PUBLISH_REQUESTS);
+        }
+        let acks = acks.iter().map(|(sub, _)| {
+            if self.subs.iter().any(|s| s.id == *sub) { status::GOOD }
+            else { status::BAD_SUBSCRIPTION_ID_INVALID }
+        }).collect();
+        self.publish.push_back(PublishRequest { request_id, handle, acks });
+        Ok(())
+    }
+
+    /// Reply to queued publish requests with the messages that are due.
+    pub fn publish(&mut self, replies: &mut Vec<PublishReply>) {
+        if self.subs.is_empty() {
+            for req in self.publish.drain(..) {
+                replies.push((req.request_id, req.handle, Err(status::BAD_NO_SUBSCRIPTION)));
+            }
+            return;
+        }
+        let time = Instant::now();
+        for sub in &mut self.subs {
+            if time >= sub.next {
+                sub.next = (sub.next + sub.interval).max(time);
+                if sub.has_notifications() {
+                    sub.due = true;
+                } else {
+                    sub.idle += 1;
+                    sub.due |= sub.idle >= sub.max_keepalive;
+                }
+            }
+            if !sub.due {
+                continue;
+            }
+            let req = match self.publish.pop_front() {
+                Some(req) => req,
+                None => continue,
+            };
+            sub.due = false;
+            sub.idle = 0;
+            let mut body = Vec::new();
+            body.put_u32(sub.id);
+            // no messages are available for retransmission
+            body.put_len(0);
+            body.put_bool(false);
+            body.put_bytes(&sub.message());
+            body.put_len(req.acks.len());
+            for ack in req.acks {
+                body.put_u32(ack);
+            }
+            body.put_len(0);
+            replies.push((req.request_id, req.handle, Ok(body)));
+        }
+    }
+
+    pub fn create_subscription(&mut self, r: &mut Reader, period: Duration)
+                               -> Result<Vec<u8>, u32> {
+        let (interval, lifetime, keepalive) = (r.f64()?, r.u32()?, r.u32()?);
+        let (_max_notifications, enabled, _priority) = (r.u32()?, r.bool()?, r.u8()?);
+        if self.subs.len() >= MAX_SUBSCRIPTIONS {
+            return Err(status::BAD_TOO_MANY_SUBSCRIPTIONS);
+        }
+        let (interval, lifetime, keepalive) = revise(interval, lifetime, keepalive, period);
+        let id = self.new_id();
+        self.subs.push(Subscription {
+            id, interval, max_keepalive: keepalive, lifetime, enabled, items: Vec::new(),
+            // the first message is sent after the first interval
+            next: Instant::now() + interval, idle: keepalive - 1, due: false, seq: 1,
+        });
+        let mut body = Vec::new();
+        body.put_u32(id);
+        body.put_f64(interval.as_secs_f64() * 1000.);
+        body.put_u32(lifetime);
+        body.put_u32(keepalive);
+        Ok(body)
+    }
+
+    pub fn modify_subscription(&mut self, r: &mut Reader, period: Duration)
+                               -> Result<Vec<u8>, u32> {
+        let (id, interval, lifetime, keepalive) = (r.u32()?, r.f64()?, r.u32()?, r.u32()?);
+        let (_max_notifications, _priority) = (r.u32()?, r.u8()?);
+        let sub = self.find(id)?;
+        let (interval, lifetime, keepalive) = revise(interval, lifetime, keepalive, period);
+        sub.interval = interval;
+        sub.lifetime = lifetime;
+        sub.max_keepalive = keepalive;
+        let mut body = Vec::new();
+        body.put_f64(interval.as_secs_f64() * 1000.);
+        body.put_u32(lifetime);
+        body.put_u32(keepalive);
+        Ok(body)
+    }
+
+    pub fn set_publishing_mode(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let enabled = r.bool()?;
+        let ids = r.array(Reader::u32)?;
+        let results = self.for_each(&ids, |subs, id| subs.find(id).map(|sub| sub.enabled = enabled))?;
+        Ok(results)
+    }
+
+    pub fn delete_subscriptions(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let ids = r.array(Reader::u32)?;
+        self.for_each(&ids, |subs, id| {
+            let len = subs.subs.len();
+            subs.subs.retain(|sub| sub.id != id);
+            if subs.subs.len() < len { Ok(()) } else { Err(status::BAD_SUBSCRIPTION_ID_INVALID) }
+        })
+    }
+
+    /// Apply an operation to each of the IDs, and encode the results.
+    fn for_each<T>(&mut self, ids: &[T], mut f: impl FnMut(&mut Self, T) -> Result<(), u32>)
+                   -> Result<Vec<u8>, u32> where T: Copy {
+        if ids.is_empty() {
+            return Err(status::BAD_NOTHING_TO_DO);
+        }
+        let mut body = Vec::new();
+        body.put_len(ids.len());
+        for &id in ids {
+            body.put_u32(f(self, id).err().unwrap_or(status::GOOD));
+        }
+        body.put_len(0);
+        Ok(body)
+    }
+
+    pub fn create_monitored_items(&mut self, r: &mut Reader, space: &AddressSpace,
+                                  period: Duration) -> Result<Vec<u8>, u32> {
+        let (sub_id, timestamps) = (r.u32()?, r.u32()?);
+        let requests = r.array(|r| {
+            let (node, attribute, range, _encoding) =
+                (r.node_id()?, r.u32()?, r.string()?, r.qualified_name()?);
+            let mode = r.u32()?;
+            Ok((node, attribute, range, mode, Parameters::decode(r)?))
+        })?;
+        if timestamps > 3 {
+            return Err(status::BAD_TIMESTAMPS_TO_RETURN_INVALID);
+        }
+        let next_id = self.next_id;
+        let sub = self.find(sub_id)?;
+        if requests.is_empty() {
+            return Err(status::BAD_NOTHING_TO_DO);
+        }
+        let mut new_id = next_id;
+        let mut body = Vec::new();
+        body.put_len(requests.len());
+        for (node, attribute, range, mode, params) in requests {
+            let var = match space.read(&node, attribute) {
+                ReadResult::Plc(area, index) => Ok(Some((area, space.var(area, index).clone()))),
+                ReadResult::Done(DataValue { status, .. })
+                    if status == status::BAD_NODE_ID_UNKNOWN ||
+                       status == status::BAD_ATTRIBUTE_ID_INVALID => Err(status),
+                ReadResult::Done(_) => Ok(None),
+            };
+            let result = var.and_then(|var| {
+                if mode > MODE_REPORTING {
+                    return Err(status::BAD_MONITORING_MODE_INVALID);
+                }
+                if range.map_or(false, |r| !r.is_empty()) {
+                    return Err(status::BAD_INDEX_RANGE_INVALID);
+                }
+                let (status_only, deadband) = params.filter()?;
+                new_id += 1;
+                sub.items.push(MonitoredItem {
+                    id: new_id, node, attribute, var, client_handle: params.client_handle, mode,
+                    timestamps, status_only, deadband, last: None, queued: None,
+                });
+                Ok(new_id)
+            });
+            match result {
+                Ok(id) => {
+                    body.put_u32(status::GOOD);
+                    body.put_u32(id);
+                    body.put_f64(params.revised_interval(sub.interval, period));
+                    body.put_u32(1);
+                }
+                Err(status) => {
+                    body.put_u32(status);
+                    body.put_u32(0);
+                    body.put_f64(0.);
+                    body.put_u32(0);
+                }
+            }
+            body.put_null_extension_object();
+        }
+        body.put_len(0);
+        self.next_id = new_id;
+        Ok(body)
+    }
+
+    pub fn modify_monitored_items(&mut self, r: &mut Reader, period: Duration)
+                                  -> Result<Vec<u8>, u32> {
+        let (sub_id, timestamps) = (r.u32()?, r.u32()?);
+        let requests = r.array(|r| Ok((r.u32()?, Parameters::decode(r)?)))?;
+        if timestamps > 3 {
+            return Err(status::BAD_TIMESTAMPS_TO_RETURN_INVALID);
+        }
+        let sub = self.find(sub_id)?;
+        if requests.is_empty() {
+            return Err(status::BAD_NOTHING_TO_DO);
+        }
+        let mut body = Vec::new();
+        body.put_len(requests.len());
+        for (id, params) in requests {
+            let interval = sub.interval;
+            let result = sub.items.iter_mut().find(|item| item.id == id)
+                                  .ok_or(status::BAD_MONITORED_ITEM_ID_INVALID)
+                                  .and_then(|item| {
+                let (status_only, deadband) = params.filter()?;
+                item.client_handle = params.client_handle;
+                item.timestamps = timestamps;
+                item.status_only = status_only;
+                item.deadband = deadband;
+                Ok(())
+            });
+            body.put_u32(result.err().unwrap_or(status::GOOD));
+            body.put_f64(params.revised_interval(interval, period));
+            body.put_u32(1);
+            body.put_null_extension_object();
+        }
+        body.put_len(0);
+        Ok(body)
+    }
+
+    pub fn set_monitoring_mode(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let (sub_id, mode) = (r.u32()?, r.u32()?);
+        let ids = r.array(Reader::u32)?;
+        if mode > MODE_REPORTING {
+            return Err(status::BAD_MONITORING_MODE_INVALID);
+        }
+        self.find(sub_id)?;
+        self.for_each(&ids, |subs, id| {
+            let sub = subs.find(sub_id)?;
+            let item = sub.items.iter_mut().find(|item| item.id == id)
+                                .ok_or(status::BAD_MONITORED_ITEM_ID_INVALID)?;
+            if mode == MODE_DISABLED {
+                item.last = None;
+                item.queued = None;
+            }
+            item.mode = mode;
+            Ok(())
+        })
+    }
+
+    pub fn delete_monitored_items(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let sub_id = r.u32()?;
+        let ids = r.array(Reader::u32)?;
+        self.find(sub_id)?;
+        self.for_each(&ids, |subs, id| {
+            let sub = subs.find(sub_id)?;
+            let len = sub.items.len();
+            sub.items.retain(|item| item.id != id);
+            if sub.items.len() < len { Ok(()) } else { Err(status::BAD_MONITORED_ITEM_ID_INVALID) }
+        })
+    }
+}
+
+/// The requested parameters of a monitored item.
+struct Parameters {
+    client_handle: u32,
+    interval: f64,
+    filter: (NodeId, Vec<u8>),
+}
+
+impl Parameters {
+    fn decode(r: &mut Reader) -> DecodeResult<Self> {
+        let (client_handle, interval) = (r.u32()?, r.f64()?);
+        let (ty, body) = r.extension_object()?;
+        // the queue size is always 1, and discarding the oldest doesn't matter
+        let (_queue_size, _discard_oldest) = (r.u32()?, r.bool()?);
+        Ok(Self { client_handle, interval, filter: (ty, body.to_vec()) })
+    }
+
+    /// Decode the filter into the status-only flag and the deadband.
+    fn filter(&self) -> Result<(bool, Option<f64>), u32> {
+        let (ty, body) = &self.filter;
+        if ty.is_null() {
+            return Ok((false, None));
+        }
+        if ty.as_ns0() != Some(id::DATA_CHANGE_FILTER) {
+            return Err(status::BAD_MONITORED_ITEM_FILTER_UNSUPPORTED);
+        }
+        let mut r = Reader::new(body);
+        let (trigger, kind, value) = (r.u32()?, r.u32()?, r.f64()?);
+        let deadband = match kind {
+            DEADBAND_NONE => None,
+            DEADBAND_ABSOLUTE => Some(value),
+            _ => return Err(status::BAD_MONITORED_ITEM_FILTER_UNSUPPORTED),
+        };
+        Ok((trigger == TRIGGER_STATUS, deadband))
+    }
+
+    /// The sampling interval in ms: values are sampled every cycle, and a
+    /// negative interval means the publishing interval.
+    fn revised_interval(&self, publishing: Duration, period: Duration) -> f64 {
+        let interval = if self.interval < 0. { publishing } else { period };
+        interval.as_secs_f64() * 1000.
+    }
+}
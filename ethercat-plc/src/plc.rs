@@ -4,23 +4,57 @@
 //! Wrap an EtherCAT master and slave configuration and provide a PLC-like
 //! environment for cyclic task execution.
 
-use std::{thread, time::{Instant, Duration}, marker::PhantomData};
+use std::{thread, time::{Instant, Duration}, marker::PhantomData, path::PathBuf, ops::Range};
+use std::any::Any;
+use std::sync::Arc;
 use anyhow::{bail, Context};
-use crossbeam_channel::{unbounded, Sender, Receiver};
+use crossbeam_channel::{bounded, Sender, Receiver, TrySendError};
 use log::*;
 use ethercat as ec;
 
-use crate::image::{ProcessImage, ExternImage, ProcessConfig};
-use crate::server::{Server, Request, Response};
+use crate::image::{ProcessImage, ExternImage, ProcessConfig, VarInfo};
+use crate::server::{new_client_id, Server, Request, Response, Area, PlcInfo, QUEUE_SIZE};
+use crate::sim::Wiring;
+use crate::record::{Recorder, AppliedWrite};
+use crate::changes::Changes;
+use crate::locks::Locks;
+use crate::shm::Segment;
+use crate::metrics::Metrics;
 
 #[derive(Default)]
 pub struct PlcBuilder {
     name: String,
+    version: String,
     master_id: Option<u32>,
     cycle_freq: Option<u32>,
     server_addr: Option<String>,
     logfile_base: Option<String>,
     debug_logging: bool,
+    wiring: Option<PathBuf>,
+    record_path: Option<PathBuf>,
+    process_view: Option<Vec<String>>,
+    server_config: Option<Box<dyn Any + Send>>,
+    read_only_vars: Vec<String>,
+    read_only_ranges: Vec<Range<usize>>,
+    lock_timeout: Option<Duration>,
+    allow_force_release: bool,
+    extra_servers: Vec<ServerStart>,
+    shm_name: Option<String>,
+}
+
+/// Starts a server for the given PLC info, and returns the PLC side of its
+/// channels.
+type ServerStart = Box<dyn FnOnce(&PlcInfo) -> anyhow::Result<Box<dyn Endpoint>>>;
+
+fn server_start<S: Server>(addr: String, config: S::Config) -> ServerStart {
+    Box::new(move |info| {
+        let (w_from_plc, r_from_plc) = bounded(QUEUE_SIZE);
+        let (w_to_plc, r_to_plc) = bounded(QUEUE_SIZE);
+        S::start(&addr, info, config, w_to_plc, r_from_plc)
+            .with_context(|| format!("starting external server on {}", addr))?;
+        Ok(Box::new(ServerChannels { requests: r_to_plc, responses: w_from_plc,
+                                     pending: Vec::new(), samples: Vec::new(), deferred: None }))
+    })
 }
 
 impl PlcBuilder {
@@ -31,6 +65,12 @@ impl PlcBuilder {
         }
     }
 
+    /// Set the version of the PLC program, which is reported to clients.
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = version.into();
+        self
+    }
+
     pub fn master_id(mut self, id: u32) -> Self {
         self.master_id = Some(id);
         self
@@ -46,53 +86,223 @@ impl PlcBuilder {
         self
     }
 
+    /// Start another server in addition to the one given to `build`, e.g.
+    /// to serve several protocols at once.  All servers share the extern
+    /// image, the read-only ranges and the write locks.
+    pub fn add_server<S: Server>(mut self, addr: impl Into<String>, config: S::Config) -> Self {
+        self.extra_servers.push(server_start::<S>(addr.into(), config));
+        self
+    }
+
+    /// Publish the extern image in a POSIX shared memory segment with the
+    /// given name (e.g. `/plc`), which local processes can access with
+    /// `shm::ShmClient`.
+    pub fn shared_memory(mut self, name: impl Into<String>) -> Self {
+        self.shm_name = Some(name.into());
+        self
+    }
+
+    /// Set the protocol specific configuration for the server.  The type must
+    /// match the `Config` type of the server given to `build`.
+    pub fn server_config<C: Any + Send>(mut self, config: C) -> Self {
+        self.server_config = Some(Box::new(config));
+        self
+    }
+
     pub fn logging_cfg(mut self, logfile_base: Option<String>, debug_logging: bool) -> Self {
         self.logfile_base = logfile_base;
         self.debug_logging = debug_logging;
         self
     }
 
-    pub fn build_simulator<E: ExternImage, S: Server>(self) -> anyhow::Result<PlcSimulator<E, S>> {
-        mlzlog::init(self.logfile_base, &self.name,
+    /// Wire up simulated process image variables according to the given
+    /// description file, see the `sim` module for the format.
+    pub fn with_wiring(mut self, path: impl Into<PathBuf>) -> Self {
+        self.wiring = Some(path.into());
+        self
+    }
+
+    /// Record the process data of every cycle into the given file, see the
+    /// `record` module for the format and replay.
+    pub fn record_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.record_path = Some(path.into());
+        self
+    }
+
+    /// Expose only the given process image variables to clients, instead of
+    /// the whole image.  The variables are laid out in the given order, each
+    /// one starting at an even address (i.e. a Modbus register boundary).
+    pub fn process_view<I, V>(mut self, vars: I) -> Self
+    where I: IntoIterator<Item=V>, V: Into<String>
+    {
+        self.process_view = Some(vars.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Protect the given extern image variables from writes by remote
+    /// clients, in addition to fields marked with `#[plc(read_only)]`.
+    /// Structured variables (e.g. `indexer.data`) protect all their members.
+    pub fn read_only<I, V>(mut self, vars: I) -> Self
+    where I: IntoIterator<Item=V>, V: Into<String>
+    {
+        self.read_only_vars.extend(vars.into_iter().map(Into::into));
+        self
+    }
+
+    /// Protect a range of byte addresses in the extern image from writes
+    /// by remote clients.  Modbus register `n` is at address `2*n`.
+    pub fn read_only_range(mut self, range: Range<usize>) -> Self {
+        self.read_only_ranges.push(range);
+        self
+    }
+
+    /// Set the time after which clients' write locks expire, unless the
+    /// client gives a timeout when claiming.  The default is 30 seconds.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    /// Allow clients to release write locks held by other clients.  This is
+    /// off by default, because any client could then take over a range
+    /// that another one locked.
+    pub fn allow_force_release(mut self, allow: bool) -> Self {
+        self.allow_force_release = allow;
+        self
+    }
+
+    fn resolve_read_only<E: ExternImage>(&self) -> anyhow::Result<Vec<Range<usize>>> {
+        let vars = E::image_vars();
+        let mut ranges = self.read_only_ranges.clone();
+        ranges.extend(vars.iter().filter(|v| v.read_only).map(|v| v.range()));
+        for name in &self.read_only_vars {
+            let len = ranges.len();
+            ranges.extend(vars.iter().filter(|v| v.is_within(name)).map(|v| v.range()));
+            if ranges.len() == len {
+                bail!("read-only: unknown variable {:?}", name);
+            }
+        }
+        Ok(ranges)
+    }
+
+    fn resolve_process_view<P: ProcessImage>(&self) -> anyhow::Result<Option<ProcessView>> {
+        let names = match &self.process_view {
+            Some(names) => names,
+            None => return Ok(None),
+        };
+        let vars = P::image_vars();
+        let mut segments = Vec::new();
+        for name in names {
+            match vars.iter().find(|v| &v.name == name) {
+                Some(var) => segments.push(var.range()),
+                None => bail!("process view: unknown variable {:?}", name),
+            }
+        }
+        Ok(Some(ProcessView { segments }))
+    }
+
+    fn init_logging(&self) -> anyhow::Result<()> {
+        mlzlog::init(self.logfile_base.clone(), &self.name,
                      mlzlog::Settings { show_appname: false,
                                         debug: self.debug_logging,
                                         ..Default::default() })
-            .context("setting up logging")?;
-
-        let channels = if let Some(addr) = self.server_addr {
-            let (w_from_plc, r_from_plc) = unbounded();
-            let (w_to_plc, r_to_plc) = unbounded();
-            S::start(&addr, w_to_plc, r_from_plc)
-                .context("starting external server")?;
-            Some((r_to_plc, w_from_plc))
-        } else {
-            None
+            .context("setting up logging")
+    }
+
+    /// Cycle period in nanoseconds.
+    fn period(&self) -> u64 {
+        1_000_000_000 / self.cycle_freq.unwrap_or(1000) as u64
+    }
+
+    fn start_servers<S: Server, E: ExternImage>(&mut self, view: Option<ProcessView>,
+                                                process_vars: Vec<VarInfo>, metrics: Arc<Metrics>)
+                                                -> anyhow::Result<Option<Servers>> {
+        let config = match self.server_config.take() {
+            Some(config) => *config.downcast::<S::Config>().map_err(
+                |_| anyhow::anyhow!("server config has the wrong type for this server"))?,
+            None => S::Config::default(),
+        };
+        let mut starts = Vec::new();
+        if let Some(addr) = self.server_addr.take() {
+            starts.push(server_start::<S>(addr, config));
+        }
+        starts.append(&mut self.extra_servers);
+        if starts.is_empty() && self.shm_name.is_none() {
+            return Ok(None);
+        }
+        let info = PlcInfo {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            extern_vars: E::image_vars(),
+            process_vars,
+            metrics,
         };
+        let protected = self.resolve_read_only::<E>()?;
+        let mut channels = starts.into_iter().map(|start| start(&info))
+                                             .collect::<anyhow::Result<Vec<_>>>()?;
+        // the shared image is last, so that it is published with all writes
+        if let Some(name) = &self.shm_name {
+            let segment = Segment::create(name, E::size())
+                .with_context(|| format!("creating shared memory segment {}", name))?;
+            channels.push(Box::new(SharedImage { segment, hid: new_client_id(), deferred: None }));
+        }
+        let locks = Locks::new(self.lock_timeout.unwrap_or(Duration::from_secs(30)),
+                               self.allow_force_release);
+        Ok(Some(Servers { channels, view, access: Access { protected, locks } }))
+    }
+
+    pub fn build_simulator<E: ExternImage, S: Server>(mut self) -> anyhow::Result<PlcSimulator<E, S>> {
+        self.init_logging()?;
+        let metrics = Arc::new(Metrics::new(Duration::from_nanos(self.period())));
+        let servers = self.start_servers::<S, E>(None, Vec::new(), metrics.clone())?;
 
         Ok(PlcSimulator {
-            server_channel: channels,
-            sleep: 1_000_000_000 / self.cycle_freq.unwrap_or(1000) as u64,
+            servers,
+            metrics,
+            sleep: self.period(),
+            _types: PhantomData,
+        })
+    }
+
+    /// Build a simulator that runs the same cycle function as a real `Plc`,
+    /// on a process image that is not connected to any hardware.  Outputs
+    /// are fed back to inputs according to the wiring file, if given.
+    pub fn build_plant_simulator<P: ProcessImage, E: ExternImage,
+                                 S: Server>(mut self) -> anyhow::Result<PlantSimulator<P, E, S>> {
+        self.init_logging()?;
+        let view = self.resolve_process_view::<P>()?;
+        let process_vars = match &view {
+            Some(view) => view.map_vars(&P::image_vars()),
+            None => P::image_vars(),
+        };
+        let metrics = Arc::new(Metrics::new(Duration::from_nanos(self.period())));
+        let servers = self.start_servers::<S, E>(view, process_vars, metrics.clone())?;
+
+        let wiring = match &self.wiring {
+            Some(path) => Wiring::load::<P>(path)?,
+            None => Wiring::default(),
+        };
+
+        Ok(PlantSimulator {
+            data: vec![0; P::size()],
+            wiring,
+            servers,
+            metrics,
+            sleep: self.period(),
             _types: PhantomData,
         })
     }
 
     pub fn build<P: ProcessImage, E: ExternImage, PC: ProcessConfig,
-                 S: Server>(self, cfg: PC) -> anyhow::Result<Plc<P, E, S>> {
-        mlzlog::init(self.logfile_base, &self.name,
-                     mlzlog::Settings { show_appname: false,
-                                        debug: self.debug_logging,
-                                        ..Default::default() })
-            .context("setting up logging")?;
-
-        let channels = if let Some(addr) = self.server_addr {
-            let (w_from_plc, r_from_plc) = unbounded();
-            let (w_to_plc, r_to_plc) = unbounded();
-            S::start(&addr, w_to_plc, r_from_plc)
-                .context("starting external server")?;
-            Some((r_to_plc, w_from_plc))
-        } else {
-            None
+                 S: Server>(mut self, cfg: PC) -> anyhow::Result<Plc<P, E, S>> {
+        self.init_logging()?;
+        let view = self.resolve_process_view::<P>()?;
+        let process_vars = match &view {
+            Some(view) => view.map_vars(&P::image_vars()),
+            None => P::image_vars(),
         };
+        let metrics = Arc::new(Metrics::new(Duration::from_nanos(self.period())));
+        let servers = self.start_servers::<S, E>(view, process_vars, metrics.clone())?;
 
         let mut master = ec::Master::open(self.master_id.unwrap_or(0),
                                           ec::MasterAccess::ReadWrite)
@@ -181,52 +391,337 @@ impl PlcBuilder {
             bail!("domain size mismatch: real {} != assumed {}", domain_size, P::size());
         }
 
+        let recorder = match &self.record_path {
+            Some(path) => Some(Recorder::create(path, P::size(), E::size())?),
+            None => None,
+        };
+
         master.set_application_time(1)
             .context("setting application time")?;  // 0 is not good
         master.activate()
             .context("activating master")?;
         info!("PLC: EtherCAT master activated");
 
+        poll_al_states(self.master_id.unwrap_or(0), P::get_slave_ids().len(), metrics.clone());
+
         Ok(Plc {
             master,
             domain,
-            server_channel: channels,
-            sleep: 1_000_000_000 / self.cycle_freq.unwrap_or(1000) as u64,
+            servers,
+            recorder,
+            metrics,
+            sleep: self.period(),
             _types: PhantomData,
         })
     }
 }
 
-pub type ServerChannels<X> = (Receiver<Request<X>>, Sender<Response<X>>);
-
-pub fn data_exchange<E: ExternImage, X: std::fmt::Debug>(chan: &mut ServerChannels<X>, ext: &mut E) {
-    while let Ok(mut req) = chan.0.try_recv() {
-        let mut done = false;
-        debug!("PLC sim got request: {:?}", req);
-        let data = ext.cast();
-        let resp = if req.addr + req.count > E::size() {
-            Response::Error(req, 2)
-        } else {
-            let from = req.addr;
-            let to = from + req.count;
-            if let Some(ref mut values) = req.write {
-                // write request
-                data[from..to].copy_from_slice(values);
-                let values = req.write.take().unwrap();
-                // let a PLC cycle run after a write request
-                done = true;
-                Response::Ok(req, values)
-            } else {
-                // read request
-                Response::Ok(req, data[from..to].to_vec())
+/// Update the AL states of the slaves in the metrics once per second.  This
+/// uses its own read-only master handle, to keep the ioctls out of the cycle.
+fn poll_al_states(master_id: u32, slave_count: usize, metrics: Arc<Metrics>) {
+    thread::spawn(move || {
+        let master = match ec::Master::open(master_id, ec::MasterAccess::ReadOnly) {
+            Ok(master) => master,
+            Err(e) => return warn!("could not open master to poll slave states: {}", e),
+        };
+        loop {
+            let states = (0..slave_count).map(|pos| {
+                master.get_slave_info(ec::SlavePos::from(pos as u16))
+                      .map_or(0, |info| info.al_state as u8)
+            }).collect();
+            metrics.set_al_states(states);
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+}
+
+/// Record the timing of the cycle that started at `started`, and wait until
+/// the next cycle is due.
+fn wait_for_next_cycle(metrics: &Metrics, cycle_start: &mut Instant, started: Instant, sleep: u64) {
+    let now = Instant::now();
+    let jitter = started.saturating_duration_since(*cycle_start);
+    *cycle_start += Duration::from_nanos(sleep);
+    metrics.record_cycle(jitter, now - started, *cycle_start <= now);
+    if *cycle_start > now {
+        thread::sleep(*cycle_start - now);
+    }
+}
+
+/// A selection of process image variables that is exposed to clients.
+struct ProcessView {
+    segments: Vec<Range<usize>>,
+}
+
+impl ProcessView {
+    fn extract(&self, data: &[u8]) -> Vec<u8> {
+        let mut view = Vec::new();
+        for segment in &self.segments {
+            view.extend_from_slice(&data[segment.clone()]);
+            if view.len() % 2 == 1 {
+                view.push(0);
+            }
+        }
+        view
+    }
+
+    /// Translate variables to their addresses in the view.  Variables not
+    /// in the view are omitted.
+    fn map_vars(&self, vars: &[VarInfo]) -> Vec<VarInfo> {
+        let mut result = Vec::new();
+        let mut view_offset = 0;
+        for segment in &self.segments {
+            for var in vars {
+                let range = var.range();
+                if range.start >= segment.start && range.end <= segment.end {
+                    result.push(VarInfo {
+                        offset: view_offset + range.start - segment.start,
+                        ..var.clone()
+                    });
+                }
+            }
+            view_offset += segment.len() + segment.len() % 2;
+        }
+        result
+    }
+}
+
+/// Restrictions on client writes, shared by all servers.
+struct Access {
+    /// Extern image ranges that clients may not write.
+    protected: Vec<Range<usize>>,
+    locks: Locks,
+}
+
+impl Access {
+    /// Whether a write request would change any protected byte.
+    fn write_protected<X>(&self, req: &Request<X>) -> bool {
+        self.protected.iter().any(|range| req.writes_to(range))
+    }
+}
+
+/// The PLC side of a server's channels, independent of its request type.
+trait Endpoint {
+    /// Handle pending requests, adding applied writes to `writes`.
+    fn exchange(&mut self, access: &mut Access, ext: &mut [u8], process: Option<&[u8]>,
+                writes: &mut Vec<AppliedWrite>);
+}
+
+/// All servers of the PLC.
+struct Servers {
+    channels: Vec<Box<dyn Endpoint>>,
+    view: Option<ProcessView>,
+    access: Access,
+}
+
+impl Servers {
+    /// Handle pending requests from all servers, returning the applied
+    /// writes.
+    ///
+    /// Requests for the process image area are answered from `process`, if
+    /// given; clients can only read it.
+    fn exchange<E: ExternImage>(&mut self, ext: &mut E, process: Option<&[u8]>) -> Vec<AppliedWrite> {
+        let mut writes = Vec::new();
+        let view_data;
+        let process = match (&self.view, process) {
+            (Some(view), Some(data)) => {
+                view_data = view.extract(data);
+                Some(&view_data[..])
             }
+            (_, process) => process,
         };
+        for chan in &mut self.channels {
+            chan.exchange(&mut self.access, ext.cast(), process, &mut writes);
+        }
+        writes
+    }
+}
+
+/// Apply a write request to the image.
+fn apply_write<X>(data: &mut [u8], req: &Request<X>, values: &[u8]) -> AppliedWrite {
+    let from = req.addr;
+    let to = from + req.count;
+    if let Some(mask) = &req.mask {
+        for ((byte, value), mask) in data[from..to].iter_mut().zip(values).zip(mask) {
+            *byte = (*byte & !mask) | (value & mask);
+        }
+    } else {
+        data[from..to].copy_from_slice(values);
+    }
+    AppliedWrite {
+        client: req.hid, addr: from, data: data[from..to].to_vec(), mask: req.mask.clone()
+    }
+}
+
+/// Whether a request writes to bytes already written in this cycle.
+fn overlaps_writes<X>(req: &Request<X>, writes: &[AppliedWrite]) -> bool {
+    writes.iter().any(|w| req.writes_to(&(w.addr..w.addr + w.data.len())))
+}
+
+/// The extern image in shared memory, which receives write requests from
+/// local processes.
+struct SharedImage {
+    segment: Segment,
+    /// Client ID for all writes from the segment.
+    hid: usize,
+    deferred: Option<Request<()>>,
+}
+
+impl Endpoint for SharedImage {
+    fn exchange(&mut self, access: &mut Access, data: &mut [u8], _process: Option<&[u8]>,
+                writes: &mut Vec<AppliedWrite>) {
+        let hid = self.hid;
+        let segment = &self.segment;
+        let next = || segment.next_write().map(|(addr, values, mask)| Request {
+            hid, area: Area::Extern, addr, count: values.len(), write: Some(values),
+            mask: Some(mask), read_back: None, lock: None, extra: ()
+        });
+        while let Some(req) = self.deferred.take().or_else(next) {
+            if overlaps_writes(&req, writes) {
+                self.deferred = Some(req);
+                break;
+            }
+            if req.addr + req.count > data.len() || access.write_protected(&req) ||
+                !access.locks.may_write(&req)
+            {
+                debug!("rejecting write from shared memory: {:?}", req);
+                segment.reject();
+                continue;
+            }
+            let values = req.write.as_deref().unwrap_or_default();
+            writes.push(apply_write(data, &req, values));
+        }
+        segment.publish(data);
+    }
+}
+
+struct ServerChannels<X> {
+    requests: Receiver<Request<X>>,
+    responses: Sender<Response<X>>,
+    /// Write requests whose read back is due after the next cycle.
+    pending: Vec<Request<X>>,
+    /// Read requests that are answered after the next cycle.
+    samples: Vec<Request<X>>,
+    /// A request that is handled in the next cycle.
+    deferred: Option<Request<X>>,
+}
+
+impl<X: std::fmt::Debug> ServerChannels<X> {
+    fn respond(&self, resp: Response<X>) {
         debug!("PLC sim response: {:?}", resp);
-        if let Err(e) = chan.1.send(resp) {
-            warn!("could not send back response: {}", e);
+        // the server keeps the number of outstanding requests within the
+        // channel capacity, so this doesn't block the cycle
+        match self.responses.try_send(resp) {
+            Ok(()) => (),
+            Err(TrySendError::Full(resp)) => warn!("response queue full, dropping {:?}", resp),
+            Err(TrySendError::Disconnected(_)) => warn!("could not send back response: server gone"),
         }
-        if done {
-            break;
+    }
+}
+
+impl<X: std::fmt::Debug> Endpoint for ServerChannels<X> {
+    /// All queued requests are handled in one go, except that a write to
+    /// bytes already written in this cycle waits for the next cycle, so that
+    /// the cycle code sees every written value.
+    fn exchange(&mut self, access: &mut Access, data: &mut [u8], process: Option<&[u8]>,
+                writes: &mut Vec<AppliedWrite>) {
+        let size = data.len();
+
+        // first, finish requests with read back, now that a cycle has run
+        let read_back = |req: Request<X>| {
+            let (from, count) = req.read_back.unwrap_or_default();
+            let source = match req.area {
+                Area::Extern => &data[..],
+                Area::Process => process.unwrap_or(&[]),
+            };
+            match source.get(from..from + count) {
+                Some(values) => Response::Ok(req, values.to_vec()),
+                None => Response::Error(req, 2),
+            }
+        };
+        for req in std::mem::take(&mut self.pending) {
+            self.respond(read_back(req));
+        }
+        // clients can keep several reads in flight to get a sample of every
+        // cycle, so only one is answered per client and area
+        let mut sampled = Vec::new();
+        for req in std::mem::take(&mut self.samples) {
+            if sampled.contains(&(req.hid, req.area)) {
+                self.samples.push(req);
+            } else {
+                sampled.push((req.hid, req.area));
+                self.respond(read_back(req));
+            }
+        }
+
+        while let Some(mut req) = self.deferred.take().or_else(|| self.requests.try_recv().ok()) {
+            if overlaps_writes(&req, writes) {
+                self.deferred = Some(req);
+                break;
+            }
+            debug!("PLC sim got request: {:?}", req);
+            if let Some(op) = req.lock {
+                let range = req.addr..req.addr + req.count;
+                let resp = if req.area != Area::Extern || !access.locks.allows(op) {
+                    Response::Error(req, 1)
+                } else if range.end > size {
+                    Response::Error(req, 2)
+                } else if !access.locks.apply(req.hid, range, op) {
+                    Response::Error(req, 6)
+                } else {
+                    Response::Ok(req, Vec::new())
+                };
+                self.respond(resp);
+                continue;
+            }
+            if req.area == Area::Process {
+                let process = process.unwrap_or(&[]);
+                let read_back_invalid = req.read_back.map_or(false, |(addr, count)| {
+                    addr + count > process.len()
+                });
+                let resp = if req.write.is_some() {
+                    Response::Error(req, 1)
+                } else if req.addr + req.count > process.len() || read_back_invalid {
+                    Response::Error(req, 2)
+                } else if req.read_back.is_some() {
+                    self.samples.push(req);
+                    continue;
+                } else {
+                    let values = process[req.addr..req.addr + req.count].to_vec();
+                    Response::Ok(req, values)
+                };
+                self.respond(resp);
+                continue;
+            }
+            let read_back_invalid = req.read_back.map_or(false, |(addr, count)| addr + count > size);
+            let resp = if req.addr + req.count > size || read_back_invalid {
+                Response::Error(req, 2)
+            } else if req.write.is_some() && access.write_protected(&req) {
+                debug!("rejecting write to protected range: {:?}", req);
+                Response::Error(req, 2)
+            } else if req.write.is_some() && !access.locks.may_write(&req) {
+                debug!("rejecting write to range locked by another client: {:?}", req);
+                Response::Error(req, 6)
+            } else {
+                if let Some(values) = req.write.take() {
+                    // write request
+                    writes.push(apply_write(data, &req, &values));
+                    // let a PLC cycle run before reading back
+                    if req.read_back.is_some() {
+                        self.pending.push(req);
+                        continue;
+                    }
+                    Response::Ok(req, values)
+                } else if req.read_back.is_some() {
+                    // read request to be answered after the next cycle
+                    self.samples.push(req);
+                    continue;
+                } else {
+                    // read request
+                    let values = data[req.addr..req.addr + req.count].to_vec();
+                    Response::Ok(req, values)
+                }
+            };
+            self.respond(resp);
         }
     }
 }
@@ -236,52 +731,75 @@ pub struct Plc<P, E, S: Server> {
     master: ec::Master,
     domain: ec::DomainIdx,
     sleep:  u64,
-    server_channel: Option<ServerChannels<S::Extra>>,
-    _types: PhantomData<(P, E)>,
+    servers: Option<Servers>,
+    recorder: Option<Recorder>,
+    metrics: Arc<Metrics>,
+    _types: PhantomData<(P, E, S)>,
 }
 
 impl<P: ProcessImage, E: ExternImage, S: Server> Plc<P, E, S> {
     pub fn run<F>(&mut self, mut cycle_fn: F)
     where F: FnMut(&mut P, &mut E)
+    {
+        self.run_with_changes(|data, ext, _| cycle_fn(data, ext))
+    }
+
+    /// Like `run`, but also pass the client writes applied to the extern
+    /// image since the previous cycle to the cycle function.
+    pub fn run_with_changes<F>(&mut self, mut cycle_fn: F)
+    where F: FnMut(&mut P, &mut E, &Changes)
     {
         let mut ext = E::default();
+        let mut writes = Vec::new();
         let mut cycle_start = Instant::now();
 
         loop {
+            let started = Instant::now();
+
             // process data exchange + logic
-            if let Err(e) = self.single_cycle(&mut cycle_fn, &mut ext) {
+            if let Err(e) = self.single_cycle(&mut cycle_fn, &mut ext, &mut writes) {
                 // XXX: logging unconditionally here is bad, could repeat endlessly
                 warn!("error in cycle: {}", e);
             }
 
-            // external data exchange
-            if let Some(chan) = self.server_channel.as_mut() {
-                data_exchange(chan, &mut ext);
+            // external data exchange; writes are kept until the cycle
+            // function has seen them
+            if let Some(servers) = self.servers.as_mut() {
+                let process = self.master.domain_data(self.domain).ok();
+                let applied = servers.exchange(&mut ext, process.as_deref());
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder.add_writes(&applied);
+                }
+                writes.extend(applied);
             }
 
-            // wait until next cycle
-            let now = Instant::now();
-            cycle_start += Duration::from_nanos(self.sleep);
-            if cycle_start > now {
-                thread::sleep(cycle_start - now);
-            }
+            wait_for_next_cycle(&self.metrics, &mut cycle_start, started, self.sleep);
         }
     }
 
-    fn single_cycle<F>(&mut self, mut cycle_fn: F, ext: &mut E) -> anyhow::Result<()>
-    where F: FnMut(&mut P, &mut E)
+    fn single_cycle<F>(&mut self, mut cycle_fn: F, ext: &mut E,
+                       writes: &mut Vec<AppliedWrite>) -> anyhow::Result<()>
+    where F: FnMut(&mut P, &mut E, &Changes)
     {
         self.master.receive()
             .context("receiving Ethercat data")?;
         self.master.domain(self.domain).process()
             .context("processing domain data")?;
 
-        // XXX: check working counters periodically, etc.
-        // println!("master state: {:?}", self.master.state());
-        // println!("domain state: {:?}", self.master.domain(self.domain).state());
+        if let Ok(state) = self.master.domain(self.domain).state() {
+            self.metrics.set_working_counter(state.wc_state as u8, state.working_counter);
+        }
 
-        let data = P::cast(self.master.domain_data(self.domain)?);
-        cycle_fn(data, ext);
+        let data = self.master.domain_data(self.domain)?;
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.begin_cycle(data, ext.cast()) {
+                warn!("error writing recording, stopping: {}", e);
+                self.recorder = None;
+            }
+        }
+        let changes = Changes::new(ext, writes);
+        cycle_fn(P::cast(data), ext, &changes);
+        writes.clear();
 
         self.master.domain(self.domain).queue()
             .context("queueing new domain data")?;
@@ -295,32 +813,189 @@ impl<P: ProcessImage, E: ExternImage, S: Server> Plc<P, E, S> {
 /// An object similar to Plc, but not connected to an Ethercat master.
 pub struct PlcSimulator<E, S: Server> {
     sleep: u64,
-    server_channel: Option<(Receiver<Request<S::Extra>>, Sender<Response<S::Extra>>)>,
-    _types: PhantomData<E>,
+    servers: Option<Servers>,
+    metrics: Arc<Metrics>,
+    _types: PhantomData<(E, S)>,
 }
 
 impl<E: ExternImage, S: Server> PlcSimulator<E, S> {
     pub fn run<F>(&mut self, mut cycle_fn: F)
     where F: FnMut(&mut E)
+    {
+        self.run_with_changes(|ext, _| cycle_fn(ext))
+    }
+
+    /// Like `run`, but also pass the client writes applied to the extern
+    /// image since the previous cycle to the cycle function.
+    pub fn run_with_changes<F>(&mut self, mut cycle_fn: F)
+    where F: FnMut(&mut E, &Changes)
     {
         let mut ext = E::default();
+        let mut writes = Vec::new();
         let mut cycle_start = Instant::now();
 
         loop {
+            let started = Instant::now();
+
             // simulate a cycle
-            cycle_fn(&mut ext);
+            let changes = Changes::new(&ext, &writes);
+            cycle_fn(&mut ext, &changes);
 
             // data exchange with upper layer
-            if let Some(chan) = self.server_channel.as_mut() {
-                data_exchange(chan, &mut ext);
+            if let Some(servers) = self.servers.as_mut() {
+                writes = servers.exchange(&mut ext, None);
             }
 
-            // wait until next cycle
-            let now = Instant::now();
-            cycle_start += Duration::from_nanos(self.sleep);
-            if cycle_start > now {
-                thread::sleep(cycle_start - now);
+            wait_for_next_cycle(&self.metrics, &mut cycle_start, started, self.sleep);
+        }
+    }
+}
+
+
+/// An object similar to Plc, with a simulated process image that is wired
+/// up according to a `Wiring` description.
+pub struct PlantSimulator<P, E, S: Server> {
+    data: Vec<u8>,
+    wiring: Wiring,
+    sleep: u64,
+    servers: Option<Servers>,
+    metrics: Arc<Metrics>,
+    _types: PhantomData<(P, E, S)>,
+}
+
+impl<P: ProcessImage, E: ExternImage, S: Server> PlantSimulator<P, E, S> {
+    pub fn run<F>(&mut self, mut cycle_fn: F)
+    where F: FnMut(&mut P, &mut E)
+    {
+        self.run_with_changes(|data, ext, _| cycle_fn(data, ext))
+    }
+
+    /// Like `run`, but also pass the client writes applied to the extern
+    /// image since the previous cycle to the cycle function.
+    pub fn run_with_changes<F>(&mut self, mut cycle_fn: F)
+    where F: FnMut(&mut P, &mut E, &Changes)
+    {
+        let mut ext = E::default();
+        let mut writes = Vec::new();
+        let mut cycle_start = Instant::now();
+
+        loop {
+            let started = Instant::now();
+
+            // simulate the plant, then run the logic
+            self.wiring.apply(&mut self.data);
+            let changes = Changes::new(&ext, &writes);
+            cycle_fn(P::cast(&mut self.data), &mut ext, &changes);
+
+            // data exchange with upper layer
+            if let Some(servers) = self.servers.as_mut() {
+                writes = servers.exchange(&mut ext, Some(&self.data));
             }
+
+            wait_for_next_cycle(&self.metrics, &mut cycle_start, started, self.sleep);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::LockOp;
+    use super::*;
+
+    fn request(hid: usize, addr: usize, count: usize, write: Option<Vec<u8>>) -> Request<()> {
+        Request { hid, area: Area::Extern, addr, count, write, mask: None, read_back: None,
+                  lock: None, extra: () }
+    }
+
+    fn channels(capacity: usize) -> (ServerChannels<()>, Sender<Request<()>>,
+                                      Receiver<Response<()>>) {
+        let (w_to_plc, r_to_plc) = bounded(QUEUE_SIZE);
+        let (w_from_plc, r_from_plc) = bounded(capacity);
+        let chan = ServerChannels { requests: r_to_plc, responses: w_from_plc,
+                                    pending: Vec::new(), samples: Vec::new(), deferred: None };
+        (chan, w_to_plc, r_from_plc)
+    }
+
+    fn access() -> Access {
+        Access { protected: Vec::new(), locks: Locks::new(Duration::from_secs(1), false) }
+    }
+
+    #[test]
+    fn batched_requests() {
+        let (mut chan, to_plc, from_plc) = channels(QUEUE_SIZE);
+        let mut access = access();
+        let mut data = [0; 8];
+        let mut writes = Vec::new();
+
+        // all requests are handled in one cycle, up to a write to bytes
+        // that were already written in this cycle
+        for req in [request(1, 0, 2, Some(vec![1, 2])), request(2, 0, 4, None),
+                    request(2, 6, 2, Some(vec![7, 8])), request(2, 1, 2, Some(vec![5, 6])),
+                    request(1, 4, 2, None)] {
+            to_plc.send(req).unwrap();
+        }
+        chan.exchange(&mut access, &mut data, None, &mut writes);
+        assert_eq!(writes.iter().map(|w| (w.client, w.addr)).collect::<Vec<_>>(),
+                   [(1, 0), (2, 6)]);
+        let values = from_plc.try_iter().map(|resp| match resp {
+            Response::Ok(_, values) => values,
+            Response::Error(req, code) => panic!("error {} for {:?}", code, req),
+        }).collect::<Vec<_>>();
+        assert_eq!(values, [vec![1, 2], vec![1, 2, 0, 0], vec![7, 8]]);
+
+        // the rest follows in the next cycle, in order
+        writes.clear();
+        chan.exchange(&mut access, &mut data, None, &mut writes);
+        assert_eq!(writes.len(), 1);
+        assert_eq!(data, [1, 5, 6, 0, 0, 0, 7, 8]);
+        assert_eq!(from_plc.len(), 2);
+    }
+
+    #[test]
+    fn dropped_responses() {
+        let (mut chan, to_plc, from_plc) = channels(2);
+        let mut access = access();
+        let mut data = [0; 8];
+
+        // a server that doesn't keep within the channel capacity loses
+        // responses, but can't block the cycle
+        for addr in 0..3 {
+            to_plc.send(request(1, addr, 1, None)).unwrap();
         }
+        chan.exchange(&mut access, &mut data, None, &mut Vec::new());
+        assert_eq!(from_plc.len(), 2);
+        assert!(to_plc.is_empty());
+
+        // neither can a server that has gone away
+        to_plc.send(request(1, 0, 1, None)).unwrap();
+        drop(from_plc);
+        chan.exchange(&mut access, &mut data, None, &mut Vec::new());
+        assert!(to_plc.is_empty());
+    }
+
+    #[test]
+    fn lock_requests() {
+        let (mut chan, to_plc, from_plc) = channels(QUEUE_SIZE);
+        let mut access = access();
+        let mut data = [0; 8];
+        let lock = |hid, op| Request { lock: Some(op), ..request(hid, 0, 4, None) };
+        let mut exchange = |req| {
+            to_plc.send(req).unwrap();
+            chan.exchange(&mut access, &mut data, None, &mut Vec::new());
+            match from_plc.try_recv().unwrap() {
+                Response::Ok(..) => 0,
+                Response::Error(_, code) => code,
+            }
+        };
+
+        assert_eq!(exchange(lock(1, LockOp::Claim(None))), 0);
+        assert_eq!(exchange(lock(2, LockOp::Claim(None))), 6);
+        assert_eq!(exchange(request(2, 2, 1, Some(vec![1]))), 6);
+        // force release is refused unless allowed
+        assert_eq!(exchange(lock(2, LockOp::ForceRelease)), 1);
+        assert_eq!(exchange(request(2, 2, 1, Some(vec![1]))), 6);
+        // the servers release all locks of a client that disconnects
+        assert_eq!(exchange(lock(1, LockOp::ReleaseAll)), 0);
+        assert_eq!(exchange(request(2, 2, 1, Some(vec![1]))), 0);
     }
 }
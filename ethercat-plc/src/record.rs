@@ -0,0 +1,439 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Recording of process data and offline replay through a cycle function.
+//!
+//! A recording is a binary file starting with a header (magic, version,
+//! domain size and extern image size), followed by one record per cycle:
+//!
+//! * timestamp in nanoseconds since the Unix epoch (u64)
+//! * the domain data as received, before the cycle function ran
+//! * the extern image before the cycle function ran
+//! * the number of server writes applied after the cycle function ran and
+//!   before the next cycle (u32), and for each write its address and length
+//!   (u32 each), a flag (u8) whether the write was masked, the data and, if
+//!   masked, the mask
+//!
+//! All integers are little endian.  Since a record is only complete when the
+//! next cycle begins, the last record may be missing or truncated if the PLC
+//! was killed; replay ignores it in that case.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, LE};
+use log::*;
+
+use crate::changes::Changes;
+use crate::image::{ExternImage, ProcessImage};
+
+const MAGIC: &[u8; 8] = b"ECPLCREC";
+const VERSION: u16 = 2;
+
+/// A write from a remote client that was applied to the extern image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedWrite {
+    /// ID of the client connection that sent the write.  This is not
+    /// recorded, and always 0 in replayed records.
+    pub client: usize,
+    pub addr: usize,
+    /// The resulting data at `addr`.
+    pub data: Vec<u8>,
+    /// The bits of `data` that the client wrote, if it didn't write all.
+    pub mask: Option<Vec<u8>>,
+}
+
+impl AppliedWrite {
+    /// The byte ranges that the client wrote.  Bytes that are covered by the
+    /// write, but completely masked out, are not included.
+    pub fn ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for (i, addr) in (self.addr..self.addr + self.data.len()).enumerate() {
+            if self.mask.as_ref().map_or(false, |mask| mask[i] == 0) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(range) if range.end == addr => range.end += 1,
+                _ => ranges.push(addr..addr + 1),
+            }
+        }
+        ranges
+    }
+
+    /// Whether the client wrote any byte in the given range.
+    pub fn touches(&self, range: Range<usize>) -> bool {
+        let from = range.start.max(self.addr);
+        let to = range.end.min(self.addr + self.data.len());
+        match &self.mask {
+            None => from < to,
+            Some(mask) => (from..to).any(|addr| mask[addr - self.addr] != 0),
+        }
+    }
+
+    /// Apply the write to image data, returning false if it doesn't fit.
+    pub fn apply(&self, data: &mut [u8]) -> bool {
+        let target = match data.get_mut(self.addr..self.addr + self.data.len()) {
+            Some(target) => target,
+            None => return false,
+        };
+        match &self.mask {
+            None => target.copy_from_slice(&self.data),
+            Some(mask) => for ((byte, value), mask) in target.iter_mut().zip(&self.data).zip(mask) {
+                *byte = (*byte & !mask) | (value & mask);
+            }
+        }
+        true
+    }
+}
+
+/// Writes a recording of each PLC cycle to a file.
+pub struct Recorder {
+    file: BufWriter<File>,
+    /// The record of the last cycle that ran, empty before the first one.
+    record: Vec<u8>,
+    /// Writes applied since that cycle.
+    nwrites: u32,
+    writes: Vec<u8>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>, domain_size: usize, ext_size: usize) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut file = BufWriter::new(File::create(path)
+            .with_context(|| format!("creating recording {}", path.display()))?);
+        let mut header = MAGIC.to_vec();
+        header.write_u16::<LE>(VERSION)?;
+        header.write_u32::<LE>(domain_size as u32)?;
+        header.write_u32::<LE>(ext_size as u32)?;
+        file.write_all(&header)
+            .with_context(|| format!("writing recording {}", path.display()))?;
+        Ok(Self { file, record: Vec::new(), nwrites: 0, writes: Vec::new() })
+    }
+
+    /// Write out the record of the previous cycle, and start a new record
+    /// with this cycle's input data.  The file is flushed, so that only the
+    /// current record is lost if the PLC is killed.
+    pub fn begin_cycle(&mut self, domain: &[u8], ext: &[u8]) -> io::Result<()> {
+        self.write_record()?;
+        self.file.flush()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.record.extend_from_slice(&(now.as_nanos() as u64).to_le_bytes());
+        self.record.extend_from_slice(domain);
+        self.record.extend_from_slice(ext);
+        Ok(())
+    }
+
+    /// Add writes applied after the cycle function ran to the current record.
+    /// If cycles fail before they begin, this collects the writes of all
+    /// exchanges until the next cycle that runs.
+    pub fn add_writes(&mut self, writes: &[AppliedWrite]) {
+        if self.record.is_empty() {
+            // applied before the first cycle, part of its extern image
+            return;
+        }
+        self.nwrites += writes.len() as u32;
+        for write in writes {
+            self.writes.extend_from_slice(&(write.addr as u32).to_le_bytes());
+            self.writes.extend_from_slice(&(write.data.len() as u32).to_le_bytes());
+            self.writes.push(write.mask.is_some() as u8);
+            self.writes.extend_from_slice(&write.data);
+            if let Some(mask) = &write.mask {
+                self.writes.extend_from_slice(mask);
+            }
+        }
+    }
+
+    /// Write out the current record and flush the file.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_record()?;
+        self.file.flush()
+    }
+
+    fn write_record(&mut self) -> io::Result<()> {
+        if self.record.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&self.record)?;
+        self.file.write_all(&self.nwrites.to_le_bytes())?;
+        self.file.write_all(&self.writes)?;
+        self.record.clear();
+        self.writes.clear();
+        self.nwrites = 0;
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("error writing recording: {}", e);
+        }
+    }
+}
+
+/// The data recorded for a single cycle.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub time: Duration,
+    pub domain: Vec<u8>,
+    pub ext: Vec<u8>,
+    pub writes: Vec<AppliedWrite>,
+}
+
+/// Reads back the records of a recording.
+pub struct Replay {
+    file: BufReader<File>,
+    domain_size: usize,
+    ext_size: usize,
+}
+
+impl Replay {
+    /// Open a recording, checking that it matches the given image types.
+    pub fn open<P: ProcessImage, E: ExternImage>(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(File::open(path)
+            .with_context(|| format!("opening recording {}", path.display()))?);
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic).context("reading recording header")?;
+        if &magic != MAGIC {
+            bail!("{} is not a PLC recording", path.display());
+        }
+        let version = file.read_u16::<LE>()?;
+        if version != VERSION {
+            bail!("unsupported recording version {}", version);
+        }
+        let domain_size = file.read_u32::<LE>()? as usize;
+        let ext_size = file.read_u32::<LE>()? as usize;
+        if domain_size != P::size() || ext_size != E::size() {
+            bail!("recording image sizes {}/{} don't match {}/{}",
+                  domain_size, ext_size, P::size(), E::size());
+        }
+        Ok(Self { file, domain_size, ext_size })
+    }
+
+    fn read_record(&mut self) -> anyhow::Result<Option<Record>> {
+        let mut stamp = [0; 8];
+        match self.file.read_exact(&mut stamp) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            res => res.context("reading record")?,
+        }
+        match self.read_record_data(Duration::from_nanos(LE::read_u64(&stamp))) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                warn!("recording ends with an incomplete record, ignoring it");
+                Ok(None)
+            }
+            res => res.map(Some).context("reading record"),
+        }
+    }
+
+    fn read_record_data(&mut self, time: Duration) -> io::Result<Record> {
+        let mut domain = vec![0; self.domain_size];
+        self.file.read_exact(&mut domain)?;
+        let mut ext = vec![0; self.ext_size];
+        self.file.read_exact(&mut ext)?;
+        let nwrites = self.file.read_u32::<LE>()?;
+        let mut writes = Vec::new();
+        for _ in 0..nwrites {
+            let addr = self.file.read_u32::<LE>()? as usize;
+            let len = self.file.read_u32::<LE>()? as usize;
+            // check before allocating, the file may be corrupt
+            if addr.checked_add(len).map_or(true, |end| end > self.ext_size) {
+                return Err(io::Error::new(ErrorKind::InvalidData, format!(
+                    "write of {} bytes at {} outside of the extern image", len, addr)));
+            }
+            let mut data = vec![0; len];
+            let masked = self.file.read_u8()? != 0;
+            self.file.read_exact(&mut data)?;
+            let mask = if masked {
+                let mut mask = vec![0; data.len()];
+                self.file.read_exact(&mut mask)?;
+                Some(mask)
+            } else {
+                None
+            };
+            writes.push(AppliedWrite { client: 0, addr, data, mask });
+        }
+        Ok(Record { time, domain, ext, writes })
+    }
+}
+
+impl Iterator for Replay {
+    type Item = anyhow::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Result of replaying a recording.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplaySummary {
+    /// Number of cycles that were replayed.
+    pub cycles: usize,
+    /// Number of cycles where the extern image at cycle start differed from
+    /// the one in the recording.
+    pub diverged: usize,
+}
+
+/// Feed a recording back through a cycle function.
+///
+/// The extern image is initialized from the first record; afterwards it
+/// evolves through the cycle function and the recorded server writes, and is
+/// compared to the recorded one at the start of each cycle.
+pub fn replay<P, E, F>(path: impl AsRef<Path>, mut cycle_fn: F) -> anyhow::Result<ReplaySummary>
+where P: ProcessImage, E: ExternImage, F: FnMut(&mut P, &mut E)
+{
+    replay_with_changes(path, |data, ext, _| cycle_fn(data, ext))
+}
+
+/// Like `replay`, but also pass the recorded server writes to the cycle
+/// function, like `Plc::run_with_changes` does.
+pub fn replay_with_changes<P, E, F>(path: impl AsRef<Path>, mut cycle_fn: F)
+                                    -> anyhow::Result<ReplaySummary>
+where P: ProcessImage, E: ExternImage, F: FnMut(&mut P, &mut E, &Changes)
+{
+    replay_domain::<P, E, _>(path, |domain, ext, changes| cycle_fn(P::cast(domain), ext, changes))
+}
+
+/// Like `replay_with_changes`, but pass the domain data as bytes.  This is
+/// the common part of `replay` and the golden trace tests.
+pub(crate) fn replay_domain<P, E, F>(path: impl AsRef<Path>, mut cycle_fn: F)
+                                     -> anyhow::Result<ReplaySummary>
+where P: ProcessImage, E: ExternImage, F: FnMut(&mut [u8], &mut E, &Changes)
+{
+    let mut ext = E::default();
+    let mut writes = Vec::new();
+    let mut summary = ReplaySummary::default();
+
+    for record in Replay::open::<P, E>(path)? {
+        let mut record = record?;
+        if summary.cycles == 0 {
+            ext.cast().copy_from_slice(&record.ext);
+        } else if ext.cast() != &record.ext[..] {
+            if summary.diverged == 0 {
+                warn!("replay: extern image diverged from recording in cycle {}",
+                      summary.cycles);
+            }
+            summary.diverged += 1;
+        }
+
+        let changes = Changes::new(&ext, &writes);
+        cycle_fn(&mut record.domain, &mut ext, &changes);
+
+        let data = ext.cast();
+        for write in &record.writes {
+            if !write.apply(data) {
+                bail!("invalid write in recording at cycle {}", summary.cycles);
+            }
+        }
+        writes = record.writes;
+        summary.cycles += 1;
+    }
+    Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use ethercat_derive::{ExternImage, ProcessImage};
+    use crate::beckhoff::EL1008;
+    use super::*;
+
+    #[repr(C, packed)]
+    #[derive(ProcessImage)]
+    struct Plant {
+        inp: EL1008,
+    }
+
+    #[repr(C)]
+    #[derive(Default, ExternImage)]
+    struct Ext {
+        count: u16,
+        value: u16,
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("ethercat-plc-record-{}-{}", std::process::id(), name))
+    }
+
+    fn write(addr: usize, data: &[u8], mask: Option<&[u8]>) -> AppliedWrite {
+        AppliedWrite { client: 3, addr, data: data.to_vec(), mask: mask.map(<[u8]>::to_vec) }
+    }
+
+    fn records(path: &Path) -> Vec<Record> {
+        Replay::open::<Plant, Ext>(path).unwrap().collect::<anyhow::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip");
+        let mut recorder = Recorder::create(&path, 1, 4).unwrap();
+        // writes before the first cycle are part of its extern image
+        recorder.add_writes(&[write(0, &[9], None)]);
+        recorder.begin_cycle(&[1], &[0, 0, 0, 0]).unwrap();
+        recorder.add_writes(&[write(2, &[5, 0], None)]);
+        recorder.add_writes(&[write(3, &[0xF0], Some(&[0x0F]))]);
+        recorder.begin_cycle(&[2], &[1, 0, 5, 0]).unwrap();
+        // the finished records are in the file while recording
+        assert_eq!(records(&path).len(), 1);
+        recorder.begin_cycle(&[3], &[2, 0, 5, 0]).unwrap();
+        drop(recorder);
+
+        let records = records(&path);
+        assert_eq!(records.iter().map(|r| (r.domain[0], r.ext.clone())).collect::<Vec<_>>(),
+                   [(1, vec![0, 0, 0, 0]), (2, vec![1, 0, 5, 0]), (3, vec![2, 0, 5, 0])]);
+        assert_eq!(records[0].writes, [
+            AppliedWrite { client: 0, ..write(2, &[5, 0], None) },
+            AppliedWrite { client: 0, ..write(3, &[0xF0], Some(&[0x0F])) },
+        ]);
+        assert!(records[1].writes.is_empty() && records[2].writes.is_empty());
+        assert!(records[0].time <= records[2].time);
+
+        // the cycle function counts, and the write of the value is replayed
+        let summary = replay::<Plant, Ext, _>(&path, |_, ext| ext.count += 1).unwrap();
+        assert_eq!((summary.cycles, summary.diverged), (3, 0));
+        let summary = replay::<Plant, Ext, _>(&path, |_, _| ()).unwrap();
+        assert_eq!((summary.cycles, summary.diverged), (3, 2));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn damaged_recordings() {
+        let path = temp_path("damaged");
+        let mut recorder = Recorder::create(&path, 1, 4).unwrap();
+        recorder.begin_cycle(&[1], &[0; 4]).unwrap();
+        recorder.add_writes(&[write(1, &[1, 2], None)]);
+        recorder.begin_cycle(&[2], &[0; 4]).unwrap();
+        recorder.finish().unwrap();
+        let complete = fs::read(&path).unwrap();
+        // header, and records of 8 + 1 + 4 + 4 bytes plus the write
+        assert_eq!(complete.len(), 18 + 17 + 11 + 17);
+
+        // a truncated last record is ignored
+        fs::write(&path, &complete[..complete.len() - 3]).unwrap();
+        assert_eq!(records(&path).len(), 1);
+
+        // a write outside of the extern image is an error, whatever its size
+        for (addr, len) in [(3, 2), (0, 0xFFFF_FFFF), (0xFFFF_FFFF, 1)] {
+            let mut corrupt = complete.clone();
+            LE::write_u32(&mut corrupt[18 + 17..], addr);
+            LE::write_u32(&mut corrupt[18 + 21..], len);
+            fs::write(&path, &corrupt).unwrap();
+            let err = Replay::open::<Plant, Ext>(&path).unwrap().next().unwrap().unwrap_err();
+            assert_eq!(err.downcast_ref::<io::Error>().unwrap().kind(), ErrorKind::InvalidData);
+        }
+
+        fs::write(&path, b"ECPLCREC\x01\x00").unwrap();
+        assert!(Replay::open::<Plant, Ext>(&path).is_err());
+        assert!(Recorder::create(&path, 2, 4).is_ok());
+        assert!(Replay::open::<Plant, Ext>(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
+
@@ -0,0 +1,1294 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! A SECoP server for the `TcpServer`, giving ECS clients access to the
+//! devices of a PLC that follows the MLZ specification (see `mlz_spec`).
+//!
+//! SECoP is a line-based protocol with JSON data.  The description of the
+//! SEC node is built from the devices listed by the PLC's indexer: the first
+//! connection that needs it queries the indexer through the extern image,
+//! one query per cycle, and the result is shared by all connections of the
+//! server.  Requests are answered once the description is available.  If
+//! reading it fails, the next new connection tries again.  Devices of types
+//! without a struct in `mlz_spec` are skipped.
+//!
+//! Every device becomes a `Drivable` module, named like the device in lower
+//! case, with the parameters
+//!
+//! * `value` (read-only)
+//! * `target`: changing it writes the target and sets the status to `START`
+//! * `status` (read-only): the state of the status word, with the names of
+//!   the set aux bits from the indexer as text
+//! * the other members of the device struct (except `aux`) as custom
+//!   parameters, e.g. `_param1` of a `FlatOutput1`
+//!
+//! and the `stop` command, which sets the status to `STOP` if the device is
+//! busy.  Limits and unit of `value` and `target` are also taken from the
+//! indexer.
+//!
+//! Replies to `change` and `do` contain the values after the PLC has run
+//! one cycle with the written data.  Active modules send updates at the end
+//! of each cycle in which a parameter changed.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, NativeEndian as NE};
+use log::*;
+
+use crate::image::{ImageVars, VarInfo};
+use crate::json::{from_json, int_range, span, to_json, Value};
+use crate::mlz_spec::*;
+use crate::server::{Area, Handler, PlcInfo, Request, Response};
+
+/// Reply to the `*IDN?` request.
+const IDENT: &str = "ISSE&SINE2020,SECoP,V2019-09-16,v1.0";
+/// Maximum length of a request line.
+const MAX_LINE: usize = 1 << 16;
+/// Maximum number of requests received while the description is read.
+const MAX_PENDING: usize = 1024;
+/// Updates are skipped while more output than this is waiting to be sent.
+const MAX_BACKLOG: usize = 1 << 16;
+/// Number of sample requests kept in flight for updates.  The PLC answers
+/// one per cycle, so the others bridge delays of the server.
+const SAMPLES_IN_FLIGHT: usize = 2;
+/// Number of times an unanswered indexer query is repeated.
+const MAX_TRIES: usize = 20;
+
+/// Byte address of the indexer offset in the extern image, after the magic
+/// number.
+const OFFSET_ADDR: usize = 4;
+/// Set by the PLC in the request word when the data answers the request.
+const ANSWERED: u16 = 0x8000;
+/// Byte offset of the name in the data of the device info.
+const INFO_NAME: usize = 20;
+
+/// Units of the indexer, by the low byte of the unit code.
+const UNITS: &[&str] = &[
+    "", "V", "A", "W", "m", "g", "Hz", "T", "K", "degC", "degF", "bar", "deg", "Ohm",
+    "m/s", "m^2/s", "m^3/s", "s", "cts", "bar/s", "bar/s^2", "F", "H",
+];
+
+/// Configuration for the `SecopHandler`.
+#[derive(Debug, Clone, Default)]
+pub struct SecopConfig {
+    /// The equipment ID of the SEC node; by default the PLC name given by
+    /// the indexer.
+    pub equipment_id: Option<String>,
+    /// The description read from the indexer, shared by all connections of
+    /// the server.  Keep the default.
+    pub description: SharedDescription,
+}
+
+/// The SEC node description, read once for all connections that share it.
+#[derive(Clone, Default)]
+pub struct SharedDescription(Arc<Mutex<Discovery>>);
+
+impl fmt::Debug for SharedDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SharedDescription")
+    }
+}
+
+impl SharedDescription {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Discovery> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Default)]
+enum Discovery {
+    #[default]
+    Unknown,
+    /// Being read from the indexer by the connection with the given ID.
+    Querying(usize, Indexer),
+    Ready(Arc<Node>),
+    Failed(String),
+}
+
+/// The request data kept for the reply.
+#[derive(Debug, Default)]
+pub struct SecopExtra(Op);
+
+#[derive(Debug, Default, Clone, Copy)]
+enum Op {
+    /// For requests made by the server, e.g. to release locks.
+    #[default]
+    None,
+    /// A query of the indexer.
+    Query,
+    /// Waiting for another connection to read the description.
+    Wait,
+    Read(usize, Param),
+    Change(usize, Param),
+    /// Reading the status before a stop, or (if set) writing it.
+    Stop(usize, bool),
+    /// The initial values for activating one or all modules.
+    Activate(Option<usize>),
+    /// A sample for updates of the active modules.
+    Sample,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Param {
+    Value,
+    Status,
+    Target,
+    Custom(usize),
+}
+
+/// Error class and text of an error reply.
+type Error = (&'static str, String);
+
+fn error(class: &'static str, text: impl Into<String>) -> Error {
+    (class, text.into())
+}
+
+/// The error for a PLC error code.
+fn plc_error(code: u8, write: bool) -> Error {
+    match code {
+        2 if write => error("ReadOnly", "write to a protected variable"),
+        4 => error("CommunicationFailed", "PLC has gone away"),
+        6 => error("IsBusy", "variable is locked by another client"),
+        _ => error("InternalError", format!("PLC error {}", code)),
+    }
+}
+
+/// Write a message; the specifier is left out if empty and without data,
+/// and is `.` if empty with data.
+fn send(output: &mut Vec<u8>, action: &str, specifier: &str, data: Option<Value>) {
+    output.extend_from_slice(action.as_bytes());
+    if !specifier.is_empty() || data.is_some() {
+        output.push(b' ');
+        output.extend_from_slice(if specifier.is_empty() { b"." } else { specifier.as_bytes() });
+    }
+    if let Some(data) = data {
+        output.push(b' ');
+        output.extend_from_slice(data.to_string().as_bytes());
+    }
+    output.push(b'\n');
+}
+
+fn send_error(output: &mut Vec<u8>, action: &str, specifier: &str, (class, text): Error) {
+    debug!("error reply to {} {}: {}", action, specifier, text);
+    send(output, &format!("error_{}", action), specifier,
+         Some(Value::Array(vec![Value::str(class), Value::str(text), Value::object([])])));
+}
+
+/// A value with the timestamp qualifier.
+fn qualified(value: Value) -> Value {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Value::Array(vec![value, Value::object([("t", Value::num(format!("{:.6}", now.as_secs_f64())))])])
+}
+
+/// A string from indexer data, which is padded with zero bytes.
+fn string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().into()
+}
+
+/// The unit for a unit code of the indexer, which has the decimal exponent
+/// in the high byte.
+fn unit(code: u16) -> String {
+    let name = UNITS.get((code & 0xFF) as usize).copied().unwrap_or_default();
+    let prefix = match (code >> 8) as i8 {
+        0 => "",
+        -9 => "n",
+        -6 => "u",
+        -3 => "m",
+        -2 => "c",
+        3 => "k",
+        6 => "M",
+        9 => "G",
+        exp => return format!("1e{} {}", exp, name),
+    };
+    format!("{}{}", prefix, name)
+}
+
+/// A valid SECoP identifier made from a device name.
+fn identifier(name: &str) -> String {
+    let mut id = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect::<String>();
+    if !id.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        id.insert_str(0, "dev");
+    }
+    id
+}
+
+/// A step of reading the description from the indexer.
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    /// Reading the magic number and the offset of the indexer.
+    Header,
+    Size,
+    PlcName,
+    Version,
+    Device(u8),
+    DeviceName(u8),
+    Aux(u8, u8),
+}
+
+impl Step {
+    /// The request word of the step's query.
+    fn request(self) -> u16 {
+        let (dev, info) = match self {
+            Step::Header | Step::Size => (0, 1),
+            Step::PlcName => (0, 4),
+            Step::Version => (0, 5),
+            Step::Device(n) => (n, 0),
+            Step::DeviceName(n) => (n, 4),
+            Step::Aux(n, bit) => (n, 0x10 + bit),
+        };
+        (info as u16) << 8 | dev as u16
+    }
+}
+
+/// A device as described by the indexer.
+#[derive(Debug, Default)]
+struct Device {
+    name: String,
+    typcode: u16,
+    offset: usize,
+    unit: u16,
+    absmin: f32,
+    absmax: f32,
+    /// Names of the aux bits.
+    aux: [String; 8],
+}
+
+/// Reads the description from the indexer, one query per cycle.
+struct Indexer {
+    /// Byte offset of the indexer (its request word) in the extern image.
+    offset: usize,
+    /// Size of the indexer in bytes, including the request word.
+    size: usize,
+    step: Step,
+    steps: VecDeque<Step>,
+    /// Whether the next request only reads the indexer after a cycle,
+    /// instead of writing the query again.
+    wait: bool,
+    tries: usize,
+    plc_name: String,
+    version: String,
+    devices: Vec<Device>,
+}
+
+impl Indexer {
+    fn new() -> Self {
+        Self { offset: 0, size: 4, step: Step::Header, steps: VecDeque::new(), wait: false,
+               tries: 0, plc_name: String::new(), version: String::new(), devices: Vec::new() }
+    }
+
+    /// The request for the current step.  Queries are written to the
+    /// request word, and the indexer is read back after a cycle.
+    fn request(&self, hid: usize) -> Request<SecopExtra> {
+        let (addr, count, write, read_back) = match self.step {
+            Step::Header => (0, OFFSET_ADDR + 2, None, None),
+            _ if self.wait => (self.offset, self.size, None, Some((self.offset, self.size))),
+            step => (self.offset, 2, Some(step.request().to_ne_bytes().to_vec()),
+                     Some((self.offset, self.size))),
+        };
+        Request { hid, area: Area::Extern, addr, count, write, mask: None, read_back,
+                  lock: None, extra: SecopExtra(Op::Query) }
+    }
+
+    /// Process the reply to the current request.  Returns whether the
+    /// description is complete.
+    fn answer(&mut self, reply: Result<Vec<u8>, u8>) -> Result<bool, String> {
+        if let Step::Header = self.step {
+            let data = reply.map_err(|_| "extern image too small for an indexer")?;
+            if NE::read_f32(&data) != MAGIC {
+                return Err("no magic number in the extern image".into());
+            }
+            self.offset = NE::read_u16(&data[OFFSET_ADDR..]) as usize;
+            self.step = Step::Size;
+            return Ok(false);
+        }
+        let query = self.step.request();
+        let data = match reply {
+            Ok(data) if NE::read_u16(&data) == query | ANSWERED => data,
+            reply => {
+                self.tries += 1;
+                if self.tries > MAX_TRIES {
+                    return Err(format!("no answer to query {:#06x}", query));
+                }
+                // wait if the PLC hasn't answered yet, or rejected the write
+                // (e.g. because another client has a lock); write the query
+                // again if another client has overwritten it
+                self.wait = reply.map_or(true, |data| NE::read_u16(&data) == query);
+                return Ok(false);
+            }
+        };
+        self.tries = 0;
+        self.wait = false;
+        let data = &data[2..];
+        match self.step {
+            Step::Size => {
+                self.size = NE::read_u16(data) as usize;
+                if self.size < 2 + INFO_NAME {
+                    return Err(format!("indexer size {} too small", self.size));
+                }
+                self.steps.extend([Step::PlcName, Step::Version, Step::Device(1)]);
+            }
+            Step::PlcName => self.plc_name = string(data),
+            Step::Version => self.version = string(data),
+            Step::Device(n) => {
+                let typcode = NE::read_u16(data);
+                // the device list ends with an empty entry
+                if typcode != 0 {
+                    let flags = NE::read_u16(&data[8..]) as u32 | (NE::read_u16(&data[10..]) as u32) << 16;
+                    self.devices.push(Device {
+                        name: string(&data[INFO_NAME..]),
+                        typcode,
+                        offset: NE::read_u16(&data[4..]) as usize,
+                        unit: NE::read_u16(&data[6..]),
+                        absmin: NE::read_f32(&data[12..]),
+                        absmax: NE::read_f32(&data[16..]),
+                        aux: Default::default(),
+                    });
+                    self.steps.push_back(Step::DeviceName(n));
+                    self.steps.extend((0..8).filter(|bit| flags & 1 << bit != 0)
+                                            .map(|bit| Step::Aux(n, bit)));
+                    if n < 255 {
+                        self.steps.push_back(Step::Device(n + 1));
+                    }
+                }
+            }
+            Step::DeviceName(_) => {
+                // the device info only has room for short names
+                let name = string(data);
+                if let Some(dev) = self.devices.last_mut().filter(|_| !name.is_empty()) {
+                    dev.name = name;
+                }
+            }
+            Step::Aux(_, bit) => {
+                if let Some(dev) = self.devices.last_mut() {
+                    dev.aux[bit as usize] = string(data);
+                }
+            }
+            Step::Header => (),
+        }
+        match self.steps.pop_front() {
+            Some(step) => {
+                self.step = step;
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+}
+
+/// A device of the PLC as a SECoP module.
+struct Module {
+    name: String,
+    description: String,
+    /// Name of the device struct in `mlz_spec`.
+    type_name: &'static str,
+    /// Byte range of the device in the extern image.
+    range: Range<usize>,
+    value: VarInfo,
+    target: VarInfo,
+    status: VarInfo,
+    /// The aux word of devices that have one; otherwise the aux bits are
+    /// the low byte of the status word.
+    aux: Option<VarInfo>,
+    /// Other members, as custom parameters.
+    custom: Vec<VarInfo>,
+    aux_names: [String; 8],
+    /// Limits of value and target, if given by the indexer.
+    limits: Option<(f32, f32)>,
+    unit: String,
+}
+
+impl Module {
+    fn new(dev: Device, extern_vars: &[VarInfo]) -> Result<Self, String> {
+        let (type_name, layout) = match dev.typcode {
+            DISCRETE_OUTPUT => ("DiscreteOutput", DiscreteOutput::vars()),
+            FLAT_OUTPUT_1 => ("FlatOutput1", FlatOutput1::vars()),
+            code => return Err(format!("unsupported type code {:#06x}", code)),
+        };
+        // the device struct must be in the extern image at the given offset
+        let mut vars = Vec::new();
+        for var in layout {
+            let offset = dev.offset + var.offset;
+            match extern_vars.iter().find(|v| v.offset == offset && v.ty == var.ty) {
+                Some(v) => vars.push(VarInfo { offset, read_only: v.read_only, ..var }),
+                None => return Err(format!("no {} variable at offset {} for {}",
+                                           var.ty.name(), offset, var.name)),
+            }
+        }
+        let range = span(&vars);
+        let mut take = |name| vars.iter().position(|v: &VarInfo| v.name == name)
+                                         .map(|i| vars.remove(i));
+        let (value, target, status, aux) = (take("value"), take("target"), take("status"), take("aux"));
+        let (value, target, status) = match (value, target, status) {
+            (Some(value), Some(target), Some(status)) => (value, target, status),
+            _ => return Err(format!("{} is not a drivable device", type_name)),
+        };
+        let limits = if dev.absmin < dev.absmax { Some((dev.absmin, dev.absmax)) } else { None };
+        Ok(Self {
+            name: identifier(&dev.name),
+            description: format!("{} ({})", dev.name, type_name),
+            type_name, range, value, target, status, aux, custom: vars, aux_names: dev.aux,
+            limits, unit: unit(dev.unit),
+        })
+    }
+
+    fn params(&self) -> impl Iterator<Item = Param> {
+        [Param::Value, Param::Status, Param::Target].into_iter()
+            .chain((0..self.custom.len()).map(Param::Custom))
+    }
+
+    fn param_name(&self, param: Param) -> String {
+        match param {
+            Param::Value => "value".into(),
+            Param::Status => "status".into(),
+            Param::Target => "target".into(),
+            Param::Custom(i) => format!("_{}", self.custom[i].name),
+        }
+    }
+
+    fn param(&self, name: &str) -> Option<Param> {
+        self.params().find(|&param| self.param_name(param) == name)
+    }
+
+    fn var(&self, param: Param) -> &VarInfo {
+        match param {
+            Param::Value => &self.value,
+            Param::Status => &self.status,
+            Param::Target => &self.target,
+            Param::Custom(i) => &self.custom[i],
+        }
+    }
+
+    /// The value of a parameter from image data starting at `base`.
+    fn value(&self, param: Param, data: &[u8], base: usize) -> Value {
+        if param != Param::Status {
+            return to_json(self.var(param), data, base);
+        }
+        let status = NE::read_u16(&data[self.status.offset - base..]);
+        let aux = match &self.aux {
+            Some(aux) => NE::read_u16(&data[aux.offset - base..]),
+            None => status,
+        };
+        let (code, state) = match status & 0xF000 {
+            RESET => (300, "resetting"),
+            IDLE => (100, "idle"),
+            DISABLED => (0, "disabled"),
+            WARN => (200, "warning"),
+            START => (300, "starting"),
+            BUSY => (300, "busy"),
+            STOP => (300, "stopping"),
+            ERROR => (400, "error"),
+            _ => (400, "invalid state"),
+        };
+        let text = (0..8).filter(|bit| aux & 1 << bit != 0).map(|bit| {
+            match &self.aux_names[bit] {
+                name if name.is_empty() => format!("aux bit {}", bit),
+                name => name.clone(),
+            }
+        }).collect::<Vec<_>>().join(", ");
+        Value::Array(vec![Value::num(code), Value::str(if text.is_empty() { state.into() } else { text })])
+    }
+
+    /// Whether the device is busy, according to the status in image data
+    /// starting at `base`.
+    fn busy(&self, data: &[u8], base: usize) -> bool {
+        matches!(NE::read_u16(&data[self.status.offset - base..]) & 0xF000, START | BUSY)
+    }
+
+    /// The data info of a numeric parameter, with the limits and unit of
+    /// the device for value and target.
+    fn datainfo(&self, param: Param) -> Value {
+        let var = self.var(param);
+        let (limits, unit) = match param {
+            Param::Value | Param::Target => (self.limits, &*self.unit),
+            _ => (None, ""),
+        };
+        let mut info = match (int_range(var.ty), limits) {
+            (None, None) => vec![("type", Value::str("double"))],
+            (None, Some((min, max))) => vec![("type", Value::str("double")),
+                                             ("min", Value::num(min)), ("max", Value::num(max))],
+            (Some((min, max)), limits) => {
+                let (min, max) = limits.map_or((min, max), |(lo, hi)| {
+                    (min.max(lo.ceil() as i128), max.min(hi.floor() as i128))
+                });
+                vec![("type", Value::str("int")), ("min", Value::num(min)), ("max", Value::num(max))]
+            }
+        };
+        if !unit.is_empty() {
+            info.push(("unit", Value::str(unit)));
+        }
+        Value::object(info)
+    }
+
+    fn describe(&self) -> Value {
+        let accessible = |description: &str, datainfo, readonly| Value::object([
+            ("description", Value::str(description)), ("datainfo", datainfo),
+            ("readonly", Value::Bool(readonly)),
+        ]);
+        let status_info = Value::object([
+            ("type", Value::str("tuple")),
+            ("members", Value::Array(vec![
+                Value::object([("type", Value::str("enum")), ("members", Value::object([
+                    ("DISABLED", Value::num(0)), ("IDLE", Value::num(100)),
+                    ("WARN", Value::num(200)), ("BUSY", Value::num(300)),
+                    ("ERROR", Value::num(400)),
+                ]))]),
+                Value::object([("type", Value::str("string"))]),
+            ])),
+        ]);
+        let mut accessibles = vec![
+            ("value".into(), accessible("current value", self.datainfo(Param::Value), true)),
+            ("status".into(), accessible("current status", status_info, true)),
+            ("target".into(), accessible("target value", self.datainfo(Param::Target),
+                                         self.target.read_only)),
+        ];
+        for (i, var) in self.custom.iter().enumerate() {
+            accessibles.push((self.param_name(Param::Custom(i)),
+                              accessible(&format!("{} of the device", var.name),
+                                         self.datainfo(Param::Custom(i)), var.read_only)));
+        }
+        accessibles.push(("stop".into(), Value::object([
+            ("description", Value::str("stop the device")),
+            ("datainfo", Value::object([("type", Value::str("command"))])),
+        ])));
+        Value::object([
+            ("description", Value::str(&self.description)),
+            ("interface_classes", Value::Array(vec![Value::str("Drivable")])),
+            ("implementation", Value::str(format!("ethercat_plc.mlz_spec.{}", self.type_name))),
+            ("accessibles", Value::Object(accessibles)),
+        ])
+    }
+
+    /// Encode a value to write to a parameter.
+    fn encode(&self, param: Param, value: &Value) -> Result<Vec<u8>, Error> {
+        let var = self.var(param);
+        let v = match value {
+            Value::Number(_) => value.as_f64().unwrap_or(f64::NAN),
+            _ => return Err(error("WrongType", format!("expected a number, got {}", value))),
+        };
+        if let (Param::Target, Some((min, max))) = (param, self.limits) {
+            if v < min as f64 || v > max as f64 {
+                return Err(error("RangeError", format!("{} is outside of [{}, {}]", value, min, max)));
+            }
+        }
+        from_json(var.ty, value).ok_or_else(|| match value.as_i128() {
+            None if !var.ty.is_float() => error("WrongType", format!("expected an integer, got {}", value)),
+            _ => error("RangeError", format!("{} is out of range for {}", value, var.ty.name())),
+        })
+    }
+
+    /// A write of variables of the module, which is read back after a cycle.
+    fn write(&self, hid: usize, writes: &[(&VarInfo, Vec<u8>)], op: Op) -> Request<SecopExtra> {
+        let range = span(writes.iter().map(|(var, _)| *var));
+        let mut data = vec![0; range.len()];
+        let mut mask = vec![0; range.len()];
+        for (var, bytes) in writes {
+            let part = var.offset - range.start..var.offset - range.start + bytes.len();
+            data[part.clone()].copy_from_slice(bytes);
+            mask[part].fill(0xFF);
+        }
+        let mask = if mask.iter().all(|&m| m == 0xFF) { None } else { Some(mask) };
+        Request { hid, area: Area::Extern, addr: range.start, count: range.len(), write: Some(data),
+                  mask, read_back: Some((self.range.start, self.range.len())), lock: None,
+                  extra: SecopExtra(op) }
+    }
+}
+
+/// The byte range covering the given modules.
+fn covering<'a>(modules: impl IntoIterator<Item = &'a Module>) -> Range<usize> {
+    let (start, end) = modules.into_iter().fold((usize::MAX, 0), |(start, end), module| {
+        (start.min(module.range.start), end.max(module.range.end))
+    });
+    start..end
+}
+
+/// The SEC node, as read from the indexer.
+struct Node {
+    description: Value,
+    modules: Vec<Module>,
+}
+
+impl Node {
+    fn new(indexer: Indexer, info: &PlcInfo, config: &SecopConfig) -> Self {
+        let mut modules = Vec::<Module>::new();
+        for dev in indexer.devices {
+            let name = dev.name.clone();
+            match Module::new(dev, &info.extern_vars) {
+                Ok(module) if modules.iter().any(|m| m.name == module.name) =>
+                    warn!("skipping device {}: duplicate module name {}", name, module.name),
+                Ok(module) => modules.push(module),
+                Err(e) => warn!("skipping device {}: {}", name, e),
+            }
+        }
+        let plc_name = if indexer.plc_name.is_empty() { &info.name } else { &indexer.plc_name };
+        let equipment_id = config.equipment_id.as_deref().unwrap_or(plc_name);
+        let description = Value::object([
+            ("equipment_id", Value::str(equipment_id)),
+            ("description", Value::str(format!("PLC {} {}", plc_name, indexer.version).trim())),
+            ("firmware", Value::str(format!("ethercat-plc {}", env!("CARGO_PKG_VERSION")))),
+            ("modules", Value::Object(modules.iter().map(|m| (m.name.clone(), m.describe()))
+                                                     .collect())),
+        ]);
+        debug!("read {} modules from the indexer", modules.len());
+        Self { description, modules }
+    }
+
+    /// The module and parameter (or command) name of a specifier.
+    fn accessible<'a>(&self, specifier: &'a str) -> Result<(usize, &'a str), Error> {
+        let (module, accessible) = specifier.split_once(':').ok_or_else(|| {
+            error("ProtocolError", format!("expected module:accessible, got {:?}", specifier))
+        })?;
+        Ok((self.module(module)?, accessible))
+    }
+
+    fn module(&self, name: &str) -> Result<usize, Error> {
+        self.modules.iter().position(|m| m.name == name)
+            .ok_or_else(|| error("NoSuchModule", format!("no module {}", name)))
+    }
+
+    fn param(&self, specifier: &str) -> Result<(usize, Param), Error> {
+        let (module, name) = self.accessible(specifier)?;
+        match self.modules[module].param(name) {
+            Some(param) => Ok((module, param)),
+            None => Err(error("NoSuchParameter", format!("no parameter {}", specifier))),
+        }
+    }
+
+    fn specifier(&self, module: usize, param: Param) -> String {
+        format!("{}:{}", self.modules[module].name, self.modules[module].param_name(param))
+    }
+}
+
+enum State {
+    /// The description hasn't been needed yet.
+    Unknown,
+    /// Waiting for the description, read by this or another connection.
+    Waiting,
+    Ready(Arc<Node>),
+    Failed(String),
+}
+
+/// Handles the SECoP protocol for one connection.
+pub struct SecopHandler {
+    hid: usize,
+    info: Arc<PlcInfo>,
+    config: SecopConfig,
+    state: State,
+    /// Lines received while the description is being read.
+    pending: VecDeque<String>,
+    /// Requests made while handling a response, for the next `poll`.
+    requests: Vec<Request<SecopExtra>>,
+    /// The last sent parameter values of the active modules.
+    active: Vec<Option<Vec<Value>>>,
+    samples_in_flight: usize,
+    /// Requests answered without the PLC, and errors among them.
+    direct: (usize, usize),
+}
+
+impl SecopHandler {
+    fn node(&self) -> Result<&Node, Error> {
+        match &self.state {
+            State::Ready(node) => Ok(node),
+            State::Failed(e) => Err(error("CommunicationFailed",
+                                          format!("could not read the indexer: {}", e))),
+            _ => Err(error("InternalError", "description not available")),
+        }
+    }
+
+    /// Take the shared description if it is available.  Otherwise, start
+    /// reading it or wait for the connection that does, and return the
+    /// request for that.
+    fn discover(&mut self) -> Option<Request<SecopExtra>> {
+        let description = self.config.description.clone();
+        let mut shared = description.lock();
+        match &*shared {
+            Discovery::Ready(node) => self.ready(node.clone()),
+            Discovery::Failed(e) => self.state = State::Failed(e.clone()),
+            Discovery::Unknown => {
+                let indexer = Indexer::new();
+                let req = indexer.request(self.hid);
+                *shared = Discovery::Querying(self.hid, indexer);
+                return Some(req);
+            }
+            // check again after the next cycle
+            Discovery::Querying(..) => return Some(Request {
+                hid: self.hid, area: Area::Extern, addr: 0, count: 2, write: None, mask: None,
+                read_back: Some((0, 2)), lock: None, extra: SecopExtra(Op::Wait)
+            }),
+        }
+        None
+    }
+
+    fn ready(&mut self, node: Arc<Node>) {
+        self.active = node.modules.iter().map(|_| None).collect();
+        self.state = State::Ready(node);
+    }
+
+    /// Handle the received lines, once the description is available.
+    fn process(&mut self, requests: &mut Vec<Request<SecopExtra>>, output: &mut Vec<u8>) {
+        if let State::Unknown = self.state {
+            self.state = State::Waiting;
+            requests.extend(self.discover());
+        }
+        if let State::Waiting = self.state {
+            return;
+        }
+        while let Some(line) = self.pending.pop_front() {
+            debug!("got request: {}", line);
+            let mut parts = line.splitn(3, ' ');
+            let action = parts.next().unwrap_or_default();
+            let specifier = parts.next().unwrap_or_default();
+            let data = parts.next();
+            match self.handle(action, specifier, data, output) {
+                Ok(Some(req)) => requests.push(req),
+                Ok(None) => self.direct.0 += 1,
+                Err(e) => {
+                    self.direct.0 += 1;
+                    self.direct.1 += 1;
+                    send_error(output, action, specifier, e);
+                }
+            }
+        }
+    }
+
+    /// Handle a request, returning the request for the PLC if one is
+    /// needed, or writing the reply directly.
+    fn handle(&mut self, action: &str, specifier: &str, data: Option<&str>,
+              output: &mut Vec<u8>) -> Result<Option<Request<SecopExtra>>, Error> {
+        let hid = self.hid;
+        let read = |range: &Range<usize>, op| Request {
+            hid, area: Area::Extern, addr: range.start, count: range.len(), write: None,
+            mask: None, read_back: None, lock: None, extra: SecopExtra(op)
+        };
+        match action {
+            "*IDN?" => send(output, IDENT, "", None),
+            "ping" => send(output, "pong", specifier, Some(qualified(Value::Null))),
+            "describe" => send(output, "describing", ".", Some(self.node()?.description.clone())),
+            "activate" => {
+                let node = self.node()?;
+                let module = if specifier.is_empty() { None } else { Some(node.module(specifier)?) };
+                let range = match module {
+                    Some(module) => node.modules[module].range.clone(),
+                    None if node.modules.is_empty() => {
+                        send(output, "active", "", None);
+                        return Ok(None);
+                    }
+                    None => covering(&node.modules),
+                };
+                return Ok(Some(read(&range, Op::Activate(module))));
+            }
+            "deactivate" => {
+                match specifier {
+                    "" => self.active.iter_mut().for_each(|active| *active = None),
+                    name => {
+                        let module = self.node()?.module(name)?;
+                        self.active[module] = None;
+                    }
+                }
+                send(output, "inactive", specifier, None);
+            }
+            "read" => {
+                let node = self.node()?;
+                let (module, param) = node.param(specifier)?;
+                return Ok(Some(read(&node.modules[module].range, Op::Read(module, param))));
+            }
+            "change" => {
+                let node = self.node()?;
+                let (index, param) = node.param(specifier)?;
+                let module = &node.modules[index];
+                let var = module.var(param);
+                if matches!(param, Param::Value | Param::Status) || var.read_only {
+                    return Err(error("ReadOnly", format!("{} is read-only", specifier)));
+                }
+                let value = Value::parse(data.unwrap_or_default())
+                    .map_err(|e| error("ProtocolError", format!("invalid JSON: {}", e)))?;
+                let mut writes = vec![(var, module.encode(param, &value)?)];
+                if param == Param::Target {
+                    writes.push((&module.status, START.to_ne_bytes().to_vec()));
+                }
+                return Ok(Some(module.write(hid, &writes, Op::Change(index, param))));
+            }
+            "do" => {
+                let node = self.node()?;
+                let (module, command) = node.accessible(specifier)?;
+                if command != "stop" {
+                    return Err(error("NoSuchCommand", format!("no command {}", specifier)));
+                }
+                if !matches!(data.map(str::trim), None | Some("null")) {
+                    return Err(error("WrongType", "stop has no argument"));
+                }
+                return Ok(Some(read(&node.modules[module].range, Op::Stop(module, false))));
+            }
+            _ => return Err(error("ProtocolError", format!("unknown action {:?}", action))),
+        }
+        Ok(None)
+    }
+
+    /// Process the reply to an indexer query.
+    fn queried(&mut self, reply: Result<Vec<u8>, u8>) {
+        let description = self.config.description.clone();
+        let mut shared = description.lock();
+        let indexer = match &mut *shared {
+            Discovery::Querying(hid, indexer) if *hid == self.hid => indexer,
+            _ => return,
+        };
+        match indexer.answer(reply) {
+            Ok(false) => self.requests.push(indexer.request(self.hid)),
+            Ok(true) => {
+                if let Discovery::Querying(_, indexer) = std::mem::take(&mut *shared) {
+                    let node = Arc::new(Node::new(indexer, &self.info, &self.config));
+                    *shared = Discovery::Ready(node.clone());
+                    drop(shared);
+                    self.ready(node);
+                }
+            }
+            Err(e) => {
+                warn!("could not read the description from the indexer: {}", e);
+                *shared = Discovery::Failed(e.clone());
+                self.state = State::Failed(e);
+            }
+        }
+    }
+
+    /// Handle the lines received while waiting for the description.
+    fn resume(&mut self, output: &mut Vec<u8>) {
+        if !matches!(self.state, State::Waiting) {
+            let mut requests = std::mem::take(&mut self.requests);
+            self.process(&mut requests, output);
+            self.requests = requests;
+        }
+    }
+
+    /// Send updates of the active modules for a sample starting at `base`.
+    fn sampled(&mut self, data: &[u8], base: usize, output: &mut Vec<u8>) {
+        let node = match &self.state {
+            State::Ready(node) => node,
+            _ => return,
+        };
+        let sample = base..base + data.len();
+        for (index, (module, last)) in node.modules.iter().zip(&mut self.active).enumerate() {
+            let last = match last {
+                Some(last) => last,
+                None => continue,
+            };
+            // the sample can be from before the module was activated
+            if output.len() > MAX_BACKLOG || module.range.start < sample.start ||
+                module.range.end > sample.end {
+                continue;
+            }
+            for (param, last) in module.params().zip(last.iter_mut()) {
+                let value = module.value(param, data, base);
+                if *last != value {
+                    send(output, "update", &node.specifier(index, param), Some(qualified(value.clone())));
+                    *last = value;
+                }
+            }
+        }
+    }
+}
+
+impl Handler for SecopHandler {
+    type Extra = SecopExtra;
+    type Config = SecopConfig;
+
+    fn new(hid: usize, info: Arc<PlcInfo>, config: &SecopConfig) -> Self {
+        let mut shared = config.description.lock();
+        if let Discovery::Failed(_) = *shared {
+            *shared = Discovery::Unknown;
+        }
+        drop(shared);
+        Self { hid, info, config: config.clone(), state: State::Unknown, pending: VecDeque::new(),
+               requests: Vec::new(), active: Vec::new(), samples_in_flight: 0, direct: (0, 0) }
+    }
+
+    fn receive(&mut self, input: &[u8], requests: &mut Vec<Request<SecopExtra>>,
+               output: &mut Vec<u8>) -> io::Result<usize> {
+        let mut pos = 0;
+        while let Some(n) = input[pos..].iter().position(|&b| b == b'\n') {
+            let line = &input[pos..pos + n];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            pos += n + 1;
+            if !line.is_empty() {
+                self.pending.push_back(String::from_utf8_lossy(line).into_owned());
+            }
+        }
+        if input.len() - pos > MAX_LINE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "request line too long"));
+        }
+        if self.pending.len() > MAX_PENDING {
+            return Err(io::Error::new(ErrorKind::InvalidData, "too many requests"));
+        }
+        self.process(requests, output);
+        Ok(pos)
+    }
+
+    fn respond(&mut self, response: Response<SecopExtra>, output: &mut Vec<u8>) {
+        let (req, reply) = match response {
+            Response::Ok(req, data) => (req, Ok(data)),
+            Response::Error(req, code) => (req, Err(code)),
+        };
+        let base = req.read_back.map_or(req.addr, |(addr, _)| addr);
+        let op = req.extra.0;
+        if let Op::Query = op {
+            self.queried(reply);
+            return self.resume(output);
+        }
+        if let Op::Wait = op {
+            if let Some(req) = self.discover() {
+                self.requests.push(req);
+            }
+            return self.resume(output);
+        }
+        if let Op::Sample = op {
+            self.samples_in_flight -= 1;
+            match reply {
+                Ok(data) => self.sampled(&data, base, output),
+                Err(code) => debug!("sample failed with error {}", code),
+            }
+            return;
+        }
+        let node = match &self.state {
+            State::Ready(node) => node,
+            _ => return,
+        };
+        let (action, specifier) = match op {
+            Op::Read(module, param) => ("read", node.specifier(module, param)),
+            Op::Change(module, param) => ("change", node.specifier(module, param)),
+            Op::Stop(module, _) => ("do", format!("{}:stop", node.modules[module].name)),
+            Op::Activate(module) => ("activate", module.map_or(String::new(),
+                                                               |m| node.modules[m].name.clone())),
+            _ => return,
+        };
+        let data = match reply {
+            Ok(data) => data,
+            Err(code) => return send_error(output, action, &specifier, plc_error(code, req.write.is_some())),
+        };
+        match op {
+            Op::Read(module, param) => {
+                let value = node.modules[module].value(param, &data, base);
+                send(output, "reply", &specifier, Some(qualified(value)));
+            }
+            Op::Change(module, param) => {
+                let value = node.modules[module].value(param, &data, base);
+                send(output, "changed", &specifier, Some(qualified(value)));
+            }
+            Op::Stop(index, false) if node.modules[index].busy(&data, base) => {
+                let module = &node.modules[index];
+                let req = module.write(self.hid, &[(&module.status, STOP.to_ne_bytes().to_vec())],
+                                       Op::Stop(index, true));
+                self.requests.push(req);
+            }
+            Op::Stop(..) => send(output, "done", &specifier, Some(qualified(Value::Null))),
+            Op::Activate(only) => {
+                for (index, module) in node.modules.iter().enumerate() {
+                    if only.map_or(false, |m| m != index) {
+                        continue;
+                    }
+                    let values = module.params().map(|param| module.value(param, &data, base))
+                                                .collect::<Vec<_>>();
+                    for (param, value) in module.params().zip(&values) {
+                        send(output, "update", &node.specifier(index, param),
+                             Some(qualified(value.clone())));
+                    }
+                    self.active[index] = Some(values);
+                }
+                send(output, "active", &specifier, None);
+            }
+            _ => (),
+        }
+    }
+
+    fn poll(&mut self, requests: &mut Vec<Request<SecopExtra>>) {
+        requests.append(&mut self.requests);
+        let node = match &self.state {
+            State::Ready(node) => node,
+            _ => return,
+        };
+        let range = covering(node.modules.iter().zip(&self.active)
+                                 .filter(|(_, active)| active.is_some())
+                                 .map(|(module, _)| module));
+        if range.start >= range.end {
+            return;
+        }
+        while self.samples_in_flight < SAMPLES_IN_FLIGHT {
+            self.samples_in_flight += 1;
+            requests.push(Request {
+                hid: self.hid, area: Area::Extern, addr: range.start, count: range.len(),
+                write: None, mask: None, read_back: Some((range.start, range.len())), lock: None,
+                extra: SecopExtra(Op::Sample)
+            });
+        }
+    }
+
+    fn direct_replies(&mut self) -> (usize, usize) {
+        std::mem::take(&mut self.direct)
+    }
+}
+
+impl Drop for SecopHandler {
+    fn drop(&mut self) {
+        // let another connection take over reading the description
+        let mut shared = self.config.description.lock();
+        if matches!(*shared, Discovery::Querying(hid, _) if hid == self.hid) {
+            *shared = Discovery::Unknown;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::image::VarType;
+    use super::*;
+
+    /// Byte offset and size of the indexer.
+    const INDEXER: usize = 8;
+    const INDEXER_SIZE: usize = 24;
+    /// Byte offset of the `DiscreteOutput`.
+    const MOTOR: usize = 32;
+
+    /// A PLC with an indexer listing one device.
+    struct Plc {
+        image: Vec<u8>,
+    }
+
+    impl Plc {
+        fn new() -> Self {
+            let mut image = vec![0; MOTOR + 6];
+            NE::write_f32(&mut image, MAGIC);
+            NE::write_u16(&mut image[OFFSET_ADDR..], INDEXER as u16);
+            NE::write_i16(&mut image[MOTOR..], 5);
+            NE::write_i16(&mut image[MOTOR + 2..], 5);
+            NE::write_u16(&mut image[MOTOR + 4..], IDLE);
+            Plc { image }
+        }
+
+        fn info() -> Arc<PlcInfo> {
+            Arc::new(PlcInfo {
+                name: "test".into(),
+                extern_vars: vec![
+                    VarInfo::new("magic", 0, VarType::F32),
+                    VarInfo::new("motor.value", MOTOR, VarType::I16),
+                    VarInfo::new("motor.target", MOTOR + 2, VarType::I16),
+                    VarInfo::new("motor.status", MOTOR + 4, VarType::U16),
+                ],
+                ..Default::default()
+            })
+        }
+
+        /// Answer the indexer request, as done by the PLC every cycle.
+        fn cycle(&mut self) {
+            let request = NE::read_u16(&self.image[INDEXER..]);
+            if request & ANSWERED != 0 {
+                return;
+            }
+            let mut data = [0; INDEXER_SIZE - 2];
+            match (request & 0xFF, request >> 8) {
+                (0, 1) => NE::write_u16(&mut data, INDEXER_SIZE as u16),
+                (0, 4) => data[..4].copy_from_slice(b"plc1"),
+                (1, 0) => {
+                    NE::write_u16(&mut data, DISCRETE_OUTPUT);
+                    NE::write_u16(&mut data[4..], MOTOR as u16);
+                    NE::write_u16(&mut data[6..], 0xFD04);
+                    data[8] = 0b10;
+                    NE::write_f32(&mut data[16..], 100.);
+                    data[INFO_NAME..].copy_from_slice(b"Mo");
+                }
+                (1, 4) => data[..5].copy_from_slice(b"Motor"),
+                (1, 0x11) => data[..6].copy_from_slice(b"moving"),
+                // no version, and no more devices
+                _ => (),
+            }
+            NE::write_u16(&mut self.image[INDEXER..], request | ANSWERED);
+            self.image[INDEXER + 2..INDEXER + INDEXER_SIZE].copy_from_slice(&data);
+        }
+
+        fn answer(&mut self, req: Request<SecopExtra>) -> Response<SecopExtra> {
+            let (addr, count) = req.read_back.unwrap_or((req.addr, req.count));
+            if addr + count > self.image.len() || req.addr + req.count > self.image.len() {
+                return Response::Error(req, 2);
+            }
+            if let Some(data) = &req.write {
+                for (i, byte) in data.iter().enumerate() {
+                    let mask = req.mask.as_ref().map_or(0xFF, |mask| mask[i]);
+                    let old = &mut self.image[req.addr + i];
+                    *old = *old & !mask | byte & mask;
+                }
+            }
+            self.cycle();
+            let data = self.image[addr..addr + count].to_vec();
+            Response::Ok(req, data)
+        }
+    }
+
+    struct Conn {
+        handler: SecopHandler,
+        requests: Vec<Request<SecopExtra>>,
+        output: Vec<u8>,
+    }
+
+    impl Conn {
+        fn new(hid: usize, config: &SecopConfig) -> Self {
+            Conn { handler: SecopHandler::new(hid, Plc::info(), config), requests: Vec::new(),
+                   output: Vec::new() }
+        }
+
+        fn send(&mut self, input: &str) {
+            let used = self.handler.receive(input.as_bytes(), &mut self.requests,
+                                            &mut self.output).unwrap();
+            assert_eq!(used, input.len());
+        }
+
+        /// The lines sent so far, with the data of values without the
+        /// timestamp.
+        fn lines(&mut self) -> Vec<String> {
+            let output = std::mem::take(&mut self.output);
+            String::from_utf8(output).unwrap().lines().map(|line| {
+                let mut parts = line.splitn(3, ' ');
+                let (action, specifier) = (parts.next().unwrap(), parts.next().unwrap_or(""));
+                match parts.next().map(|data| Value::parse(data).unwrap()) {
+                    Some(Value::Array(items)) if items.len() == 2 && items[1].get("t").is_some() =>
+                        format!("{} {} {}", action, specifier, items[0]),
+                    _ => line.into(),
+                }
+            }).collect()
+        }
+    }
+
+    /// Run PLC cycles, answering the requests of the connections.
+    fn run(plc: &mut Plc, conns: &mut [&mut Conn]) {
+        for _ in 0..20 {
+            for conn in conns.iter_mut() {
+                for req in std::mem::take(&mut conn.requests) {
+                    conn.handler.respond(plc.answer(req), &mut conn.output);
+                }
+                conn.handler.poll(&mut conn.requests);
+            }
+        }
+    }
+
+    #[test]
+    fn description() {
+        let mut plc = Plc::new();
+        let config = SecopConfig::default();
+        let (mut first, mut second) = (Conn::new(1, &config), Conn::new(2, &config));
+        first.send("*IDN?\ndescribe\n");
+        second.send("describe\n");
+        run(&mut plc, &mut [&mut first, &mut second]);
+        let lines = first.lines();
+        assert_eq!(lines[0], IDENT);
+        assert_eq!(lines[1..], second.lines());
+        let description = Value::parse(lines[1].strip_prefix("describing . ").unwrap()).unwrap();
+        assert_eq!(description.get("equipment_id"), Some(&Value::str("plc1")));
+        assert_eq!(description.get("description"), Some(&Value::str("PLC plc1")));
+        let motor = description.get("modules").and_then(|m| m.get("motor")).unwrap();
+        assert_eq!(motor.get("description"), Some(&Value::str("Motor (DiscreteOutput)")));
+        let target = motor.get("accessibles").and_then(|a| a.get("target")).unwrap();
+        assert_eq!(target.get("datainfo").unwrap().to_string(),
+                   r#"{"type":"int","min":0,"max":100,"unit":"mm"}"#);
+
+        // later connections take the description right away
+        let mut third = Conn::new(3, &config);
+        third.send("describe\n");
+        assert_eq!(third.lines(), lines[1..]);
+    }
+
+    #[test]
+    fn failed_description() {
+        let mut plc = Plc::new();
+        plc.image[0] = 0;
+        let config = SecopConfig { equipment_id: Some("node".into()), ..Default::default() };
+        let mut conn = Conn::new(1, &config);
+        conn.send("describe\nping x\nping\n");
+        run(&mut plc, &mut [&mut conn]);
+        assert_eq!(conn.lines(), [
+            r#"error_describe . ["CommunicationFailed","could not read the indexer: no magic number in the extern image",{}]"#,
+            "pong x null",
+            "pong . null",
+        ]);
+
+        // new connections try again
+        let mut plc = Plc::new();
+        let mut conn = Conn::new(2, &config);
+        conn.send("describe\n");
+        run(&mut plc, &mut [&mut conn]);
+        assert!(conn.lines()[0].starts_with(r#"describing . {"equipment_id":"node","#));
+    }
+
+    #[test]
+    fn requests() {
+        let mut plc = Plc::new();
+        let mut conn = Conn::new(1, &SecopConfig::default());
+        conn.send("read motor:value\r\nread motor:status\nchange motor:target 7\n");
+        run(&mut plc, &mut [&mut conn]);
+        conn.send("read motor:status\ndo motor:stop\n");
+        run(&mut plc, &mut [&mut conn]);
+        conn.send("do motor:stop null\n");
+        run(&mut plc, &mut [&mut conn]);
+        assert_eq!(conn.lines(), [
+            "reply motor:value 5",
+            r#"reply motor:status [100,"idle"]"#,
+            "changed motor:target 7",
+            r#"reply motor:status [300,"starting"]"#,
+            "done motor:stop null",
+            "done motor:stop null",
+        ]);
+        assert_eq!(NE::read_u16(&plc.image[MOTOR + 4..]), STOP);
+
+        conn.send("change motor:target 200\nchange motor:target 1.5\nchange motor:value 1\n\
+                   change motor:target [\nread motor\nread pump:value\nread motor:speed\n\
+                   do motor:go\ndo motor:stop 1\nhello\n");
+        assert_eq!(conn.lines(), [
+            r#"error_change motor:target ["RangeError","200 is outside of [0, 100]",{}]"#,
+            r#"error_change motor:target ["WrongType","expected an integer, got 1.5",{}]"#,
+            r#"error_change motor:value ["ReadOnly","motor:value is read-only",{}]"#,
+            r#"error_change motor:target ["ProtocolError","invalid JSON: unexpected end at offset 1",{}]"#,
+            r#"error_read motor ["ProtocolError","expected module:accessible, got \"motor\"",{}]"#,
+            r#"error_read pump:value ["NoSuchModule","no module pump",{}]"#,
+            r#"error_read motor:speed ["NoSuchParameter","no parameter motor:speed",{}]"#,
+            r#"error_do motor:go ["NoSuchCommand","no command motor:go",{}]"#,
+            r#"error_do motor:stop ["WrongType","stop has no argument",{}]"#,
+            r#"error_hello . ["ProtocolError","unknown action \"hello\"",{}]"#,
+        ]);
+        assert_eq!(conn.handler.direct_replies(), (10, 10));
+    }
+
+    #[test]
+    fn updates() {
+        let mut plc = Plc::new();
+        let mut conn = Conn::new(1, &SecopConfig::default());
+        conn.send("activate\n");
+        run(&mut plc, &mut [&mut conn]);
+        assert_eq!(conn.lines(), [
+            "update motor:value 5",
+            r#"update motor:status [100,"idle"]"#,
+            "update motor:target 5",
+            "active",
+        ]);
+        NE::write_i16(&mut plc.image[MOTOR..], 9);
+        NE::write_u16(&mut plc.image[MOTOR + 4..], BUSY | 0b11);
+        run(&mut plc, &mut [&mut conn]);
+        assert_eq!(conn.lines(), [
+            "update motor:value 9",
+            r#"update motor:status [300,"aux bit 0, moving"]"#,
+        ]);
+        conn.send("deactivate motor\n");
+        NE::write_i16(&mut plc.image[MOTOR..], 10);
+        run(&mut plc, &mut [&mut conn]);
+        assert_eq!(conn.lines(), ["inactive motor"]);
+
+        // lines are limited in length and number
+        let mut conn = Conn::new(2, &SecopConfig::default());
+        let long = vec![b'x'; MAX_LINE + 1];
+        assert!(conn.handler.receive(&long, &mut Vec::new(), &mut Vec::new()).is_err());
+        let many = "ping\n".repeat(MAX_PENDING + 1);
+        let mut conn = Conn::new(3, &SecopConfig::default());
+        assert!(conn.handler.receive(many.as_bytes(), &mut Vec::new(), &mut Vec::new()).is_err());
+    }
+}
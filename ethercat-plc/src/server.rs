@@ -1,36 +1,123 @@
 // Part of ethercat-rs. Copyright 2018-2024 by the authors.
 // This work is dual-licensed under Apache 2.0 and MIT terms.
 
-//! Modbus server allowing access to the PLC "memory" variables.
+//! Servers allowing access to the PLC "memory" variables.
 
-use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::io::{Result, Read, Write, ErrorKind};
-use std::net::{TcpListener, TcpStream};
-use std::thread;
+use std::io::Result;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use log::*;
-use byteorder::{ByteOrder, BE, LE};
-use crossbeam_channel::{unbounded, Sender, Receiver};
+use byteorder::{ByteOrder, LE};
+use crossbeam_channel::{Sender, Receiver};
 
+use crate::image::VarInfo;
+use crate::metrics::Metrics;
+
+mod tcp;
+
+pub use self::tcp::{TcpServer, TcpConfig};
+
+/// Capacity of the channels between a server and the PLC.  Servers must
+/// not have more requests waiting for a response, so that the PLC never
+/// blocks when sending responses.
+pub const QUEUE_SIZE: usize = 256;
+
+static CLIENT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Get a new ID for a client connection (the `hid` of its requests), which
+/// is unique among all servers of the PLC.
+pub fn new_client_id() -> usize {
+    CLIENT_ID.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// The memory area that a request refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Area {
+    /// The extern image, shared between cycle code and clients.
+    Extern,
+    /// The EtherCAT process image (read-only for clients).
+    Process,
+}
 
 #[derive(Debug)]
 pub struct Request<T> {
     pub hid: usize,
+    pub area: Area,
     pub addr: usize,
     pub count: usize,
     pub write: Option<Vec<u8>>,
+    /// For writes, only the bits set in the mask are changed.
+    pub mask: Option<Vec<u8>>,
+    /// For writes, a range (address and count) to read back after the PLC
+    /// has run one cycle with the written data.  For reads, the reply is
+    /// likewise delayed by a cycle, and contains this range instead; only
+    /// one such read of each client and area is answered per cycle.
+    pub read_back: Option<(usize, usize)>,
+    /// If given, the request doesn't read or write, but changes the write
+    /// ownership of the extern image range.
+    pub lock: Option<LockOp>,
     pub extra: T,
 }
 
+impl<T> Request<T> {
+    /// Whether this is a write request that changes any byte in the range.
+    pub(crate) fn writes_to(&self, range: &Range<usize>) -> bool {
+        self.write.is_some() &&
+            (range.start.max(self.addr)..range.end.min(self.addr + self.count)).any(|addr| {
+                self.mask.as_ref().map_or(true, |mask| mask[addr - self.addr] != 0)
+            })
+    }
+}
+
+/// Operations on write locks.
+///
+/// A client holding a lock on a range of the extern image is the only one
+/// that can write to it; other clients' writes are rejected with error code
+/// 6 ("device busy" in Modbus).  Locks expire if not renewed, either by
+/// claiming again or by writing to the range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockOp {
+    /// Claim the range, for the given time or the PLC's default lock
+    /// timeout.  Fails if another client holds a lock on any part of it.
+    Claim(Option<Duration>),
+    /// Release the client's own locks overlapping the range.
+    Release,
+    /// Release all locks overlapping the range, regardless of owner.  Only
+    /// accepted if enabled with `PlcBuilder::allow_force_release`, else
+    /// rejected with error code 1.
+    ForceRelease,
+    /// Release all locks of the client, e.g. when it disconnects.
+    ReleaseAll,
+}
+
 #[derive(Debug)]
 pub enum Response<T> {
     Ok(Request<T>, Vec<u8>),
     Error(Request<T>, u8),
 }
 
+/// Information about the PLC that is made available to servers.
+#[derive(Debug, Clone, Default)]
+pub struct PlcInfo {
+    pub name: String,
+    pub version: String,
+    /// Variables of the extern image.
+    pub extern_vars: Vec<VarInfo>,
+    /// Variables of the process image, at the addresses seen by clients.
+    pub process_vars: Vec<VarInfo>,
+    /// Statistics of the PLC, to which servers add their own.
+    pub metrics: Arc<Metrics>,
+}
+
 pub trait Server {
     type Extra: Debug + Send + 'static;
-    fn start(addr: &str, w_to_plc: Sender<Request<Self::Extra>>,
+    /// Protocol specific configuration, set with `PlcBuilder::server_config`.
+    type Config: Default + Send + 'static;
+    fn start(addr: &str, info: &PlcInfo, config: Self::Config,
+             w_to_plc: Sender<Request<Self::Extra>>,
              r_from_plc: Receiver<Response<Self::Extra>>,) -> Result<()>;
 }
 
@@ -39,363 +126,135 @@ pub struct NoServer;
 
 impl Server for NoServer {
     type Extra = ();
+    type Config = ();
 
-    fn start(_: &str, _: Sender<Request<()>>, _: Receiver<Response<()>>) -> Result<()> {
+    fn start(_: &str, _: &PlcInfo, _: (), _: Sender<Request<()>>,
+             _: Receiver<Response<()>>) -> Result<()> {
         Ok(())
     }
 }
 
 
-pub enum HandlerEvent<T> {
-    Request(Request<T>),
-    New((usize, Sender<Response<T>>)),
-    Finished(usize),
-}
-
+/// A protocol handler for one connection of the `TcpServer`.
+///
+/// Handlers don't do any I/O: the server feeds them the data received from
+/// the client, and sends out the replies they produce.
 pub trait Handler {
-    type Extra: Debug + Send + 'static;
-    fn new(client: TcpStream, hid: usize, requests: Sender<HandlerEvent<Self::Extra>>,
-           replies: Receiver<Response<Self::Extra>>) -> Self;
-    fn sender(client: TcpStream, replies: Receiver<Response<Self::Extra>>);
-    fn handle(self);
-}
-
-pub struct TcpServer<H: Handler> {
-    to_plc:   Sender<Request<H::Extra>>,
-    from_plc: Receiver<Response<H::Extra>>,
-}
-
-impl<H: Handler + Send + 'static> Server for TcpServer<H> {
-    type Extra = H::Extra;
-
-    fn start(addr: &str, w_to_plc: Sender<Request<H::Extra>>,
-             r_from_plc: Receiver<Response<H::Extra>>,) -> Result<()> {
-        let (w_clients, r_clients) = unbounded();
-        let tcp_sock = TcpListener::bind(addr)?;
-
-        let srv: Self = TcpServer { to_plc: w_to_plc, from_plc: r_from_plc };
-
-        thread::spawn(move || Self::tcp_listener(tcp_sock, w_clients));
-        thread::spawn(move || srv.dispatcher(r_clients));
-
-        Ok(())
+    type Extra: Debug + Default + Send + 'static;
+    type Config: Clone + Default + Send + Sync + 'static;
+    fn new(hid: usize, info: Arc<PlcInfo>, config: &Self::Config) -> Self;
+    /// Process received data, which can end with an incomplete request.
+    /// Requests for the PLC are added to `requests`, direct replies are
+    /// written to `output`.  Returns the number of bytes consumed, or an
+    /// error if the connection can't be used anymore.
+    fn receive(&mut self, input: &[u8], requests: &mut Vec<Request<Self::Extra>>,
+               output: &mut Vec<u8>) -> Result<usize>;
+    /// Write the reply to a response from the PLC to `output`.
+    fn respond(&mut self, response: Response<Self::Extra>, output: &mut Vec<u8>);
+    /// Add requests that the handler makes on its own, e.g. for
+    /// subscriptions.  Called after responses have been delivered.
+    fn poll(&mut self, _requests: &mut Vec<Request<Self::Extra>>) {}
+    /// The number of requests that the handler answered itself since the
+    /// last call, and how many of them with an error.  They are counted in
+    /// the server's metrics along with the requests to the PLC.
+    fn direct_replies(&mut self) -> (usize, usize) {
+        (0, 0)
     }
-}
-
-impl<H: Handler + Send> TcpServer<H> {
-    /// Listen for connections on the TCP socket and spawn handlers for it.
-    fn tcp_listener(tcp_sock: TcpListener, handler_sender: Sender<HandlerEvent<H::Extra>>) {
-        mlzlog::set_thread_prefix("TCP: ");
-
-        info!("listening on {}", tcp_sock.local_addr().unwrap());
-        let mut handler_id = 0;
-
-        while let Ok((stream, _)) = tcp_sock.accept() {
-            let (w_rep, r_rep) = unbounded();
-            let w_req = handler_sender.clone();
-            handler_id += 1;
-            if let Err(e) = w_req.send(HandlerEvent::New((handler_id, w_rep))) {
-                warn!("couldn't send new handler event: {}", e);
-            } else {
-                thread::spawn(move || H::new(stream, handler_id,
-                                             w_req, r_rep).handle());
-            }
-        }
-    }
-
-    fn dispatcher(self, r_clients: Receiver<HandlerEvent<H::Extra>>) {
-        mlzlog::set_thread_prefix("Dispatcher: ");
-
-        let mut handlers = BTreeMap::new();
-
-        for event in r_clients {
-            match event {
-                HandlerEvent::New((id, chan)) => {
-                    handlers.insert(id, chan);
-                }
-                HandlerEvent::Finished(id) => {
-                    handlers.remove(&id);
-                }
-                HandlerEvent::Request(req) => {
-                    let hid = req.hid;
-                    if let Err(e) = self.to_plc.send(req) {
-                        warn!("couldn't send request to PLC: {}", e);
-                    } else {
-                        let resp = self.from_plc.recv().unwrap();
-                        if let Err(e) = handlers[&hid].send(resp) {
-                            warn!("couldn't send reply to handler: {}", e);
-                        }
-                    }
-                }
-            }
-        }
-    }
-}
-
-#[derive(Debug)]
-pub struct ModbusExtra {
-    tid: u16,
-    fc: u8,
-}
-
-pub struct ModbusHandler {
-    hid:      usize,
-    client:   TcpStream,
-    requests: Sender<HandlerEvent<ModbusExtra>>,
-}
-
-impl Handler for ModbusHandler {
-    type Extra = ModbusExtra;
-
-    fn new(client: TcpStream, hid: usize, requests: Sender<HandlerEvent<ModbusExtra>>,
-           replies: Receiver<Response<ModbusExtra>>) -> Self {
-        let send_client = client.try_clone().expect("could not clone socket");
-        thread::spawn(move || ModbusHandler::sender(send_client, replies));
-        ModbusHandler { client, hid, requests }
-    }
-
-    fn sender(mut client: TcpStream, replies: Receiver<Response<ModbusExtra>>) {
-        let mut buf = [0u8; 256];
-        mlzlog::set_thread_prefix(format!("{} sender: ", client.peer_addr().unwrap()));
-
-        for response in replies {
-            debug!("sending response: {:?}", response);
-            let count = match response {
-                Response::Ok(req, values) => {
-                    BE::write_u16(&mut buf, req.extra.tid);
-                    buf[7] = req.extra.fc;
-                    match req.extra.fc {
-                        3 | 4 => {
-                            let nbytes = values.len();
-                            buf[8] = nbytes as u8;
-                            buf[9..9+nbytes].copy_from_slice(&values);
-                            9 + nbytes
-                        }
-                        6 => {
-                            BE::write_u16(&mut buf[8..], req.addr as u16);
-                            buf[10..12].copy_from_slice(&values);
-                            12
-                        }
-                        16 => {
-                            BE::write_u16(&mut buf[8..], req.addr as u16);
-                            BE::write_u16(&mut buf[10..], values.len() as u16 / 2);
-                            12
-                        }
-                        x => panic!("impossible function code {}", x)
-                    }
-                }
-                Response::Error(req, ec) => {
-                    BE::write_u16(&mut buf, req.extra.tid);
-                    buf[7] = req.extra.fc | 0x80;
-                    buf[8] = ec;
-                    9
-                }
-            };
-            BE::write_u16(&mut buf[4..], (count - 6) as u16);
-            if let Err(err) = client.write_all(&buf[..count]) {
-                warn!("write error: {}", err);
-                break;
-            }
-        }
-    }
-
-    fn handle(mut self) {
-        let mut headbuf = [0u8; 8];
-        let mut bodybuf = [0u8; 250];  // max frame size is 255
-        let mut errbuf  = [0, 0, 0, 0, 0, 9, 0, 0, 0];
-
-        mlzlog::set_thread_prefix(format!("{}: ", self.client.peer_addr().unwrap()));
-        info!("connection accepted");
-
-        loop {
-            if let Err(err) = self.client.read_exact(&mut headbuf) {
-                if err.kind() != ErrorKind::UnexpectedEof {
-                    warn!("error reading request head: {}", err);
-                }
-                break;
-            }
-            if headbuf[2..4] != [0, 0] {
-                warn!("protocol ID mismatch: {:?}", headbuf);
-                break;
-            }
-            let tid = BE::read_u16(&headbuf);
-            let data_len = BE::read_u16(&headbuf[4..6]) as usize;
-            if let Err(err) = self.client.read_exact(&mut bodybuf[..data_len - 2]) {
-                warn!("error reading request body: {}", err);
-                break;
-            }
-            if headbuf[6] != 0 {
-                warn!("invalid slave {}", headbuf[6]);
-                continue;
-            }
-            let fc = headbuf[7];
-            let req = match fc {
-                3 | 4 => { // read registers
-                    if data_len != 6 {
-                        warn!("invalid data length for fc {}", fc);
-                        continue;
-                    }
-                    let addr = 2 * BE::read_u16(&bodybuf[..2]) as usize;
-                    let count = 2 * BE::read_u16(&bodybuf[2..4]) as usize;
-                    Request { hid: self.hid, addr, count, write: None,
-                              extra: ModbusExtra { tid, fc } }
-                }
-                6 => { // write single register
-                    if data_len != 6 {
-                        warn!("invalid data length for fc {}", fc);
-                        continue;
-                    }
-                    let addr = 2 * BE::read_u16(&bodybuf[..2]) as usize;
-                    Request { hid: self.hid, addr, count: 2, write: Some(bodybuf[2..4].to_vec()),
-                              extra: ModbusExtra { tid, fc } }
-                }
-                16 => { // write multiple registers
-                    if data_len < 7 {
-                        warn!("insufficient data length for fc {}", fc);
-                        continue;
-                    }
-                    let addr = 2 * BE::read_u16(&bodybuf[..2]) as usize;
-                    let bytecount = bodybuf[4] as usize;
-                    if data_len != 7 + bytecount {
-                        warn!("invalid data length for fc {}", fc);
-                        continue;
-                    }
-                    let values = bodybuf[5..5+bytecount].to_vec();
-                    Request { hid: self.hid, addr, count: values.len(), write: Some(values),
-                              extra: ModbusExtra { tid, fc } }
-                }
-                _ => {
-                    warn!("unknown function code {}", fc);
-                    BE::write_u16(&mut errbuf, tid);
-                    errbuf[7] = fc | 0x80;
-                    errbuf[8] = 1;
-                    if let Err(err) = self.client.write_all(&errbuf) {
-                        warn!("error writing error response: {}", err);
-                        break;
-                    }
-                    continue;
-                }
-            };
-            debug!("got request: {:?}", req);
-            if let Err(e) = self.requests.send(HandlerEvent::Request(req)) {
-                warn!("couldn't send request to server: {}", e);
-            }
-        }
-        info!("connection closed");
-        if let Err(e) = self.requests.send(HandlerEvent::Finished(self.hid)) {
-            warn!("couldn't send finish event to server: {}", e);
-        }
+    /// Whether the connection should be closed once all output is sent,
+    /// e.g. because the client asked for it.
+    fn finished(&self) -> bool {
+        false
     }
 }
 
 
 pub struct SimpleHandler {
-    hid:      usize,
-    client:   TcpStream,
-    requests: Sender<HandlerEvent<bool>>,
+    hid: usize,
+    /// Number of requests with an invalid function since the last call to
+    /// `direct_replies`.
+    invalid: usize,
 }
 
 const SIMPLE_READ:  u32 = 0x7EAD;
 const SIMPLE_WRITE: u32 = 0xF71E;
 const SIMPLE_ERR:   u32 = 0xE770;
+/// Claim a write lock on the range, with the PLC's default timeout.
+const SIMPLE_LOCK:  u32 = 0x10CC;
+/// Release own locks in the range.
+const SIMPLE_FREE:  u32 = 0xF4EE;
+/// Release all locks in the range.
+const SIMPLE_FORCE: u32 = 0xF0CE;
 
 impl Handler for SimpleHandler {
-    type Extra = bool;
+    /// The function of the request.
+    type Extra = u32;
+    type Config = ();
 
-    fn new(client: TcpStream, hid: usize, requests: Sender<HandlerEvent<bool>>,
-           replies: Receiver<Response<bool>>) -> Self {
-        client.set_nodelay(true).unwrap();
-        let send_client = client.try_clone().expect("could not clone socket");
-        thread::spawn(move || SimpleHandler::sender(send_client, replies));
-        SimpleHandler { client, hid, requests }
+    fn new(hid: usize, _info: Arc<PlcInfo>, _config: &()) -> Self {
+        SimpleHandler { hid, invalid: 0 }
     }
 
-    fn sender(mut client: TcpStream, replies: Receiver<Response<bool>>) {
-        let mut buf = [0u8; 12];
-        mlzlog::set_thread_prefix(format!("{} sender: ", client.peer_addr().unwrap()));
-
-        for response in replies {
-            debug!("sending response: {:?}", response);
-            match response {
-                Response::Ok(req, values) => {
-                    if req.extra {
-                        LE::write_u32(&mut buf, SIMPLE_READ);
-                        LE::write_u32(&mut buf[4..], req.addr as u32);
-                        LE::write_u32(&mut buf[8..], req.count as u32);
-                        if let Err(err) = client.write_all(&buf) {
-                            warn!("write error: {}", err);
-                            break;
-                        }
-                        if let Err(err) = client.write_all(&values) {
-                            warn!("write error: {}", err);
-                            break;
-                        }
-                    } else {
-                        LE::write_u32(&mut buf, SIMPLE_WRITE);
-                        LE::write_u32(&mut buf[4..], req.addr as u32);
-                        LE::write_u32(&mut buf[8..], req.count as u32);
-                        if let Err(err) = client.write_all(&buf) {
-                            warn!("write error: {}", err);
-                            break;
-                        }
-                    }
-                }
-                Response::Error(req, ec) => {
-                    LE::write_u32(&mut buf, SIMPLE_ERR);
-                    LE::write_u32(&mut buf[4..], req.addr as u32);
-                    LE::write_u32(&mut buf[4..], ec as u32);
-                    if let Err(err) = client.write_all(&buf) {
-                        warn!("write error: {}", err);
-                        break;
-                    }
-                }
-            }
-        }
-    }
-
-    fn handle(mut self) {
-        let mut headbuf = [0u8; 12];
-
-        mlzlog::set_thread_prefix(format!("{}: ", self.client.peer_addr().unwrap()));
-        info!("connection accepted");
-
-        loop {
-            if let Err(err) = self.client.read_exact(&mut headbuf) {
-                if err.kind() != ErrorKind::UnexpectedEof {
-                    warn!("error reading request head: {}", err);
-                }
-                break;
-            }
-            let func = LE::read_u32(&headbuf);
+    fn receive(&mut self, input: &[u8], requests: &mut Vec<Request<u32>>,
+               _output: &mut Vec<u8>) -> Result<usize> {
+        let mut pos = 0;
+        while let Some(headbuf) = input.get(pos..pos + 12) {
+            let func = LE::read_u32(headbuf);
             let addr = LE::read_u32(&headbuf[4..]) as usize;
             let count = LE::read_u32(&headbuf[8..]) as usize;
-            let req = if func == SIMPLE_READ {
-                Request { hid: self.hid, addr, count, write: None, extra: true }
+            let lock = match func {
+                SIMPLE_LOCK => Some(LockOp::Claim(None)),
+                SIMPLE_FREE => Some(LockOp::Release),
+                SIMPLE_FORCE => Some(LockOp::ForceRelease),
+                _ => None,
+            };
+            let req = if func == SIMPLE_READ || lock.is_some() {
+                pos += 12;
+                Request { hid: self.hid, area: Area::Extern, addr, count, write: None,
+                          mask: None, read_back: None, lock, extra: func }
             } else if func == SIMPLE_WRITE {
-                let mut bodybuf = Vec::new();
-                if let Err(err) = std::io::Write::by_ref(&mut self.client)
-                    .take(count as u64).read_to_end(&mut bodybuf)
-                {
-                    warn!("error reading request body: {}", err);
-                    break;
-                }
-                if bodybuf.len() != count {
-                    warn!("error reading request body: connection closed");
-                    break;
-                }
-                Request { hid: self.hid, addr, count, write: Some(bodybuf), extra: false }
+                let body = match input.get(pos + 12..).filter(|body| body.len() >= count) {
+                    Some(body) => body[..count].to_vec(),
+                    None => break,
+                };
+                pos += 12 + count;
+                Request { hid: self.hid, area: Area::Extern, addr, count, write: Some(body),
+                          mask: None, read_back: None, lock: None, extra: func }
             } else {
                 warn!("invalid function {}", func);
+                self.invalid += 1;
+                pos += 12;
                 continue;
             };
             debug!("got request: {:?}", req);
-            if let Err(e) = self.requests.send(HandlerEvent::Request(req)) {
-                warn!("couldn't send request to server: {}", e);
-            }
+            requests.push(req);
         }
-        info!("connection closed");
-        if let Err(e) = self.requests.send(HandlerEvent::Finished(self.hid)) {
-            warn!("couldn't send finish event to server: {}", e);
+        Ok(pos)
+    }
+
+    fn respond(&mut self, response: Response<u32>, output: &mut Vec<u8>) {
+        let mut buf = [0u8; 12];
+        debug!("sending response: {:?}", response);
+        match response {
+            Response::Ok(req, values) => {
+                LE::write_u32(&mut buf, req.extra);
+                LE::write_u32(&mut buf[4..], req.addr as u32);
+                LE::write_u32(&mut buf[8..], req.count as u32);
+                output.extend_from_slice(&buf);
+                if req.extra == SIMPLE_READ {
+                    output.extend_from_slice(&values);
+                }
+            }
+            Response::Error(req, ec) => {
+                LE::write_u32(&mut buf, SIMPLE_ERR);
+                LE::write_u32(&mut buf[4..], req.addr as u32);
+                LE::write_u32(&mut buf[8..], ec as u32);
+                output.extend_from_slice(&buf);
+            }
         }
     }
+
+    fn direct_replies(&mut self) -> (usize, usize) {
+        let invalid = std::mem::take(&mut self.invalid);
+        (invalid, invalid)
+    }
 }
@@ -0,0 +1,773 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! The event-driven TCP server.
+//!
+//! One thread waits for events on the listening socket and all client
+//! connections with `poll`, lets the connections' handlers translate
+//! between the received bytes and requests, and passes the requests on to
+//! the PLC.  A second thread receives the responses from the PLC, and wakes
+//! up the first one to deliver them.
+//!
+//! At most `QUEUE_SIZE` requests are in flight.  Beyond that, requests are
+//! queued and no more data is read from clients until the PLC catches up.
+//!
+//! Besides TCP, the server can listen on a Unix domain socket, for local
+//! clients whose access is controlled by file permissions.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::marker::PhantomData;
+use std::fs;
+use std::io::{self, Read, Write, ErrorKind};
+use std::mem::size_of;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::*;
+use crossbeam_channel::{unbounded, Sender, Receiver, TrySendError};
+
+use crate::metrics::ServerMetrics;
+use super::{new_client_id, Area, Handler, LockOp, PlcInfo, Request, Response, Server, QUEUE_SIZE};
+
+/// Connections with more unprocessed input than this are closed.
+const MAX_INPUT: usize = 1 << 20;
+/// Connections with more unsent output than this are not read from, until
+/// the client has caught up.
+const MAX_OUTPUT: usize = 1 << 16;
+
+/// Configuration for the `TcpServer`.
+#[derive(Debug, Clone)]
+pub struct TcpConfig<C> {
+    /// Maximum number of simultaneous connections.  Further connections are
+    /// closed right after accepting them.
+    pub max_connections: usize,
+    /// Close connections on which the client hasn't sent anything for this
+    /// long.
+    pub idle_timeout: Option<Duration>,
+    /// Enable TCP keepalive probes after this idle time, to detect peers
+    /// that went away without closing the connection.
+    pub keepalive: Option<Duration>,
+    /// For Unix domain sockets, the permissions of the socket file (e.g.
+    /// `0o660` to allow access for a group).
+    pub socket_mode: Option<u32>,
+    /// Configuration for the protocol handlers.
+    pub handler: C,
+}
+
+impl<C: Default> Default for TcpConfig<C> {
+    fn default() -> Self {
+        Self {
+            max_connections: 64,
+            idle_timeout: None,
+            keepalive: Some(Duration::from_secs(60)),
+            socket_mode: None,
+            handler: C::default(),
+        }
+    }
+}
+
+/// A server for stream connections, with a `Handler` implementing the
+/// protocol.  The address is a TCP socket address, or `unix:` followed by
+/// the path of a Unix domain socket.
+pub struct TcpServer<H: Handler>(PhantomData<H>);
+
+impl<H: Handler + Send + 'static> Server for TcpServer<H> {
+    type Extra = H::Extra;
+    type Config = TcpConfig<H::Config>;
+
+    fn start(addr: &str, info: &PlcInfo, config: TcpConfig<H::Config>,
+             w_to_plc: Sender<Request<H::Extra>>,
+             r_from_plc: Receiver<Response<H::Extra>>,) -> io::Result<()> {
+        let listener = Listener::bind(addr, config.socket_mode)?;
+        Self::spawn(listener, info, config, w_to_plc, r_from_plc)
+    }
+}
+
+impl<H: Handler + Send + 'static> TcpServer<H> {
+    /// Start the threads serving an already bound listener.
+    fn spawn(listener: Listener, info: &PlcInfo, config: TcpConfig<H::Config>,
+             w_to_plc: Sender<Request<H::Extra>>,
+             r_from_plc: Receiver<Response<H::Extra>>) -> io::Result<()> {
+        let (wake_r, wake_w) = UnixStream::pair()?;
+        wake_r.set_nonblocking(true)?;
+        wake_w.set_nonblocking(true)?;
+        let (w_replies, r_replies) = unbounded();
+        let handler = std::any::type_name::<H>().rsplit("::").next().unwrap_or_default();
+        let metrics = info.metrics.add_server(&listener.describe(), handler);
+
+        let event_loop = EventLoop::<H> {
+            listener, wake: wake_r, config, info: Arc::new(info.clone()), metrics,
+            to_plc: w_to_plc, replies: r_replies, queue: VecDeque::new(), in_flight: 0,
+            conns: BTreeMap::new(),
+        };
+
+        thread::spawn(move || event_loop.run());
+        thread::spawn(move || Self::forwarder(r_from_plc, w_replies, wake_w));
+
+        Ok(())
+    }
+
+    /// Pass responses from the PLC on to the event loop.
+    fn forwarder(from_plc: Receiver<Response<H::Extra>>,
+                 replies: Sender<Response<H::Extra>>, mut wake: UnixStream) {
+        mlzlog::set_thread_prefix("Dispatcher: ");
+
+        for resp in from_plc {
+            if replies.send(resp).is_err() {
+                return;
+            }
+            // if the socket buffer is full, a wakeup is pending anyway
+            let _ = wake.write(&[0]);
+        }
+        warn!("PLC has gone away");
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    fn bind(addr: &str, mode: Option<u32>) -> io::Result<Self> {
+        let listener = match addr.strip_prefix("unix:") {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                // remove a stale socket left over by a previous run, but
+                // not one that another process is still listening on
+                if fs::symlink_metadata(&path).map_or(false, |m| m.file_type().is_socket()) {
+                    if UnixStream::connect(&path).is_ok() {
+                        return Err(io::Error::new(ErrorKind::AddrInUse, format!(
+                            "socket {} is in use by another process", path.display())));
+                    }
+                    fs::remove_file(&path)?;
+                }
+                let listener = UnixListener::bind(&path)?;
+                if let Some(mode) = mode {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+                }
+                listener.set_nonblocking(true)?;
+                Listener::Unix(listener, path)
+            }
+            None => {
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(listener)
+            }
+        };
+        Ok(listener)
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map_or("?".into(), |a| a.to_string()),
+            Listener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    /// Accept a connection, returning it and a description of the peer.
+    fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => listener.accept()
+                .map(|(stream, peer)| (Stream::Tcp(stream), peer.to_string())),
+            Listener::Unix(listener, _) => listener.accept().map(|(stream, _)| {
+                let peer = match peer_pid(&stream) {
+                    Some(pid) => format!("pid {}", pid),
+                    None => "unix client".into(),
+                };
+                (Stream::Unix(stream), peer)
+            }),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// The process ID of the peer of a Unix domain socket.
+fn peer_pid(stream: &UnixStream) -> Option<i32> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: we pass a valid ucred struct and its size
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if ret == 0 { Some(cred.pid) } else { None }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+struct Connection<H> {
+    stream: Stream,
+    peer: String,
+    handler: H,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    last_active: Instant,
+}
+
+impl<H: Handler> Connection<H> {
+    /// Read and process a chunk of available data, adding the requests to
+    /// `requests`.  Returns false if the peer closed the connection.
+    fn receive(&mut self, requests: &mut Vec<Request<H::Extra>>) -> io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        let n = match self.stream.read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) =>
+                return Ok(true),
+            Err(e) => return Err(e),
+        };
+        self.last_active = Instant::now();
+        self.input.extend_from_slice(&buf[..n]);
+        let used = self.handler.receive(&self.input, requests, &mut self.output)?;
+        self.input.drain(..used);
+        if self.input.len() > MAX_INPUT {
+            return Err(io::Error::new(ErrorKind::InvalidData, "request too large"));
+        }
+        Ok(true)
+    }
+
+    /// Send as much pending output as possible.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => { self.output.drain(..n); }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+struct EventLoop<H: Handler> {
+    listener: Listener,
+    wake:     UnixStream,
+    config:   TcpConfig<H::Config>,
+    info:     Arc<PlcInfo>,
+    metrics:  Arc<ServerMetrics>,
+    to_plc:   Sender<Request<H::Extra>>,
+    replies:  Receiver<Response<H::Extra>>,
+    /// Requests waiting to be sent to the PLC.
+    queue:    VecDeque<Request<H::Extra>>,
+    /// Number of requests sent to the PLC and not yet answered.
+    in_flight: usize,
+    conns:    BTreeMap<usize, Connection<H>>,
+}
+
+fn pollfd(fd: RawFd, events: i16) -> libc::pollfd {
+    libc::pollfd { fd, events, revents: 0 }
+}
+
+fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    let secs = idle.as_secs().max(1).min(i32::MAX as u64) as i32;
+    let options = [(libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1),
+                   (libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs),
+                   (libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs)];
+    for &(level, name, value) in options.iter() {
+        // SAFETY: the option value is a valid c_int of the given size
+        let ret = unsafe {
+            libc::setsockopt(stream.as_raw_fd(), level, name,
+                             &value as *const i32 as *const libc::c_void,
+                             size_of::<i32>() as libc::socklen_t)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl<H: Handler> EventLoop<H> {
+    fn run(mut self) {
+        mlzlog::set_thread_prefix("TCP: ");
+
+        info!("listening on {}", self.listener.describe());
+        let mut fds = Vec::new();
+        let mut hids = Vec::new();
+
+        loop {
+            fds.clear();
+            hids.clear();
+            fds.push(pollfd(self.listener.as_raw_fd(), libc::POLLIN));
+            fds.push(pollfd(self.wake.as_raw_fd(), libc::POLLIN));
+            for (&hid, conn) in &self.conns {
+                let mut events = 0;
+                if self.queue.is_empty() && conn.output.len() < MAX_OUTPUT {
+                    events |= libc::POLLIN;
+                }
+                if !conn.output.is_empty() {
+                    events |= libc::POLLOUT;
+                }
+                fds.push(pollfd(conn.stream.as_raw_fd(), events));
+                hids.push(hid);
+            }
+
+            // SAFETY: we pass a valid slice of pollfds
+            let n = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t,
+                                        self.poll_timeout()) };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    error!("poll failed: {}", err);
+                    return;
+                }
+                continue;
+            }
+
+            if fds[0].revents != 0 {
+                self.accept();
+            }
+            if fds[1].revents != 0 {
+                self.dispatch_replies();
+            }
+            for (fd, &hid) in fds[2..].iter().zip(&hids) {
+                if fd.revents != 0 {
+                    self.service(hid);
+                }
+            }
+            self.close_idle();
+        }
+    }
+
+    /// Time until the next idle timeout, in milliseconds for `poll`.
+    fn poll_timeout(&self) -> i32 {
+        let timeout = match self.config.idle_timeout {
+            Some(timeout) => timeout,
+            None => return -1,
+        };
+        self.conns.values()
+            .map(|conn| (conn.last_active + timeout).saturating_duration_since(Instant::now()))
+            .min()
+            .map_or(-1, |t| ((t.as_micros() + 999) / 1000).min(i32::MAX as u128) as i32)
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("error accepting connection: {}", e);
+                    return;
+                }
+            };
+            if self.conns.len() >= self.config.max_connections {
+                warn!("{}: refusing connection, too many clients", peer);
+                continue;
+            }
+            if let Err(e) = self.setup(&stream) {
+                warn!("{}: error setting up connection: {}", peer, e);
+                continue;
+            }
+            info!("{}: connection accepted", peer);
+            let hid = new_client_id();
+            let handler = H::new(hid, self.info.clone(), &self.config.handler);
+            self.conns.insert(hid, Connection {
+                stream, peer, handler, input: Vec::new(), output: Vec::new(),
+                last_active: Instant::now(),
+            });
+            self.metrics.set_clients(self.conns.len());
+        }
+    }
+
+    fn setup(&self, stream: &Stream) -> io::Result<()> {
+        match stream {
+            Stream::Tcp(stream) => {
+                stream.set_nonblocking(true)?;
+                stream.set_nodelay(true)?;
+                if let Some(idle) = self.config.keepalive {
+                    set_keepalive(stream, idle)?;
+                }
+            }
+            Stream::Unix(stream) => stream.set_nonblocking(true)?,
+        }
+        Ok(())
+    }
+
+    fn dispatch_replies(&mut self) {
+        let mut buf = [0u8; 64];
+        while matches!(self.wake.read(&mut buf), Ok(n) if n > 0) {}
+
+        let mut touched = Vec::new();
+        while let Ok(resp) = self.replies.try_recv() {
+            self.in_flight -= 1;
+            touched.extend(self.deliver(resp));
+        }
+        for _ in 0..self.queue.len() {
+            if self.in_flight >= QUEUE_SIZE {
+                break;
+            }
+            if let Some(req) = self.queue.pop_front() {
+                touched.extend(self.send_to_plc(req));
+            }
+        }
+        touched.sort_unstable();
+        touched.dedup();
+        let mut requests = Vec::new();
+        for &hid in &touched {
+            if let Some(conn) = self.conns.get_mut(&hid) {
+                conn.handler.poll(&mut requests);
+            }
+        }
+        self.metrics.add_requests(requests.len());
+        for req in requests {
+            self.submit(req);
+        }
+        for hid in touched {
+            self.flush(hid);
+        }
+    }
+
+    /// Send pending output of a connection, and close it on errors or if
+    /// the handler is done with it.
+    fn flush(&mut self, hid: usize) {
+        let conn = match self.conns.get_mut(&hid) {
+            Some(conn) => conn,
+            None => return,
+        };
+        match conn.flush() {
+            Err(e) => self.close(hid, Some(e)),
+            Ok(()) if conn.output.is_empty() && conn.handler.finished() => self.close(hid, None),
+            Ok(()) => (),
+        }
+    }
+
+    /// Hand a response to the handler of its connection.  Returns the
+    /// handler ID, unless the connection has been closed in the meantime.
+    fn deliver(&mut self, resp: Response<H::Extra>) -> Option<usize> {
+        let hid = match &resp {
+            Response::Ok(req, _) => req.hid,
+            Response::Error(req, _) => {
+                self.metrics.add_errors(1);
+                req.hid
+            }
+        };
+        let conn = self.conns.get_mut(&hid)?;
+        conn.handler.respond(resp, &mut conn.output);
+        Some(hid)
+    }
+
+    /// Send a request to the PLC, after the already queued ones.
+    fn submit(&mut self, req: Request<H::Extra>) {
+        if self.queue.is_empty() {
+            self.send_to_plc(req);
+        } else {
+            self.queue.push_back(req);
+        }
+    }
+
+    /// Send a request to the PLC, or queue it if too many are in flight.
+    /// Returns the handler ID if the request was answered right away.
+    fn send_to_plc(&mut self, req: Request<H::Extra>) -> Option<usize> {
+        if self.in_flight >= QUEUE_SIZE {
+            self.queue.push_back(req);
+            return None;
+        }
+        match self.to_plc.try_send(req) {
+            Ok(()) => {
+                self.in_flight += 1;
+                None
+            }
+            Err(TrySendError::Full(req)) => {
+                self.queue.push_front(req);
+                None
+            }
+            Err(TrySendError::Disconnected(req)) => {
+                debug!("PLC has gone away, rejecting request");
+                self.deliver(Response::Error(req, 4))
+            }
+        }
+    }
+
+    fn service(&mut self, hid: usize) {
+        let mut requests = Vec::new();
+        let conn = match self.conns.get_mut(&hid) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let result = conn.flush().and_then(|_| conn.receive(&mut requests));
+        let (replies, errors) = conn.handler.direct_replies();
+        self.metrics.add_requests(requests.len() + replies);
+        self.metrics.add_errors(errors);
+        for req in requests {
+            self.submit(req);
+        }
+        match result {
+            // send direct replies, and replies to requests rejected right away
+            Ok(true) => self.flush(hid),
+            Ok(false) => self.close(hid, None),
+            Err(e) => self.close(hid, Some(e)),
+        }
+    }
+
+    fn close_idle(&mut self) {
+        let timeout = match self.config.idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let idle = self.conns.iter()
+            .filter(|(_, conn)| conn.last_active.elapsed() >= timeout)
+            .map(|(&hid, _)| hid)
+            .collect::<Vec<_>>();
+        for hid in idle {
+            self.close(hid, Some(io::Error::new(ErrorKind::TimedOut, "idle timeout")));
+        }
+    }
+
+    fn close(&mut self, hid: usize, reason: Option<io::Error>) {
+        if let Some(conn) = self.conns.remove(&hid) {
+            self.metrics.set_clients(self.conns.len());
+            match reason {
+                None => info!("{}: connection closed", conn.peer),
+                Some(e) => warn!("{}: closing connection: {}", conn.peer, e),
+            }
+            // release the client's locks; the response is dropped
+            let req = Request {
+                hid, area: Area::Extern, addr: 0, count: 0, write: None, mask: None,
+                read_back: None, lock: Some(LockOp::ReleaseAll), extra: Default::default()
+            };
+            self.submit(req);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use byteorder::{ByteOrder, LE};
+    use crossbeam_channel::bounded;
+    use crate::server::{SimpleHandler, SIMPLE_READ, SIMPLE_WRITE};
+    use super::*;
+
+    /// Start a server with the `SimpleHandler` and a PLC channel of the
+    /// given capacity.  Returns the listening address, and the PLC side of
+    /// the channels.
+    fn start(addr: &str, config: TcpConfig<()>, capacity: usize)
+             -> (String, Receiver<Request<u32>>, Sender<Response<u32>>) {
+        let (w_to_plc, r_to_plc) = bounded(capacity);
+        let (w_from_plc, r_from_plc) = bounded(QUEUE_SIZE);
+        let listener = Listener::bind(addr, config.socket_mode).unwrap();
+        let addr = listener.describe();
+        TcpServer::<SimpleHandler>::spawn(listener, &PlcInfo::default(), config,
+                                          w_to_plc, r_from_plc).unwrap();
+        (addr, r_to_plc, w_from_plc)
+    }
+
+    fn read_request(addr: u32, count: u32) -> Vec<u8> {
+        let mut buf = vec![0; 12];
+        LE::write_u32_into(&[SIMPLE_READ, addr, count], &mut buf);
+        buf
+    }
+
+    /// Get the next request from the server, skipping the lock releases of
+    /// closed connections.
+    fn next_request(r_to_plc: &Receiver<Request<u32>>) -> Request<u32> {
+        loop {
+            let req = r_to_plc.recv_timeout(Duration::from_secs(5)).unwrap();
+            if req.lock != Some(LockOp::ReleaseAll) {
+                return req;
+            }
+        }
+    }
+
+    /// Answer the next request from the server with bytes of its address.
+    fn answer(r_to_plc: &Receiver<Request<u32>>, w_from_plc: &Sender<Response<u32>>) {
+        let req = next_request(r_to_plc);
+        let values = vec![req.addr as u8; req.count];
+        w_from_plc.send(Response::Ok(req, values)).unwrap();
+    }
+
+    /// Read the reply to a read request from `read_request`.
+    fn read_reply(stream: &mut impl Read, addr: u32, count: u32) {
+        let mut reply = vec![0; 12 + count as usize];
+        stream.read_exact(&mut reply).unwrap();
+        let mut expected = read_request(addr, count);
+        expected.resize(reply.len(), addr as u8);
+        assert_eq!(reply, expected);
+    }
+
+    #[test]
+    fn unix_socket() {
+        let path = env::temp_dir().join(format!("ethercat-plc-tcp-{}.sock", std::process::id()));
+        let addr = format!("unix:{}", path.display());
+        // a stale socket from a previous run is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        let config = TcpConfig { socket_mode: Some(0o600), ..TcpConfig::default() };
+        let (listen_addr, r_to_plc, w_from_plc) = start(&addr, config, QUEUE_SIZE);
+        assert_eq!(listen_addr, addr);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // but not a socket in use
+        let err = Listener::bind(&addr, None).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(&read_request(4, 2)).unwrap();
+        answer(&r_to_plc, &w_from_plc);
+        read_reply(&mut stream, 4, 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn connect(addr: &str) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    fn is_closed(stream: &mut TcpStream) -> bool {
+        matches!(stream.read(&mut [0]), Ok(0))
+    }
+
+    #[test]
+    fn connection_limit() {
+        let config = TcpConfig { max_connections: 1, ..TcpConfig::default() };
+        let (addr, r_to_plc, w_from_plc) = start("127.0.0.1:0", config, QUEUE_SIZE);
+        let mut first = connect(&addr);
+        first.write_all(&read_request(1, 1)).unwrap();
+        answer(&r_to_plc, &w_from_plc);
+        read_reply(&mut first, 1, 1);
+
+        // further connections are closed right away
+        let mut second = connect(&addr);
+        assert!(is_closed(&mut second));
+
+        // until the first one is gone, and has released its locks
+        drop(first);
+        let req = r_to_plc.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(req.lock, Some(LockOp::ReleaseAll));
+        let mut third = connect(&addr);
+        third.write_all(&read_request(2, 1)).unwrap();
+        answer(&r_to_plc, &w_from_plc);
+        read_reply(&mut third, 2, 1);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let timeout = Duration::from_millis(300);
+        let config = TcpConfig { idle_timeout: Some(timeout), ..TcpConfig::default() };
+        let (addr, r_to_plc, w_from_plc) = start("127.0.0.1:0", config, QUEUE_SIZE);
+
+        // requests keep a connection open, while a silent one is closed
+        let mut active = connect(&addr);
+        let mut silent = connect(&addr);
+        for i in 0..5 {
+            thread::sleep(timeout / 3);
+            active.write_all(&read_request(i, 1)).unwrap();
+            answer(&r_to_plc, &w_from_plc);
+            read_reply(&mut active, i, 1);
+        }
+        assert!(is_closed(&mut silent));
+
+        // the timeout also applies without any other activity
+        let started = Instant::now();
+        assert!(is_closed(&mut active));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= timeout / 2 && elapsed < 2 * timeout, "closed after {:?}", elapsed);
+    }
+
+    fn wait_for_requests(r_to_plc: &Receiver<Request<u32>>, n: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while r_to_plc.len() < n {
+            assert!(Instant::now() < deadline, "only {} requests arrived", r_to_plc.len());
+            thread::sleep(Duration::from_millis(5));
+        }
+        // and no more
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(r_to_plc.len(), n);
+    }
+
+    #[test]
+    fn pipelining() {
+        // a PLC channel with room for only two requests
+        let (addr, r_to_plc, w_from_plc) = start("127.0.0.1:0", TcpConfig::default(), 2);
+        let mut stream = connect(&addr);
+        let mut write = vec![0; 14];
+        LE::write_u32_into(&[SIMPLE_WRITE, 9, 2], &mut write[..12]);
+        write[12..].copy_from_slice(&[1, 2]);
+        let input = [read_request(1, 1), read_request(2, 2), write.clone(), read_request(3, 3)];
+        stream.write_all(&input.concat()).unwrap();
+
+        // the requests don't wait for each other's responses, but the rest
+        // is queued until the PLC catches up
+        wait_for_requests(&r_to_plc, 2);
+        answer(&r_to_plc, &w_from_plc);
+        answer(&r_to_plc, &w_from_plc);
+        wait_for_requests(&r_to_plc, 2);
+        let req = r_to_plc.recv().unwrap();
+        assert_eq!(req.write.as_deref(), Some(&[1, 2][..]));
+        w_from_plc.send(Response::Ok(req, vec![1, 2])).unwrap();
+        answer(&r_to_plc, &w_from_plc);
+
+        // the replies are in order
+        read_reply(&mut stream, 1, 1);
+        read_reply(&mut stream, 2, 2);
+        let mut reply = [0; 12];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, write[..12]);
+        read_reply(&mut stream, 3, 3);
+
+        // at most QUEUE_SIZE requests are in flight
+        let (addr, r_to_plc, w_from_plc) = start("127.0.0.1:0", TcpConfig::default(), 1000);
+        let mut stream = connect(&addr);
+        let n = QUEUE_SIZE as u32 + 10;
+        stream.write_all(&(0..n).flat_map(|i| read_request(i, 1)).collect::<Vec<_>>()).unwrap();
+        wait_for_requests(&r_to_plc, QUEUE_SIZE);
+        for _ in 0..n {
+            answer(&r_to_plc, &w_from_plc);
+        }
+        for i in 0..n {
+            read_reply(&mut stream, i, 1);
+        }
+    }
+}
@@ -0,0 +1,452 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Publishing the extern image in POSIX shared memory, for local processes
+//! that need the variables at cycle rate.
+//!
+//! The segment starts with a `Header`, followed by the image and a ring of
+//! write request slots:
+//!
+//! * After each cycle, the PLC copies the extern image into the segment.
+//!   The copy is protected by a sequence counter, which is odd while the
+//!   PLC is writing; readers retry until they got a copy with the same even
+//!   counter before and after.
+//! * Clients write by claiming the next free slot of the ring, filling in
+//!   address, data and mask, and marking it as ready.  The PLC applies ready
+//!   slots in order at the end of each cycle, with the same checks as for
+//!   server requests (read-only ranges and write locks).  Rejected writes
+//!   are counted in the header.
+//! * The slot state contains the ring index it was claimed for.  If a slot
+//!   is not ready within `STALE_CLAIM`, the writer is assumed to have died,
+//!   and the PLC frees the slot and moves on.  A writer that tries to claim
+//!   or mark such a slot as ready afterwards gets an error.
+//!
+//! Use `PlcBuilder::shared_memory` to create the segment, and `ShmClient`
+//! to access it from another process.
+
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::cell::Cell;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use log::*;
+
+use crate::image::ExternImage;
+
+const MAGIC: u32 = 0x4550_4C43;  // "EPLC"
+const VERSION: u32 = 2;
+
+/// Number of write request slots.
+pub const RING_SLOTS: u32 = 64;
+/// Maximum number of bytes in one write request.
+pub const SLOT_DATA: usize = 256;
+
+/// Time after which a claimed, but not ready slot is skipped.
+pub const STALE_CLAIM: Duration = Duration::from_secs(1);
+
+const SLOT_FREE: u64 = 0;
+const SLOT_CLAIMED: u64 = 1;
+const SLOT_READY: u64 = 2;
+
+/// Slot state value for the given ring index.
+fn slot_state(index: u32, state: u64) -> u64 {
+    (index as u64) << 32 | state
+}
+
+/// State of a slot that can be claimed for the given ring index: it was
+/// freed for the index one round before.
+fn claimable(index: u32) -> u64 {
+    slot_state(index.wrapping_sub(RING_SLOTS), SLOT_FREE)
+}
+
+#[repr(C)]
+struct Header {
+    magic: u32,
+    version: u32,
+    image_size: u32,
+    ring_slots: u32,
+    slot_data: u32,
+    _pad: u32,
+    /// Sequence counter of the image copy, odd while it is being written.
+    seq: AtomicU64,
+    /// Number of the last published cycle.
+    cycle: AtomicU64,
+    /// Number of rejected write requests.
+    rejected: AtomicU64,
+    /// Next slot to be claimed by a writer.
+    head: AtomicU32,
+    /// Next slot to be applied by the PLC.
+    tail: AtomicU32,
+}
+
+#[repr(C)]
+struct Slot {
+    /// Ring index in the upper, and state in the lower half.
+    state: AtomicU64,
+    addr: u32,
+    len: u32,
+    data: [u8; SLOT_DATA],
+    mask: [u8; SLOT_DATA],
+}
+
+fn image_offset() -> usize {
+    std::mem::size_of::<Header>()
+}
+
+fn ring_offset(image_size: usize) -> usize {
+    (image_offset() + image_size + 7) & !7
+}
+
+fn segment_size(image_size: usize) -> usize {
+    ring_offset(image_size) + RING_SLOTS as usize * std::mem::size_of::<Slot>()
+}
+
+fn c_name(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid segment name"))
+}
+
+/// A mapping of a segment.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    /// Layout of the segment; not read from the header after creating or
+    /// opening, since other processes can change it.
+    image_size: usize,
+    ring_slots: u32,
+}
+
+// SAFETY: the mapping is only accessed through atomics, or with the
+// protocol described in the module docs
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(fd: i32, len: usize, image_size: usize) -> io::Result<Self> {
+        // SAFETY: mapping a file descriptor we own, with a checked size
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED, fd, 0)
+        };
+        let err = io::Error::last_os_error();
+        // SAFETY: the mapping keeps its own reference to the segment
+        unsafe { libc::close(fd) };
+        if ptr == libc::MAP_FAILED {
+            return Err(err);
+        }
+        Ok(Self { ptr: ptr as *mut u8, len, image_size, ring_slots: RING_SLOTS })
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY: the segment starts with a header, and is page aligned
+        unsafe { &*(self.ptr as *const Header) }
+    }
+
+    fn image(&self) -> *mut u8 {
+        // SAFETY: the image follows the header within the mapping
+        unsafe { self.ptr.add(image_offset()) }
+    }
+
+    fn slot(&self, index: u32) -> *mut Slot {
+        let slot = (index % self.ring_slots) as usize;
+        // SAFETY: the ring follows the image, with `ring_slots` slots
+        unsafe { self.ptr.add(ring_offset(self.image_size)).cast::<Slot>().add(slot) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: unmapping our own mapping
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// The PLC side of the segment.
+pub(crate) struct Segment {
+    map: Mapping,
+    name: CString,
+    /// The ring index that was first seen claimed, but not ready, and when.
+    waiting: Cell<Option<(u32, Instant)>>,
+}
+
+impl Segment {
+    /// Create the segment, replacing an existing one of the same name.
+    pub fn create(name: &str, image_size: usize) -> io::Result<Self> {
+        let name = c_name(name)?;
+        let len = segment_size(image_size);
+        // SAFETY: plain libc calls with a valid C string
+        let fd = unsafe {
+            libc::shm_unlink(name.as_ptr());
+            libc::shm_open(name.as_ptr(), libc::O_CREAT | libc::O_EXCL | libc::O_RDWR, 0o660)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: resizing the segment we just created
+        if unsafe { libc::ftruncate(fd, len as libc::off_t) } != 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        let map = Mapping::new(fd, len, image_size)?;
+        // SAFETY: nobody else can use the segment before the magic is set,
+        // and the new segment is zero filled
+        unsafe {
+            let header = map.ptr as *mut Header;
+            (*header).version = VERSION;
+            (*header).image_size = image_size as u32;
+            (*header).ring_slots = RING_SLOTS;
+            (*header).slot_data = SLOT_DATA as u32;
+            for index in 0..RING_SLOTS {
+                (*map.slot(index)).state.store(claimable(index), Ordering::Relaxed);
+            }
+        }
+        fence(Ordering::Release);
+        unsafe { (*(map.ptr as *mut Header)).magic = MAGIC };
+        Ok(Self { map, name, waiting: Cell::new(None) })
+    }
+
+    /// Copy the image into the segment.
+    pub fn publish(&self, image: &[u8]) {
+        let header = self.map.header();
+        let seq = header.seq.load(Ordering::Relaxed);
+        header.seq.store(seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        // SAFETY: the image area has the size of the image
+        unsafe { ptr::copy_nonoverlapping(image.as_ptr(), self.map.image(), image.len()) };
+        header.seq.store(seq + 2, Ordering::Release);
+        header.cycle.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take the next ready write request, if any.
+    pub fn next_write(&self) -> Option<(usize, Vec<u8>, Vec<u8>)> {
+        let header = self.map.header();
+        let tail = header.tail.load(Ordering::Relaxed);
+        if tail == header.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = self.map.slot(tail);
+        // SAFETY: the slot belongs to us once it is marked ready
+        unsafe {
+            let state = (*slot).state.load(Ordering::Acquire);
+            if state != slot_state(tail, SLOT_READY) {
+                self.check_stale(tail, state);
+                return None;
+            }
+            let len = ((*slot).len as usize).min(SLOT_DATA);
+            let write = ((*slot).addr as usize, (&(*slot).data)[..len].to_vec(),
+                         (&(*slot).mask)[..len].to_vec());
+            (*slot).state.store(slot_state(tail, SLOT_FREE), Ordering::Relaxed);
+            header.tail.store(tail.wrapping_add(1), Ordering::Release);
+            Some(write)
+        }
+    }
+
+    /// Skip the slot at the tail if its writer didn't finish in time.
+    fn check_stale(&self, tail: u32, state: u64) {
+        match self.waiting.get() {
+            Some((index, since)) if index == tail => if since.elapsed() < STALE_CLAIM {
+                return;
+            }
+            _ => return self.waiting.set(Some((tail, Instant::now()))),
+        }
+        let slot = self.map.slot(tail);
+        // SAFETY: the state is atomic; if the writer marks the slot ready in
+        // the meantime, it is applied in the next cycle instead
+        let freed = unsafe {
+            (*slot).state.compare_exchange(state, slot_state(tail, SLOT_FREE),
+                                           Ordering::AcqRel, Ordering::Relaxed)
+        };
+        if freed.is_ok() {
+            warn!("shared memory write slot {} was not filled in, skipping it", tail);
+            self.map.header().tail.store(tail.wrapping_add(1), Ordering::Release);
+            self.reject();
+        }
+    }
+
+    pub fn reject(&self) {
+        self.map.header().rejected.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        // SAFETY: plain libc call with a valid C string
+        unsafe { libc::shm_unlink(self.name.as_ptr()) };
+    }
+}
+
+/// Access to the extern image of a PLC running in another process.
+pub struct ShmClient {
+    map: Mapping,
+}
+
+impl ShmClient {
+    /// Map the segment with the given name (as given to
+    /// `PlcBuilder::shared_memory`).
+    pub fn open(name: &str) -> io::Result<Self> {
+        let name = c_name(name)?;
+        // SAFETY: plain libc call with a valid C string
+        let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_RDWR, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fstat fills in the zeroed struct
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        let len = stat.st_size as usize;
+        if len < image_offset() {
+            unsafe { libc::close(fd) };
+            return Err(io::Error::new(ErrorKind::InvalidData, "segment too small"));
+        }
+        let mut map = Mapping::new(fd, len, 0)?;
+        let header = map.header();
+        if header.magic != MAGIC || header.version != VERSION {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a PLC image segment"));
+        }
+        let image_size = header.image_size as usize;
+        if header.slot_data as usize != SLOT_DATA || header.ring_slots != RING_SLOTS ||
+            segment_size(image_size) > len
+        {
+            return Err(io::Error::new(ErrorKind::InvalidData, "segment layout mismatch"));
+        }
+        map.image_size = image_size;
+        Ok(Self { map })
+    }
+
+    /// Size of the extern image.
+    pub fn image_size(&self) -> usize {
+        self.map.image_size
+    }
+
+    /// Number of cycles published so far.
+    pub fn cycle(&self) -> u64 {
+        self.map.header().cycle.load(Ordering::Acquire)
+    }
+
+    /// Number of write requests rejected by the PLC so far.
+    pub fn rejected(&self) -> u64 {
+        self.map.header().rejected.load(Ordering::Relaxed)
+    }
+
+    /// Copy a consistent snapshot of the image into `buf`, which must have
+    /// the size of the image.
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<()> {
+        if buf.len() != self.image_size() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "buffer size mismatch"));
+        }
+        let header = self.map.header();
+        loop {
+            let seq = header.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            // SAFETY: the image area has the size of the image; torn
+            // copies are detected by the sequence counter
+            unsafe { ptr::copy_nonoverlapping(self.map.image(), buf.as_mut_ptr(), buf.len()) };
+            fence(Ordering::Acquire);
+            if header.seq.load(Ordering::Relaxed) == seq {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Read a snapshot of the image as the extern image type of the PLC.
+    pub fn read_image<E: ExternImage>(&self) -> io::Result<E> {
+        if E::size() != self.image_size() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "image type size mismatch"));
+        }
+        let mut image = E::default();
+        self.read(image.cast())?;
+        Ok(image)
+    }
+
+    /// Request a write of `data` to the image at `addr`.  It is applied at
+    /// the end of the current PLC cycle.
+    pub fn write(&self, addr: usize, data: &[u8]) -> io::Result<()> {
+        self.write_masked(addr, data, &vec![0xFF; data.len()])
+    }
+
+    /// Request a write, changing only the bits set in `mask`.
+    pub fn write_masked(&self, addr: usize, data: &[u8], mask: &[u8]) -> io::Result<()> {
+        if data.len() > SLOT_DATA || mask.len() != data.len() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "invalid write size"));
+        }
+        if addr + data.len() > self.image_size() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "write outside of image"));
+        }
+        let header = self.map.header();
+        let mut head = header.head.load(Ordering::Relaxed);
+        loop {
+            let tail = header.tail.load(Ordering::Acquire);
+            if head.wrapping_sub(tail) >= RING_SLOTS {
+                return Err(io::Error::new(ErrorKind::WouldBlock, "write ring is full"));
+            }
+            match header.head.compare_exchange_weak(head, head.wrapping_add(1),
+                                                    Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        let slot = self.map.slot(head);
+        let claimed = slot_state(head, SLOT_CLAIMED);
+        // SAFETY: the claimed slot belongs to us until it is marked ready,
+        // or the PLC has skipped it
+        unsafe {
+            if (*slot).state.compare_exchange(claimable(head), claimed,
+                                              Ordering::Acquire, Ordering::Relaxed).is_err() {
+                return Err(io::Error::new(ErrorKind::TimedOut, "write slot was skipped by the PLC"));
+            }
+            (*slot).addr = addr as u32;
+            (*slot).len = data.len() as u32;
+            (&mut (*slot).data)[..data.len()].copy_from_slice(data);
+            (&mut (*slot).mask)[..mask.len()].copy_from_slice(mask);
+            if (*slot).state.compare_exchange(claimed, slot_state(head, SLOT_READY),
+                                              Ordering::Release, Ordering::Relaxed).is_err() {
+                return Err(io::Error::new(ErrorKind::TimedOut, "write slot was skipped by the PLC"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_claim_is_skipped() {
+        let name = format!("/ethercat-plc-test-{}", std::process::id());
+        let segment = Segment::create(&name, 16).unwrap();
+        let client = ShmClient::open(&name).unwrap();
+        client.write(0, &[1]).unwrap();
+        // a writer that claims a slot and dies before filling it in
+        client.map.header().head.fetch_add(1, Ordering::AcqRel);
+        client.write(1, &[2]).unwrap();
+
+        assert_eq!(segment.next_write(), Some((0, vec![1], vec![0xFF])));
+        assert_eq!(segment.next_write(), None);
+        assert_eq!(segment.next_write(), None);
+        assert_eq!(client.rejected(), 0);
+
+        let since = segment.waiting.get().unwrap().1;
+        segment.waiting.set(Some((1, since - STALE_CLAIM)));
+        assert_eq!(segment.next_write(), None);
+        assert_eq!(client.rejected(), 1);
+        assert_eq!(segment.next_write(), Some((1, vec![2], vec![0xFF])));
+        assert_eq!(segment.next_write(), None);
+
+        // the dead writer can't use the slot anymore
+        let slot = client.map.slot(1);
+        let claim = unsafe {
+            (*slot).state.compare_exchange(claimable(1), slot_state(1, SLOT_CLAIMED),
+                                           Ordering::Acquire, Ordering::Relaxed)
+        };
+        assert!(claim.is_err());
+    }
+}
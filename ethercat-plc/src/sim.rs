@@ -0,0 +1,245 @@
+// Part of ethercat-rs. Copyright 2018-2024 by the authors.
+// This work is dual-licensed under Apache 2.0 and MIT terms.
+
+//! Declarative description of a simulated plant.
+//!
+//! A wiring file connects variables of the process image, named by their
+//! dotted paths, so that outputs written by the PLC program show up on
+//! inputs in the next cycle.  Each non-empty line has the form
+//!
+//! ```text
+//! # comment
+//! ana_out.ch1 -> ana_in.ch1  gain=0.5 offset=10 delay=20 noise=2.5
+//! dig_out.output -> dig_in.input
+//! 1234 -> ana_in.ch2
+//! ```
+//!
+//! The source can be a variable or a decimal constant.  `gain` and `offset`
+//! are applied as `gain * source + offset`, `delay` is given in cycles, and
+//! `noise` is the standard deviation of gaussian noise added to the result.
+
+use std::{collections::VecDeque, fs, path::Path};
+use anyhow::{anyhow, bail, Context};
+
+use crate::image::{ProcessImage, VarInfo};
+
+#[derive(Debug)]
+enum Source {
+    Var(VarInfo),
+    Const(f64),
+}
+
+#[derive(Debug)]
+struct Link {
+    source: Source,
+    target: VarInfo,
+    gain: f64,
+    offset: f64,
+    noise: f64,
+    delay: usize,
+    history: VecDeque<f64>,
+}
+
+/// Whether a source is a plain decimal number like `-12.5`, and not a name
+/// such as `inf` that `f64` would also parse.
+fn is_number(source: &str) -> bool {
+    let digits = source.strip_prefix('-').unwrap_or(source);
+    digits.starts_with(|c: char| c.is_ascii_digit()) &&
+        digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+/// A set of links between process image variables, applied once per cycle.
+#[derive(Debug, Default)]
+pub struct Wiring {
+    links: Vec<Link>,
+    rng: u64,
+}
+
+impl Wiring {
+    /// Load a wiring description for the process image `P` from a file.
+    pub fn load<P: ProcessImage>(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("reading wiring file {}", path.display()))?;
+        Self::parse::<P>(&text)
+            .with_context(|| format!("parsing wiring file {}", path.display()))
+    }
+
+    /// Parse a wiring description for the process image `P`.
+    pub fn parse<P: ProcessImage>(text: &str) -> anyhow::Result<Self> {
+        let vars = P::image_vars();
+        let find = |name: &str| vars.iter().find(|v| v.name == name).cloned()
+            .ok_or_else(|| anyhow!("unknown variable {:?}", name));
+
+        let mut links = Vec::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let link = (|| {
+                let (source, rest) = line.split_once("->")
+                    .ok_or_else(|| anyhow!("missing '->'"))?;
+                let mut parts = rest.split_whitespace();
+                let target = find(parts.next().ok_or_else(|| anyhow!("missing target"))?)?;
+                let source = source.trim();
+                let source = match source.parse() {
+                    Ok(value) if is_number(source) => Source::Const(value),
+                    _ => Source::Var(find(source)?),
+                };
+                let mut link = Link { source, target, gain: 1.0, offset: 0.0, noise: 0.0,
+                                      delay: 0, history: VecDeque::new() };
+                for option in parts {
+                    let (key, value) = option.split_once('=')
+                        .ok_or_else(|| anyhow!("invalid option {:?}", option))?;
+                    let invalid = || anyhow!("invalid value for {}: {:?}", key, value);
+                    match key {
+                        "gain" => link.gain = value.parse().map_err(|_| invalid())?,
+                        "offset" => link.offset = value.parse().map_err(|_| invalid())?,
+                        "noise" => link.noise = value.parse().map_err(|_| invalid())?,
+                        "delay" => link.delay = value.parse().map_err(|_| invalid())?,
+                        _ => bail!("unknown option {:?}", key),
+                    }
+                }
+                Ok(link)
+            })().with_context(|| format!("line {}", lineno + 1))?;
+            links.push(link);
+        }
+        Ok(Wiring { links, rng: 0x2545_f491_4f6c_dd1d })
+    }
+
+    /// Apply all links to the raw process image data.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        // first sample all sources, so that the result doesn't depend on
+        // the order of links
+        let samples = self.links.iter().map(|link| match &link.source {
+            Source::Var(var) => var.read_f64(data),
+            Source::Const(value) => *value,
+        }).collect::<Vec<_>>();
+
+        for (i, sample) in samples.into_iter().enumerate() {
+            let noise = if self.links[i].noise != 0.0 {
+                self.links[i].noise * self.gaussian()
+            } else {
+                0.0
+            };
+            let link = &mut self.links[i];
+            link.history.push_back(sample);
+            if link.history.len() <= link.delay {
+                continue;
+            }
+            let value = link.history.pop_front().unwrap();
+            link.target.write_f64(data, link.gain * value + link.offset + noise);
+        }
+    }
+
+    /// A cheap xorshift generator is good enough for simulated noise.
+    fn uniform(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn gaussian(&mut self) -> f64 {
+        // Box-Muller transform
+        let u1 = self.uniform().max(f64::MIN_POSITIVE);
+        let u2 = self.uniform();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beckhoff::{EL1008, EL2008, EL3104, EL4132};
+    use ethercat_derive::ProcessImage;
+
+    #[repr(C, packed)]
+    #[derive(ProcessImage)]
+    struct Plant {
+        ana_in: EL3104,
+        ana_out: EL4132,
+        dig_in: EL1008,
+        dig_out: EL2008,
+    }
+
+    fn var(name: &str) -> VarInfo {
+        Plant::image_vars().into_iter().find(|v| v.name == name).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let wiring = Wiring::parse::<Plant>("
+            # comment
+            ana_out.ch1 -> ana_in.ch1  gain=0.5 offset=10 delay=2 noise=2.5  # more
+
+            -12.5 -> ana_in.ch2
+            dig_out.output -> dig_in.input
+        ").unwrap();
+        assert_eq!(wiring.links.len(), 3);
+        let link = &wiring.links[0];
+        assert!(matches!(&link.source, Source::Var(v) if v.name == "ana_out.ch1"));
+        assert_eq!(link.target.name, "ana_in.ch1");
+        assert_eq!((link.gain, link.offset, link.delay, link.noise), (0.5, 10.0, 2, 2.5));
+        assert!(matches!(wiring.links[1].source, Source::Const(v) if v == -12.5));
+        assert!(matches!(&wiring.links[2].source, Source::Var(v) if v.name == "dig_out.output"));
+
+        for (text, error) in [
+            ("ana_out.ch1 ana_in.ch1", "line 1: missing '->'"),
+            ("\nana_out.ch1 ->", "line 2: missing target"),
+            ("ana_out.ch9 -> ana_in.ch1", "line 1: unknown variable \"ana_out.ch9\""),
+            ("1 -> ana_in.ch1 gain", "line 1: invalid option \"gain\""),
+            ("1 -> ana_in.ch1 gain=x", "line 1: invalid value for gain: \"x\""),
+            ("1 -> ana_in.ch1 delay=-1", "line 1: invalid value for delay: \"-1\""),
+            ("1 -> ana_in.ch1 phase=1", "line 1: unknown option \"phase\""),
+            // only plain numbers are constants
+            ("inf -> ana_in.ch1", "line 1: unknown variable \"inf\""),
+            ("nan -> ana_in.ch1", "line 1: unknown variable \"nan\""),
+            ("1e3 -> ana_in.ch1", "line 1: unknown variable \"1e3\""),
+            ("1.2.3 -> ana_in.ch1", "line 1: unknown variable \"1.2.3\""),
+        ] {
+            let err = Wiring::parse::<Plant>(text).unwrap_err();
+            assert_eq!(format!("{:#}", err), error);
+        }
+    }
+
+    #[test]
+    fn apply() {
+        let mut wiring = Wiring::parse::<Plant>("
+            ana_out.ch1 -> ana_in.ch1 gain=0.5 offset=10 delay=2
+            ana_in.ch1 -> ana_out.ch2
+            7 -> ana_in.ch2
+            dig_out.output -> dig_in.input
+        ").unwrap();
+        let mut data = vec![0; Plant::size()];
+        let (out1, in1, out2) = (var("ana_out.ch1"), var("ana_in.ch1"), var("ana_out.ch2"));
+        let mut seen = Vec::new();
+        for cycle in 0..5 {
+            out1.write_f64(&mut data, 100.0 * cycle as f64);
+            var("dig_out.output").write_f64(&mut data, cycle as f64);
+            wiring.apply(&mut data);
+            seen.push((in1.read_f64(&data), out2.read_f64(&data)));
+            assert_eq!(var("ana_in.ch2").read_f64(&data), 7.0);
+            assert_eq!(var("dig_in.input").read_f64(&data), cycle as f64);
+        }
+        // delayed by two cycles, and sources are sampled before any writes
+        assert_eq!(seen, [(0.0, 0.0), (0.0, 0.0), (10.0, 0.0), (60.0, 10.0), (110.0, 60.0)]);
+    }
+
+    #[test]
+    fn noise() {
+        let mut wiring = Wiring::parse::<Plant>("1000 -> ana_in.ch1 noise=10").unwrap();
+        let mut data = vec![0; Plant::size()];
+        let samples = (0..2000).map(|_| {
+            wiring.apply(&mut data);
+            var("ana_in.ch1").read_f64(&data)
+        }).collect::<Vec<_>>();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let var = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / samples.len() as f64;
+        assert!((mean - 1000.0).abs() < 1.0, "mean {}", mean);
+        assert!((var.sqrt() - 10.0).abs() < 1.0, "deviation {}", var.sqrt());
+    }
+}
+
@@ -1,7 +1,8 @@
 // Part of ethercat-rs. Copyright 2018-2024 by the authors.
 // This work is dual-licensed under Apache 2.0 and MIT terms.
 
-use ethercat_plc::{PlcBuilder, ProcessImage, ExternImage, TcpServer, ModbusHandler};
+use ethercat_plc::{PlcBuilder, ProcessImage, ExternImage, ImageVars, TcpServer, TcpConfig,
+                   ModbusHandler, ModbusConfig, RegisterOrder, WordOrder, SecopHandler};
 use ethercat_plc::beckhoff::*;
 use ethercat_plc::mlz_spec::*;
 
@@ -33,16 +34,19 @@ struct Image {
 }
 
 #[repr(C)]
-#[derive(Default)]
+#[derive(Default, ImageVars)]
 struct Indexer {
     request: u16,
+    #[plc(read_only)]
     data: [u16; 17],
 }
 
 #[repr(C)]
 #[derive(Default, ExternImage)]
 struct Extern {
+    #[plc(read_only)]
     magic: f32,
+    #[plc(read_only)]
     offset: u16,
     indexer: Indexer,
     if_blink: DiscreteOutput,
@@ -234,16 +238,26 @@ fn main() {
     let mut config = std::collections::HashMap::new();
     config.insert("motor_current", Box::new(750u16) as Box<dyn ethercat::SdoData>);
 
-    let mut plc = PlcBuilder::new("plc")
+    // big-endian registers, as Modbus clients expect (this is the default,
+    // WordOrder::Native gives the raw image bytes as in earlier versions)
+    let modbus_config = ModbusConfig {
+        order: RegisterOrder { default: WordOrder::Abcd, fields: vec![] },
+        .. Default::default()
+    };
+
+    let mut plc = PlcBuilder::new(PLC_NAME)
+        .version(PLC_VERSION)
         .cycle_freq(100)
         .with_server("0.0.0.0:5020")
+        .server_config(TcpConfig { handler: modbus_config, .. Default::default() })
+        .add_server::<TcpServer<SecopHandler>>("0.0.0.0:10767", Default::default())
         .logging_cfg(None, false)
         .build::<Image, Extern, _, TcpServer<ModbusHandler>>(config).unwrap();
 
     let mut globals = Globals::default();
     globals.devices = vec![
-        DeviceInfo { typcode: 0x1E03, name: "Blink", offset: 42, .. Default::default() },
-        DeviceInfo { typcode: 0x3008, name: "Magnet", unit: 0x0007,
+        DeviceInfo { typcode: DISCRETE_OUTPUT, name: "Blink", offset: 42, .. Default::default() },
+        DeviceInfo { typcode: FLAT_OUTPUT_1, name: "Magnet", unit: 0x0007,
                      params: [0x3c, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
                      aux: &["output disabled", "emergency shutdown"],
                      absmin: -15.0, absmax: 15.0, .. Default::default() },